@@ -0,0 +1,215 @@
+//! Loading configuration while tolerating settings the program doesn't know
+use log::warn;
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+use std::error::Error;
+use std::io::Read;
+
+/// Field named by serde's "unknown field" error `message`, with the fields expected instead
+fn unknown_field(message: &str) -> Option<(String, Vec<String>)> {
+    let rest = message.strip_prefix("unknown field `")?;
+    let mut names = rest.split('`').step_by(2).filter(|name| !name.is_empty());
+    let field = names.next()?.to_owned();
+    Some((field, names.map(String::from).collect()))
+}
+
+/// Paths of the objects in `value` with a member `field`, scored by how well they fit `expected`
+///
+/// Each member listed in `expected` counts for the object, each other one against it.
+fn holders(
+    value: &Value,
+    field: &str,
+    expected: &[String],
+    path: &mut Vec<String>,
+    found: &mut Vec<(i64, Vec<String>)>,
+) {
+    match value {
+        Value::Object(members) => {
+            if members.contains_key(field) {
+                let score = members
+                    .keys()
+                    .filter(|key| *key != field)
+                    .map(|key| if expected.contains(key) { 1 } else { -1 })
+                    .sum();
+                found.push((score, path.clone()));
+            }
+            for (key, member) in members {
+                path.push(key.to_owned());
+                holders(member, field, expected, path, found);
+                path.pop();
+            }
+        }
+        Value::Array(entries) => {
+            for (index, entry) in entries.iter().enumerate() {
+                path.push(index.to_string());
+                holders(entry, field, expected, path, found);
+                path.pop();
+            }
+        }
+        _ => (),
+    }
+}
+
+/// The object at `path` within `value`, list entries are addressed by their index
+fn object_mut<'a>(value: &'a mut Value, path: &[String]) -> Option<&'a mut Map<String, Value>> {
+    let mut value = value;
+    for segment in path {
+        value = match value {
+            Value::Object(members) => members.get_mut(segment)?,
+            Value::Array(entries) => entries.get_mut(segment.parse::<usize>().ok()?)?,
+            _ => return None,
+        };
+    }
+    value.as_object_mut()
+}
+
+/// Deserialize `value` like a config file, ignoring keys unknown to `T`
+///
+/// Returns the dotted paths of the ignored keys along with the config, so they can be warned
+/// about. Whenever deserializing fails on an unknown field, it is removed from the objects that
+/// best fit the fields serde expected there, and deserializing is tried again. Nested settings
+/// that are not set by default and list entries are covered that way, too.
+pub fn from_value_lenient<T: DeserializeOwned>(
+    mut value: Value,
+) -> Result<(T, Vec<String>), serde_json::Error> {
+    if value.is_null() {
+        value = Value::Object(Default::default());
+    }
+    let mut removed = Vec::new();
+    loop {
+        let err = match serde_json::from_value(value.clone()) {
+            Ok(config) => return Ok((config, removed)),
+            Err(err) => err,
+        };
+        let (field, expected) = match unknown_field(&err.to_string()) {
+            Some(unknown) => unknown,
+            None => return Err(err),
+        };
+        let mut found = Vec::new();
+        holders(&value, &field, &expected, &mut Vec::new(), &mut found);
+        let best = match found.iter().map(|(score, _)| *score).max() {
+            Some(best) => best,
+            None => return Err(err),
+        };
+        for (_, path) in found.into_iter().filter(|(score, _)| *score == best) {
+            if let Some(members) = object_mut(&mut value, &path) {
+                members.remove(&field);
+                removed.push(
+                    path.iter()
+                        .chain([&field])
+                        .cloned()
+                        .collect::<Vec<_>>()
+                        .join("."),
+                );
+            }
+        }
+    }
+}
+
+/// Parse a YAML config from `reader`, ignoring unknown keys if `lenient`
+///
+/// Returns the dotted paths of the ignored keys with the config, for `warn_ignored` once logging
+/// is set up.
+pub fn from_reader<T: DeserializeOwned>(
+    reader: impl Read,
+    lenient: bool,
+) -> Result<(T, Vec<String>), Box<dyn Error>> {
+    if !lenient {
+        return Ok((serde_yaml::from_reader(reader)?, Vec::new()));
+    }
+    let value: Value = serde_yaml::from_reader(reader)?;
+    Ok(from_value_lenient(value)?)
+}
+
+/// Log a warning for each config key `from_reader` ignored
+pub fn warn_ignored(keys: &[String]) {
+    for key in keys {
+        warn!("Ignoring unknown config key {}", key);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_derive::{Deserialize, Serialize};
+    use serde_json::json;
+
+    #[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+    #[serde(deny_unknown_fields, default)]
+    struct Inner {
+        port: u16,
+        hosts: Vec<String>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+    #[serde(deny_unknown_fields, default)]
+    struct Limit {
+        burst: u32,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+    #[serde(deny_unknown_fields, default)]
+    struct Config {
+        name: String,
+        inner: Inner,
+        optional: Option<Inner>,
+        limit: Option<Limit>,
+        list: Vec<Inner>,
+    }
+
+    #[test]
+    fn unknown_keys_are_ignored() {
+        let value = json!({
+            "name": "x",
+            "nmae": "typo",
+            "inner": {"port": 80, "new_setting": true},
+            "optional": {"port": 1},
+        });
+        assert!(serde_json::from_value::<Config>(value.clone()).is_err());
+
+        let (config, removed) = from_value_lenient::<Config>(value).unwrap();
+        assert_eq!(removed, ["inner.new_setting", "nmae"]);
+        assert_eq!(config.name, "x");
+        assert_eq!(config.inner.port, 80);
+        assert_eq!(config.optional.unwrap().port, 1);
+    }
+
+    #[test]
+    fn unknown_keys_of_unset_settings_are_ignored() {
+        let value = json!({
+            "limit": {"brust": 5},
+            "optional": {"port": 1, "prot": 2},
+            "list": [{"port": 1}, {"hosts": ["a"], "port": 2, "extra": 1}],
+        });
+        let (config, removed) = from_value_lenient::<Config>(value).unwrap();
+        assert_eq!(removed, ["limit.brust", "list.1.extra", "optional.prot"]);
+        assert_eq!(config.limit, Some(Limit::default()));
+        assert_eq!(config.list[1].port, 2);
+        assert_eq!(config.optional.unwrap().port, 1);
+    }
+
+    #[test]
+    fn known_keys_are_kept() {
+        let (config, removed) = from_value_lenient::<Config>(Value::Null).unwrap();
+        assert_eq!(config, Config::default());
+        assert!(removed.is_empty());
+
+        // only the misplaced key is removed, not the known one of the same name
+        let value = json!({"inner": {"port": 1}, "limit": {"port": 2, "burst": 3}});
+        let (config, removed) = from_value_lenient::<Config>(value).unwrap();
+        assert_eq!(removed, ["limit.port"]);
+        assert_eq!(config.inner.port, 1);
+
+        // values of the wrong type still fail
+        assert!(from_value_lenient::<Config>(json!({"inner": {"port": "x"}})).is_err());
+    }
+
+    #[test]
+    fn yaml_is_read_strictly_unless_lenient() {
+        let yaml = "name: x\ninner:\n  prot: 1\n";
+        assert!(from_reader::<Config>(yaml.as_bytes(), false).is_err());
+        let (config, removed) = from_reader::<Config>(yaml.as_bytes(), true).unwrap();
+        assert_eq!(config.name, "x");
+        assert_eq!(removed, ["inner.prot"]);
+    }
+}
@@ -0,0 +1,182 @@
+//! Retrying database calls that failed for transient reasons
+use log::warn;
+use postgres::error::SqlState;
+use std::fmt::Display;
+use std::thread;
+use std::time::Duration;
+
+/// Errors that may go away when trying again
+pub trait Retryable {
+    fn is_retryable(&self) -> bool;
+}
+
+impl Retryable for postgres::Error {
+    /// Lost connections and errors the server reports as temporary
+    ///
+    /// Errors without SQL state are either I/O errors or a closed connection, everything else
+    /// (like syntax errors or constraint violations) fails the same way every time.
+    fn is_retryable(&self) -> bool {
+        match self.code() {
+            Some(code) => is_retryable_state(code),
+            None => {
+                self.is_closed()
+                    || std::error::Error::source(self)
+                        .map(|source| source.is::<std::io::Error>())
+                        .unwrap_or(false)
+            }
+        }
+    }
+}
+
+/// Connection exceptions, serialization failures, deadlocks, lack of resources and restarts
+pub fn is_retryable_state(code: &SqlState) -> bool {
+    let code = code.code();
+    code.starts_with("08")
+        || code.starts_with("53")
+        || [
+            SqlState::T_R_SERIALIZATION_FAILURE.code(),
+            SqlState::T_R_DEADLOCK_DETECTED.code(),
+            SqlState::ADMIN_SHUTDOWN.code(),
+            SqlState::CRASH_SHUTDOWN.code(),
+            SqlState::CANNOT_CONNECT_NOW.code(),
+        ]
+        .contains(&code)
+}
+
+/// Exponentially growing delays between attempts
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    /// Number of attempts, including the first one
+    pub attempts: u32,
+    /// Delay after the first failure, doubled after each further one
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            attempts: 5,
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(10),
+        }
+    }
+}
+
+impl Backoff {
+    /// Delay before attempt number `attempt` (1 based, the first retry being 2)
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(2));
+        self.initial_delay
+            .saturating_mul(factor)
+            .min(self.max_delay)
+    }
+}
+
+/// Call `f` until it succeeds, fails permanently or `backoff.attempts` are used up
+///
+/// Returns the last error in the latter two cases.
+pub fn with_retry<T, E, F>(backoff: &Backoff, mut f: F) -> Result<T, E>
+where
+    E: Retryable + Display,
+    F: FnMut() -> Result<T, E>,
+{
+    let mut attempt = 1;
+    loop {
+        match f() {
+            Err(err) if err.is_retryable() && attempt < backoff.attempts => {
+                attempt += 1;
+                let delay = backoff.delay(attempt);
+                warn!("{}, retrying in {:?}", err, delay);
+                thread::sleep(delay);
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fmt;
+
+    #[derive(Debug, PartialEq)]
+    struct Error(bool);
+
+    impl Retryable for Error {
+        fn is_retryable(&self) -> bool {
+            self.0
+        }
+    }
+
+    impl fmt::Display for Error {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "retryable: {}", self.0)
+        }
+    }
+
+    fn no_delay(attempts: u32) -> Backoff {
+        Backoff {
+            attempts,
+            initial_delay: Duration::ZERO,
+            max_delay: Duration::ZERO,
+        }
+    }
+
+    #[test]
+    fn classify_sql_states() {
+        assert!(is_retryable_state(&SqlState::CONNECTION_FAILURE));
+        assert!(is_retryable_state(&SqlState::CONNECTION_EXCEPTION));
+        assert!(is_retryable_state(&SqlState::TOO_MANY_CONNECTIONS));
+        assert!(is_retryable_state(&SqlState::T_R_SERIALIZATION_FAILURE));
+        assert!(is_retryable_state(&SqlState::ADMIN_SHUTDOWN));
+        assert!(is_retryable_state(&SqlState::CANNOT_CONNECT_NOW));
+
+        assert!(!is_retryable_state(&SqlState::SYNTAX_ERROR));
+        assert!(!is_retryable_state(&SqlState::UNDEFINED_TABLE));
+        assert!(!is_retryable_state(&SqlState::UNIQUE_VIOLATION));
+        assert!(!is_retryable_state(&SqlState::INSUFFICIENT_PRIVILEGE));
+    }
+
+    #[test]
+    fn retries_transient_errors() {
+        let mut calls = 0;
+        let result = with_retry(&no_delay(5), || {
+            calls += 1;
+            if calls < 3 {
+                Err(Error(true))
+            } else {
+                Ok(calls)
+            }
+        });
+        assert_eq!(result, Ok(3));
+
+        let mut calls = 0;
+        let result: Result<(), _> = with_retry(&no_delay(4), || {
+            calls += 1;
+            Err(Error(true))
+        });
+        assert_eq!(result, Err(Error(true)));
+        assert_eq!(calls, 4);
+    }
+
+    #[test]
+    fn permanent_errors_fail_at_once() {
+        let mut calls = 0;
+        let result: Result<(), _> = with_retry(&no_delay(5), || {
+            calls += 1;
+            Err(Error(false))
+        });
+        assert_eq!(result, Err(Error(false)));
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn delays_grow_up_to_max() {
+        let backoff = Backoff::default();
+        assert_eq!(backoff.delay(2), Duration::from_millis(100));
+        assert_eq!(backoff.delay(3), Duration::from_millis(200));
+        assert_eq!(backoff.delay(5), Duration::from_millis(800));
+        assert_eq!(backoff.delay(40), Duration::from_secs(10));
+    }
+}
@@ -1,10 +1,23 @@
 use serde_json::{json, Map, Value};
 use std::fmt;
+use std::str::FromStr;
 use time::{macros::format_description, OffsetDateTime};
 
-use crate::serde::de::rfc3339;
+use crate::serde::de::lenient_timestamp;
 
-#[derive(PartialEq, Eq, Debug)]
+/// A number or name that is no valid syslog severity or facility
+#[derive(Debug, PartialEq, Eq)]
+pub struct InvalidValue(pub String);
+
+impl std::error::Error for InvalidValue {}
+
+impl fmt::Display for InvalidValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid value {}", self.0)
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum SyslogSeverity {
     Emergency = 0,
@@ -37,7 +50,47 @@ impl fmt::Display for SyslogSeverity {
     }
 }
 
-#[derive(PartialEq, Eq, Debug)]
+impl SyslogSeverity {
+    /// All severities, ordered by their numerical value
+    pub const ALL: [SyslogSeverity; 8] = {
+        use SyslogSeverity::*;
+        [
+            Emergency, Alert, Critical, Error, Warning, Notice, Info, Debug,
+        ]
+    };
+
+    pub fn as_u8(self) -> u8 {
+        self as u8
+    }
+}
+
+impl TryFrom<u8> for SyslogSeverity {
+    type Error = InvalidValue;
+
+    fn try_from(value: u8) -> Result<Self, InvalidValue> {
+        Self::ALL
+            .get(value as usize)
+            .copied()
+            .ok_or_else(|| InvalidValue(value.to_string()))
+    }
+}
+
+/// Parses names as written by `Display` as well as numerical values
+impl FromStr for SyslogSeverity {
+    type Err = InvalidValue;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(value) = s.parse::<u8>() {
+            return Self::try_from(value);
+        }
+        Self::ALL
+            .into_iter()
+            .find(|severity| severity.to_string() == s)
+            .ok_or_else(|| InvalidValue(s.to_string()))
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum SyslogFacility {
     Kern = 0,
@@ -102,6 +155,69 @@ impl fmt::Display for SyslogFacility {
     }
 }
 
+impl SyslogFacility {
+    /// All facilities, ordered by their numerical value
+    pub const ALL: [SyslogFacility; 24] = {
+        use SyslogFacility::*;
+        [
+            Kern,
+            User,
+            Mail,
+            Daemon,
+            Auth,
+            Syslog,
+            Lpr,
+            News,
+            Uucp,
+            Cron,
+            Authpriv,
+            Ftp,
+            Ntp,
+            Security,
+            Console,
+            SolarisCron,
+            Local0,
+            Local1,
+            Local2,
+            Local3,
+            Local4,
+            Local5,
+            Local6,
+            Local7,
+        ]
+    };
+
+    pub fn as_u8(self) -> u8 {
+        self as u8
+    }
+}
+
+impl TryFrom<u8> for SyslogFacility {
+    type Error = InvalidValue;
+
+    fn try_from(value: u8) -> Result<Self, InvalidValue> {
+        Self::ALL
+            .get(value as usize)
+            .copied()
+            .ok_or_else(|| InvalidValue(value.to_string()))
+    }
+}
+
+/// Parses names as written by `Display` as well as numerical values
+impl FromStr for SyslogFacility {
+    type Err = InvalidValue;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(value) = s.parse::<u8>() {
+            return Self::try_from(value);
+        }
+        Self::ALL
+            .into_iter()
+            .find(|facility| facility.to_string() == s)
+            .ok_or_else(|| InvalidValue(s.to_string()))
+    }
+}
+
 mod severity_serde {
     use super::*;
     use serde::{de::Error, Deserialize, Deserializer};
@@ -110,19 +226,12 @@ mod severity_serde {
     where
         D: Deserializer<'de>,
     {
-        use SyslogSeverity::*;
         let value = String::deserialize(d)?;
-        match value.as_ref() {
-            "0" => Ok(Emergency),
-            "1" => Ok(Alert),
-            "2" => Ok(Critical),
-            "3" => Ok(Error),
-            "4" => Ok(Warning),
-            "5" => Ok(Notice),
-            "6" => Ok(Info),
-            "7" => Ok(Debug),
-            o => Err(D::Error::custom(format_args!("Invalid value {}", o))),
-        }
+        value
+            .parse::<u8>()
+            .map_err(|_| InvalidValue(value.clone()))
+            .and_then(SyslogSeverity::try_from)
+            .map_err(D::Error::custom)
     }
 }
 
@@ -134,72 +243,117 @@ mod facility_serde {
     where
         D: Deserializer<'de>,
     {
-        use SyslogFacility::*;
         let value = String::deserialize(d)?;
-        match value.as_ref() {
-            "0" => Ok(Kern),
-            "1" => Ok(User),
-            "2" => Ok(Mail),
-            "3" => Ok(Daemon),
-            "4" => Ok(Auth),
-            "5" => Ok(Syslog),
-            "6" => Ok(Lpr),
-            "7" => Ok(News),
-            "8" => Ok(Uucp),
-            "9" => Ok(Cron),
-            "10" => Ok(Authpriv),
-            "11" => Ok(Ftp),
-            "12" => Ok(Ntp),
-            "13" => Ok(Security),
-            "14" => Ok(Console),
-            "15" => Ok(SolarisCron),
-            "16" => Ok(Local0),
-            "17" => Ok(Local1),
-            "18" => Ok(Local2),
-            "19" => Ok(Local3),
-            "20" => Ok(Local4),
-            "21" => Ok(Local5),
-            "22" => Ok(Local6),
-            "23" => Ok(Local7),
-            o => Err(D::Error::custom(format_args!("Invalid value {}", o))),
+        value
+            .parse::<u8>()
+            .map_err(|_| InvalidValue(value.clone()))
+            .and_then(SyslogFacility::try_from)
+            .map_err(D::Error::custom)
+    }
+}
+
+/// How a severity or facility is written to its field
+#[derive(serde_derive::Deserialize, serde_derive::Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CodeName {
+    /// "info", "daemon"
+    Lowercase,
+    /// "INFO", "DAEMON"
+    Uppercase,
+    /// 6, 3
+    Number,
+}
+
+impl CodeName {
+    fn value(self, name: String, number: u8) -> Value {
+        match self {
+            Self::Lowercase => name.into(),
+            Self::Uppercase => name.to_uppercase().into(),
+            Self::Number => number.into(),
         }
     }
 }
 
+/// Representations of severity and facility in the event document
+///
+/// The defaults keep the names, plus the severity's number in "syslogseverity_num".
+#[derive(serde_derive::Deserialize, serde_derive::Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(deny_unknown_fields, default)]
+pub struct SyslogFields {
+    /// value of "syslogseverity"
+    pub severity: CodeName,
+    /// also store the severity's number as "syslogseverity_num"
+    pub severity_num: bool,
+    /// value of "syslogfacility"
+    pub facility: CodeName,
+    /// also store the facility's number as "syslogfacility_num"
+    pub facility_num: bool,
+}
+
+impl Default for SyslogFields {
+    fn default() -> Self {
+        Self {
+            severity: CodeName::Lowercase,
+            severity_num: true,
+            facility: CodeName::Lowercase,
+            facility_num: false,
+        }
+    }
+}
+
+/// rsyslog time stamp used as `Event.timestamp`, which selects the event's partition
+#[derive(
+    serde_derive::Deserialize, serde_derive::Serialize, Debug, Clone, Copy, PartialEq, Eq, Default,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum EventTime {
+    /// time reported by the sender
+    #[default]
+    Timereported,
+    /// time rsyslog received the event, for senders with wrong clocks
+    Timegenerated,
+}
+
 /// log event formatted by rsyslog's "jsonmesg" property
+///
+/// Only msg, timereported, timegenerated, hostname, syslogseverity and syslogfacility are
+/// mandatory, `Event::from_rsyslogd` fills in the others if they are missing.
 #[derive(serde_derive::Deserialize, Debug)]
 pub struct RsyslogdEvent {
     /// log message string
     msg: String,
 
     /// complete raw syslog message
-    /// currently unused
-    // rawmsg: String,
+    /// only kept if requested, see `Event::from_rsyslogd`
+    rawmsg: Option<String>,
 
     /// report time of the device sending this message
-    #[serde(deserialize_with = "rfc3339")]
+    #[serde(deserialize_with = "lenient_timestamp")]
     timereported: OffsetDateTime,
 
     /// time stamp when rsyslog generated this message object
-    #[serde(deserialize_with = "rfc3339")]
+    #[serde(deserialize_with = "lenient_timestamp")]
     timegenerated: OffsetDateTime,
 
     /// host name from the message
     hostname: String,
 
-    /// tag of this message
-    syslogtag: String,
+    /// tag of this message, empty if missing
+    #[serde(default)]
+    syslogtag: Option<String>,
 
-    /// rsyslog input module which received this message
-    inputname: String,
+    /// rsyslog input module which received this message, "-" if missing
+    #[serde(default)]
+    inputname: Option<String>,
 
     /// host name of the sender that this message was received from (last hop before "our" rsyslog
-    /// instance
-    fromhost: String,
+    /// instance), "hostname" if missing
+    #[serde(default)]
+    fromhost: Option<String>,
 
-    /// IP address of "fromhost"
-    #[serde(rename = "fromhost-ip")]
-    fromhost_ip: String,
+    /// IP address of "fromhost", "-" if missing
+    #[serde(rename = "fromhost-ip", default)]
+    fromhost_ip: Option<String>,
 
     /// raw "PRI" of this message
     /// currently unused
@@ -213,21 +367,22 @@ pub struct RsyslogdEvent {
     #[serde(with = "facility_serde")]
     syslogfacility: SyslogFacility,
 
-    /// part of the tag before the optional pid
-    programname: String,
+    /// part of the tag before the optional pid, taken from "syslogtag" if missing
+    #[serde(default)]
+    programname: Option<String>,
 
-    /// syslog "PROTOCOL-VERSION"
-    #[serde(rename = "protocol-version")]
-    protocol_version: String, // <-- TODO: parse::<u8>()
+    /// syslog "PROTOCOL-VERSION", "0" if missing
+    #[serde(rename = "protocol-version", default)]
+    protocol_version: Option<String>, // <-- TODO: parse::<u8>()
 
     /// syslog "STRUCTURED-DATA"
     /// currently unused
     // #[serde(rename = "structured-data")]
     // structured_data: String, // <-- TODO: Value?
 
-    /// syslog "APP-NAME"
-    #[serde(rename = "app-name")]
-    app_name: String,
+    /// syslog "APP-NAME", "programname" if missing
+    #[serde(rename = "app-name", default)]
+    app_name: Option<String>,
 
     /// syslog "PROCID"
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -252,9 +407,14 @@ pub struct Event {
     pub doc: Value,
 }
 
-const FTS_FIELDS: &[&str] = &["hostname", "syslogtag", "msg"];
+/// Fields whose values make up the full text search document
+pub const FTS_FIELDS: &[&str] = &["hostname", "syslogtag", "msg"];
 
 impl Event {
+    pub fn builder() -> EventBuilder {
+        EventBuilder::default()
+    }
+
     pub fn search_string(&self) -> String {
         let mut parts = Vec::new();
         self.doc.as_object().unwrap().iter().for_each(|pair| {
@@ -268,26 +428,32 @@ impl Event {
     }
 
     pub fn get_printable(&self, index: &str) -> Option<String> {
-        if let Some(value) = self.doc.get(index) {
-            match value {
-                Value::String(s) => Some(s.as_str().to_string()),
-                Value::Array(_) => Some(flatten(value)),
-                Value::Bool(true) => Some("true".to_string()),
-                Value::Bool(false) => Some("false".to_string()),
-                Value::Null => Some("null".to_string()),
-                Value::Number(n) => Some(format!("{}", n)),
-                Value::Object(_) => Some(flatten(value)),
-            }
-        } else {
-            None
-        }
+        self.doc.get(index).map(printable)
     }
 }
 
-fn flatten(value: &Value) -> String {
+/// `value` as shown to users, nested values flattened to `key=value` pairs
+pub fn printable(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.as_str().to_string(),
+        Value::Array(_) => flatten(value),
+        Value::Bool(true) => "true".to_string(),
+        Value::Bool(false) => "false".to_string(),
+        Value::Null => "null".to_string(),
+        Value::Number(n) => format!("{}", n),
+        Value::Object(_) => flatten(value),
+    }
+}
+
+/// Object with the nested members of `value` moved to dotted keys, like "vars.<path>"
+pub fn flattened(value: &Value) -> Value {
     let mut unnested = Value::Object(Map::new());
     flatten_value(value, &mut unnested, "".to_string(), ".");
     unnested
+}
+
+fn flatten(value: &Value) -> String {
+    flattened(value)
         .as_object()
         .unwrap()
         .iter()
@@ -313,42 +479,139 @@ fn flatten_value(value: &Value, target: &mut Value, prefix: String, separator: &
     };
 }
 
+/// Builds an `Event` with the same document layout as events received from rsyslog
+#[derive(Debug, Clone)]
+pub struct EventBuilder {
+    timestamp: OffsetDateTime,
+    doc: Value,
+}
+
+impl Default for EventBuilder {
+    fn default() -> Self {
+        Self {
+            timestamp: OffsetDateTime::now_utc(),
+            doc: Value::Object(Map::new()),
+        }
+    }
+}
+
+impl EventBuilder {
+    /// Time stamp of the event, defaults to the current time
+    pub fn timestamp(mut self, timestamp: OffsetDateTime) -> Self {
+        self.timestamp = timestamp;
+        self
+    }
+
+    /// Set a single document field
+    pub fn field<T: serde::Serialize>(mut self, name: &str, value: T) -> Self {
+        self.doc[name] = json!(value);
+        self
+    }
+
+    /// Set a document field if `value` is not `None`
+    pub fn optional_field<T: serde::Serialize>(self, name: &str, value: Option<T>) -> Self {
+        match value {
+            Some(value) => self.field(name, value),
+            None => self,
+        }
+    }
+
+    /// Add message variables, flattened to "vars.<path>" fields
+    pub fn vars(mut self, vars: &Value) -> Self {
+        flatten_value(vars, &mut self.doc, "vars".to_string(), ".");
+        self
+    }
+
+    pub fn build(self) -> Event {
+        Event {
+            timestamp: self.timestamp,
+            doc: self.doc,
+        }
+    }
+}
+
 impl From<RsyslogdEvent> for Event {
     fn from(event: RsyslogdEvent) -> Self {
-        let mut doc = json!({
-            "msg": event.msg,
-            "timereported": event.timereported,
-            "timegenerated": event.timegenerated,
-            "hostname": event.hostname,
-            "inputname": event.inputname,
-            "syslogtag": event.syslogtag,
-            "fromhost": event.fromhost,
-            "fromhost_ip": event.fromhost_ip,
-            "syslogfacility": event.syslogfacility.to_string(),
-            "syslogseverity": event.syslogseverity.to_string(),
-            "programname": event.programname,
-            "procid": event.procid,
-            "protocol_version": event.protocol_version,
-            "app_name": event.app_name,
+        Event::from_rsyslogd(event, false, &SyslogFields::default(), EventTime::default())
+    }
+}
+
+impl Event {
+    /// Convert rsyslog's event, storing its raw message as `rawmsg` if `include_rawmsg` is set
+    ///
+    /// Severity and facility are stored as configured in `fields`, `time` selects the timestamp.
+    pub fn from_rsyslogd(
+        event: RsyslogdEvent,
+        include_rawmsg: bool,
+        fields: &SyslogFields,
+        time: EventTime,
+    ) -> Self {
+        let rawmsg = if include_rawmsg { event.rawmsg } else { None };
+        let syslogtag = event.syslogtag.unwrap_or_default();
+        let programname = event.programname.unwrap_or_else(|| {
+            syslogtag
+                .split(['[', ':'])
+                .next()
+                .unwrap_or_default()
+                .to_string()
         });
+        let app_name = event.app_name.unwrap_or_else(|| programname.clone());
+        let fromhost = event.fromhost.unwrap_or_else(|| event.hostname.clone());
+        let timestamp = match time {
+            EventTime::Timereported => event.timereported,
+            EventTime::Timegenerated => event.timegenerated,
+        };
+        let mut builder = Event::builder()
+            .timestamp(timestamp)
+            .field("msg", event.msg)
+            .field("timereported", event.timereported)
+            .field("timegenerated", event.timegenerated)
+            .field("hostname", event.hostname)
+            .field("inputname", event.inputname.as_deref().unwrap_or("-"))
+            .field("syslogtag", syslogtag)
+            .field("fromhost", fromhost)
+            .field("fromhost_ip", event.fromhost_ip.as_deref().unwrap_or("-"))
+            .field(
+                "syslogfacility",
+                fields.facility.value(
+                    event.syslogfacility.to_string(),
+                    event.syslogfacility.as_u8(),
+                ),
+            )
+            .field(
+                "syslogseverity",
+                fields.severity.value(
+                    event.syslogseverity.to_string(),
+                    event.syslogseverity.as_u8(),
+                ),
+            )
+            .optional_field(
+                "syslogseverity_num",
+                fields.severity_num.then(|| event.syslogseverity.as_u8()),
+            )
+            .optional_field(
+                "syslogfacility_num",
+                fields.facility_num.then(|| event.syslogfacility.as_u8()),
+            )
+            .field("programname", programname)
+            .field("procid", event.procid)
+            .field(
+                "protocol_version",
+                event.protocol_version.as_deref().unwrap_or("0"),
+            )
+            .field("app_name", app_name);
         // Some field were left out do reduce duplication:
-        // * rawmsg
+        // * rawmsg (unless requested)
         // * pri
         // * structured_data
         if let Some(vars) = event.message_variables {
-            flatten_value(&vars, &mut doc, "vars".to_string(), ".");
-        }
-        if let Some(msgid) = event.msgid {
-            doc["msgid"] = msgid.into();
-        }
-        if let Some(uuid) = event.uuid {
-            doc["uuid"] = uuid.into();
-        }
-
-        Event {
-            timestamp: event.timereported,
-            doc,
+            builder = builder.vars(&vars);
         }
+        builder
+            .optional_field("msgid", event.msgid)
+            .optional_field("uuid", event.uuid)
+            .optional_field("rawmsg", rawmsg)
+            .build()
     }
 }
 
@@ -371,3 +634,216 @@ impl fmt::Display for Event {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use time::macros::datetime;
+
+    const RSYSLOG_EVENT: &str = r#"{
+        "msg": " some message",
+        "rawmsg": "<30>Mar  4 05:06:07 host1 prog[123]: some message",
+        "timereported": "2022-03-04T05:06:07.123+01:00",
+        "hostname": "host1",
+        "syslogtag": "prog[123]:",
+        "inputname": "imuxsock",
+        "fromhost": "host1",
+        "fromhost-ip": "127.0.0.1",
+        "pri": "30",
+        "syslogfacility": "3",
+        "syslogseverity": "6",
+        "timegenerated": "2022-03-04T05:06:08.456+01:00",
+        "programname": "prog",
+        "protocol-version": "0",
+        "structured-data": "-",
+        "app-name": "prog",
+        "procid": "123",
+        "msgid": "-",
+        "uuid": null,
+        "$!": {"src": {"ip": "10.1.2.3", "port": 1234}, "tag": "x"}
+    }"#;
+
+    #[test]
+    fn builder_matches_rsyslog_event() {
+        let parsed: Event = serde_json::from_str::<RsyslogdEvent>(RSYSLOG_EVENT)
+            .unwrap()
+            .into();
+
+        let timereported = datetime!(2022-03-04 05:06:07.123 +1);
+        let built = Event::builder()
+            .timestamp(timereported)
+            .field("msg", " some message")
+            .field("timereported", timereported)
+            .field("timegenerated", datetime!(2022-03-04 05:06:08.456 +1))
+            .field("hostname", "host1")
+            .field("inputname", "imuxsock")
+            .field("syslogtag", "prog[123]:")
+            .field("fromhost", "host1")
+            .field("fromhost_ip", "127.0.0.1")
+            .field("syslogfacility", SyslogFacility::Daemon.to_string())
+            .field("syslogseverity", SyslogSeverity::Info.to_string())
+            .field("syslogseverity_num", 6)
+            .field("programname", "prog")
+            .field("procid", "123")
+            .field("protocol_version", "0")
+            .field("app_name", "prog")
+            .field("msgid", "-")
+            .vars(&json!({"src": {"ip": "10.1.2.3", "port": 1234}, "tag": "x"}))
+            .build();
+
+        assert_eq!(built.timestamp, parsed.timestamp);
+        assert_eq!(built.doc, parsed.doc);
+        assert_eq!(built.doc["vars.src.port"], json!(1234));
+        assert_eq!(built.search_string(), parsed.search_string());
+    }
+
+    #[test]
+    fn partial_rsyslog_event() {
+        let mut event: Value = serde_json::from_str(RSYSLOG_EVENT).unwrap();
+        let complete: Event = serde_json::from_value::<RsyslogdEvent>(event.clone())
+            .unwrap()
+            .into();
+        for field in ["inputname", "programname", "app-name", "fromhost"] {
+            event.as_object_mut().unwrap().remove(field);
+        }
+        let partial: Event = serde_json::from_value::<RsyslogdEvent>(event.clone())
+            .unwrap()
+            .into();
+        assert_eq!(partial.doc["inputname"], json!("-"));
+        // derived from syslogtag and hostname, the same as rsyslog sent them before
+        let mut expected = complete.doc;
+        expected["inputname"] = json!("-");
+        assert_eq!(partial.doc, expected);
+
+        event.as_object_mut().unwrap().remove("hostname");
+        let error = serde_json::from_value::<RsyslogdEvent>(event).unwrap_err();
+        assert_eq!(error.to_string(), "missing field `hostname`");
+    }
+
+    #[test]
+    fn include_rawmsg() {
+        let event = |include| {
+            let parsed = serde_json::from_str::<RsyslogdEvent>(RSYSLOG_EVENT).unwrap();
+            Event::from_rsyslogd(
+                parsed,
+                include,
+                &SyslogFields::default(),
+                EventTime::default(),
+            )
+        };
+        assert_eq!(event(false).doc.get("rawmsg"), None);
+        assert_eq!(
+            event(true).doc["rawmsg"],
+            json!("<30>Mar  4 05:06:07 host1 prog[123]: some message")
+        );
+        // everything else is unchanged
+        let mut with_rawmsg = event(true).doc;
+        with_rawmsg.as_object_mut().unwrap().remove("rawmsg");
+        assert_eq!(with_rawmsg, event(false).doc);
+    }
+
+    #[test]
+    fn syslog_field_representations() {
+        let event = |fields: SyslogFields| {
+            let parsed = serde_json::from_str::<RsyslogdEvent>(RSYSLOG_EVENT).unwrap();
+            let doc = Event::from_rsyslogd(parsed, false, &fields, EventTime::default()).doc;
+            [
+                "syslogseverity",
+                "syslogseverity_num",
+                "syslogfacility",
+                "syslogfacility_num",
+            ]
+            .map(|field| doc.get(field).cloned())
+        };
+
+        assert_eq!(
+            event(SyslogFields::default()),
+            [
+                Some(json!("info")),
+                Some(json!(6)),
+                Some(json!("daemon")),
+                None
+            ]
+        );
+        assert_eq!(
+            event(SyslogFields {
+                severity: CodeName::Uppercase,
+                facility: CodeName::Uppercase,
+                ..Default::default()
+            }),
+            [
+                Some(json!("INFO")),
+                Some(json!(6)),
+                Some(json!("DAEMON")),
+                None
+            ]
+        );
+        assert_eq!(
+            event(SyslogFields {
+                severity: CodeName::Number,
+                severity_num: false,
+                facility: CodeName::Number,
+                facility_num: false,
+            }),
+            [Some(json!(6)), None, Some(json!(3)), None]
+        );
+        assert_eq!(
+            event(SyslogFields {
+                facility_num: true,
+                ..Default::default()
+            }),
+            [
+                Some(json!("info")),
+                Some(json!(6)),
+                Some(json!("daemon")),
+                Some(json!(3))
+            ]
+        );
+    }
+
+    #[test]
+    fn builder_optional_fields() {
+        let event = Event::builder()
+            .optional_field("present", Some("yes"))
+            .optional_field::<&str>("absent", None)
+            .build();
+        assert_eq!(event.doc, json!({"present": "yes"}));
+    }
+
+    #[test]
+    fn severity_round_trip() {
+        for (value, severity) in (0..).zip(SyslogSeverity::ALL) {
+            assert_eq!(severity.as_u8(), value);
+            assert_eq!(SyslogSeverity::try_from(value), Ok(severity));
+            assert_eq!(severity.to_string().parse(), Ok(severity));
+            assert_eq!(value.to_string().parse(), Ok(severity));
+        }
+        assert_eq!("warning".parse(), Ok(SyslogSeverity::Warning));
+        assert!(SyslogSeverity::try_from(8).is_err());
+        assert!("warn".parse::<SyslogSeverity>().is_err());
+        assert!("-1".parse::<SyslogSeverity>().is_err());
+    }
+
+    #[test]
+    fn facility_round_trip() {
+        for (value, facility) in (0..).zip(SyslogFacility::ALL) {
+            assert_eq!(facility.as_u8(), value);
+            assert_eq!(SyslogFacility::try_from(value), Ok(facility));
+            assert_eq!(facility.to_string().parse(), Ok(facility));
+            assert_eq!(value.to_string().parse(), Ok(facility));
+        }
+        assert_eq!("local7".parse(), Ok(SyslogFacility::Local7));
+        assert!(SyslogFacility::try_from(24).is_err());
+        assert!("local8".parse::<SyslogFacility>().is_err());
+    }
+
+    #[test]
+    fn flattened_keys() {
+        let doc = json!({"host": "a", "vars": {"user": {"name": "x", "id": 1}, "ok": true}});
+        assert_eq!(
+            flattened(&doc),
+            json!({"host": "a", "vars.user.name": "x", "vars.user.id": 1, "vars.ok": true})
+        );
+        assert_eq!(flattened(&json!({})), json!({}));
+    }
+}
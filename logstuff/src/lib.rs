@@ -1,3 +1,9 @@
+pub mod config;
+pub mod db;
 pub mod event;
+pub mod logging;
+pub mod query;
 pub mod serde;
 pub mod tls;
+
+pub use query::query_events;
@@ -0,0 +1,100 @@
+//! Logger setup shared by the binaries
+use log::{Record, SetLoggerError};
+use serde_json::json;
+use std::io::Write;
+use std::{fmt, str::FromStr};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+/// How log lines are written to stderr
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormat {
+    /// env_logger's human readable lines
+    #[default]
+    Text,
+    /// One JSON object per line, for log aggregation
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("unknown log format '{}', use text or json", text)),
+        }
+    }
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Text => write!(f, "text"),
+            Self::Json => write!(f, "json"),
+        }
+    }
+}
+
+/// `record` as JSON object with time stamp, level, target, message and source location
+pub fn json_line(record: &Record, timestamp: OffsetDateTime) -> String {
+    json!({
+        "timestamp": timestamp.format(&Rfc3339).ok(),
+        "level": record.level().as_str(),
+        "target": record.target(),
+        "message": record.args().to_string(),
+        "module": record.module_path(),
+        "file": record.file(),
+        "line": record.line(),
+    })
+    .to_string()
+}
+
+/// Set up env_logger, configured by `RUST_LOG` as usual, to write lines in `format`
+pub fn init(format: LogFormat) -> Result<(), SetLoggerError> {
+    let mut builder = env_logger::Builder::from_default_env();
+    if format == LogFormat::Json {
+        builder.format(|buf, record| {
+            writeln!(buf, "{}", json_line(record, OffsetDateTime::now_utc()))
+        });
+    }
+    builder.try_init()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use log::Level;
+    use serde_json::Value;
+    use time::macros::datetime;
+
+    #[test]
+    fn json_lines() {
+        let line = json_line(
+            &Record::builder()
+                .args(format_args!("inserted {} \"events\"", 3))
+                .level(Level::Warn)
+                .target("stuffimport::app")
+                .line(Some(42))
+                .build(),
+            datetime!(2022-03-04 05:06:07 UTC),
+        );
+        let value: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["timestamp"], "2022-03-04T05:06:07Z");
+        assert_eq!(value["level"], "WARN");
+        assert_eq!(value["target"], "stuffimport::app");
+        assert_eq!(value["message"], "inserted 3 \"events\"");
+        assert_eq!(value["line"], 42);
+        assert_eq!(value["file"], Value::Null);
+        assert!(!line.contains('\n'));
+    }
+
+    #[test]
+    fn parse_format() {
+        assert_eq!("json".parse(), Ok(LogFormat::Json));
+        assert_eq!("text".parse(), Ok(LogFormat::Text));
+        assert!("xml".parse::<LogFormat>().is_err());
+        assert_eq!(LogFormat::Json.to_string(), "json");
+    }
+}
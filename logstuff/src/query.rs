@@ -0,0 +1,262 @@
+//! Reading stored events page by page
+use postgres::types::ToSql;
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::ops::Range;
+use time::OffsetDateTime;
+
+use crate::event::Event;
+
+/// Events fetched per query
+pub const PAGE_SIZE: i64 = 1000;
+
+/// Database access `query_events` needs, implemented by `postgres::Client`
+pub trait EventSource {
+    type Error;
+
+    /// Id, time stamp and document of the rows `query` selects with `params`
+    fn fetch(
+        &mut self,
+        query: &str,
+        params: &[&(dyn ToSql + Sync)],
+    ) -> Result<Vec<(i64, OffsetDateTime, Value)>, Self::Error>;
+}
+
+impl EventSource for postgres::Client {
+    type Error = postgres::Error;
+
+    fn fetch(
+        &mut self,
+        query: &str,
+        params: &[&(dyn ToSql + Sync)],
+    ) -> Result<Vec<(i64, OffsetDateTime, Value)>, Self::Error> {
+        Ok(self
+            .query(query, params)?
+            .iter()
+            .map(|row| (row.get(0), row.get(1), row.get(2)))
+            .collect())
+    }
+}
+
+/// Page of at most `$<offset + 5>` events in `table` matching `expr`, oldest first
+///
+/// The events lie within `$<offset + 1>` (inclusive) and `$<offset + 2>` (exclusive) and follow
+/// the event with time stamp `$<offset + 3>` and id `$<offset + 4>`.
+fn page_query(table: &str, expr: &str, offset: usize) -> String {
+    format!(
+        "select id::bigint, tstamp, doc from {} \
+         where ({}) and tstamp >= ${} and tstamp < ${} and (tstamp, id) > (${}, ${}::bigint) \
+         order by tstamp, id limit ${}",
+        table,
+        expr,
+        offset + 1,
+        offset + 2,
+        offset + 3,
+        offset + 4,
+        offset + 5
+    )
+}
+
+/// Iterator over the events of `query_events`, fetching a page whenever the last one is used up
+pub struct Events<'a, C: EventSource> {
+    client: &'a mut C,
+    query: String,
+    params: Vec<Value>,
+    range: Range<OffsetDateTime>,
+    /// time stamp and id of the last fetched event
+    after: (OffsetDateTime, i64),
+    page_size: i64,
+    page: VecDeque<Event>,
+    done: bool,
+}
+
+impl<'a, C: EventSource> Events<'a, C> {
+    /// Fetch this many events per query instead of `PAGE_SIZE`
+    pub fn with_page_size(mut self, page_size: i64) -> Self {
+        self.page_size = page_size.max(1);
+        self
+    }
+
+    fn fetch_page(&mut self) -> Result<(), C::Error> {
+        let mut params: Vec<&(dyn ToSql + Sync)> = self
+            .params
+            .iter()
+            .map(|value| value as &(dyn ToSql + Sync))
+            .collect();
+        params.push(&self.range.start);
+        params.push(&self.range.end);
+        params.push(&self.after.0);
+        params.push(&self.after.1);
+        params.push(&self.page_size);
+        let rows = self.client.fetch(&self.query, &params)?;
+        self.done = (rows.len() as i64) < self.page_size;
+        if let Some((id, timestamp, _)) = rows.last() {
+            self.after = (*timestamp, *id);
+        }
+        self.page = rows
+            .into_iter()
+            .map(|(_, timestamp, doc)| Event { timestamp, doc })
+            .collect();
+        Ok(())
+    }
+}
+
+impl<'a, C: EventSource> Iterator for Events<'a, C> {
+    type Item = Result<Event, C::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.page.is_empty() && !self.done {
+            if let Err(err) = self.fetch_page() {
+                self.done = true;
+                return Some(Err(err));
+            }
+        }
+        self.page.pop_front().map(Ok)
+    }
+}
+
+/// Events in `table` matching the SQL condition `expr` within `range`, oldest first
+///
+/// `expr` refers to the JSON values in `params` as `$1`, `$2`, ..., like the conditions
+/// logstuff-query builds. Events are fetched `PAGE_SIZE` at a time as the iterator advances,
+/// iteration ends after the first error.
+pub fn query_events<'a, C: EventSource>(
+    client: &'a mut C,
+    table: &str,
+    expr: &str,
+    params: &[Value],
+    range: Range<OffsetDateTime>,
+) -> Events<'a, C> {
+    Events {
+        client,
+        query: page_query(table, expr, params.len()),
+        params: params.to_vec(),
+        after: (range.start, i64::MIN),
+        range,
+        page_size: PAGE_SIZE,
+        page: VecDeque::new(),
+        done: false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+    use time::macros::datetime;
+    use time::Duration;
+
+    /// Serves `rows` in pages of the requested size, recording each query with its parameters
+    #[derive(Default)]
+    struct Mock {
+        rows: Vec<(i64, OffsetDateTime, Value)>,
+        fail_after: Option<usize>,
+        queries: Vec<(String, Vec<String>)>,
+    }
+
+    impl EventSource for Mock {
+        type Error = String;
+
+        fn fetch(
+            &mut self,
+            query: &str,
+            params: &[&(dyn ToSql + Sync)],
+        ) -> Result<Vec<(i64, OffsetDateTime, Value)>, String> {
+            let params: Vec<String> = params.iter().map(|param| format!("{:?}", param)).collect();
+            let offset = self.queries.len();
+            self.queries.push((query.into(), params.clone()));
+            if self.fail_after == Some(offset) {
+                return Err("connection lost".into());
+            }
+            let page_size: usize = params[params.len() - 1].parse().unwrap();
+            Ok(self
+                .rows
+                .iter()
+                .skip(offset * page_size)
+                .take(page_size)
+                .cloned()
+                .collect())
+        }
+    }
+
+    fn mock(count: i64) -> Mock {
+        let start = datetime!(2022-01-01 00:00 UTC);
+        Mock {
+            rows: (1..=count)
+                .map(|id| (id, start + Duration::seconds(id), json!({ "n": id })))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn day() -> Range<OffsetDateTime> {
+        datetime!(2022-01-01 00:00 UTC)..datetime!(2022-01-02 00:00 UTC)
+    }
+
+    #[test]
+    fn page_statement() {
+        assert_eq!(
+            page_query("logs", "doc @> $1", 1),
+            "select id::bigint, tstamp, doc from logs \
+             where (doc @> $1) and tstamp >= $2 and tstamp < $3 \
+             and (tstamp, id) > ($4, $5::bigint) order by tstamp, id limit $6"
+        );
+    }
+
+    #[test]
+    fn events_of_all_pages() {
+        let mut client = mock(5);
+        let params = [json!({"host": "a"})];
+        let events: Vec<Event> = query_events(&mut client, "logs", "doc @> $1", &params, day())
+            .with_page_size(2)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(events.len(), 5);
+        assert_eq!(events[0].doc, json!({"n": 1}));
+        assert_eq!(events[4].timestamp, datetime!(2022-01-01 00:00:05 UTC));
+
+        // pages of 2, 2 and 1 events, each following the last event of the one before
+        assert_eq!(client.queries.len(), 3);
+        let (query, params) = &client.queries[0];
+        assert_eq!(query, &page_query("logs", "doc @> $1", 1));
+        assert_eq!(params.len(), 6);
+        assert_eq!(params[4], i64::MIN.to_string());
+        let (_, params) = &client.queries[2];
+        assert_eq!(
+            params[3],
+            format!("{:?}", datetime!(2022-01-01 00:00:04 UTC))
+        );
+        assert_eq!(params[4], "4");
+    }
+
+    #[test]
+    fn full_last_page_needs_another_query() {
+        let mut client = mock(4);
+        let count = query_events(&mut client, "logs", "1 = 1", &[], day())
+            .with_page_size(2)
+            .count();
+        assert_eq!(count, 4);
+        assert_eq!(client.queries.len(), 3);
+
+        let mut client = mock(0);
+        assert_eq!(
+            query_events(&mut client, "logs", "1 = 1", &[], day()).count(),
+            0
+        );
+        assert_eq!(client.queries.len(), 1);
+    }
+
+    #[test]
+    fn iteration_ends_after_errors() {
+        let mut client = Mock {
+            fail_after: Some(1),
+            ..mock(5)
+        };
+        let results: Vec<_> = query_events(&mut client, "logs", "1 = 1", &[], day())
+            .with_page_size(2)
+            .collect();
+        assert_eq!(results.len(), 3);
+        assert!(results[..2].iter().all(Result::is_ok));
+        assert_eq!(results[2].as_ref().unwrap_err(), "connection lost");
+    }
+}
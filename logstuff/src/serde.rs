@@ -1,13 +1,250 @@
 pub mod de {
+    use log::warn;
     use serde::de::Deserialize as _;
     use serde::de::Error as _;
+    use std::fmt;
     use time::format_description::well_known::Rfc3339;
+    use time::format_description::FormatItem;
+    use time::macros::format_description;
     use time::OffsetDateTime;
 
+    /// Formats seen in the wild that are close to, but not quite RFC3339
+    const LENIENT_FORMATS: &[&[FormatItem]] = &[
+        format_description!(
+            "[year]-[month]-[day]T[hour]:[minute]:[second][optional [.[subsecond]]][offset_hour sign:mandatory][offset_minute]"
+        ),
+        format_description!(
+            "[year]-[month]-[day] [hour]:[minute]:[second][optional [.[subsecond]]][offset_hour sign:mandatory]:[offset_minute]"
+        ),
+        format_description!(
+            "[year]-[month]-[day] [hour]:[minute]:[second][optional [.[subsecond]]][offset_hour sign:mandatory][offset_minute]"
+        ),
+        format_description!(
+            "[year]-[month]-[day] [hour]:[minute]:[second][optional [.[subsecond]]][offset_hour sign:mandatory]"
+        ),
+    ];
+
+    /// Epoch values with an absolute value of at least this are interpreted as milliseconds
+    const EPOCH_MILLIS_THRESHOLD: f64 = 100_000_000_000.0;
+
     pub fn rfc3339<'de, D>(d: D) -> Result<OffsetDateTime, D::Error>
     where
         D: serde::de::Deserializer<'de>,
     {
         OffsetDateTime::parse(&String::deserialize(d)?, &Rfc3339).map_err(D::Error::custom)
     }
+
+    /// Parses RFC3339 and falls back to a few similar formats, keeping the time zone offset
+    pub fn lenient_timestamp<'de, D>(d: D) -> Result<OffsetDateTime, D::Error>
+    where
+        D: serde::de::Deserializer<'de>,
+    {
+        let value = String::deserialize(d)?;
+        parse_lenient(&value).map_err(D::Error::custom)
+    }
+
+    fn parse_lenient(value: &str) -> Result<OffsetDateTime, time::error::Parse> {
+        let err = match OffsetDateTime::parse(value, &Rfc3339) {
+            Ok(timestamp) => return Ok(timestamp),
+            Err(err) => err,
+        };
+        let timestamp = LENIENT_FORMATS
+            .iter()
+            .find_map(|format| OffsetDateTime::parse(value, format).ok())
+            .or_else(|| {
+                value.strip_suffix('Z').and_then(|v| {
+                    OffsetDateTime::parse(&format!("{}+00", v), LENIENT_FORMATS[3]).ok()
+                })
+            })
+            .ok_or(err)?;
+        warn!("timestamp '{}' is not RFC3339, parsed anyway", value);
+        Ok(timestamp)
+    }
+
+    /// Accepts either an RFC3339 string or a unix epoch (number or numeric string)
+    ///
+    /// Epoch values are seconds, unless they are too large to be a sensible number of seconds.
+    /// In that case they are taken as milliseconds.
+    pub fn rfc3339_or_epoch<'de, D>(d: D) -> Result<OffsetDateTime, D::Error>
+    where
+        D: serde::de::Deserializer<'de>,
+    {
+        d.deserialize_any(TimestampVisitor)
+    }
+
+    fn from_epoch<E: serde::de::Error>(epoch: f64) -> Result<OffsetDateTime, E> {
+        let nanos = if epoch.abs() >= EPOCH_MILLIS_THRESHOLD {
+            epoch * 1_000_000.0
+        } else {
+            epoch * 1_000_000_000.0
+        };
+        if !nanos.is_finite() {
+            return Err(E::custom(format_args!("invalid epoch {}", epoch)));
+        }
+        OffsetDateTime::from_unix_timestamp_nanos(nanos.round() as i128).map_err(E::custom)
+    }
+
+    fn from_epoch_int<E: serde::de::Error>(epoch: i64) -> Result<OffsetDateTime, E> {
+        let nanos = if (epoch as f64).abs() >= EPOCH_MILLIS_THRESHOLD {
+            i128::from(epoch) * 1_000_000
+        } else {
+            i128::from(epoch) * 1_000_000_000
+        };
+        OffsetDateTime::from_unix_timestamp_nanos(nanos).map_err(E::custom)
+    }
+
+    struct TimestampVisitor;
+
+    impl<'de> serde::de::Visitor<'de> for TimestampVisitor {
+        type Value = OffsetDateTime;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "an RFC3339 timestamp or a unix epoch")
+        }
+
+        fn visit_i64<E: serde::de::Error>(self, v: i64) -> Result<Self::Value, E> {
+            from_epoch_int(v)
+        }
+
+        fn visit_u64<E: serde::de::Error>(self, v: u64) -> Result<Self::Value, E> {
+            from_epoch_int(i64::try_from(v).map_err(E::custom)?)
+        }
+
+        fn visit_f64<E: serde::de::Error>(self, v: f64) -> Result<Self::Value, E> {
+            from_epoch(v)
+        }
+
+        fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<Self::Value, E> {
+            if let Ok(epoch) = v.parse::<i64>() {
+                from_epoch_int(epoch)
+            } else if let Ok(epoch) = v.parse::<f64>() {
+                from_epoch(epoch)
+            } else {
+                OffsetDateTime::parse(v, &Rfc3339).map_err(E::custom)
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+        use time::macros::datetime;
+
+        #[derive(serde_derive::Deserialize)]
+        struct Timestamp {
+            #[serde(deserialize_with = "rfc3339_or_epoch")]
+            t: OffsetDateTime,
+        }
+
+        fn parse(json: &str) -> Result<OffsetDateTime, serde_json::Error> {
+            serde_json::from_str::<Timestamp>(json).map(|ts| ts.t)
+        }
+
+        #[test]
+        fn rfc3339_strings() {
+            assert_eq!(
+                parse(r#"{"t": "2022-03-04T05:06:07Z"}"#).unwrap(),
+                datetime!(2022-03-04 05:06:07 UTC)
+            );
+            assert_eq!(
+                parse(r#"{"t": "2022-03-04T05:06:07+02:00"}"#).unwrap(),
+                datetime!(2022-03-04 05:06:07 +2)
+            );
+            assert!(parse(r#"{"t": "yesterday"}"#).is_err());
+        }
+
+        #[test]
+        fn subsecond_precision() {
+            #[derive(serde_derive::Deserialize)]
+            struct Strict {
+                #[serde(deserialize_with = "rfc3339")]
+                t: OffsetDateTime,
+            }
+
+            for (text, expected) in [
+                (
+                    "2024-01-01T00:00:00.123Z",
+                    datetime!(2024-01-01 00:00:00.123 UTC),
+                ),
+                (
+                    "2024-01-01T00:00:00.123456Z",
+                    datetime!(2024-01-01 00:00:00.123456 UTC),
+                ),
+                (
+                    "2024-01-01T01:00:00.000001+01:00",
+                    datetime!(2024-01-01 01:00:00.000001 +1),
+                ),
+            ] {
+                let json = format!(r#"{{"t": "{}"}}"#, text);
+                let strict = serde_json::from_str::<Strict>(&json).unwrap().t;
+                assert_eq!(strict, expected);
+                assert_eq!(parse(&json).unwrap(), expected);
+                assert_eq!(parse_lenient(text).unwrap(), expected);
+                assert_eq!(strict.format(&Rfc3339).unwrap(), text);
+            }
+            assert_eq!(
+                parse(r#"{"t": 1704067200123}"#).unwrap(),
+                datetime!(2024-01-01 00:00:00.123 UTC)
+            );
+        }
+
+        #[test]
+        fn lenient_formats() {
+            let expected = datetime!(2022-03-04 05:06:07 +2);
+            for value in [
+                "2022-03-04T05:06:07+02:00",
+                "2022-03-04T05:06:07+0200",
+                "2022-03-04 05:06:07+02:00",
+                "2022-03-04 05:06:07+0200",
+                "2022-03-04 05:06:07+02",
+            ] {
+                let parsed = parse_lenient(value).unwrap();
+                assert_eq!(parsed, expected);
+                assert_eq!(parsed.offset(), expected.offset());
+            }
+
+            assert_eq!(
+                parse_lenient("2022-03-04T05:06:07.123456-0130").unwrap(),
+                datetime!(2022-03-04 05:06:07.123456 -1:30)
+            );
+            assert_eq!(
+                parse_lenient("2022-03-04 05:06:07Z").unwrap(),
+                datetime!(2022-03-04 05:06:07 UTC)
+            );
+            assert!(parse_lenient("2022-03-04 05:06:07").is_err());
+            assert!(parse_lenient("Mar  4 05:06:07").is_err());
+        }
+
+        #[test]
+        fn epoch_seconds() {
+            assert_eq!(
+                parse(r#"{"t": 1646370367}"#).unwrap(),
+                datetime!(2022-03-04 05:06:07 UTC)
+            );
+            assert_eq!(
+                parse(r#"{"t": 1646370367.5}"#).unwrap(),
+                datetime!(2022-03-04 05:06:07.5 UTC)
+            );
+            assert_eq!(
+                parse(r#"{"t": "1646370367"}"#).unwrap(),
+                datetime!(2022-03-04 05:06:07 UTC)
+            );
+            assert_eq!(
+                parse(r#"{"t": -1}"#).unwrap(),
+                datetime!(1969-12-31 23:59:59 UTC)
+            );
+        }
+
+        #[test]
+        fn epoch_milliseconds() {
+            assert_eq!(
+                parse(r#"{"t": 1646370367123}"#).unwrap(),
+                datetime!(2022-03-04 05:06:07.123 UTC)
+            );
+            assert_eq!(
+                parse(r#"{"t": "1646370367123"}"#).unwrap(),
+                datetime!(2022-03-04 05:06:07.123 UTC)
+            );
+        }
+    }
 }
@@ -1,6 +1,9 @@
 use log::{debug, error, warn};
-use native_tls::{Identity, TlsConnector};
-use rustls::{Certificate, OwnedTrustAnchor, RootCertStore};
+use native_tls::{Identity, Protocol, TlsConnector};
+use rustls::{
+    Certificate, OwnedTrustAnchor, RootCertStore, SupportedCipherSuite, SupportedProtocolVersion,
+    ALL_CIPHER_SUITES, DEFAULT_CIPHER_SUITES,
+};
 use rustls_pemfile::{read_one, Item};
 use serde_derive::{Deserialize, Serialize};
 use std::{fmt, fs, io, iter};
@@ -12,33 +15,92 @@ pub enum Error {
     Io(std::io::Error),
     Tls(native_tls::Error),
     Rustls(rustls::Error),
+    UnknownCipherSuite(String),
+    /// A `ca_certs` file without any certificate
+    NoCertificates(String),
 }
 
 impl std::error::Error for Error {}
 
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub enum TlsVersion {
+    #[serde(rename = "TLSv1.0")]
+    Tls10,
+    #[serde(rename = "TLSv1.1")]
+    Tls11,
+    #[serde(rename = "TLSv1.2")]
+    Tls12,
+    #[serde(rename = "TLSv1.3")]
+    Tls13,
+}
+
+impl From<TlsVersion> for Protocol {
+    fn from(version: TlsVersion) -> Self {
+        match version {
+            TlsVersion::Tls10 => Protocol::Tlsv10,
+            TlsVersion::Tls11 => Protocol::Tlsv11,
+            TlsVersion::Tls12 => Protocol::Tlsv12,
+            TlsVersion::Tls13 => Protocol::Tlsv13,
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(deny_unknown_fields, default)]
 pub struct TlsSettings {
+    /// Connect without TLS if false, only meant for trusted servers (stuffstream rejects it)
+    pub enabled: bool,
     pub private_cert: String,
     pub private_key: String,
     pub ca_certs: Vec<String>,
     pub disable_system_trust: bool,
     pub accept_invalid_hostnames: bool,
+    pub min_protocol_version: TlsVersion,
+    /// Allowed cipher suites (rustls names, e.g. TLS13_AES_256_GCM_SHA384), empty for defaults
+    pub cipher_suites: Vec<String>,
 }
 
 impl Default for TlsSettings {
     fn default() -> Self {
         TlsSettings {
+            enabled: true,
             private_cert: "".into(),
             private_key: "".into(),
             ca_certs: Vec::new(),
             disable_system_trust: false,
             accept_invalid_hostnames: false,
+            min_protocol_version: TlsVersion::Tls12,
+            cipher_suites: Vec::new(),
         }
     }
 }
 
 impl TlsSettings {
+    /// Protocol versions enabled for rustls, which doesn't implement anything older than TLS 1.2
+    pub fn protocol_versions(&self) -> Vec<&'static SupportedProtocolVersion> {
+        let mut versions = vec![&rustls::version::TLS13];
+        if self.min_protocol_version <= TlsVersion::Tls12 {
+            versions.push(&rustls::version::TLS12);
+        }
+        versions
+    }
+
+    pub fn selected_cipher_suites(&self) -> Result<Vec<SupportedCipherSuite>, Error> {
+        if self.cipher_suites.is_empty() {
+            return Ok(DEFAULT_CIPHER_SUITES.to_vec());
+        }
+        self.cipher_suites
+            .iter()
+            .map(|name| {
+                ALL_CIPHER_SUITES
+                    .iter()
+                    .find(|suite| format!("{:?}", suite.suite()) == *name)
+                    .copied()
+                    .ok_or_else(|| Error::UnknownCipherSuite(name.to_owned()))
+            })
+            .collect()
+    }
+
     pub fn root_trust_store(&self) -> Result<RootCertStore, Error> {
         let mut root_store = RootCertStore::empty();
 
@@ -61,6 +123,7 @@ impl TlsSettings {
         Ok(root_store)
     }
 
+    /// All certificates of the `ca_certs` files, each may be a bundle of several certificates
     pub fn load_trusted_certs(&self) -> Result<Vec<Certificate>, Error> {
         let mut result = Vec::new();
         self.ca_certs
@@ -69,6 +132,7 @@ impl TlsSettings {
                 debug!("Adding trust anchors from {}", file);
                 let cert = fs::File::open(file)?;
                 let mut reader = io::BufReader::new(cert);
+                let loaded = result.len();
                 for item in iter::from_fn(|| read_one(&mut reader).transpose()) {
                     match item? {
                         Item::X509Certificate(cert) => {
@@ -79,6 +143,14 @@ impl TlsSettings {
                         }
                     };
                 }
+                if result.len() == loaded {
+                    return Err(Error::NoCertificates(file.to_owned()));
+                }
+                debug!(
+                    "Loaded {} certificates from {}",
+                    result.len() - loaded,
+                    file
+                );
                 Ok(())
             })?;
         Ok(result)
@@ -86,7 +158,9 @@ impl TlsSettings {
 
     pub fn client_config(&self) -> Result<ClientConfig, Error> {
         let builder = ClientConfig::builder()
-            .with_safe_defaults()
+            .with_cipher_suites(&self.selected_cipher_suites()?)
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(&self.protocol_versions())?
             .with_root_certificates(self.root_trust_store()?);
 
         if self.private_cert.is_empty() {
@@ -109,16 +183,14 @@ impl TlsSettings {
             connector.identity(Identity::from_pkcs12(&der, "")?);
         }
 
-        self.ca_certs
-            .iter()
-            .try_for_each(|file| -> Result<(), Error> {
-                debug!("Loading trusted certificate {}", file);
-                let cert = fs::read(file)?;
-                let cert = native_tls::Certificate::from_pem(&cert)?;
-                connector.add_root_certificate(cert);
-                Ok(())
-            })?;
+        for cert in self.load_trusted_certs()? {
+            connector.add_root_certificate(native_tls::Certificate::from_der(&cert.0)?);
+        }
 
+        if !self.cipher_suites.is_empty() {
+            warn!("Ignoring cipher suite selection, native-tls does not support it");
+        }
+        connector.min_protocol_version(Some(self.min_protocol_version.into()));
         connector.disable_built_in_roots(self.disable_system_trust);
         let connector = connector.build()?;
         debug!("TLS connector settings: {:?}", connector);
@@ -149,3 +221,116 @@ impl fmt::Display for Error {
         write!(f, "{:?}", self)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rustls::ProtocolVersion;
+
+    #[test]
+    fn default_minimum_is_tls12() {
+        let config = TlsSettings::default().client_config().unwrap();
+        assert!(config.supports_version(ProtocolVersion::TLSv1_3));
+        assert!(config.supports_version(ProtocolVersion::TLSv1_2));
+        assert!(TlsSettings::default().connector().is_ok());
+    }
+
+    #[test]
+    fn minimum_protocol_version() {
+        let settings = TlsSettings {
+            min_protocol_version: TlsVersion::Tls13,
+            ..Default::default()
+        };
+        let config = settings.client_config().unwrap();
+        assert!(config.supports_version(ProtocolVersion::TLSv1_3));
+        assert!(!config.supports_version(ProtocolVersion::TLSv1_2));
+        assert!(matches!(
+            Protocol::from(settings.min_protocol_version),
+            Protocol::Tlsv13
+        ));
+
+        let settings: TlsSettings =
+            serde_json::from_str(r#"{"min_protocol_version": "TLSv1.0"}"#).unwrap();
+        assert_eq!(settings.min_protocol_version, TlsVersion::Tls10);
+        assert_eq!(settings.protocol_versions().len(), 2);
+    }
+
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("logstuff-{}-{}", std::process::id(), name))
+            .to_string_lossy()
+            .into()
+    }
+
+    fn ca_pem() -> String {
+        let mut params = rcgen::CertificateParams::new(Vec::new());
+        params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        rcgen::Certificate::from_params(params)
+            .unwrap()
+            .serialize_pem()
+            .unwrap()
+    }
+
+    #[test]
+    fn certificate_bundles() {
+        let bundle = temp_path("bundle.pem");
+        let single = temp_path("single.pem");
+        let key = rcgen::generate_simple_self_signed(vec!["localhost".into()])
+            .unwrap()
+            .serialize_private_key_pem();
+        fs::write(&bundle, format!("{}{}\n{}", ca_pem(), key, ca_pem())).unwrap();
+        fs::write(&single, ca_pem()).unwrap();
+
+        let settings = TlsSettings {
+            ca_certs: vec![bundle.clone(), single.clone()],
+            disable_system_trust: true,
+            ..Default::default()
+        };
+        assert_eq!(settings.load_trusted_certs().unwrap().len(), 3);
+        assert_eq!(settings.root_trust_store().unwrap().len(), 3);
+        assert!(settings.connector().is_ok());
+
+        let empty = temp_path("empty.pem");
+        fs::write(&empty, key).unwrap();
+        let settings = TlsSettings {
+            ca_certs: vec![bundle.clone(), empty.clone()],
+            ..Default::default()
+        };
+        assert!(matches!(
+            settings.load_trusted_certs(),
+            Err(Error::NoCertificates(file)) if file == empty
+        ));
+
+        for file in [bundle, single, empty] {
+            fs::remove_file(file).unwrap();
+        }
+    }
+
+    #[test]
+    fn cipher_suites() {
+        let settings = TlsSettings {
+            cipher_suites: vec!["TLS13_AES_256_GCM_SHA384".into()],
+            ..Default::default()
+        };
+        let suites = settings.selected_cipher_suites().unwrap();
+        assert_eq!(suites.len(), 1);
+        assert_eq!(
+            format!("{:?}", suites[0].suite()),
+            "TLS13_AES_256_GCM_SHA384"
+        );
+
+        // only TLS 1.3 suites selected, TLS 1.2 can't be negotiated
+        let config = settings.client_config().unwrap();
+        assert!(config.supports_version(ProtocolVersion::TLSv1_3));
+        assert!(!config.supports_version(ProtocolVersion::TLSv1_2));
+
+        let settings = TlsSettings {
+            cipher_suites: vec!["TLS_RSA_WITH_RC4_128_MD5".into()],
+            ..Default::default()
+        };
+        assert!(matches!(
+            settings.client_config(),
+            Err(Error::UnknownCipherSuite(_))
+        ));
+    }
+}
@@ -61,12 +61,37 @@ pub fn parse_term(c: &mut Criterion) {
     });
 }
 
+pub fn to_sql_query(c: &mut Criterion) {
+    let p = query::ExpressionParser::new();
+    let deep_tree = (0..32)
+        .map(|i| format!(r#"(f{} = {} or not f{} like "x%")"#, i, i, i))
+        .collect::<Vec<String>>()
+        .join(" and ");
+    let cases = [
+        ("sql_simple_compare", r#"id = 42"#.to_string()),
+        ("sql_deep_tree", deep_tree),
+        (
+            "sql_in_list",
+            r#"id in (1, 2.2, "three", 4, 5.5, "six", 7, 8.8099001, "nine, I think")"#.to_string(),
+        ),
+        (
+            "sql_full_text_search",
+            r#""connection refused""#.to_string(),
+        ),
+    ];
+    for (name, text) in cases {
+        let expr = p.parse(&text).unwrap();
+        c.bench_function(name, |b| b.iter(|| black_box(&expr).to_sql_query(1)));
+    }
+}
+
 criterion_group!(
     benches,
     parse_expression,
     parse_identifier,
     parse_list,
     parse_scalar,
-    parse_term
+    parse_term,
+    to_sql_query
 );
 criterion_main!(benches);
@@ -0,0 +1,8 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|text: &str| {
+    if let Ok(expr) = logstuff_query::parse_expression(text) {
+        let _ = expr.to_sql_query(1);
+    }
+});
@@ -0,0 +1,167 @@
+//! `@name` shortcuts for stored sub-queries
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+use crate::ast::SemanticError;
+
+/// Alias names and the queries they stand for
+pub type Aliases = BTreeMap<String, String>;
+
+/// Positions and names of the `@name` references outside of quoted strings and comments
+fn references(text: &str) -> Vec<(Range<usize>, &str)> {
+    let mut found = Vec::new();
+    let mut quote = None;
+    let mut escaped = false;
+    let mut chars = text.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        match quote {
+            Some(_) if escaped => escaped = false,
+            Some(_) if c == '\\' => escaped = true,
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if matches!(c, '"' | '\'' | '`') => quote = Some(c),
+            None if c == '#' => {
+                while chars.next_if(|(_, c)| !matches!(c, '\n' | '\r')).is_some() {}
+            }
+            None if c == '@' => {
+                let mut end = start + 1;
+                while let Some((i, c)) =
+                    chars.next_if(|(_, c)| c.is_ascii_alphanumeric() || *c == '_')
+                {
+                    end = i + c.len_utf8();
+                }
+                if end > start + 1 {
+                    found.push((start..end, &text[start + 1..end]));
+                }
+            }
+            None => {}
+        }
+    }
+    found
+}
+
+/// `text` with every `@name` replaced by the parenthesized query stored for `name`
+///
+/// Expansion is not recursive, stored queries must not reference other aliases. The closing
+/// parenthesis goes on a line of its own, so a stored query may end in a `#` comment.
+pub fn expand<'a>(text: &'a str, aliases: &Aliases) -> Result<Cow<'a, str>, SemanticError> {
+    let references = references(text);
+    if references.is_empty() {
+        return Ok(Cow::Borrowed(text));
+    }
+    let mut expanded = String::with_capacity(text.len());
+    let mut copied = 0;
+    for (range, name) in references {
+        let query = aliases
+            .get(name)
+            .ok_or_else(|| SemanticError::new(format!("unknown alias @{}", name)))?;
+        if !self::references(query).is_empty() {
+            return Err(SemanticError::new(format!(
+                "alias @{} references another alias",
+                name
+            )));
+        }
+        expanded.push_str(&text[copied..range.start]);
+        expanded.push('(');
+        expanded.push_str(query);
+        expanded.push_str("\n)");
+        copied = range.end;
+    }
+    expanded.push_str(&text[copied..]);
+    Ok(Cow::Owned(expanded))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn aliases() -> Aliases {
+        [
+            (
+                "prod_errors",
+                r#"env = "prod" and syslogseverity <= "error""#,
+            ),
+            ("noisy", r#"program in ("cron", "sshd")"#),
+            ("nested", r#"@noisy or host = "a""#),
+        ]
+        .into_iter()
+        .map(|(name, query)| (name.to_string(), query.to_string()))
+        .collect()
+    }
+
+    #[test]
+    fn expansion() {
+        let aliases = aliases();
+        assert_eq!(
+            expand(r#"@prod_errors and not @noisy"#, &aliases).unwrap(),
+            "(env = \"prod\" and syslogseverity <= \"error\"\n) and not \
+             (program in (\"cron\", \"sshd\")\n)"
+        );
+        assert_eq!(
+            expand("a=1 or@noisy", &aliases).unwrap(),
+            "a=1 or(program in (\"cron\", \"sshd\")\n)"
+        );
+        assert!(matches!(
+            expand(r#"host = "a""#, &aliases).unwrap(),
+            Cow::Borrowed(_)
+        ));
+    }
+
+    #[test]
+    fn quoted_text_is_kept() {
+        let aliases = aliases();
+        for text in [
+            r#"msg = "@noisy""#,
+            r#"msg = '@noisy'"#,
+            r#"`@noisy` = 1"#,
+            r#"msg = "say \"@noisy\"""#,
+            "@ = 1",
+            "a = 1 # don't use @noisy",
+        ] {
+            assert_eq!(expand(text, &aliases).unwrap(), text);
+        }
+        assert_eq!(
+            expand(r#"msg = "\\" or @noisy"#, &aliases).unwrap(),
+            "msg = \"\\\\\" or (program in (\"cron\", \"sshd\")\n)"
+        );
+        assert_eq!(
+            expand("a = 1 # isn't\nor @noisy", &aliases).unwrap(),
+            "a = 1 # isn't\nor (program in (\"cron\", \"sshd\")\n)"
+        );
+    }
+
+    #[test]
+    fn trailing_comments_keep_the_parenthesis() {
+        let aliases = [(
+            "web".to_string(),
+            "program = \"nginx\" # and the proxies, some day".to_string(),
+        )]
+        .into_iter()
+        .collect();
+        let expanded = expand("@web and host = \"a\"", &aliases).unwrap();
+        assert_eq!(
+            expanded,
+            "(program = \"nginx\" # and the proxies, some day\n) and host = \"a\""
+        );
+        assert_eq!(
+            crate::parse_expression(&expanded).unwrap(),
+            crate::parse_expression("program = \"nginx\" and host = \"a\"").unwrap()
+        );
+    }
+
+    #[test]
+    fn unknown_and_recursive_aliases() {
+        let aliases = aliases();
+        assert_eq!(
+            expand("@missing", &aliases).unwrap_err().to_string(),
+            "unknown alias @missing"
+        );
+        assert_eq!(
+            expand("a = 1 and @nested", &aliases)
+                .unwrap_err()
+                .to_string(),
+            "alias @nested references another alias"
+        );
+    }
+}
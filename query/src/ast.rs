@@ -1,27 +1,135 @@
 use serde_json::json;
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::fmt;
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct Identifier(String);
 
+/// How query parameters are referenced in the generated SQL
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Placeholder {
+    /// PostgreSQL style `$1`, `$2`, ...
+    #[default]
+    Numbered,
+    /// `?` for every parameter, bound in the order of the returned parameter list
+    QuestionMark,
+}
+
+/// Typed column of the root table holding a field
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldColumn {
+    pub column: String,
+    /// SQL data type of the column, values compared to the column are cast to it
+    pub sql_type: String,
+}
+
+impl FieldColumn {
+    /// `value_text` cast to the column's type, null if it can't be
+    fn cast(&self, value_text: &str) -> String {
+        format!("cast_or_null({}, null::{})", value_text, self.sql_type)
+    }
+}
+
+/// Table columns and placeholder style the generated SQL uses
+///
+/// Names are inserted into the SQL as they are, quote them if necessary.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SqlOptions {
+    /// jsonb column holding the event document
+    pub doc_column: String,
+    /// tsvector column used for full text search
+    pub search_column: String,
+    /// Fields stored in columns of their own instead of the document, by field name
+    pub field_columns: BTreeMap<String, FieldColumn>,
+    pub placeholder: Placeholder,
+    /// Most parameters a query may use, including those before its offset
+    pub max_params: usize,
+}
+
+impl Default for SqlOptions {
+    fn default() -> Self {
+        Self {
+            doc_column: "doc".into(),
+            search_column: "search".into(),
+            field_columns: BTreeMap::new(),
+            placeholder: Placeholder::default(),
+            // largest number of bind parameters postgres accepts
+            max_params: 65535,
+        }
+    }
+}
+
+impl SqlOptions {
+    /// Reference to the parameter at (1 based) position `index`
+    pub fn param(&self, index: usize) -> String {
+        match self.placeholder {
+            Placeholder::Numbered => format!("${}", index),
+            Placeholder::QuestionMark => "?".into(),
+        }
+    }
+}
+
 impl Identifier {
-    pub fn string_getter(&self, param_offset: usize) -> (String, QueryParams) {
+    /// The field name
+    pub fn name(&self) -> &str {
+        &self.0
+    }
+
+    pub fn string_getter(
+        &self,
+        options: &SqlOptions,
+        param_offset: usize,
+    ) -> (String, QueryParams) {
+        if let Some(column) = options.field_columns.get(&self.0) {
+            return (format!("{}::text", column.column), QueryParams::new());
+        }
         (
-            format!("doc ->> (${}::jsonb #>> '{{}}')", param_offset),
+            format!(
+                "{} ->> ({}::jsonb #>> '{{}}')",
+                options.doc_column,
+                options.param(param_offset)
+            ),
             vec![serde_json::Value::from(self.0.to_owned())],
         )
     }
 
-    pub fn json_getter(&self, param_offset: usize) -> (String, QueryParams) {
+    pub fn json_getter(&self, options: &SqlOptions, param_offset: usize) -> (String, QueryParams) {
+        if let Some(column) = options.field_columns.get(&self.0) {
+            return (format!("to_jsonb({})", column.column), QueryParams::new());
+        }
         (
-            format!("doc -> (${}::jsonb #>> '{{}}')", param_offset),
+            format!(
+                "{} -> ({}::jsonb #>> '{{}}')",
+                options.doc_column,
+                options.param(param_offset)
+            ),
             vec![serde_json::Value::from(self.0.to_owned())],
         )
     }
 
-    pub fn numeric_getter(&self, param_offset: usize) -> (String, QueryParams) {
-        let (expr, params) = self.string_getter(param_offset);
+    pub fn numeric_getter(
+        &self,
+        options: &SqlOptions,
+        param_offset: usize,
+    ) -> (String, QueryParams) {
+        let (expr, params) = self.string_getter(options, param_offset);
         (format!("to_number_or_null({})", expr), params)
     }
+
+    pub fn timestamp_getter(
+        &self,
+        options: &SqlOptions,
+        param_offset: usize,
+    ) -> (String, QueryParams) {
+        let (expr, params) = self.string_getter(options, param_offset);
+        (format!("to_timestamp_or_null({})", expr), params)
+    }
+
+    pub fn inet_getter(&self, options: &SqlOptions, param_offset: usize) -> (String, QueryParams) {
+        let (expr, params) = self.string_getter(options, param_offset);
+        (format!("to_inet_or_null({})", expr), params)
+    }
 }
 
 impl From<String> for Identifier {
@@ -36,11 +144,19 @@ impl From<&str> for Identifier {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Scalar {
     Int(i64),
     Float(f64),
     Text(String),
+    Bool(bool),
+    Null,
+}
+
+impl From<bool> for Scalar {
+    fn from(value: bool) -> Self {
+        Self::Bool(value)
+    }
 }
 
 impl From<i64> for Scalar {
@@ -68,34 +184,104 @@ impl From<String> for Scalar {
 }
 
 impl Scalar {
+    fn as_text(&self) -> String {
+        match self {
+            Scalar::Int(i) => i.to_string(),
+            Scalar::Float(f) => f.to_string(),
+            Scalar::Text(s) => s.to_owned(),
+            Scalar::Bool(b) => b.to_string(),
+            Scalar::Null => "null".into(),
+        }
+    }
+
     fn as_json(&self) -> serde_json::Value {
         match self {
             Scalar::Int(i) => serde_json::Value::from(*i),
             Scalar::Float(f) => serde_json::Value::from(*f),
             Scalar::Text(s) => serde_json::Value::from(s.to_owned()),
+            Scalar::Bool(b) => serde_json::Value::from(*b),
+            Scalar::Null => serde_json::Value::Null,
         }
     }
 }
 
 type List = Vec<Scalar>;
 
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TimeUnit {
+    Second,
+    Minute,
+    Hour,
+    Day,
+    Week,
+}
+
+impl TimeUnit {
+    pub fn from_suffix(suffix: char) -> Option<Self> {
+        match suffix {
+            's' => Some(Self::Second),
+            'm' => Some(Self::Minute),
+            'h' => Some(Self::Hour),
+            'd' => Some(Self::Day),
+            'w' => Some(Self::Week),
+            _ => None,
+        }
+    }
+
+    fn as_interval_unit(&self) -> &'static str {
+        match self {
+            Self::Second => "seconds",
+            Self::Minute => "minutes",
+            Self::Hour => "hours",
+            Self::Day => "days",
+            Self::Week => "weeks",
+        }
+    }
+}
+
+/// Point in time relative to the query's execution, `now-<amount><unit>`
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct RelativeTime {
+    pub amount: u32,
+    pub unit: TimeUnit,
+}
+
+impl RelativeTime {
+    pub fn now() -> Self {
+        Self {
+            amount: 0,
+            unit: TimeUnit::Second,
+        }
+    }
+
+    pub fn ago(amount: u32, unit: TimeUnit) -> Self {
+        Self { amount, unit }
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub enum Value {
     Scalar(Scalar),
     List(List),
+    RelativeTime(RelativeTime),
 }
 
 impl Value {
-    pub fn to_sql_primitive_param(&self, param_offset: usize) -> (String, QueryParams) {
+    pub fn to_sql_primitive_param(
+        &self,
+        options: &SqlOptions,
+        param_offset: usize,
+    ) -> (String, QueryParams) {
         match self {
+            Value::RelativeTime(_) => self.to_sql_timestamp_param(options, param_offset),
             Value::Scalar(value) => (
-                format!("${}::jsonb #>> '{{}}'", param_offset),
+                format!("{}::jsonb #>> '{{}}'", options.param(param_offset)),
                 vec![value.as_json()],
             ),
             Value::List(list) => (
                 format!(
-                    "(select jsonb_array_elements(${}::jsonb) #>> '{{}}')",
-                    param_offset
+                    "(select jsonb_array_elements({}::jsonb) #>> '{{}}')",
+                    options.param(param_offset)
                 ),
                 vec![json!(list
                     .iter()
@@ -105,11 +291,16 @@ impl Value {
         }
     }
 
-    pub fn to_sql_json_param(&self, param_offset: usize) -> (String, QueryParams) {
+    pub fn to_sql_json_param(
+        &self,
+        options: &SqlOptions,
+        param_offset: usize,
+    ) -> (String, QueryParams) {
         match self {
-            Value::Scalar(value) => (format!("${}", param_offset), vec![value.as_json()]),
+            Value::RelativeTime(_) => self.to_sql_timestamp_param(options, param_offset),
+            Value::Scalar(value) => (options.param(param_offset), vec![value.as_json()]),
             Value::List(list) => (
-                format!("${}::jsonb", param_offset),
+                format!("{}::jsonb", options.param(param_offset)),
                 vec![json!(list
                     .iter()
                     .map(|e| e.as_json())
@@ -118,13 +309,58 @@ impl Value {
         }
     }
 
-    pub fn to_sql_numeric_param(&self, param_offset: usize) -> (String, QueryParams) {
+    pub fn to_sql_numeric_param(
+        &self,
+        options: &SqlOptions,
+        param_offset: usize,
+    ) -> Result<(String, QueryParams), SemanticError> {
         match self {
-            Value::Scalar(value) => (
-                format!("(${}::jsonb #>> '{{}}')::numeric", param_offset),
+            Value::Scalar(value) => Ok((
+                format!(
+                    "({}::jsonb #>> '{{}}')::numeric",
+                    options.param(param_offset)
+                ),
                 vec![value.as_json()],
+            )),
+            Value::List(_) => Err(SemanticError::new("a list can't be used as a number")),
+            Value::RelativeTime(_) => Ok(self.to_sql_timestamp_param(options, param_offset)),
+        }
+    }
+
+    /// Relative times become `now() - <interval>`, the interval being passed as a parameter
+    pub fn to_sql_timestamp_param(
+        &self,
+        options: &SqlOptions,
+        param_offset: usize,
+    ) -> (String, QueryParams) {
+        match self {
+            Value::RelativeTime(RelativeTime { amount: 0, .. }) => ("now()".into(), Vec::new()),
+            Value::RelativeTime(time) => (
+                format!(
+                    "(now() - ({}::jsonb #>> '{{}}')::interval)",
+                    options.param(param_offset)
+                ),
+                vec![serde_json::Value::from(format!(
+                    "{} {}",
+                    time.amount,
+                    time.unit.as_interval_unit()
+                ))],
             ),
-            Value::List(_) => unreachable!(),
+            _ => self.to_sql_primitive_param(options, param_offset),
+        }
+    }
+
+    pub fn to_sql_inet_param(
+        &self,
+        options: &SqlOptions,
+        param_offset: usize,
+    ) -> Result<(String, QueryParams), SemanticError> {
+        match self {
+            Value::Scalar(value) => Ok((
+                format!("({}::jsonb #>> '{{}}')::inet", options.param(param_offset)),
+                vec![value.as_json()],
+            )),
+            _ => Err(SemanticError::new("only a string can be used as a network")),
         }
     }
 }
@@ -144,44 +380,170 @@ impl From<List> for Value {
     }
 }
 
+impl From<RelativeTime> for Value {
+    fn from(time: RelativeTime) -> Self {
+        Self::RelativeTime(time)
+    }
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum WantedOperandType {
     Json,
     Numeric,
     String,
+    Inet,
+    Timestamp,
 }
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum Operator {
     Eq,
+    Ne,
     Lt,
     Le,
     Gt,
     Ge,
     Like,
     In,
+    InSubnet,
+    Contains,
+    IContains,
+    Has,
+    HasAny,
+    HasAll,
+    /// Null safe equality: unlike `=`, a missing field or JSON null is equal to `null` and
+    /// unequal to any value
+    Is,
 }
 
 impl Operator {
+    /// Operator with swapped operands, for `<`, `<=`, `>` and `>=`
+    pub fn reversed(&self) -> Option<Operator> {
+        match self {
+            Operator::Lt => Some(Operator::Gt),
+            Operator::Le => Some(Operator::Ge),
+            Operator::Gt => Some(Operator::Lt),
+            Operator::Ge => Some(Operator::Le),
+            _ => None,
+        }
+    }
+
     pub fn sql_symbol(&self) -> &'static str {
         match self {
-            Operator::Eq => "@>",
+            Operator::Eq | Operator::Ne | Operator::Has | Operator::HasAny | Operator::HasAll => {
+                "@>"
+            }
+            Operator::Is => "IS NOT DISTINCT FROM",
             Operator::Gt => ">",
             Operator::Ge => ">=",
             Operator::Lt => "<",
             Operator::Le => "<=",
             Operator::Like => "LIKE",
             Operator::In => "IN",
+            Operator::InSubnet => "<<",
+            Operator::Contains => "LIKE",
+            Operator::IContains => "ILIKE",
         }
     }
 
     pub fn wanted_operands(&self) -> WantedOperandType {
         match self {
-            Operator::Eq => WantedOperandType::Json,
-            Operator::Like | Operator::In => WantedOperandType::String,
+            Operator::Eq
+            | Operator::Ne
+            | Operator::Has
+            | Operator::HasAny
+            | Operator::HasAll
+            | Operator::Is => WantedOperandType::Json,
+            Operator::Like | Operator::In | Operator::Contains | Operator::IContains => {
+                WantedOperandType::String
+            }
+            Operator::InSubnet => WantedOperandType::Inet,
             _ => WantedOperandType::Numeric,
         }
     }
+
+    /// Operand type for comparisons with `value`
+    ///
+    /// Usually decided by the operator alone, but ordering a field relative to the current time
+    /// compares time stamps instead of numbers.
+    pub fn wanted_operands_for(&self, value: &Value) -> WantedOperandType {
+        match value {
+            Value::RelativeTime(_) => WantedOperandType::Timestamp,
+            _ => self.wanted_operands(),
+        }
+    }
+
+    /// Reject values this operator can't be applied to
+    pub fn check_operand(&self, value: &Value) -> Result<(), SemanticError> {
+        match (self, value) {
+            (Operator::Lt | Operator::Le | Operator::Gt | Operator::Ge, Value::RelativeTime(_)) => {
+                Ok(())
+            }
+            (_, Value::RelativeTime(_)) => Err(SemanticError::new(format!(
+                "operator {} can't be used with a relative time",
+                self
+            ))),
+            (Operator::Is, Value::Scalar(_)) => Ok(()),
+            (_, Value::Scalar(Scalar::Null)) => Err(SemanticError::new(format!(
+                "operator {} can't be used with null, use is instead",
+                self
+            ))),
+            (Operator::Eq | Operator::Ne, _) => Ok(()),
+            (Operator::In | Operator::HasAny | Operator::HasAll, Value::List(_)) => Ok(()),
+            (Operator::In | Operator::HasAny | Operator::HasAll, Value::Scalar(_)) => Err(
+                SemanticError::new(format!("operator {} requires a list", self)),
+            ),
+            (_, Value::List(_)) => Err(SemanticError::new(format!(
+                "operator {} can't be used with a list",
+                self
+            ))),
+            (_, Value::Scalar(_)) => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for Operator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Operator::Eq => "=",
+                Operator::Ne => "!=",
+                Operator::Gt => ">",
+                Operator::Ge => ">=",
+                Operator::Lt => "<",
+                Operator::Le => "<=",
+                Operator::Like => "like",
+                Operator::In => "in",
+                Operator::InSubnet => "in_subnet",
+                Operator::Contains => "contains",
+                Operator::IContains => "icontains",
+                Operator::Has => "has",
+                Operator::HasAny => "has_any",
+                Operator::HasAll => "has_all",
+                Operator::Is => "is",
+            }
+        )
+    }
+}
+
+/// A syntactically valid expression that can't be turned into SQL
+#[derive(Debug, PartialEq, Eq)]
+pub struct SemanticError(String);
+
+impl SemanticError {
+    pub fn new(message: impl Into<String>) -> Self {
+        Self(message.into())
+    }
+}
+
+impl Error for SemanticError {}
+
+impl fmt::Display for SemanticError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
 }
 
 #[derive(Debug, PartialEq)]
@@ -191,74 +553,534 @@ pub struct Comparison {
     pub(crate) value: Value,
 }
 
+/// How full text search terms are turned into a `tsquery`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TsQuery {
+    /// `"a b" or -c` syntax, the default
+    Websearch,
+    /// All words, ignoring punctuation, prefixed with `plain`
+    Plain,
+    /// The words next to each other and in order, prefixed with `phrase`
+    Phrase,
+}
+
+impl TsQuery {
+    pub fn sql_function(&self) -> &'static str {
+        match self {
+            TsQuery::Websearch => "websearch_to_tsquery",
+            TsQuery::Plain => "plainto_tsquery",
+            TsQuery::Phrase => "phraseto_tsquery",
+        }
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub enum Expression {
     Compare(Identifier, Operator, Value),
     And(Box<Expression>, Box<Expression>),
     Or(Box<Expression>, Box<Expression>),
     Not(Box<Expression>),
-    FullTextSearch(String),
+    FullTextSearch(String, TsQuery),
+    /// `jsonb_path_exists` for the whole document, the path is only ever bound as a parameter
+    JsonPath(String),
 }
 
 pub type QueryParams = Vec<serde_json::Value>;
 
 impl Expression {
-    pub fn to_sql_query(&self, param_offset: usize) -> (String, QueryParams) {
+    /// `true` for `x in ()`, which can never match anything
+    fn is_empty_in(&self) -> bool {
+        matches!(self, Expression::Compare(_, Operator::In, Value::List(list)) if list.is_empty())
+    }
+
+    /// `true` for `not x in ()`, which matches everything
+    fn is_always_true(&self) -> bool {
+        matches!(self, Expression::Not(expr) if expr.is_empty_in())
+    }
+
+    /// Reduced but equivalent tree
+    ///
+    /// Removes double negations, folds the constant `x in ()` and `not x in ()` into the
+    /// surrounding `and`/`or` and turns nested `and`s and `or`s into left-leaning chains, the
+    /// shape the parser produces for `a and b and c`.
+    pub fn simplify(self) -> Expression {
+        match self {
+            Expression::Not(expr) => match expr.simplify() {
+                Expression::Not(inner) => *inner,
+                expr => Expression::Not(Box::new(expr)),
+            },
+            Expression::And(lhs, rhs) => {
+                let (lhs, rhs) = (lhs.simplify(), rhs.simplify());
+                if lhs.is_empty_in() || rhs.is_always_true() {
+                    lhs
+                } else if rhs.is_empty_in() || lhs.is_always_true() {
+                    rhs
+                } else {
+                    chain_and(lhs, rhs)
+                }
+            }
+            Expression::Or(lhs, rhs) => {
+                let (lhs, rhs) = (lhs.simplify(), rhs.simplify());
+                if lhs.is_always_true() || rhs.is_empty_in() {
+                    lhs
+                } else if rhs.is_always_true() || lhs.is_empty_in() {
+                    rhs
+                } else {
+                    chain_or(lhs, rhs)
+                }
+            }
+            expr => expr,
+        }
+    }
+
+    /// Equivalent tree with every `not` moved down to a single condition
+    ///
+    /// Applies De Morgan's laws, `not (a or b)` becomes `not a and not b` and `not (a and b)`
+    /// becomes `not a or not b`. Double negations are removed on the way.
+    pub fn push_negations(self) -> Expression {
+        match self {
+            Expression::Not(expr) => match *expr {
+                Expression::Not(inner) => inner.push_negations(),
+                Expression::And(lhs, rhs) => Expression::Or(
+                    Box::new(Expression::Not(lhs).push_negations()),
+                    Box::new(Expression::Not(rhs).push_negations()),
+                ),
+                Expression::Or(lhs, rhs) => Expression::And(
+                    Box::new(Expression::Not(lhs).push_negations()),
+                    Box::new(Expression::Not(rhs).push_negations()),
+                ),
+                expr => Expression::Not(Box::new(expr)),
+            },
+            Expression::And(lhs, rhs) => Expression::And(
+                Box::new(lhs.push_negations()),
+                Box::new(rhs.push_negations()),
+            ),
+            Expression::Or(lhs, rhs) => Expression::Or(
+                Box::new(lhs.push_negations()),
+                Box::new(rhs.push_negations()),
+            ),
+            expr => expr,
+        }
+    }
+
+    /// Names of all fields the expression compares
+    pub fn referenced_identifiers(&self) -> BTreeSet<&str> {
+        match self {
+            Expression::And(lhs, rhs) | Expression::Or(lhs, rhs) => {
+                let mut ids = lhs.referenced_identifiers();
+                ids.extend(rhs.referenced_identifiers());
+                ids
+            }
+            Expression::Not(expr) => expr.referenced_identifiers(),
+            Expression::Compare(id, ..) => BTreeSet::from([id.name()]),
+            Expression::FullTextSearch(..) | Expression::JsonPath(_) => BTreeSet::new(),
+        }
+    }
+
+    /// `true` if the expression contains a `jsonpath` condition, which may access any field
+    pub fn uses_json_path(&self) -> bool {
+        match self {
+            Expression::And(lhs, rhs) | Expression::Or(lhs, rhs) => {
+                lhs.uses_json_path() || rhs.uses_json_path()
+            }
+            Expression::Not(expr) => expr.uses_json_path(),
+            Expression::JsonPath(_) => true,
+            Expression::FullTextSearch(..) | Expression::Compare(..) => false,
+        }
+    }
+
+    /// Full text search terms that have to be present in matching events
+    ///
+    /// Terms below a `not` are skipped, they can never be part of a match.
+    pub fn full_text_terms(&self) -> Vec<(&str, TsQuery)> {
+        match self {
+            Expression::And(lhs, rhs) | Expression::Or(lhs, rhs) => {
+                let mut terms = lhs.full_text_terms();
+                terms.extend(rhs.full_text_terms());
+                terms
+            }
+            Expression::FullTextSearch(s, tsquery) => vec![(s.as_str(), *tsquery)],
+            Expression::Not(_) | Expression::Compare(..) | Expression::JsonPath(_) => Vec::new(),
+        }
+    }
+
+    /// `true` if the expression contains a full text search
+    fn searches_full_text(&self) -> bool {
+        match self {
+            Expression::And(lhs, rhs) | Expression::Or(lhs, rhs) => {
+                lhs.searches_full_text() || rhs.searches_full_text()
+            }
+            Expression::Not(expr) => expr.searches_full_text(),
+            Expression::FullTextSearch(..) => true,
+            Expression::Compare(..) | Expression::JsonPath(_) => false,
+        }
+    }
+
+    /// `true` if the expression contains anything but full text searches
+    fn filters_fields(&self) -> bool {
+        match self {
+            Expression::And(lhs, rhs) | Expression::Or(lhs, rhs) => {
+                lhs.filters_fields() || rhs.filters_fields()
+            }
+            Expression::Not(expr) => expr.filters_fields(),
+            Expression::FullTextSearch(..) => false,
+            Expression::Compare(..) | Expression::JsonPath(_) => true,
+        }
+    }
+
+    /// `true` if a full text search is `or`ed with field conditions
+    ///
+    /// Such queries are correct, but PostgreSQL can only use the full text index for them if
+    /// every condition on the other side of the `or` is indexed as well.
+    pub fn mixes_full_text_with_fields(&self) -> bool {
+        match self {
+            Expression::Or(lhs, rhs) => {
+                (lhs.searches_full_text() && rhs.filters_fields())
+                    || (lhs.filters_fields() && rhs.searches_full_text())
+                    || lhs.mixes_full_text_with_fields()
+                    || rhs.mixes_full_text_with_fields()
+            }
+            Expression::And(lhs, rhs) => {
+                lhs.mixes_full_text_with_fields() || rhs.mixes_full_text_with_fields()
+            }
+            Expression::Not(expr) => expr.mixes_full_text_with_fields(),
+            Expression::FullTextSearch(..) | Expression::Compare(..) | Expression::JsonPath(_) => {
+                false
+            }
+        }
+    }
+
+    /// Find the errors `to_sql_query_with` would, without generating SQL
+    ///
+    /// The number of parameters is not checked.
+    pub fn check(&self) -> Result<(), SemanticError> {
+        match self {
+            Expression::And(lhs, rhs) | Expression::Or(lhs, rhs) => {
+                lhs.check()?;
+                rhs.check()
+            }
+            Expression::Not(expr) => expr.check(),
+            Expression::FullTextSearch(..) | Expression::JsonPath(_) => Ok(()),
+            Expression::Compare(id, op, value) => {
+                if id.0 == "syslogseverity" {
+                    if let Some(comparison) = severity_comparison(op, value) {
+                        return comparison.map(|_| ());
+                    }
+                }
+                check_operands(op, value)
+            }
+        }
+    }
+
+    pub fn to_sql_query(
+        &self,
+        param_offset: usize,
+    ) -> Result<(String, QueryParams), SemanticError> {
+        self.to_sql_query_with(&SqlOptions::default(), param_offset)
+    }
+
+    /// SQL for this expression, failing if it would use more than `options.max_params` parameters
+    ///
+    /// Parameters are numbered from `param_offset`, which starts at 1.
+    pub fn to_sql_query_with(
+        &self,
+        options: &SqlOptions,
+        param_offset: usize,
+    ) -> Result<(String, QueryParams), SemanticError> {
+        if param_offset == 0 {
+            return Err(SemanticError::new("parameters are numbered from 1"));
+        }
+        let (expr, params) = self.to_sql(options, param_offset)?;
+        let total = param_offset - 1 + params.len();
+        if total > options.max_params {
+            return Err(SemanticError::new(format!(
+                "query needs {} parameters, at most {} are allowed",
+                total, options.max_params
+            )));
+        }
+        Ok((expr, params))
+    }
+
+    fn to_sql(
+        &self,
+        options: &SqlOptions,
+        param_offset: usize,
+    ) -> Result<(String, QueryParams), SemanticError> {
+        if self.is_empty_in() {
+            return Ok(("false".into(), QueryParams::new()));
+        }
         match self {
             Expression::And(lhs, rhs) => {
-                let (left_expr, left_params) = lhs.to_sql_query(param_offset);
-                let (right_expr, right_params) = rhs.to_sql_query(param_offset + left_params.len());
+                let (left_expr, left_params) = lhs.to_sql(options, param_offset)?;
+                let (right_expr, right_params) =
+                    rhs.to_sql(options, param_offset + left_params.len())?;
                 let mut params = left_params;
                 params.extend(right_params);
-                (format!("({} AND {})", left_expr, right_expr), params)
+                Ok((format!("({} AND {})", left_expr, right_expr), params))
             }
             Expression::Or(lhs, rhs) => {
-                let (left_expr, left_params) = lhs.to_sql_query(param_offset);
-                let (right_expr, right_params) = rhs.to_sql_query(param_offset + left_params.len());
+                let (left_expr, left_params) = lhs.to_sql(options, param_offset)?;
+                let (right_expr, right_params) =
+                    rhs.to_sql(options, param_offset + left_params.len())?;
                 let mut params = left_params;
                 params.extend(right_params);
-                (format!("({} OR {})", left_expr, right_expr), params)
+                Ok((format!("({} OR {})", left_expr, right_expr), params))
             }
+            Expression::Not(expr) if expr.is_empty_in() => Ok(("true".into(), QueryParams::new())),
             Expression::Not(expr) => {
-                let (expr, params) = expr.to_sql_query(param_offset);
-                (format!("(NOT {})", expr), params)
+                let (expr, params) = expr.to_sql(options, param_offset)?;
+                Ok((format!("(NOT {})", expr), params))
             }
-            Expression::FullTextSearch(s) => (
+            Expression::FullTextSearch(s, tsquery) => Ok((
                 format!(
-                    "search @@ websearch_to_tsquery(${}::jsonb #>> '{{}}')",
-                    param_offset
+                    "{} @@ {}({}::jsonb #>> '{{}}')",
+                    options.search_column,
+                    tsquery.sql_function(),
+                    options.param(param_offset)
                 ),
                 vec![serde_json::Value::from(s.to_owned())],
-            ),
+            )),
+            Expression::JsonPath(path) => Ok((
+                format!(
+                    "jsonb_path_exists({}, ({}::jsonb #>> '{{}}')::jsonpath)",
+                    options.doc_column,
+                    options.param(param_offset)
+                ),
+                vec![serde_json::Value::from(path.to_owned())],
+            )),
+            Expression::Compare(id, Operator::Ne, value) => {
+                let (expr, params) =
+                    compare_to_sql(options, id, &Operator::Eq, value, param_offset)?;
+                Ok((format!("(NOT {})", expr), params))
+            }
             Expression::Compare(id, op, value) => {
-                let (id_expr, value_expr, params) = match op.wanted_operands() {
-                    WantedOperandType::String => {
-                        let (id_expr, mut id_params) = id.string_getter(param_offset);
-                        let (value_expr, value_params) =
-                            value.to_sql_primitive_param(param_offset + id_params.len());
-                        id_params.extend(value_params);
-                        (id_expr, value_expr, id_params)
-                    }
-                    WantedOperandType::Json => {
-                        let (id_expr, mut id_params) = id.json_getter(param_offset);
-                        let (value_expr, value_params) =
-                            value.to_sql_json_param(param_offset + id_params.len());
-                        id_params.extend(value_params);
-                        (id_expr, value_expr, id_params)
-                    }
-                    WantedOperandType::Numeric => {
-                        let (id_expr, mut id_params) = id.numeric_getter(param_offset);
-                        let (value_expr, value_params) =
-                            value.to_sql_numeric_param(param_offset + id_params.len());
-                        id_params.extend(value_params);
-                        (id_expr, value_expr, id_params)
-                    }
-                };
-                (
-                    format!("{} {} {}", id_expr, op.sql_symbol(), value_expr),
-                    params,
-                )
+                compare_to_sql(options, id, op, value, param_offset)
             }
         }
     }
 }
+
+/// `lhs and rhs`, with an `and` on the right hand side moved to the left
+fn chain_and(lhs: Expression, rhs: Expression) -> Expression {
+    match rhs {
+        Expression::And(inner, last) => Expression::And(Box::new(chain_and(lhs, *inner)), last),
+        rhs => Expression::And(Box::new(lhs), Box::new(rhs)),
+    }
+}
+
+/// `lhs or rhs`, with an `or` on the right hand side moved to the left
+fn chain_or(lhs: Expression, rhs: Expression) -> Expression {
+    match rhs {
+        Expression::Or(inner, last) => Expression::Or(Box::new(chain_or(lhs, *inner)), last),
+        rhs => Expression::Or(Box::new(lhs), Box::new(rhs)),
+    }
+}
+
+/// Escape LIKE's wildcards (and the escape character) to match `text` literally
+fn escape_like(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+/// Severity names in syslog order, as stored in `syslogseverity`
+const SEVERITIES: [&str; 8] = [
+    "emergency",
+    "alert",
+    "critical",
+    "error",
+    "warning",
+    "notice",
+    "info",
+    "debug",
+];
+
+/// `syslogseverity < "error"` as comparison of `syslogseverity_num`
+///
+/// Lower numbers are more severe, so the operator is reversed to make `warning < error` hold.
+fn severity_comparison(
+    op: &Operator,
+    value: &Value,
+) -> Option<Result<(Operator, Value), SemanticError>> {
+    let reversed = op.reversed()?;
+    let name = match value {
+        Value::Scalar(Scalar::Text(name)) => name,
+        _ => return None,
+    };
+    Some(
+        SEVERITIES
+            .iter()
+            .position(|severity| severity.eq_ignore_ascii_case(name))
+            .map(|num| (reversed, Value::from(num as i64)))
+            .ok_or_else(|| SemanticError::new(format!("unknown severity {}", name))),
+    )
+}
+
+/// Reject values `op` can't compare to, severity names aside
+fn check_operands(op: &Operator, value: &Value) -> Result<(), SemanticError> {
+    if let (Some(_), Value::Scalar(Scalar::Text(_))) = (op.reversed(), value) {
+        return Err(SemanticError::new(format!(
+            "operator {} requires a number or relative time",
+            op
+        )));
+    }
+    op.check_operand(value)
+}
+
+/// `id` containing any or all of `elements`, each checked with `@>` so numbers and booleans match
+fn has_elements_to_sql(
+    options: &SqlOptions,
+    id: &Identifier,
+    op: &Operator,
+    elements: &[Scalar],
+    param_offset: usize,
+) -> (String, QueryParams) {
+    let (joiner, empty) = match op {
+        Operator::HasAll => (" AND ", "true"),
+        _ => (" OR ", "false"),
+    };
+    if elements.is_empty() {
+        return (empty.into(), QueryParams::new());
+    }
+    let mut exprs = Vec::new();
+    let mut params = QueryParams::new();
+    for element in elements {
+        let (id_expr, id_params) = id.json_getter(options, param_offset + params.len());
+        params.extend(id_params);
+        let (value_expr, value_params) = Value::from(vec![element.clone()])
+            .to_sql_json_param(options, param_offset + params.len());
+        params.extend(value_params);
+        exprs.push(format!("{} @> {}", id_expr, value_expr));
+    }
+    (format!("({})", exprs.join(joiner)), params)
+}
+
+/// `column` compared to `value` cast to the column's type, so that an index on it can be used
+///
+/// `None` for comparisons needing the generic getters, like relative times or text matching.
+fn column_compare_to_sql(
+    options: &SqlOptions,
+    column: &FieldColumn,
+    op: &Operator,
+    value: &Value,
+    param_offset: usize,
+) -> Option<(String, QueryParams)> {
+    let param = options.param(param_offset);
+    match (op, value) {
+        (Operator::In, Value::List(list)) => Some((
+            format!(
+                "{} = ANY(array(select {}))",
+                column.column,
+                column.cast(&format!(
+                    "jsonb_array_elements({}::jsonb) #>> '{{}}'",
+                    param
+                ))
+            ),
+            vec![serde_json::Value::Array(
+                list.iter().map(Scalar::as_json).collect(),
+            )],
+        )),
+        (
+            Operator::Eq | Operator::Is | Operator::Lt | Operator::Le | Operator::Gt | Operator::Ge,
+            Value::Scalar(scalar),
+        ) => {
+            let symbol = match op {
+                Operator::Eq => "=",
+                _ => op.sql_symbol(),
+            };
+            Some((
+                format!(
+                    "{} {} {}",
+                    column.column,
+                    symbol,
+                    column.cast(&format!("{}::jsonb #>> '{{}}'", param))
+                ),
+                vec![scalar.as_json()],
+            ))
+        }
+        _ => None,
+    }
+}
+
+fn compare_to_sql(
+    options: &SqlOptions,
+    id: &Identifier,
+    op: &Operator,
+    value: &Value,
+    param_offset: usize,
+) -> Result<(String, QueryParams), SemanticError> {
+    if id.0 == "syslogseverity" {
+        if let Some(comparison) = severity_comparison(op, value) {
+            let (op, value) = comparison?;
+            let id = Identifier::from("syslogseverity_num");
+            return compare_to_sql(options, &id, &op, &value, param_offset);
+        }
+    }
+    check_operands(op, value)?;
+    if let (Operator::HasAny | Operator::HasAll, Value::List(elements)) = (op, value) {
+        return Ok(has_elements_to_sql(options, id, op, elements, param_offset));
+    }
+    if let Some(column) = options.field_columns.get(&id.0) {
+        if let Some(comparison) = column_compare_to_sql(options, column, op, value, param_offset) {
+            return Ok(comparison);
+        }
+    }
+    let (value, escape) = match (op, value) {
+        (Operator::Contains | Operator::IContains, Value::Scalar(needle)) => (
+            &Value::from(format!("%{}%", escape_like(&needle.as_text()))),
+            " ESCAPE '\\'",
+        ),
+        // an array containing an array with the element, never a scalar equal to it
+        (Operator::Has, Value::Scalar(element)) => (&Value::from(vec![element.clone()]), ""),
+        _ => (value, ""),
+    };
+    let (id_expr, value_expr, params) = match op.wanted_operands_for(value) {
+        WantedOperandType::String => {
+            let (id_expr, mut id_params) = id.string_getter(options, param_offset);
+            let (value_expr, value_params) =
+                value.to_sql_primitive_param(options, param_offset + id_params.len());
+            id_params.extend(value_params);
+            (id_expr, value_expr, id_params)
+        }
+        WantedOperandType::Json => {
+            let (id_expr, mut id_params) = id.json_getter(options, param_offset);
+            let (value_expr, value_params) =
+                value.to_sql_json_param(options, param_offset + id_params.len());
+            id_params.extend(value_params);
+            let (id_expr, value_expr) = match op {
+                // missing fields and JSON null both become SQL NULL
+                Operator::Is => (
+                    format!("nullif({}, 'null'::jsonb)", id_expr),
+                    format!("nullif({}::jsonb, 'null'::jsonb)", value_expr),
+                ),
+                _ => (id_expr, value_expr),
+            };
+            (id_expr, value_expr, id_params)
+        }
+        WantedOperandType::Numeric => {
+            let (id_expr, mut id_params) = id.numeric_getter(options, param_offset);
+            let (value_expr, value_params) =
+                value.to_sql_numeric_param(options, param_offset + id_params.len())?;
+            id_params.extend(value_params);
+            (id_expr, value_expr, id_params)
+        }
+        WantedOperandType::Timestamp => {
+            let (id_expr, mut id_params) = id.timestamp_getter(options, param_offset);
+            let (value_expr, value_params) =
+                value.to_sql_timestamp_param(options, param_offset + id_params.len());
+            id_params.extend(value_params);
+            (id_expr, value_expr, id_params)
+        }
+        WantedOperandType::Inet => {
+            let (id_expr, mut id_params) = id.inet_getter(options, param_offset);
+            let (value_expr, value_params) =
+                value.to_sql_inet_param(options, param_offset + id_params.len())?;
+            id_params.extend(value_params);
+            (id_expr, value_expr, id_params)
+        }
+    };
+    Ok((
+        format!("{} {} {}{}", id_expr, op.sql_symbol(), value_expr, escape),
+        params,
+    ))
+}
@@ -1,6 +1,6 @@
 use std::io::{self, BufRead};
 
-use logstuff_query::ExpressionParser;
+use logstuff_query::{ExpressionParser, QueryError};
 
 fn main() {
     let stdin = io::stdin();
@@ -17,7 +17,8 @@ fn main() {
                     .enumerate()
                     .for_each(|pair| println!("\t${} = {:?}", pair.0 + 1, pair.1));
             }
-            Err(err) => println!("Could not parse query: {:?}", err),
+            Err(QueryError::Parse(err)) => println!("Could not parse query: {:?}", err),
+            Err(QueryError::Semantic(reason)) => println!("Invalid query: {}", reason),
         }
     }
 }
@@ -113,7 +113,7 @@ mod test {
     #[test]
     fn init_and_delete() {
         let p = init_parsers();
-        let text = CStr::from_bytes_with_nul(b"#error\0").unwrap();
+        let text = c"#error";
         unsafe {
             assert_eq!(test_parse_query(p, text.as_ptr()), 0);
             assert_eq!(test_parse_identifier(p, text.as_ptr()), 0);
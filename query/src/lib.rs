@@ -1,11 +1,40 @@
 use lalrpop_util::lalrpop_mod;
+use std::collections::{BTreeMap, BTreeSet};
 use std::error::Error;
 use std::fmt;
 
+pub mod alias;
 pub mod ast;
 pub mod c_interface;
 
-pub use ast::QueryParams;
+pub use alias::Aliases;
+pub use ast::{FieldColumn, Placeholder, QueryParams, SqlOptions, TsQuery};
+
+/// Columns holding fields instead of the document, by field name
+pub type FieldColumns = BTreeMap<String, FieldColumn>;
+
+/// Field names queries may use, `None` allows all
+pub type AllowedFields = Option<BTreeSet<String>>;
+
+/// Reject `ids` not in `allowed`
+fn check_allowed<'a>(
+    allowed: &AllowedFields,
+    ids: impl IntoIterator<Item = &'a str>,
+) -> Result<(), ast::SemanticError> {
+    let allowed = match allowed {
+        Some(allowed) => allowed,
+        None => return Ok(()),
+    };
+    for id in ids {
+        if !allowed.contains(id) {
+            return Err(ast::SemanticError::new(format!(
+                "field {} can't be queried",
+                id
+            )));
+        }
+    }
+    Ok(())
+}
 
 lalrpop_mod!(
     #[allow(clippy::all)]
@@ -14,78 +43,427 @@ lalrpop_mod!(
 
 pub struct IdentifierParser {
     parser: query::IdentifierParser,
+    options: SqlOptions,
+    allowed_fields: AllowedFields,
 }
 
 impl Default for IdentifierParser {
     fn default() -> Self {
         Self {
             parser: query::IdentifierParser::new(),
+            options: SqlOptions::default(),
+            allowed_fields: None,
         }
     }
 }
 
 impl IdentifierParser {
+    /// Read fields from the given jsonb document column
+    ///
+    /// Takes the same arguments as `ExpressionParser::with_columns`, identifiers never use the
+    /// search column.
+    pub fn with_columns(mut self, doc: &str, search: &str) -> Self {
+        self.options.doc_column = doc.into();
+        self.options.search_column = search.into();
+        self
+    }
+
+    /// Reference parameters using the given placeholder style
+    pub fn with_placeholder(mut self, placeholder: Placeholder) -> Self {
+        self.options.placeholder = placeholder;
+        self
+    }
+
+    /// Reject fields not in `fields`, see `ExpressionParser::with_allowed_fields`
+    pub fn with_allowed_fields(mut self, fields: AllowedFields) -> Self {
+        self.allowed_fields = fields;
+        self
+    }
+
+    /// Read the fields in `columns` from their column, see `ExpressionParser::with_field_columns`
+    pub fn with_field_columns(mut self, columns: FieldColumns) -> Self {
+        self.options.field_columns = columns;
+        self
+    }
+
+    fn parse(&self, text: &str) -> Result<ast::Identifier, QueryError> {
+        let id = self.parser.parse(text)?;
+        check_allowed(&self.allowed_fields, [id.name()])?;
+        Ok(id)
+    }
+
     pub fn sql_string(
         &self,
         text: &str,
         param_offset: usize,
-    ) -> Result<(String, QueryParams), ParseError> {
-        let id = self.parser.parse(text)?;
-        Ok(id.string_getter(param_offset))
+    ) -> Result<(String, QueryParams), QueryError> {
+        let id = self.parse(text)?;
+        Ok(id.string_getter(&self.options, param_offset))
     }
 
     pub fn sql_json(
         &self,
         text: &str,
         param_offset: usize,
-    ) -> Result<(String, QueryParams), ParseError> {
-        let id = self.parser.parse(text)?;
-        Ok(id.json_getter(param_offset))
+    ) -> Result<(String, QueryParams), QueryError> {
+        let id = self.parse(text)?;
+        Ok(id.json_getter(&self.options, param_offset))
+    }
+
+    /// The field's value as `timestamptz`, null if it isn't a time stamp
+    pub fn sql_timestamp(
+        &self,
+        text: &str,
+        param_offset: usize,
+    ) -> Result<(String, QueryParams), QueryError> {
+        let id = self.parse(text)?;
+        Ok(id.timestamp_getter(&self.options, param_offset))
     }
 }
 
 pub struct ExpressionParser {
     parser: query::ExpressionParser,
+    options: SqlOptions,
+    aliases: Aliases,
+    allowed_fields: AllowedFields,
 }
 
 impl Default for ExpressionParser {
     fn default() -> Self {
         Self {
             parser: query::ExpressionParser::new(),
+            options: SqlOptions::default(),
+            aliases: Aliases::new(),
+            allowed_fields: None,
         }
     }
 }
 
 impl ExpressionParser {
+    /// Generate SQL against the given jsonb document and tsvector search columns
+    pub fn with_columns(mut self, doc: &str, search: &str) -> Self {
+        self.options.doc_column = doc.into();
+        self.options.search_column = search.into();
+        self
+    }
+
+    /// Reference parameters using the given placeholder style
+    pub fn with_placeholder(mut self, placeholder: Placeholder) -> Self {
+        self.options.placeholder = placeholder;
+        self
+    }
+
+    /// Expand `@name` in queries to the stored query for `name` before parsing
+    pub fn with_aliases(mut self, aliases: Aliases) -> Self {
+        self.aliases = aliases;
+        self
+    }
+
+    /// Reject queries comparing fields not in `fields`
+    ///
+    /// `jsonpath` conditions could read any field, they are rejected as well. Full text search
+    /// stays possible.
+    pub fn with_allowed_fields(mut self, fields: AllowedFields) -> Self {
+        self.allowed_fields = fields;
+        self
+    }
+
+    /// Read the fields in `columns` from the given column instead of the document
+    ///
+    /// Equality, ordering and `in` compare the column with the values cast to its type, so an
+    /// index on it can be used. Values that can't be cast match nothing. Other operators compare
+    /// the column's text or JSON, like the document's values.
+    pub fn with_field_columns(mut self, columns: FieldColumns) -> Self {
+        self.options.field_columns = columns;
+        self
+    }
+
+    /// Parse `text` after expanding aliases, rejecting fields that are not allowed
+    fn parse(&self, text: &str) -> Result<Box<ast::Expression>, QueryError> {
+        let text = alias::expand(text, &self.aliases)?;
+        let tree = self.parser.parse(&text)?;
+        if self.allowed_fields.is_some() && tree.uses_json_path() {
+            return Err(QueryError::Semantic(
+                "jsonpath can't be used with restricted fields".into(),
+            ));
+        }
+        check_allowed(&self.allowed_fields, tree.referenced_identifiers())?;
+        Ok(tree)
+    }
+
+    /// Reject queries using more than `max_params` parameters (default 65535, postgres' limit)
+    pub fn with_max_params(mut self, max_params: usize) -> Self {
+        self.options.max_params = max_params;
+        self
+    }
+
     pub fn to_sql(
         &self,
         text: &str,
         param_offset: usize,
-    ) -> Result<(String, QueryParams), ParseError> {
+    ) -> Result<(String, QueryParams), QueryError> {
         if text.is_empty() {
             Ok(("1 = 1".into(), QueryParams::new()))
         } else {
-            let tree = self.parser.parse(text)?;
-            Ok(tree.to_sql_query(param_offset))
+            let tree = self.parse(text)?;
+            Ok(tree
+                .simplify()
+                .to_sql_query_with(&self.options, param_offset)?)
+        }
+    }
+
+    /// Check `text` for syntax and semantic errors without generating SQL
+    ///
+    /// Queries passing may still use more than the allowed number of parameters.
+    pub fn validate(&self, text: &str) -> Result<(), QueryError> {
+        if text.is_empty() {
+            return Ok(());
+        }
+        Ok(self.parse(text)?.check()?)
+    }
+
+    /// Hints about `text` that may make the query slow, empty if there are none
+    pub fn performance_hints(&self, text: &str) -> Result<Vec<&'static str>, ParseError> {
+        if text.is_empty() {
+            return Ok(Vec::new());
+        }
+        let text = alias::expand(text, &self.aliases)?;
+        let tree = self.parser.parse(&text)?.simplify();
+        let mut hints = Vec::new();
+        if tree.mixes_full_text_with_fields() {
+            hints.push(
+                "full text search or'ed with field conditions may not use the full text index",
+            );
+        }
+        Ok(hints)
+    }
+
+    /// All full text terms in `text`, each with the kind of `tsquery` it is searched with
+    ///
+    /// Combining the terms' tsqueries with `||` highlights every one of them. Empty if the query
+    /// does not search full text.
+    pub fn full_text_query(&self, text: &str) -> Result<Vec<(String, TsQuery)>, ParseError> {
+        if text.is_empty() {
+            return Ok(Vec::new());
+        }
+        let text = alias::expand(text, &self.aliases)?;
+        let tree = self.parser.parse(&text)?;
+        Ok(tree
+            .full_text_terms()
+            .into_iter()
+            .map(|(term, tsquery)| (term.to_owned(), tsquery))
+            .collect())
+    }
+
+    /// What could follow the text before byte offset `cursor`, for autocompletion
+    ///
+    /// A partially typed last word counts as complete, e.g. `hos` suggests operators.
+    pub fn suggestions(
+        &self,
+        text: &str,
+        cursor: usize,
+    ) -> Result<BTreeSet<Suggestion>, ParseError> {
+        let before = text.get(..cursor).ok_or_else(|| ParseError {
+            location: cursor,
+            expected: Vec::new(),
+            reason: Some("cursor is not at a character boundary".into()),
+        })?;
+        let expected = match self.parser.parse(before) {
+            Err(lalrpop_util::ParseError::UnrecognizedEOF { expected, .. }) => expected,
+            // "," never follows a complete expression, so the parser reports what could
+            Ok(_) => match self.parser.parse(&format!("{}\n,", before)) {
+                Err(lalrpop_util::ParseError::UnrecognizedToken { expected, .. }) => expected,
+                _ => Vec::new(),
+            },
+            Err(err) => return Err(err.into()),
+        };
+        // the parser's expected set may contain tokens that are rejected right away, like ")"
+        // without an open parenthesis
+        let expected: Vec<String> = expected
+            .into_iter()
+            .filter(
+                |token| match token.strip_prefix('"').and_then(|t| t.strip_suffix('"')) {
+                    Some(literal) if literal != "relative time" => !matches!(
+                        self.parser.parse(&format!("{}\n{}", before, literal)),
+                        Err(lalrpop_util::ParseError::UnrecognizedToken { .. })
+                    ),
+                    _ => true,
+                },
+            )
+            .collect();
+        Ok(Suggestion::categorize(&expected))
+    }
+}
+
+/// Category of tokens that may come next in a query
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Suggestion {
+    FieldName,
+    Operator,
+    String,
+    Number,
+    RelativeTime,
+    /// Bare word compared by its order, like a severity name
+    Name,
+    List,
+    /// A keyword or punctuation like "and" or ")"
+    Literal(String),
+}
+
+const OPERATORS: [&str; 15] = [
+    "=",
+    "!=",
+    "<",
+    "<=",
+    ">",
+    ">=",
+    "like",
+    "contains",
+    "icontains",
+    "in",
+    "in_subnet",
+    "has",
+    "has_any",
+    "has_all",
+    "is",
+];
+
+impl Suggestion {
+    /// Group the expected tokens reported by the parser
+    fn categorize(expected: &[String]) -> BTreeSet<Suggestion> {
+        let literals: Vec<&str> = expected
+            .iter()
+            .filter_map(|token| token.strip_prefix('"')?.strip_suffix('"'))
+            .collect();
+        let patterns: Vec<&str> = expected
+            .iter()
+            .filter_map(|token| token.strip_prefix("r#\"")?.strip_suffix("\"#"))
+            .collect();
+
+        let mut suggestions = BTreeSet::new();
+        for pattern in &patterns {
+            suggestions.insert(
+                if pattern.starts_with("\\\"") || pattern.starts_with('\'') {
+                    Suggestion::String
+                } else if pattern.starts_with("[a-zA-Z_]") || pattern.starts_with('`') {
+                    Suggestion::FieldName
+                } else {
+                    Suggestion::Number
+                },
+            );
+        }
+        // bare words in place of a value are names, not fields
+        let value_expected = suggestions.contains(&Suggestion::Number);
+        if value_expected && suggestions.remove(&Suggestion::FieldName) {
+            suggestions.insert(Suggestion::Name);
+        }
+
+        let list_expected = literals.contains(&"()");
+        for literal in literals {
+            suggestions.insert(match literal {
+                "relative time" => Suggestion::RelativeTime,
+                "()" => Suggestion::List,
+                "(" if list_expected => Suggestion::List,
+                "not in (" => Suggestion::Operator,
+                _ if OPERATORS.contains(&literal) => Suggestion::Operator,
+                _ => Suggestion::Literal(literal.into()),
+            });
+        }
+        suggestions
+    }
+}
+
+impl fmt::Display for Suggestion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Suggestion::FieldName => write!(f, "field name"),
+            Suggestion::Operator => write!(f, "operator"),
+            Suggestion::String => write!(f, "string"),
+            Suggestion::Number => write!(f, "number"),
+            Suggestion::RelativeTime => write!(f, "relative time"),
+            Suggestion::Name => write!(f, "name"),
+            Suggestion::List => write!(f, "list"),
+            Suggestion::Literal(literal) => write!(f, "{}", literal),
         }
     }
 }
 
+/// Parse `text` into an expression tree
+///
+/// Any input, valid or not, results in either an `Expression` or a `ParseError`. This makes it
+/// the entry point of choice for fuzzing.
+pub fn parse_expression(text: &str) -> Result<ast::Expression, ParseError> {
+    let parser = query::ExpressionParser::new();
+    Ok(*parser.parse(text)?)
+}
+
+/// Why a query can't be turned into SQL
+#[derive(Debug)]
+pub enum QueryError {
+    /// Invalid syntax
+    Parse(ParseError),
+    /// Valid syntax that makes no sense, like `x in 5`
+    Semantic(String),
+}
+
+impl Error for QueryError {}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QueryError::Parse(err) => write!(f, "{}", err),
+            QueryError::Semantic(reason) => write!(f, "invalid query: {}", reason),
+        }
+    }
+}
+
+impl From<ParseError> for QueryError {
+    fn from(err: ParseError) -> Self {
+        Self::Parse(err)
+    }
+}
+
+impl From<ast::SemanticError> for QueryError {
+    fn from(err: ast::SemanticError) -> Self {
+        Self::Semantic(err.to_string())
+    }
+}
+
+impl<T, E> From<lalrpop_util::ParseError<usize, T, E>> for QueryError {
+    fn from(err: lalrpop_util::ParseError<usize, T, E>) -> Self {
+        Self::Parse(err.into())
+    }
+}
+
 #[derive(Debug)]
 pub struct ParseError {
     location: usize,
     expected: Vec<String>,
+    reason: Option<String>,
 }
 
 impl Error for ParseError {}
 
 impl fmt::Display for ParseError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(
-            f,
-            "parse error at location {}, expected: {:?}",
-            self.location, self.expected
-        )
+        match &self.reason {
+            Some(reason) => write!(f, "invalid query: {}", reason),
+            None => write!(
+                f,
+                "parse error at location {}, expected: {:?}",
+                self.location, self.expected
+            ),
+        }
+    }
+}
+
+impl From<ast::SemanticError> for ParseError {
+    fn from(err: ast::SemanticError) -> Self {
+        Self {
+            location: 0,
+            expected: Vec::new(),
+            reason: Some(err.to_string()),
+        }
     }
 }
 
@@ -95,22 +473,27 @@ impl<T, E> From<lalrpop_util::ParseError<usize, T, E>> for ParseError {
             lalrpop_util::ParseError::InvalidToken { location } => Self {
                 location,
                 expected: Vec::new(),
+                reason: None,
             },
             lalrpop_util::ParseError::UnrecognizedEOF { location, expected } => Self {
                 location,
                 expected: expected.to_vec(),
+                reason: None,
             },
             lalrpop_util::ParseError::UnrecognizedToken { token, expected } => Self {
                 location: token.0,
                 expected: expected.to_vec(),
+                reason: None,
             },
             lalrpop_util::ParseError::ExtraToken { token } => Self {
                 location: token.0,
                 expected: Vec::new(),
+                reason: None,
             },
             _ => Self {
                 location: 0,
                 expected: Vec::new(),
+                reason: None,
             },
         }
     }
@@ -118,8 +501,11 @@ impl<T, E> From<lalrpop_util::ParseError<usize, T, E>> for ParseError {
 
 #[cfg(test)]
 mod test {
-    use super::query;
-    use crate::ast::{Expression, Identifier, Operator, Scalar, Value};
+    use super::{query, QueryError};
+    use crate::ast::{
+        Expression, Identifier, Operator, Placeholder, RelativeTime, Scalar, SemanticError,
+        SqlOptions, TimeUnit, TsQuery, Value,
+    };
     use serde_json::json;
 
     #[test]
@@ -127,27 +513,41 @@ mod test {
         let p = query::ExpressionParser::new();
         assert_eq!(
             *p.parse(r#"not "fts""#).unwrap(),
-            Expression::Not(Box::new(Expression::FullTextSearch("fts".into())))
+            Expression::Not(Box::new(Expression::FullTextSearch(
+                "fts".into(),
+                TsQuery::Websearch
+            )))
         );
 
         assert_eq!(
             *p.parse(r#"not "fts1" and "fts2""#).unwrap(),
             Expression::And(
                 Box::new(Expression::Not(Box::new(Expression::FullTextSearch(
-                    "fts1".into()
+                    "fts1".into(),
+                    TsQuery::Websearch
                 )))),
-                Box::new(Expression::FullTextSearch("fts2".into()))
+                Box::new(Expression::FullTextSearch(
+                    "fts2".into(),
+                    TsQuery::Websearch
+                ))
             )
         );
         assert_eq!(
             *p.parse(r#""fts1" or not "fts2" and "fts3""#).unwrap(),
             Expression::Or(
-                Box::new(Expression::FullTextSearch("fts1".into())),
+                Box::new(Expression::FullTextSearch(
+                    "fts1".into(),
+                    TsQuery::Websearch
+                )),
                 Box::new(Expression::And(
                     Box::new(Expression::Not(Box::new(Expression::FullTextSearch(
-                        "fts2".into()
+                        "fts2".into(),
+                        TsQuery::Websearch
                     )))),
-                    Box::new(Expression::FullTextSearch("fts3".into()))
+                    Box::new(Expression::FullTextSearch(
+                        "fts3".into(),
+                        TsQuery::Websearch
+                    ))
                 ))
             )
         );
@@ -155,20 +555,326 @@ mod test {
             *p.parse(r#"("a" or "b") and "c""#).unwrap(),
             Expression::And(
                 Box::new(Expression::Or(
-                    Box::new(Expression::FullTextSearch("a".into())),
-                    Box::new(Expression::FullTextSearch("b".into()))
+                    Box::new(Expression::FullTextSearch("a".into(), TsQuery::Websearch)),
+                    Box::new(Expression::FullTextSearch("b".into(), TsQuery::Websearch))
                 )),
-                Box::new(Expression::FullTextSearch("c".into()))
+                Box::new(Expression::FullTextSearch("c".into(), TsQuery::Websearch))
             )
         );
     }
 
+    #[test]
+    fn implicit_and() {
+        let p = query::ExpressionParser::new();
+        assert_eq!(
+            *p.parse(r#""a" "b""#).unwrap(),
+            Expression::And(
+                Box::new(Expression::FullTextSearch("a".into(), TsQuery::Websearch)),
+                Box::new(Expression::FullTextSearch("b".into(), TsQuery::Websearch))
+            )
+        );
+        assert_eq!(
+            p.parse(r#"hostname = "x" msg like "%err%""#).unwrap(),
+            p.parse(r#"hostname = "x" and msg like "%err%""#).unwrap()
+        );
+        // binds like an explicit "and"
+        assert_eq!(
+            p.parse(r#""a" "b" or "c" not "d""#).unwrap(),
+            p.parse(r#""a" and "b" or "c" and not "d""#).unwrap()
+        );
+        assert_eq!(
+            p.parse(r#"x not in (1) ("a" or "b")"#).unwrap(),
+            p.parse(r#"x not in (1) and ("a" or "b")"#).unwrap()
+        );
+        assert!(p.parse(r#""a" "b" and"#).is_err());
+        assert!(p.parse(r#""a" or"#).is_err());
+    }
+
+    #[test]
+    fn lone_field_names() {
+        let p = query::ExpressionParser::new();
+        let is_true = |id: &str| {
+            Box::new(Expression::Compare(
+                id.into(),
+                Operator::Eq,
+                Value::from(Scalar::Bool(true)),
+            ))
+        };
+        assert_eq!(
+            *p.parse("enabled and not disabled").unwrap(),
+            Expression::And(
+                is_true("enabled"),
+                Box::new(Expression::Not(is_true("disabled")))
+            )
+        );
+        assert_eq!(
+            p.parse("enabled not disabled").unwrap(),
+            p.parse("enabled and not disabled").unwrap()
+        );
+        assert_eq!(
+            p.parse("enabled = true").unwrap(),
+            p.parse("`enabled`").unwrap()
+        );
+        assert_eq!(
+            p.parse(r#"enabled or "a""#).unwrap(),
+            p.parse(r#"enabled = TRUE or "a""#).unwrap()
+        );
+        assert_eq!(
+            *p.parse("x = false").unwrap(),
+            Expression::Compare("x".into(), Operator::Eq, Value::from(Scalar::Bool(false)))
+        );
+
+        // "not" after a field name still starts "not in", or a negated term
+        assert_eq!(
+            p.parse("x not in(1)").unwrap(),
+            p.parse("not x in (1)").unwrap()
+        );
+        assert_eq!(
+            p.parse("x not index = 1").unwrap(),
+            p.parse("x and not index = 1").unwrap()
+        );
+
+        // a string right after a lone field name most likely misses its "="
+        assert!(p.parse(r#"host "a""#).is_err());
+        assert!(p.parse(r#"x = 1 host "a""#).is_err());
+        assert!(p.parse(r#"not host "a""#).is_err());
+        assert!(p.parse("host 1").is_err());
+        assert!(p.parse("host true").is_err());
+        assert_eq!(
+            p.parse(r#"host and "a""#).unwrap(),
+            p.parse(r#"host = true and "a""#).unwrap()
+        );
+        assert_eq!(
+            p.parse(r#"host ("a")"#).unwrap(),
+            p.parse(r#"host = true and "a""#).unwrap()
+        );
+        assert_eq!(
+            p.parse(r#""a" host x = 1 "b""#).unwrap(),
+            p.parse(r#""a" and host and x = 1 and "b""#).unwrap()
+        );
+
+        let p = super::ExpressionParser::default();
+        let (sql, params) = p.to_sql("enabled", 1).unwrap();
+        assert_eq!(sql, "doc -> ($1::jsonb #>> '{}') @> $2");
+        assert_eq!(params, vec![json!("enabled"), json!(true)]);
+    }
+
+    #[test]
+    fn field_columns() {
+        let columns: super::FieldColumns = [
+            ("hostname", "host", "text"),
+            ("syslogseverity_num", "severity", "smallint"),
+        ]
+        .into_iter()
+        .map(|(field, column, sql_type)| {
+            let column = super::FieldColumn {
+                column: column.into(),
+                sql_type: sql_type.into(),
+            };
+            (field.to_string(), column)
+        })
+        .collect();
+        let p = super::ExpressionParser::default().with_field_columns(columns.clone());
+        let (sql, params) = p
+            .to_sql(
+                r#"hostname = "a" and syslogseverity_num < 4 and msg = "b""#,
+                1,
+            )
+            .unwrap();
+        // the bare column, so that its index can be used
+        assert_eq!(
+            sql,
+            "((host = cast_or_null($1::jsonb #>> '{}', null::text) \
+             AND severity < cast_or_null($2::jsonb #>> '{}', null::smallint)) \
+             AND doc -> ($3::jsonb #>> '{}') @> $4)"
+        );
+        assert_eq!(params, vec![json!("a"), json!(4), json!("msg"), json!("b")]);
+
+        let to_sql = |query: &str| p.to_sql(query, 1).unwrap();
+        assert_eq!(
+            to_sql("syslogseverity < warning"),
+            (
+                "severity > cast_or_null($1::jsonb #>> '{}', null::smallint)".to_string(),
+                vec![json!(4)]
+            )
+        );
+        assert_eq!(
+            to_sql(r#"hostname != "a""#).0,
+            "(NOT host = cast_or_null($1::jsonb #>> '{}', null::text))"
+        );
+        assert_eq!(
+            to_sql("hostname is null").0,
+            "host IS NOT DISTINCT FROM cast_or_null($1::jsonb #>> '{}', null::text)"
+        );
+        assert_eq!(
+            to_sql("syslogseverity_num in (3, 4)"),
+            (
+                "severity = ANY(array(select cast_or_null(jsonb_array_elements($1::jsonb) #>> '{}', \
+                 null::smallint)))"
+                    .to_string(),
+                vec![json!([3, 4])]
+            )
+        );
+        // text matching and relative times still use the column's text
+        assert_eq!(
+            to_sql(r#"hostname like "a%""#).0,
+            "host::text LIKE $1::jsonb #>> '{}'"
+        );
+        assert_eq!(
+            to_sql("syslogseverity_num < now-1h").0,
+            "to_timestamp_or_null(severity::text) < (now() - ($1::jsonb #>> '{}')::interval)"
+        );
+
+        let p = super::IdentifierParser::default().with_field_columns(columns);
+        assert_eq!(
+            p.sql_json("hostname", 1).unwrap(),
+            ("to_jsonb(host)".to_string(), vec![])
+        );
+        assert_eq!(
+            p.sql_string("msg", 1).unwrap(),
+            (
+                "doc ->> ($1::jsonb #>> '{}')".to_string(),
+                vec![json!("msg")]
+            )
+        );
+    }
+
+    #[test]
+    fn allowed_fields() {
+        let allowed = Some(["host", "syslogseverity"].map(String::from).into());
+        let p = super::ExpressionParser::default()
+            .with_allowed_fields(allowed.clone())
+            .with_aliases(
+                [("secret".to_string(), "password = 1".to_string())]
+                    .into_iter()
+                    .collect(),
+            );
+        for query in [
+            r#"host = "a""#,
+            r#"not (host in ("a", "b") or syslogseverity <= error)"#,
+            r#""password""#,
+            "",
+        ] {
+            assert!(p.to_sql(query, 1).is_ok(), "{}", query);
+            assert!(p.validate(query).is_ok(), "{}", query);
+        }
+        for (query, reason) in [
+            (
+                r#"host = "a" or password = 1"#,
+                "field password can't be queried",
+            ),
+            ("`host.name` = 1", "field host.name can't be queried"),
+            ("@secret", "field password can't be queried"),
+            (
+                r#"jsonpath "$.password""#,
+                "jsonpath can't be used with restricted fields",
+            ),
+        ] {
+            match p.to_sql(query, 1) {
+                Err(QueryError::Semantic(err)) => assert_eq!(err, reason),
+                other => panic!("{}: {:?}", query, other),
+            }
+            assert!(p.validate(query).is_err(), "{}", query);
+        }
+
+        let p = super::IdentifierParser::default().with_allowed_fields(allowed);
+        assert!(p.sql_string("host", 1).is_ok());
+        assert!(matches!(
+            p.sql_json("password", 1),
+            Err(QueryError::Semantic(_))
+        ));
+        assert!(super::ExpressionParser::default()
+            .to_sql("password = 1", 1)
+            .is_ok());
+    }
+
+    #[test]
+    fn case_insensitive_keywords() {
+        let p = query::ExpressionParser::new();
+        assert_eq!(
+            p.parse(r#""a" OR "b""#).unwrap(),
+            p.parse(r#""a" or "b""#).unwrap()
+        );
+        assert_eq!(
+            p.parse(r#"x NOT IN (1)"#).unwrap(),
+            p.parse(r#"x not in (1)"#).unwrap()
+        );
+        assert_eq!(
+            p.parse(r#"NOT "a" And x Like "b%" aNd y ICONTAINS "c""#)
+                .unwrap(),
+            p.parse(r#"not "a" and x like "b%" and y icontains "c""#)
+                .unwrap()
+        );
+        assert_eq!(
+            p.parse(r#"ip IN_SUBNET "10.0.0.0/8""#).unwrap(),
+            p.parse(r#"ip in_subnet "10.0.0.0/8""#).unwrap()
+        );
+        // identifiers merely starting with a keyword are not affected
+        assert_eq!(
+            *p.parse(r#"Order = 1"#).unwrap(),
+            Expression::Compare("Order".into(), Operator::Eq, Value::from(1))
+        );
+    }
+
+    #[test]
+    fn keywords_as_identifiers_need_backticks() {
+        let p = query::ExpressionParser::new();
+        for name in ["Or", "IN", "Not", "Like", "now"] {
+            assert!(p.parse(&format!("{} = 1", name)).is_err(), "{}", name);
+            assert_eq!(
+                *p.parse(&format!("`{}` = 1", name)).unwrap(),
+                Expression::Compare(name.into(), Operator::Eq, Value::from(1)),
+            );
+        }
+        assert_eq!(
+            *p.parse("`IN` IN (1) AND `Not` = 2").unwrap(),
+            Expression::And(
+                Box::new(Expression::Compare(
+                    "IN".into(),
+                    Operator::In,
+                    Value::List(vec![Scalar::from(1)])
+                )),
+                Box::new(Expression::Compare(
+                    "Not".into(),
+                    Operator::Eq,
+                    Value::from(2)
+                ))
+            )
+        );
+        assert_eq!(
+            *p.parse("`now` > now-1h").unwrap(),
+            Expression::Compare(
+                "now".into(),
+                Operator::Gt,
+                Value::from(RelativeTime::ago(1, TimeUnit::Hour))
+            )
+        );
+        // names merely starting with "now" are plain identifiers
+        assert_eq!(
+            *p.parse("nowhere = 1").unwrap(),
+            Expression::Compare("nowhere".into(), Operator::Eq, Value::from(1))
+        );
+    }
+
+    #[test]
+    fn parse_expression_does_not_panic() {
+        assert!(crate::parse_expression(r#"id = 99999999999999999999"#).is_err());
+        assert!(crate::parse_expression(r#"id < -99999999999999999999"#).is_err());
+        assert!(crate::parse_expression(r#"id in (1, 99999999999999999999)"#).is_err());
+        assert!(crate::parse_expression(r#"((("a")"#).is_err());
+        assert!(crate::parse_expression("\"\u{0}").is_err());
+        assert_eq!(
+            crate::parse_expression(r#"id = -9223372036854775808"#).unwrap(),
+            Expression::Compare("id".into(), Operator::Eq, Value::from(i64::MIN))
+        );
+    }
+
     #[test]
     fn parse_term() {
         let p = query::TermParser::new();
         assert_eq!(
             *p.parse(r#""asdf""#).unwrap(),
-            Expression::FullTextSearch("asdf".into())
+            Expression::FullTextSearch("asdf".into(), TsQuery::Websearch)
         );
         assert_eq!(
             *p.parse(r#"ident = "value""#).unwrap(),
@@ -235,8 +941,9 @@ mod test {
 
     #[test]
     fn to_sql() {
-        let (query, params) =
-            Expression::Compare("id".into(), Operator::Eq, Value::from(123)).to_sql_query(5);
+        let (query, params) = Expression::Compare("id".into(), Operator::Eq, Value::from(123))
+            .to_sql_query(5)
+            .unwrap();
         let expected_query = format!(
             "doc -> ($5::jsonb #>> '{{}}') {} $6",
             Operator::Eq.sql_symbol()
@@ -247,32 +954,890 @@ mod test {
             vec![serde_json::Value::from("id"), serde_json::Value::from(123)]
         );
 
-        let (query, params) = Expression::FullTextSearch("asdf".into()).to_sql_query(1);
+        let (query, params) = Expression::FullTextSearch("asdf".into(), TsQuery::Websearch)
+            .to_sql_query(1)
+            .unwrap();
         assert_eq!(query, "search @@ websearch_to_tsquery($1::jsonb #>> '{}')");
         assert_eq!(params[0], "asdf");
 
         let (query, params) = Expression::And(
-            Box::new(Expression::FullTextSearch("a".into())),
-            Box::new(Expression::FullTextSearch("b".into())),
+            Box::new(Expression::FullTextSearch("a".into(), TsQuery::Websearch)),
+            Box::new(Expression::FullTextSearch("b".into(), TsQuery::Websearch)),
         )
-        .to_sql_query(11);
+        .to_sql_query(11)
+        .unwrap();
         let expected_query = format!(
             "({} AND {})",
-            Expression::FullTextSearch("a".into()).to_sql_query(11).0,
-            Expression::FullTextSearch("b".into()).to_sql_query(12).0
+            Expression::FullTextSearch("a".into(), TsQuery::Websearch)
+                .to_sql_query(11)
+                .unwrap()
+                .0,
+            Expression::FullTextSearch("b".into(), TsQuery::Websearch)
+                .to_sql_query(12)
+                .unwrap()
+                .0
         );
         assert_eq!(query, expected_query);
         assert_eq!(params, vec!["a", "b"]);
     }
 
+    #[test]
+    fn empty_in_list() {
+        let p = crate::ExpressionParser::default();
+        assert_eq!(
+            crate::parse_expression("x not in ()").unwrap(),
+            Expression::Not(Box::new(Expression::Compare(
+                "x".into(),
+                Operator::In,
+                Value::from(Vec::new())
+            )))
+        );
+
+        let (query, params) = p.to_sql("x in ()", 1).unwrap();
+        assert_eq!(query, "false");
+        assert!(params.is_empty());
+
+        let (query, params) = p.to_sql("x not in ()", 1).unwrap();
+        assert_eq!(query, "true");
+        assert!(params.is_empty());
+
+        // the constant is folded away
+        let (query, params) = p.to_sql(r#"x in () or "a""#, 1).unwrap();
+        assert_eq!(query, "search @@ websearch_to_tsquery($1::jsonb #>> '{}')");
+        assert_eq!(params, vec!["a"]);
+
+        let (query, params) = p.to_sql("x not in (1)", 1).unwrap();
+        assert_eq!(
+            query,
+            "(NOT doc ->> ($1::jsonb #>> '{}') IN (select jsonb_array_elements($2::jsonb) #>> '{}'))"
+        );
+        assert_eq!(params, vec![json!("x"), json!([1])]);
+    }
+
+    #[test]
+    fn operand_type_mismatch() {
+        let list = || Value::from(vec![Scalar::from(1), Scalar::from(2)]);
+        for op in [
+            Operator::Lt,
+            Operator::Le,
+            Operator::Gt,
+            Operator::Ge,
+            Operator::Like,
+        ] {
+            assert!(Expression::Compare("x".into(), op, list())
+                .to_sql_query(1)
+                .is_err());
+        }
+        assert_eq!(
+            Expression::Compare("x".into(), Operator::Lt, list()).to_sql_query(1),
+            Err(SemanticError::new("operator < can't be used with a list"))
+        );
+        assert_eq!(
+            Expression::Compare("x".into(), Operator::In, Value::from(5)).to_sql_query(1),
+            Err(SemanticError::new("operator in requires a list"))
+        );
+
+        // nested mismatches are found, too
+        let nested = Expression::Not(Box::new(Expression::And(
+            Box::new(Expression::FullTextSearch("a".into(), TsQuery::Websearch)),
+            Box::new(Expression::Compare(
+                "x".into(),
+                Operator::In,
+                Value::from(5),
+            )),
+        )));
+        assert!(nested.to_sql_query(1).is_err());
+
+        assert!(Expression::Compare("x".into(), Operator::Eq, list())
+            .to_sql_query(1)
+            .is_ok());
+
+        // the grammar doesn't allow these at all
+        let p = crate::ExpressionParser::default();
+        assert!(p.to_sql("x < (1, 2)", 1).is_err());
+        assert!(p.to_sql("x in 5", 1).is_err());
+    }
+
+    #[test]
+    fn in_subnet() {
+        let p = crate::ExpressionParser::default();
+        let (query, params) = p
+            .to_sql(r#"fromhost_ip in_subnet "10.0.0.0/8""#, 3)
+            .unwrap();
+        assert_eq!(
+            query,
+            "to_inet_or_null(doc ->> ($3::jsonb #>> '{}')) << ($4::jsonb #>> '{}')::inet"
+        );
+        assert_eq!(params, vec!["fromhost_ip", "10.0.0.0/8"]);
+
+        assert!(p.to_sql(r#"fromhost_ip in_subnet 10"#, 1).is_err());
+        assert!(p
+            .to_sql(r#"fromhost_ip in_subnet ("10.0.0.0/8")"#, 1)
+            .is_err());
+        assert!(Expression::Compare(
+            "fromhost_ip".into(),
+            Operator::InSubnet,
+            Value::from(vec![Scalar::from("10.0.0.0/8")])
+        )
+        .to_sql_query(1)
+        .is_err());
+    }
+
+    #[test]
+    fn relative_time() {
+        let p = crate::ExpressionParser::default();
+        assert_eq!(
+            crate::parse_expression("timereported > now-30m").unwrap(),
+            Expression::Compare(
+                "timereported".into(),
+                Operator::Gt,
+                Value::from(RelativeTime::ago(30, TimeUnit::Minute))
+            )
+        );
+
+        let (query, params) = p.to_sql("timereported > now-30m", 1).unwrap();
+        assert_eq!(
+            query,
+            "to_timestamp_or_null(doc ->> ($1::jsonb #>> '{}')) > (now() - ($2::jsonb #>> '{}')::interval)"
+        );
+        assert_eq!(params, vec!["timereported", "30 minutes"]);
+
+        let (query, params) = p.to_sql("timereported <= now-2d", 1).unwrap();
+        assert_eq!(
+            query,
+            "to_timestamp_or_null(doc ->> ($1::jsonb #>> '{}')) <= (now() - ($2::jsonb #>> '{}')::interval)"
+        );
+        assert_eq!(params, vec!["timereported", "2 days"]);
+
+        let (query, params) = p.to_sql("timegenerated < now", 1).unwrap();
+        assert_eq!(
+            query,
+            "to_timestamp_or_null(doc ->> ($1::jsonb #>> '{}')) < now()"
+        );
+        assert_eq!(params, vec!["timegenerated"]);
+
+        // numeric comparisons are unaffected
+        let (query, _) = p.to_sql("size > 30", 1).unwrap();
+        assert!(query.starts_with("to_number_or_null("));
+
+        assert!(p.to_sql("timereported > now-30", 1).is_err());
+        assert!(p.to_sql("timereported > now-99999999999h", 1).is_err());
+        assert!(p.to_sql("timereported = now-1h", 1).is_err());
+        assert!(p.to_sql("now-1h > 5", 1).is_err());
+        assert!(
+            Expression::Compare("x".into(), Operator::Eq, Value::from(RelativeTime::now()))
+                .to_sql_query(1)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn not_equal() {
+        let p = crate::ExpressionParser::default();
+        assert_eq!(
+            crate::parse_expression(r#"id != "value""#).unwrap(),
+            Expression::Compare("id".into(), Operator::Ne, Value::from("value"))
+        );
+
+        let (query, params) = p.to_sql(r#"id != "value""#, 1).unwrap();
+        assert_eq!(query, "(NOT doc -> ($1::jsonb #>> '{}') @> $2)");
+        assert_eq!(params, vec!["id", "value"]);
+        assert_eq!((query, params), p.to_sql(r#"not id = "value""#, 1).unwrap());
+
+        let (query, params) = p.to_sql(r#""a" and id != (1, 2)"#, 1).unwrap();
+        assert_eq!(
+            query,
+            "(search @@ websearch_to_tsquery($1::jsonb #>> '{}') AND (NOT doc -> ($2::jsonb #>> '{}') @> $3::jsonb))"
+        );
+        assert_eq!(params, vec![json!("a"), json!("id"), json!([1, 2])]);
+    }
+
+    #[test]
+    fn contains() {
+        let p = crate::ExpressionParser::default();
+        let (query, params) = p.to_sql(r#"msg contains "timeout""#, 1).unwrap();
+        assert_eq!(
+            query,
+            r#"doc ->> ($1::jsonb #>> '{}') LIKE $2::jsonb #>> '{}' ESCAPE '\'"#
+        );
+        assert_eq!(params, vec!["msg", "%timeout%"]);
+
+        let (query, params) = p.to_sql(r#"msg icontains "Timeout""#, 1).unwrap();
+        assert_eq!(
+            query,
+            r#"doc ->> ($1::jsonb #>> '{}') ILIKE $2::jsonb #>> '{}' ESCAPE '\'"#
+        );
+        assert_eq!(params, vec!["msg", "%Timeout%"]);
+
+        // wildcards and the escape character in the needle are matched literally
+        let (_, params) = p.to_sql(r#"msg contains "100%_done\\""#, 1).unwrap();
+        assert_eq!(params[1], r#"%100\%\_done\\%"#);
+
+        assert!(p.to_sql(r#"msg contains ("a", "b")"#, 1).is_err());
+        assert!(Expression::Compare(
+            "msg".into(),
+            Operator::Contains,
+            Value::from(vec![Scalar::from("a")])
+        )
+        .to_sql_query(1)
+        .is_err());
+    }
+
+    #[test]
+    fn array_membership() {
+        let p = crate::ExpressionParser::default();
+        let (query, params) = p.to_sql(r#"tags has "db""#, 1).unwrap();
+        assert_eq!(query, r#"doc -> ($1::jsonb #>> '{}') @> $2::jsonb"#);
+        assert_eq!(params, vec![json!("tags"), json!(["db"])]);
+
+        let (query, params) = p.to_sql(r#"ports has 443"#, 1).unwrap();
+        assert_eq!(query, r#"doc -> ($1::jsonb #>> '{}') @> $2::jsonb"#);
+        assert_eq!(params[1], json!([443]));
+
+        let (query, params) = p.to_sql(r#"tags has_any ("db", "web")"#, 1).unwrap();
+        assert_eq!(
+            query,
+            r#"(doc -> ($1::jsonb #>> '{}') @> $2::jsonb OR doc -> ($3::jsonb #>> '{}') @> $4::jsonb)"#
+        );
+        assert_eq!(
+            params,
+            vec![json!("tags"), json!(["db"]), json!("tags"), json!(["web"])]
+        );
+
+        let (query, _) = p.to_sql(r#"tags HAS_ALL ("db", "web")"#, 1).unwrap();
+        assert_eq!(
+            query,
+            r#"(doc -> ($1::jsonb #>> '{}') @> $2::jsonb AND doc -> ($3::jsonb #>> '{}') @> $4::jsonb)"#
+        );
+
+        // numbers and booleans are matched as such, not as their text
+        let (query, params) = p.to_sql("ports has_any (80, 443)", 1).unwrap();
+        assert_eq!(
+            query,
+            r#"(doc -> ($1::jsonb #>> '{}') @> $2::jsonb OR doc -> ($3::jsonb #>> '{}') @> $4::jsonb)"#
+        );
+        assert_eq!(params[1], json!([80]));
+        assert_eq!(params[3], json!([443]));
+
+        let (query, params) = p.to_sql(r#"x has_all (1, "a", true)"#, 3).unwrap();
+        assert_eq!(
+            query,
+            "(doc -> ($3::jsonb #>> '{}') @> $4::jsonb AND doc -> ($5::jsonb #>> '{}') @> $6::jsonb \
+             AND doc -> ($7::jsonb #>> '{}') @> $8::jsonb)"
+        );
+        assert_eq!(
+            params,
+            vec![
+                json!("x"),
+                json!([1]),
+                json!("x"),
+                json!(["a"]),
+                json!("x"),
+                json!([true])
+            ]
+        );
+
+        assert_eq!(p.to_sql("x has_any ()", 1).unwrap().0, "false");
+        assert_eq!(p.to_sql("x has_all ()", 1).unwrap().0, "true");
+
+        assert!(p.to_sql(r#"tags has ("db", "web")"#, 1).is_err());
+        assert!(p.to_sql(r#"tags has_any "db""#, 1).is_err());
+        assert!(
+            Expression::Compare("tags".into(), Operator::HasAll, Value::from("db"))
+                .to_sql_query(1)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn tsquery_modes() {
+        let p = super::ExpressionParser::default();
+        let sql = |text| p.to_sql(text, 1).unwrap();
+        assert_eq!(
+            sql(r#""disk full""#),
+            (
+                "search @@ websearch_to_tsquery($1::jsonb #>> '{}')".into(),
+                vec![json!("disk full")]
+            )
+        );
+        assert_eq!(
+            sql(r#"plain "disk full""#).0,
+            "search @@ plainto_tsquery($1::jsonb #>> '{}')"
+        );
+        assert_eq!(
+            sql(r#"PHRASE 'disk full'"#),
+            (
+                "search @@ phraseto_tsquery($1::jsonb #>> '{}')".into(),
+                vec![json!("disk full")]
+            )
+        );
+        assert_eq!(
+            sql(r#"phrase "a b" or not plain "c""#).0,
+            "(search @@ phraseto_tsquery($1::jsonb #>> '{}') \
+             OR (NOT search @@ plainto_tsquery($2::jsonb #>> '{}')))"
+        );
+        assert!(p.to_sql(r#"phrase x"#, 1).is_err());
+        assert_eq!(
+            p.full_text_query(r#"phrase "a b" and plain "c""#).unwrap(),
+            [
+                ("a b".to_string(), TsQuery::Phrase),
+                ("c".to_string(), TsQuery::Plain)
+            ]
+        );
+    }
+
+    #[test]
+    fn full_text_or_fields() {
+        let p = super::ExpressionParser::default();
+        assert_eq!(
+            p.to_sql(r#""x" or y = 1"#, 1).unwrap(),
+            (
+                "(search @@ websearch_to_tsquery($1::jsonb #>> '{}') \
+                 OR doc -> ($2::jsonb #>> '{}') @> $3)"
+                    .into(),
+                vec![json!("x"), json!("y"), json!(1)]
+            )
+        );
+        assert_eq!(p.performance_hints(r#""x" or y = 1"#).unwrap().len(), 1);
+        assert_eq!(
+            p.performance_hints(r#"a = 1 and (b = 2 or not "x")"#)
+                .unwrap()
+                .len(),
+            1
+        );
+        assert!(p.performance_hints(r#""x" or "y""#).unwrap().is_empty());
+        assert!(p.performance_hints(r#""x" and y = 1"#).unwrap().is_empty());
+        assert!(p.performance_hints(r#"x = 1 or y = 1"#).unwrap().is_empty());
+        assert!(p.performance_hints("").unwrap().is_empty());
+        assert!(p.performance_hints(r#""x" or"#).is_err());
+    }
+
+    #[test]
+    fn aliases() {
+        let aliases = [(
+            "errors".to_string(),
+            r#"syslogseverity <= "error""#.to_string(),
+        )];
+        let p = super::ExpressionParser::default().with_aliases(aliases.into_iter().collect());
+        assert_eq!(
+            p.to_sql(r#"@errors and "disk""#, 1).unwrap(),
+            p.to_sql(r#"(syslogseverity <= "error") and "disk""#, 1)
+                .unwrap()
+        );
+        assert!(p.validate("@errors").is_ok());
+        assert_eq!(
+            p.validate("@warnings").unwrap_err().to_string(),
+            "invalid query: unknown alias @warnings"
+        );
+        assert_eq!(
+            p.full_text_query(r#""disk" and @errors"#).unwrap(),
+            [("disk".to_string(), super::TsQuery::Websearch)]
+        );
+        assert!(super::ExpressionParser::default()
+            .to_sql("@errors", 1)
+            .is_err());
+    }
+
+    #[test]
+    fn comments() {
+        let p = super::ExpressionParser::default();
+        let plain = p.to_sql(r#"hostname = "x" and a = 1"#, 1).unwrap();
+        assert_eq!(
+            p.to_sql(r#"hostname = "x" # only prod"#, 1).unwrap(),
+            p.to_sql(r#"hostname = "x""#, 1).unwrap()
+        );
+        assert_eq!(
+            p.to_sql(
+                "# web servers\nhostname = \"x\" # only prod\n  and a = 1 #\n",
+                1
+            )
+            .unwrap(),
+            plain
+        );
+        assert_eq!(
+            p.to_sql("hostname = \"x\" and # first\r\n# second\na = 1", 1)
+                .unwrap(),
+            plain
+        );
+        assert_eq!(
+            p.to_sql(r##"msg = "# not a comment""##, 1).unwrap().1[1],
+            json!("# not a comment")
+        );
+        assert!(p.to_sql(r##"hostname = # "x""##, 1).is_err());
+        assert_eq!(
+            p.suggestions(r#"hostname = "x" # comment"#, 24).unwrap(),
+            p.suggestions(r#"hostname = "x" "#, 15).unwrap()
+        );
+    }
+
+    #[test]
+    fn jsonpath() {
+        let p = crate::ExpressionParser::default();
+        let (query, params) = p
+            .to_sql(r#"jsonpath "$.a[*].b ? (@ > 3)" and x = 1"#, 1)
+            .unwrap();
+        assert_eq!(
+            query,
+            "(jsonb_path_exists(doc, ($1::jsonb #>> '{}')::jsonpath) \
+             AND doc -> ($2::jsonb #>> '{}') @> $3)"
+        );
+        assert_eq!(
+            params,
+            vec![json!("$.a[*].b ? (@ > 3)"), json!("x"), json!(1)]
+        );
+
+        // the path never ends up in the SQL text
+        let (query, params) = p
+            .to_sql(r#"jsonpath "'); drop table logs; --""#, 1)
+            .unwrap();
+        assert_eq!(
+            query,
+            "jsonb_path_exists(doc, ($1::jsonb #>> '{}')::jsonpath)"
+        );
+        assert_eq!(params, vec![json!("'); drop table logs; --")]);
+
+        assert!(p.to_sql(r#"jsonpath $.a"#, 1).is_err());
+        assert!(p.to_sql(r#"jsonpath 5"#, 1).is_err());
+        assert!(p.full_text_query(r#"jsonpath "$.a""#).unwrap().is_empty());
+    }
+
+    #[test]
+    fn severity_ordering() {
+        let p = crate::ExpressionParser::default();
+        let (query, params) = p.to_sql(r#"syslogseverity >= warning"#, 1).unwrap();
+        assert_eq!(
+            query,
+            "to_number_or_null(doc ->> ($1::jsonb #>> '{}')) <= ($2::jsonb #>> '{}')::numeric"
+        );
+        assert_eq!(params, vec![json!("syslogseverity_num"), json!(4)]);
+
+        // warning < error: less severe means a higher number
+        let (query, params) = p.to_sql(r#"syslogseverity < "Error""#, 1).unwrap();
+        assert!(query.contains(" > "));
+        assert_eq!(params[1], json!(3));
+        let (query, _) = p.to_sql(r#"syslogseverity <= debug"#, 1).unwrap();
+        assert!(query.contains(" >= "));
+        let (query, _) = p.to_sql(r#"syslogseverity > info"#, 1).unwrap();
+        assert!(query.contains(" < "));
+
+        // equality still compares the stored names
+        let (_, params) = p.to_sql(r#"syslogseverity = "error""#, 1).unwrap();
+        assert_eq!(params, vec![json!("syslogseverity"), json!("error")]);
+
+        assert!(p.to_sql(r#"syslogseverity < fatal"#, 1).is_err());
+        assert!(p.to_sql(r#"other < error"#, 1).is_err());
+    }
+
+    #[test]
+    fn quoted_identifiers() {
+        let p = crate::ExpressionParser::default();
+        let (query, params) = p.to_sql("`weird key` = 1", 1).unwrap();
+        assert_eq!(query, "doc -> ($1::jsonb #>> '{}') @> $2");
+        assert_eq!(params, vec![json!("weird key"), json!(1)]);
+
+        let (_, params) = p
+            .to_sql(r#"`2xx count` > 10 and `and` like "%""#, 1)
+            .unwrap();
+        assert_eq!(params[0], json!("2xx count"));
+        assert_eq!(params[2], json!("and"));
+
+        let (_, params) = p.to_sql(r#"`a\`b\\c` = "x""#, 1).unwrap();
+        assert_eq!(params[0], json!(r#"a`b\c"#));
+
+        assert!(p.to_sql("2xx = 1", 1).is_err());
+        assert!(p.to_sql("`` = 1", 1).is_err());
+
+        let p = crate::IdentifierParser::default();
+        let (getter, params) = p.sql_string("`response time (ms)`", 1).unwrap();
+        assert_eq!(getter, "doc ->> ($1::jsonb #>> '{}')");
+        assert_eq!(params, vec![json!("response time (ms)")]);
+    }
+
+    #[test]
+    fn validate() {
+        let p = crate::ExpressionParser::default();
+        for valid in [
+            "",
+            r#""text""#,
+            r#"host = "a" and not (x in (1, 2) or y < now-1h)"#,
+            "x has_any (1, 2)",
+            "syslogseverity <= warning",
+            "x is not null",
+            r#"jsonpath "$.a""#,
+        ] {
+            assert!(p.validate(valid).is_ok(), "{}", valid);
+            assert!(p.to_sql(valid, 1).is_ok(), "{}", valid);
+        }
+        for invalid in [
+            "host =",
+            r#"(host = "a""#,
+            "x like (1, 2)",
+            "x in 1",
+            r#"x > "a""#,
+            "syslogseverity < fatal",
+            "x = null",
+            "x contains now",
+        ] {
+            assert!(p.validate(invalid).is_err(), "{}", invalid);
+            assert!(p.to_sql(invalid, 1).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn error_kinds() {
+        use crate::QueryError;
+        let p = crate::ExpressionParser::default().with_max_params(2);
+        for syntax in [
+            "host =",
+            r#"(host = "a""#,
+            "id = 99999999999999999999",
+            "x in 1",
+            "x like (1, 2)",
+        ] {
+            assert!(
+                matches!(p.to_sql(syntax, 1), Err(QueryError::Parse(_))),
+                "{}",
+                syntax
+            );
+            assert!(matches!(p.validate(syntax), Err(QueryError::Parse(_))));
+        }
+        for semantic in [r#"x > "a""#, "syslogseverity < fatal"] {
+            assert!(
+                matches!(p.to_sql(semantic, 1), Err(QueryError::Semantic(_))),
+                "{}",
+                semantic
+            );
+            assert!(matches!(p.validate(semantic), Err(QueryError::Semantic(_))));
+        }
+        match p.to_sql("a = 1 and b = 2", 1) {
+            Err(QueryError::Semantic(reason)) => {
+                assert_eq!(reason, "query needs 4 parameters, at most 2 are allowed")
+            }
+            other => panic!("{:?}", other),
+        }
+        match p.to_sql("a = 1", 0) {
+            Err(QueryError::Semantic(reason)) => {
+                assert_eq!(reason, "parameters are numbered from 1")
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn null_safe_equality() {
+        let p = crate::ExpressionParser::default();
+        let (query, params) = p.to_sql("x is null", 1).unwrap();
+        assert_eq!(
+            query,
+            "nullif(doc -> ($1::jsonb #>> '{}'), 'null'::jsonb) IS NOT DISTINCT FROM \
+             nullif($2::jsonb, 'null'::jsonb)"
+        );
+        assert_eq!(params, vec![json!("x"), json!(null)]);
+
+        let (query, params) = p.to_sql(r#"x is not "a""#, 1).unwrap();
+        assert_eq!(
+            query,
+            "(NOT nullif(doc -> ($1::jsonb #>> '{}'), 'null'::jsonb) IS NOT DISTINCT FROM \
+             nullif($2::jsonb, 'null'::jsonb))"
+        );
+        assert_eq!(params, vec![json!("x"), json!("a")]);
+
+        let (_, params) = p.to_sql("x IS 1.5", 1).unwrap();
+        assert_eq!(params[1], json!(1.5));
+
+        // null is only a value for is
+        assert!(p.to_sql("x = null", 1).is_err());
+        assert!(p.to_sql("x is (1, 2)", 1).is_err());
+        assert_eq!(
+            *query::ExpressionParser::new().parse("x is null").unwrap(),
+            Expression::Compare("x".into(), Operator::Is, Value::from(Scalar::Null))
+        );
+    }
+
+    #[test]
+    fn too_many_params() {
+        // lists are bound as a single parameter, every comparison needs two
+        let terms: Vec<String> = (0..6).map(|i| format!("x = {}", i)).collect();
+        let text = terms.join(" or ");
+        let p = crate::ExpressionParser::default().with_max_params(10);
+        let err = p.to_sql(&text, 1).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid query: query needs 12 parameters, at most 10 are allowed"
+        );
+        assert!(crate::ExpressionParser::default().to_sql(&text, 1).is_ok());
+
+        // parameters before the offset count as well
+        let p = crate::ExpressionParser::default().with_max_params(4);
+        assert!(p.to_sql("a = 1 b = 2", 1).is_ok());
+        assert!(p.to_sql("a = 1 b = 2", 2).is_err());
+        assert!(p.to_sql("a in (1, 2, 3) b = 2", 1).is_ok());
+    }
+
+    #[test]
+    fn suggestions() {
+        use crate::Suggestion;
+        let p = crate::ExpressionParser::default();
+        let suggest = |text: &str| -> Vec<String> {
+            p.suggestions(text, text.len())
+                .unwrap()
+                .iter()
+                .map(|s| s.to_string())
+                .collect()
+        };
+        assert_eq!(
+            suggest(""),
+            vec![
+                "field name",
+                "string",
+                "(",
+                "jsonpath",
+                "not",
+                "phrase",
+                "plain"
+            ]
+        );
+        // a lone field name is complete already, it means "host = true", but a string right after
+        // it is taken for a missing "="
+        assert_eq!(
+            suggest("host"),
+            vec![
+                "field name",
+                "operator",
+                "(",
+                "and",
+                "jsonpath",
+                "not",
+                "or",
+                "phrase",
+                "plain"
+            ]
+        );
+        assert_eq!(
+            suggest("host = "),
+            vec!["string", "number", "list", "false", "true"]
+        );
+        assert_eq!(
+            suggest("severity <"),
+            vec!["string", "number", "relative time", "name"]
+        );
+        assert_eq!(suggest("host in (1"), vec![")", ","]);
+        assert_eq!(
+            suggest(r#"host = "a" "#),
+            vec![
+                "field name",
+                "string",
+                "(",
+                "and",
+                "jsonpath",
+                "not",
+                "or",
+                "phrase",
+                "plain"
+            ]
+        );
+        assert_eq!(suggest("jsonpath"), vec!["string"]);
+        assert_eq!(suggest("phrase"), vec!["string"]);
+
+        // only the text before the cursor counts
+        assert_eq!(
+            p.suggestions(r#"host = "a""#, 4).unwrap(),
+            p.suggestions("host", 4).unwrap()
+        );
+        assert!(!p
+            .suggestions(r#"host = "a""#, 6)
+            .unwrap()
+            .contains(&Suggestion::Operator));
+        assert!(p.suggestions("= host", 6).is_err());
+        assert!(p.suggestions("höst", 2).is_err());
+    }
+
+    #[test]
+    fn full_text_query() {
+        let p = super::ExpressionParser::default();
+        let terms = |text| -> Vec<String> {
+            p.full_text_query(text)
+                .unwrap()
+                .into_iter()
+                .map(|(term, _)| term)
+                .collect()
+        };
+        assert!(terms("").is_empty());
+        assert!(terms(r#"a = 1"#).is_empty());
+        assert_eq!(
+            terms(r#""error" and (a = 1 or "timeout")"#),
+            ["error", "timeout"]
+        );
+        assert_eq!(terms(r#""error" and not "debug""#), ["error"]);
+        assert!(p.full_text_query(r#""error" and"#).is_err());
+    }
+
+    #[test]
+    fn custom_columns() {
+        let p = super::ExpressionParser::default().with_columns("payload", "fts");
+        let (sql, params) = p
+            .to_sql(r#"a = "x" and b < 5 and c = ("y") and "term""#, 1)
+            .unwrap();
+        assert_eq!(
+            sql,
+            "(((payload -> ($1::jsonb #>> '{}') @> $2 \
+             AND to_number_or_null(payload ->> ($3::jsonb #>> '{}')) < ($4::jsonb #>> '{}')::numeric) \
+             AND payload -> ($5::jsonb #>> '{}') @> $6::jsonb) \
+             AND fts @@ websearch_to_tsquery($7::jsonb #>> '{}'))"
+        );
+        assert_eq!(params.len(), 7);
+
+        let (default_sql, _) = super::ExpressionParser::default()
+            .to_sql(r#"a = "x" and "term""#, 1)
+            .unwrap();
+        assert_eq!(
+            default_sql,
+            "(doc -> ($1::jsonb #>> '{}') @> $2 \
+             AND search @@ websearch_to_tsquery($3::jsonb #>> '{}'))"
+        );
+
+        let p = super::IdentifierParser::default().with_columns("payload", "fts");
+        let (sql, _) = p.sql_string("a", 3).unwrap();
+        assert_eq!(sql, "payload ->> ($3::jsonb #>> '{}')");
+        let (sql, _) = p.sql_json("a", 3).unwrap();
+        assert_eq!(sql, "payload -> ($3::jsonb #>> '{}')");
+    }
+
+    #[test]
+    fn placeholder_styles() {
+        let query = r#"a = "x" and (b in (1, 2) or c > now-5m) and msg contains "y" and "term""#;
+        let (numbered, numbered_params) =
+            super::ExpressionParser::default().to_sql(query, 1).unwrap();
+        let (question, question_params) = super::ExpressionParser::default()
+            .with_placeholder(Placeholder::QuestionMark)
+            .to_sql(query, 1)
+            .unwrap();
+
+        assert_eq!(numbered_params.len(), 9);
+        assert_eq!(numbered_params, question_params);
+        assert!(!question.contains('$'));
+        assert_eq!(question.matches('?').count(), 9);
+        // numbered placeholders appear in parameter order, so replacing them yields the same SQL
+        let mut expected = numbered.clone();
+        for i in (1..=9).rev() {
+            expected = expected.replace(&format!("${}", i), "?");
+        }
+        assert_eq!(question, expected);
+        let order: Vec<usize> = numbered
+            .split('$')
+            .skip(1)
+            .map(|rest| {
+                let digits: String = rest.chars().take_while(char::is_ascii_digit).collect();
+                digits.parse().unwrap()
+            })
+            .collect();
+        assert_eq!(order, (1..=9).collect::<Vec<usize>>());
+
+        let p = super::IdentifierParser::default().with_placeholder(Placeholder::QuestionMark);
+        let (sql, _) = p.sql_string("a", 3).unwrap();
+        assert_eq!(sql, "doc ->> (?::jsonb #>> '{}')");
+    }
+
+    #[test]
+    fn simplify() {
+        let p = query::ExpressionParser::new();
+        let simplified = |text| p.parse(text).unwrap().simplify();
+        let fts = |s: &str| Box::new(Expression::FullTextSearch(s.into(), TsQuery::Websearch));
+
+        assert_eq!(simplified(r#"not (not "a")"#), *fts("a"));
+        assert_eq!(
+            simplified(r#"not (not (not "a"))"#),
+            Expression::Not(fts("a"))
+        );
+        assert_eq!(
+            simplified(r#""a" and not (not "b")"#),
+            *p.parse(r#""a" and "b""#).unwrap()
+        );
+
+        // nested ands and ors are flattened into the parser's left leaning chains
+        assert_eq!(
+            simplified(r#""a" and ("b" and ("c" and "d"))"#),
+            *p.parse(r#""a" and "b" and "c" and "d""#).unwrap()
+        );
+        assert_eq!(
+            simplified(r#""a" or ("b" or "c")"#),
+            *p.parse(r#""a" or "b" or "c""#).unwrap()
+        );
+        assert_eq!(
+            simplified(r#""a" and ("b" or "c")"#),
+            *p.parse(r#""a" and ("b" or "c")"#).unwrap()
+        );
+
+        // constant conjuncts and disjuncts are folded
+        assert_eq!(simplified(r#"not x in () and "a""#), *fts("a"));
+        assert_eq!(
+            simplified(r#""a" and x in ()"#),
+            *p.parse("x in ()").unwrap()
+        );
+        assert_eq!(simplified(r#""a" or x in ()"#), *fts("a"));
+        assert_eq!(
+            simplified(r#""a" or not x in ()"#),
+            *p.parse("not x in ()").unwrap()
+        );
+        assert_eq!(
+            simplified(r#"not (not x in ()) and "a""#),
+            *p.parse("x in ()").unwrap()
+        );
+
+        let p = super::ExpressionParser::default();
+        assert_eq!(
+            p.to_sql(r#"not (not "a") and not x in ()"#, 1).unwrap().0,
+            "search @@ websearch_to_tsquery($1::jsonb #>> '{}')"
+        );
+    }
+
+    #[test]
+    fn not_over_or() {
+        let p = super::ExpressionParser::default();
+        assert_eq!(
+            p.to_sql(r#"not ("a" or "b")"#, 1).unwrap().0,
+            "(NOT (search @@ websearch_to_tsquery($1::jsonb #>> '{}') OR search @@ websearch_to_tsquery($2::jsonb #>> '{}')))"
+        );
+
+        let p = query::ExpressionParser::new();
+        let pushed = |text| p.parse(text).unwrap().push_negations();
+        assert_eq!(
+            pushed(r#"not ("a" or "b")"#),
+            *p.parse(r#"not "a" and not "b""#).unwrap()
+        );
+        assert_eq!(
+            pushed(r#"not ("a" and x = 1)"#),
+            *p.parse(r#"not "a" or not x = 1"#).unwrap()
+        );
+        assert_eq!(
+            pushed(r#"not ("a" or not ("b" and "c"))"#),
+            *p.parse(r#"not "a" and ("b" and "c")"#).unwrap()
+        );
+        assert_eq!(
+            pushed(r#""a" and not (not "b" or "c")"#),
+            *p.parse(r#""a" and ("b" and not "c")"#).unwrap()
+        );
+        assert_eq!(
+            pushed(r#"not x in (1, 2)"#),
+            *p.parse(r#"not x in (1, 2)"#).unwrap()
+        );
+
+        let (sql, params) = pushed(r#"not ("a" or "b")"#).to_sql_query(1).unwrap();
+        assert_eq!(
+            sql,
+            "((NOT search @@ websearch_to_tsquery($1::jsonb #>> '{}')) AND (NOT search @@ websearch_to_tsquery($2::jsonb #>> '{}')))"
+        );
+        assert_eq!(params, vec![json!("a"), json!("b")]);
+    }
+
     #[test]
     fn primitive_sql_value() {
-        let (expr, params) = Value::from(123).to_sql_primitive_param(1);
+        let (expr, params) = Value::from(123).to_sql_primitive_param(&SqlOptions::default(), 1);
         assert_eq!(expr, "$1::jsonb #>> '{}'");
         assert_eq!(params, vec![123]);
 
         let (expr, params) = Value::from(vec![Scalar::from(1), Scalar::from(2), Scalar::from(3)])
-            .to_sql_primitive_param(32);
+            .to_sql_primitive_param(&SqlOptions::default(), 32);
         assert_eq!(expr, "(select jsonb_array_elements($32::jsonb) #>> '{}')");
         assert_eq!(params.len(), 1);
         assert_eq!(params[0], json!(vec![1, 2, 3]));
@@ -280,12 +1845,12 @@ mod test {
 
     #[test]
     fn json_sql_value() {
-        let (expr, params) = Value::from(123).to_sql_json_param(1);
+        let (expr, params) = Value::from(123).to_sql_json_param(&SqlOptions::default(), 1);
         assert_eq!(expr, "$1");
         assert_eq!(params, vec![123]);
 
         let (expr, params) = Value::from(vec![Scalar::from(1), Scalar::from(2), Scalar::from(3)])
-            .to_sql_json_param(32);
+            .to_sql_json_param(&SqlOptions::default(), 32);
         assert_eq!(expr, "$32::jsonb");
         assert_eq!(params.len(), 1);
         assert_eq!(params[0], json!(vec![1, 2, 3]));
@@ -41,6 +41,23 @@ alter table logs.logs owner to write_logs;
 create index idx_logs_id_tstamp on logs.logs(id, tstamp);
 create index idx_search on logs.logs using GIN(search);
 
+-- typed columns of the root partitioner's "columns" setting have to be added
+-- to this table, e.g. for {field: hostname, column: host, type: text}:
+-- alter table logs.logs add column host text;
+
+-- optional audit trail written by stuffstream (see its "audit_table" setting)
+create table logs.audit(
+	tstamp timestamp with time zone not null,
+	source_ip inet,
+	subject text,
+	endpoint text not null,
+	query text,
+	range_start timestamp with time zone not null,
+	range_end timestamp with time zone not null
+);
+revoke select on logs.audit from read_logs;
+grant insert on logs.audit to stuffstream;
+
 -- create table logs.logs_2021_10 partition of logs.logs for values from ('2021-10-01') to ('2021-11-01');
 -- alter table logs.logs_2021_10 owner to write_logs;
 
@@ -57,6 +74,31 @@ BEGIN
 END;
 $$ LANGUAGE plpgsql;
 
+CREATE FUNCTION logs.to_inet_or_null(input text) RETURNS INET AS $$
+BEGIN
+	RETURN input::INET;
+EXCEPTION WHEN OTHERS THEN
+	RETURN NULL;
+END;
+$$ LANGUAGE plpgsql IMMUTABLE;
+CREATE FUNCTION logs.to_timestamp_or_null(input text) RETURNS TIMESTAMP WITH TIME ZONE AS $$
+BEGIN
+	RETURN input::TIMESTAMP WITH TIME ZONE;
+EXCEPTION WHEN OTHERS THEN
+	RETURN NULL;
+END;
+$$ LANGUAGE plpgsql STABLE;
+-- input cast to the type of type_of, e.g. cast_or_null('12', null::integer), null if it can't be
+CREATE FUNCTION logs.cast_or_null(input text, type_of anyelement) RETURNS anyelement AS $$
+DECLARE
+	result ALIAS FOR $0;
+BEGIN
+	result := input;
+	RETURN result;
+EXCEPTION WHEN OTHERS THEN
+	RETURN NULL;
+END;
+$$ LANGUAGE plpgsql STABLE;
 
 -- thanks to Michael Fuhr (https://www.postgresql.org/message-id/20050810133157.GA46247@winnie.fuhr.org)
 CREATE FUNCTION logs.count_estimate(query text) RETURNS integer AS $$
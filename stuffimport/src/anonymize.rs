@@ -0,0 +1,123 @@
+//! Truncating IP addresses before they are stored
+use serde_json::Value;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use logstuff::event::Event;
+
+/// Fields holding IP addresses to replace with their network prefix
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct AnonymizeIp {
+    pub fields: Vec<String>,
+    pub ipv4_prefix: u8,
+    pub ipv6_prefix: u8,
+}
+
+impl Default for AnonymizeIp {
+    fn default() -> Self {
+        Self {
+            fields: vec!["fromhost_ip".into()],
+            ipv4_prefix: 24,
+            ipv6_prefix: 48,
+        }
+    }
+}
+
+impl AnonymizeIp {
+    /// `address` with all bits after the configured prefix cleared
+    pub fn truncate(&self, address: IpAddr) -> IpAddr {
+        match address {
+            IpAddr::V4(v4) => IpAddr::V4(truncate_v4(v4, self.ipv4_prefix)),
+            IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+                Some(v4) => IpAddr::V6(truncate_v4(v4, self.ipv4_prefix).to_ipv6_mapped()),
+                None => IpAddr::V6(truncate_v6(v6, self.ipv6_prefix)),
+            },
+        }
+    }
+
+    /// Truncate the addresses in `event`, values that aren't an address are left alone
+    pub fn apply(&self, event: &mut Event) {
+        for field in &self.fields {
+            let value = match event.doc.get_mut(field) {
+                Some(Value::String(value)) => value,
+                _ => continue,
+            };
+            match value.trim().parse::<IpAddr>() {
+                Ok(address) => *value = self.truncate(address).to_string(),
+                Err(_) => debug!("not anonymizing {}, '{}' is no IP address", field, value),
+            }
+        }
+    }
+}
+
+fn truncate_v4(address: Ipv4Addr, prefix: u8) -> Ipv4Addr {
+    let mask = u32::MAX
+        .checked_shl(32 - u32::from(prefix.min(32)))
+        .unwrap_or(0);
+    Ipv4Addr::from(u32::from(address) & mask)
+}
+
+fn truncate_v6(address: Ipv6Addr, prefix: u8) -> Ipv6Addr {
+    let mask = u128::MAX
+        .checked_shl(128 - u32::from(prefix.min(128)))
+        .unwrap_or(0);
+    Ipv6Addr::from(u128::from(address) & mask)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn anonymized(doc: Value) -> Value {
+        let mut event = Event::builder().build();
+        event.doc = doc;
+        AnonymizeIp::default().apply(&mut event);
+        event.doc
+    }
+
+    #[test]
+    fn ipv4_to_24() {
+        let doc = anonymized(json!({"fromhost_ip": "192.168.17.234", "msg": "10.1.2.3"}));
+        assert_eq!(doc["fromhost_ip"], "192.168.17.0");
+        assert_eq!(doc["msg"], "10.1.2.3");
+
+        let anonymize = AnonymizeIp::default();
+        let mapped = "::ffff:10.1.2.3".parse().unwrap();
+        assert_eq!(anonymize.truncate(mapped).to_string(), "::ffff:10.1.2.0");
+    }
+
+    #[test]
+    fn ipv6_to_48() {
+        let doc = anonymized(json!({"fromhost_ip": "2001:db8:abcd:12:34::1"}));
+        assert_eq!(doc["fromhost_ip"], "2001:db8:abcd::");
+    }
+
+    #[test]
+    fn other_prefixes() {
+        let anonymize = AnonymizeIp {
+            ipv4_prefix: 0,
+            ipv6_prefix: 200,
+            ..Default::default()
+        };
+        let v4 = "192.168.17.234".parse().unwrap();
+        assert_eq!(anonymize.truncate(v4).to_string(), "0.0.0.0");
+        let v6 = "2001:db8::1".parse().unwrap();
+        assert_eq!(anonymize.truncate(v6), v6);
+    }
+
+    #[test]
+    fn invalid_values_are_kept() {
+        for value in [
+            json!("-"),
+            json!(""),
+            json!("host1"),
+            json!(17),
+            json!(null),
+        ] {
+            let doc = anonymized(json!({ "fromhost_ip": value.clone() }));
+            assert_eq!(doc["fromhost_ip"], value);
+        }
+        assert_eq!(anonymized(json!({})), json!({}));
+    }
+}
@@ -1,23 +1,64 @@
 use lru_cache::LruCache;
-use postgres_native_tls::MakeTlsConnector;
-use std::io::Write as _;
+use std::io::BufRead;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 use std::{fmt, io};
+use time::{Date, Time};
 
-use logstuff::event::{Event, RsyslogdEvent};
+use logstuff::db::{with_retry, Backoff};
+use logstuff::event::{Event, EventBuilder, EventTime, RsyslogdEvent, SyslogFields};
 use logstuff::tls;
 
+use crate::anonymize::AnonymizeIp;
 use crate::application::{Application, Stopping};
+use crate::batch::{self, InputFormat};
+use crate::check::{self, Report};
 use crate::config::Config;
+use crate::connector::Connector;
+use crate::handshake::Handshake;
+use crate::idle::IdleReader;
 use crate::partition::{self, Partitioner};
+use crate::pipeline::{self, Pipeline};
+use crate::reindex;
+use crate::reload::{self, Generational, Reloader, SharedPartitions};
+use crate::sampling::Sampling;
+use crate::workers;
 
 /// Core program logic
 ///
 /// Must implement the `Application` trait.
 pub struct App {
+    importers: Vec<Importer>,
+    use_workers: bool,
+    queue_size: usize,
+    handshake: Handshake,
+    async_import: Option<AsyncImport>,
+    /// file to import instead of standard input
+    input: Option<PathBuf>,
+    /// standard input (empty with `input`), at EOF after `idle_timeout_sec` without data if set
+    stdin: Box<dyn BufRead + Send>,
+}
+
+/// Runtime and pipeline of the asynchronous import
+struct AsyncImport {
+    runtime: tokio::runtime::Runtime,
+    pipeline: Pipeline<pipeline::Database>,
+}
+
+/// Parses events and inserts them using its own database connection
+struct Importer {
     client: postgres::Client,
-    partitions: Vec<Box<dyn partition::Partitioner>>,
+    partitions: SharedPartitions,
+    format: InputFormat,
     use_vars_msg: bool,
-    prepared_inserts: LruCache<String, postgres::Statement>,
+    include_rawmsg: bool,
+    syslog_fields: SyslogFields,
+    partition_by: EventTime,
+    anonymize_ip: Option<AnonymizeIp>,
+    sampling: Option<Sampling>,
+    prepared_inserts: Generational<LruCache<String, postgres::Statement>>,
+    created_tables: Generational<partition::CreatedTables>,
 }
 
 /// Error type for the core program logic
@@ -27,115 +68,378 @@ pub enum Error {
     Io(io::Error),
     Json(serde_json::Error),
     Partition(partition::Error),
+    Reload(reload::Error),
     Tls(tls::Error),
 }
 
 impl Application for App {
     type Err = Error;
 
-    fn new(_opts: crate::Args, config: Config) -> Result<Self, Self::Err> {
-        env_logger::init();
-        let connector = MakeTlsConnector::new(config.tls.connector()?);
-        let client = postgres::Client::connect(&config.db_url, connector)?;
+    fn new(opts: crate::Args, mut config: Config) -> Result<Self, Self::Err> {
+        let connector = Connector::new(&config.tls)?;
+        let settings = reload::fixed_settings(&config)?;
+        let partitions = Arc::new(std::mem::take(&mut config.partitions));
+        let parts: Vec<&dyn Partitioner> = partitions.iter().map(|part| part.as_ref()).collect();
+        partition::validate(&EventBuilder::default().build(), &parts)?;
+        let partitions = SharedPartitions::new(partitions);
+        // files are imported one event after the other
+        let from_file = opts.input.is_some();
+        if let (Some(path), false) = (&opts.config_path, from_file) {
+            reload::reload_on_hangup(Reloader::new(
+                path.clone(),
+                opts.lenient_config,
+                settings,
+                partitions.clone(),
+            ))?;
+        }
+        if config.async_import && !from_file {
+            let async_import = AsyncImport::connect(&config, connector, partitions)?;
+            config.handshake.write_ready(io::stdout())?;
+            return Ok(App {
+                importers: Vec::new(),
+                use_workers: false,
+                queue_size: config.queue_size,
+                handshake: config.handshake,
+                async_import: Some(async_import),
+                input: None,
+                stdin: Box::new(io::empty()),
+            });
+        }
+        let worker_threads = if from_file { 0 } else { config.worker_threads };
+        let importers = (0..worker_threads.max(1))
+            .map(|_| -> Result<Importer, Error> {
+                Ok(Importer {
+                    client: with_retry(&Backoff::default(), || connector.connect(&config.db_url))?,
+                    partitions: partitions.clone(),
+                    format: opts.format,
+                    use_vars_msg: config.use_vars_msg,
+                    include_rawmsg: config.include_rawmsg,
+                    syslog_fields: config.syslog_fields,
+                    partition_by: config.partition_by,
+                    anonymize_ip: config.anonymize_ip.clone(),
+                    sampling: config.sampling.clone(),
+                    prepared_inserts: Generational::new(LruCache::new(config.statement_cache_size)),
+                    created_tables: Generational::new(Default::default()),
+                })
+            })
+            .collect::<Result<_, _>>()?;
 
-        // tell rsyslogd that we are ready
-        writeln!(io::stdout(), "OK")?;
+        if !from_file {
+            // tell rsyslogd that we are ready
+            config.handshake.write_ready(io::stdout())?;
+        }
 
         Ok(App {
-            client,
-            partitions: config.partitions,
-            use_vars_msg: config.use_vars_msg,
-            prepared_inserts: LruCache::new(config.statement_cache_size),
+            importers,
+            use_workers: worker_threads > 0,
+            queue_size: config.queue_size,
+            handshake: config.handshake,
+            async_import: None,
+            input: opts.input,
+            // the idle reader's thread would wait on standard input even when importing a file
+            stdin: match (from_file, config.idle_timeout_sec) {
+                (true, _) => Box::new(io::empty()),
+                (false, 0) => Box::new(io::BufReader::new(io::stdin())),
+                (false, timeout) => Box::new(io::BufReader::new(IdleReader::new(
+                    io::stdin(),
+                    Duration::from_secs(timeout),
+                ))),
+            },
         })
     }
 
     fn run_once(&mut self) -> Result<Stopping, Self::Err> {
-        let mut line = String::new();
-        let bytes = io::stdin().read_line(&mut line)?;
-        let line: &str = line.trim();
-
-        if !line.is_empty() {
-            self.handle_event(line)?;
+        if let Some(path) = &self.input {
+            let importer = &mut self.importers[0];
+            batch::import_file(path, |line| importer.handle_event(line))?;
+            return Ok(Stopping::Yes);
+        }
+        if let Some(AsyncImport { runtime, pipeline }) = &self.async_import {
+            runtime.block_on(pipeline.run(
+                tokio::io::BufReader::new(tokio::io::stdin()),
+                tokio::io::stdout(),
+            ))?;
+            info!("input at EOF");
+            return Ok(Stopping::Yes);
+        }
+        if self.use_workers {
+            let handlers = self
+                .importers
+                .drain(..)
+                .map(|mut importer| move |line: &str| importer.handle_event(line))
+                .collect();
+            workers::run(
+                std::mem::replace(&mut self.stdin, Box::new(io::empty())),
+                io::stdout(),
+                handlers,
+                self.queue_size,
+                &self.handshake,
+            )?;
+            info!("input at EOF");
+            return Ok(Stopping::Yes);
         }
 
-        if bytes == 0 {
+        let mut line = String::new();
+        if self.stdin.read_line(&mut line)? == 0 {
             info!("input at EOF");
-            Ok(Stopping::Yes)
-        } else {
-            Ok(Stopping::No)
+            return Ok(Stopping::Yes);
         }
+
+        let imported = self.importers[0].handle_event(&line)?;
+        self.handshake.write_reply(io::stdout(), imported)?;
+        Ok(Stopping::No)
+    }
+}
+
+impl AsyncImport {
+    fn connect(
+        config: &Config,
+        connector: Connector,
+        partitions: SharedPartitions,
+    ) -> Result<Self, Error> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let client = with_retry(&Backoff::default(), || {
+            connector.connect_async(&runtime, &config.db_url)
+        })?;
+        Ok(Self {
+            runtime,
+            pipeline: Pipeline {
+                sink: pipeline::Database::new(client, partitions, config.statement_cache_size),
+                use_vars_msg: config.use_vars_msg,
+                include_rawmsg: config.include_rawmsg,
+                syslog_fields: config.syslog_fields,
+                partition_by: config.partition_by,
+                anonymize_ip: config.anonymize_ip.clone(),
+                sampling: config.sampling.clone(),
+                depth: config.queue_size,
+                handshake: config.handshake.clone(),
+                idle_timeout: match config.idle_timeout_sec {
+                    0 => None,
+                    timeout => Some(Duration::from_secs(timeout)),
+                },
+            },
+        })
     }
 }
 
-impl App {
-    fn insert_single_shot(&mut self, event: &Event, search: &str) -> Result<(), Error> {
-        let root_table = self.partitions[0].table_name(event)?;
-        if !self.prepared_inserts.contains_key(&root_table) {
+impl Importer {
+    fn insert_single_shot(
+        &mut self,
+        generation: u64,
+        partitions: &reload::Partitions,
+        event: &Event,
+        search: &str,
+    ) -> Result<(), Error> {
+        let root_table = partitions[0].table_name(event)?;
+        let prepared_inserts = self.prepared_inserts.get(generation, LruCache::clear);
+        if !prepared_inserts.contains_key(&root_table) {
             info!("Preparing insert statement for root table {}", root_table);
-            self.prepared_inserts.insert(
+            prepared_inserts.insert(
                 root_table.to_owned(),
-                self.client.prepare(
-                    format!(
-                        "insert into {} (tstamp, doc, search) values ($1, $2, to_tsvector($3))",
-                        root_table
-                    )
-                    .as_str(),
-                )?,
+                self.client.prepare(&partition::insert_statement(
+                    &root_table,
+                    partitions[0].columns(),
+                ))?,
             );
         }
 
+        let params = partition::InsertParams::new(event, search, partitions[0].columns());
         self.client.execute(
-            self.prepared_inserts.get_mut(&root_table).unwrap(),
-            &[&event.timestamp, &event.doc, &search],
+            prepared_inserts.get_mut(&root_table).unwrap(),
+            &params.to_params(),
         )?;
         Ok(())
     }
 
-    fn insert_event(&mut self, event: &Event) -> Result<(), Error> {
-        let mut changed_event;
-        let event = if self.use_vars_msg && event.get_printable("vars.msg").is_some() {
-            changed_event = event.clone();
-            let old_msg = changed_event.get_printable("msg").unwrap();
-            changed_event.doc["msg"] = changed_event.get_printable("vars.msg").unwrap().into();
-            changed_event.doc["vars.msg"] = old_msg.into();
-            &changed_event
-        } else {
-            event
-        };
+    /// Create the tables for `event` unless created before, returns whether any were created
+    fn create_tables(
+        &mut self,
+        generation: u64,
+        partitions: &reload::Partitions,
+        event: &Event,
+    ) -> Result<bool, Error> {
+        let parts: Vec<&dyn Partitioner> = partitions
+            .iter()
+            .map(|boxed| (*boxed).as_ref() as &dyn Partitioner)
+            .collect();
+        let created_tables = self
+            .created_tables
+            .get(generation, partition::CreatedTables::clear);
+        // other workers and importers may create the same tables at the same time
+        let mut transaction = self.client.transaction()?;
+        transaction.execute(partition::CREATE_LOCK, &[])?;
+        let created = created_tables.create(event, &parts, |statement| {
+            transaction.execute(statement, &[])?;
+            Ok(())
+        })?;
+        if let Err(err) = transaction.commit() {
+            created_tables.clear();
+            return Err(err.into());
+        }
+        Ok(created)
+    }
 
+    fn insert_event(&mut self, event: &Event) -> Result<(), Error> {
         let search = event.search_string();
-        if self.insert_single_shot(event, &search).is_err() {
+        let (generation, partitions) = self.partitions.current();
+        if self
+            .insert_single_shot(generation, &partitions, event, &search)
+            .is_err()
+        {
             info!("Event insertion failed, trying to create missing partitions");
-            crate::partition::create_tables(
-                &mut self.client,
-                event,
-                &self
-                    .partitions
+            let created = self.create_tables(generation, &partitions, event)?;
+            if created {
+                debug!("Partitions created, retrying event insertion");
+            } else {
+                debug!("Partitions were created before, retrying event insertion");
+            }
+            if let Err(err) = self.insert_single_shot(generation, &partitions, event, &search) {
+                if created {
+                    return Err(err);
+                }
+                // tables created before may have been dropped since, e.g. by retention
+                info!("Event insertion still failed, creating its partitions again");
+                let parts: Vec<&dyn Partitioner> = partitions
                     .iter()
                     .map(|boxed| (*boxed).as_ref() as &dyn Partitioner)
-                    .collect::<Vec<&dyn Partitioner>>(),
-            )?;
-            debug!("Partitions created, retrying event insertion");
-            self.insert_single_shot(event, &search)
-                .expect("event insertion still failed after creating partitions");
+                    .collect();
+                self.created_tables
+                    .get(generation, partition::CreatedTables::clear)
+                    .remove(event, &parts)?;
+                self.create_tables(generation, &partitions, event)?;
+                self.insert_single_shot(generation, &partitions, event, &search)?;
+            }
         }
 
         Ok(())
     }
 
-    fn handle_event(&mut self, line: &str) -> Result<(), Error> {
-        match serde_json::from_str::<RsyslogdEvent>(line) {
-            Ok(rsyslog_event) => {
-                let stuff_event: Event = rsyslog_event.into();
-                self.insert_event(&stuff_event)?;
-                writeln!(io::stdout(), "OK")?;
+    /// Import the event in `line`, returns whether it is to be confirmed
+    fn handle_event(&mut self, line: &str) -> Result<bool, Error> {
+        let mut event = match parse_event(
+            line,
+            self.format,
+            self.use_vars_msg,
+            self.include_rawmsg,
+            &self.syslog_fields,
+            self.partition_by,
+        ) {
+            Some(event) => event,
+            None => return Ok(false),
+        };
+        if let Some(sampling) = &self.sampling {
+            if sampling.should_drop(&event) {
+                debug!("Dropping event by sampling");
+                return Ok(true);
             }
-            Err(error) => error!("could not parse event: '{}': {}", line, error),
         }
-        Ok(())
+        if let Some(anonymize_ip) = &self.anonymize_ip {
+            anonymize_ip.apply(&mut event);
+        }
+        self.insert_event(&event)?;
+        Ok(true)
     }
 }
 
+/// The event to import for an input line, `None` for empty or unparsable lines
+pub(crate) fn parse_event(
+    line: &str,
+    format: InputFormat,
+    use_vars_msg: bool,
+    include_rawmsg: bool,
+    syslog_fields: &SyslogFields,
+    partition_by: EventTime,
+) -> Option<Event> {
+    let line = line.trim();
+    if line.is_empty() {
+        return None;
+    }
+    if format == InputFormat::PlainJson {
+        return batch::parse_plain_event(line);
+    }
+    match serde_json::from_str::<RsyslogdEvent>(line) {
+        Ok(rsyslog_event) => {
+            let mut event =
+                Event::from_rsyslogd(rsyslog_event, include_rawmsg, syslog_fields, partition_by);
+            if use_vars_msg {
+                if let Some(vars_msg) = event.get_printable("vars.msg") {
+                    let old_msg = event.get_printable("msg").unwrap();
+                    event.doc["msg"] = vars_msg.into();
+                    event.doc["vars.msg"] = old_msg.into();
+                }
+            }
+            Some(event)
+        }
+        Err(error) => {
+            error!("could not parse event: '{}': {}", line, error);
+            None
+        }
+    }
+}
+
+/// Create all partitions for events from `from` until the end of `to`
+pub fn create_partitions(config: Config, from: Date, to: Date) -> Result<(), Error> {
+    let connector = Connector::new(&config.tls)?;
+    let mut client = with_retry(&Backoff::default(), || connector.connect(&config.db_url))?;
+    let parts: Vec<&dyn Partitioner> = config
+        .partitions
+        .iter()
+        .map(|boxed| (*boxed).as_ref() as &dyn Partitioner)
+        .collect();
+    let start = from.with_time(Time::MIDNIGHT).assume_utc();
+    let end = to
+        .next_day()
+        .unwrap_or(to)
+        .with_time(Time::MIDNIGHT)
+        .assume_utc();
+    for table in partition::create_tables_for_range(&mut client, start, end, &parts)? {
+        info!("Partition {} is ready", table);
+    }
+    Ok(())
+}
+
+/// Recompute the search vectors of the root table's events with an id above `after`
+pub fn reindex(config: Config, batch_size: i64, after: i64) -> Result<(), Error> {
+    let connector = Connector::new(&config.tls)?;
+    let mut client = with_retry(&Backoff::default(), || connector.connect(&config.db_url))?;
+    let root = config.partitions.first().ok_or_else(|| {
+        partition::Error::NoPartition("reindexing needs a root table partitioner".into())
+    })?;
+    let table = root.table_name(&Event::builder().build())?;
+    let updated = reindex::reindex(
+        &mut client,
+        &table,
+        root.id_column().unwrap_or("id"),
+        batch_size,
+        after,
+    )?;
+    info!("Reindexed {} events in {}", updated, table);
+    Ok(())
+}
+
+/// Check the database connection and schema, printing a report to stdout
+///
+/// Returns whether all checks passed.
+pub fn check(config: Config) -> Result<bool, Error> {
+    let connector = Connector::new(&config.tls)?;
+    let parts: Vec<&dyn Partitioner> = config
+        .partitions
+        .iter()
+        .map(|boxed| (*boxed).as_ref() as &dyn Partitioner)
+        .collect();
+    let checks = check::schema_checks(&parts)?;
+    let report = match connector.connect(&config.db_url) {
+        Ok(mut client) => Report::run(&checks, |check| {
+            Ok(client.query_one(check.query, &[&check.name])?.get(0))
+        }),
+        Err(err) => Report::connection_failed(&err.into()),
+    };
+    print!("{}", report);
+    Ok(report.passed())
+}
+
 impl From<serde_json::Error> for Error {
     fn from(error: serde_json::Error) -> Self {
         Self::Json(error)
@@ -160,6 +464,12 @@ impl From<partition::Error> for Error {
     }
 }
 
+impl From<reload::Error> for Error {
+    fn from(error: reload::Error) -> Self {
+        Self::Reload(error)
+    }
+}
+
 impl From<tls::Error> for Error {
     fn from(error: tls::Error) -> Self {
         Self::Tls(error)
@@ -176,6 +486,7 @@ impl fmt::Display for Error {
             Io(e) => write!(f, "I/O Error: {}", e),
             Json(e) => write!(f, "json de-/serialization failed: {}", e),
             Partition(e) => write!(f, "Could not create partitions: {}", e),
+            Reload(e) => write!(f, "Could not reload partitions: {}", e),
             Tls(e) => write!(f, "TLS Error: {}", e),
         }
     }
@@ -0,0 +1,166 @@
+//! Importing events from files instead of rsyslog's standard input
+use serde_json::Value;
+use std::fs::File;
+use std::io::{self, BufRead};
+use std::path::Path;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+use logstuff::event::Event;
+
+use crate::app::Error;
+
+/// Log progress after this many lines
+const PROGRESS_INTERVAL: u64 = 10000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum InputFormat {
+    /// rsyslog's JSON template, as sent to omprog
+    RsyslogJson,
+    /// One JSON object per line, its RFC3339 `timestamp` member being the event's time
+    PlainJson,
+}
+
+/// The event in a line of `InputFormat::PlainJson`, `None` for unusable lines
+pub fn parse_plain_event(line: &str) -> Option<Event> {
+    let doc: Value = match serde_json::from_str(line) {
+        Ok(doc @ Value::Object(_)) => doc,
+        Ok(_) => {
+            error!("not a JSON object: '{}'", line);
+            return None;
+        }
+        Err(error) => {
+            error!("could not parse event: '{}': {}", line, error);
+            return None;
+        }
+    };
+    let timestamp = doc
+        .get("timestamp")
+        .and_then(Value::as_str)
+        .and_then(|timestamp| OffsetDateTime::parse(timestamp, &Rfc3339).ok());
+    match timestamp {
+        Some(timestamp) => Some(Event { timestamp, doc }),
+        None => {
+            error!("event without RFC3339 timestamp: '{}'", line);
+            None
+        }
+    }
+}
+
+/// Lines read and events imported
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Progress {
+    pub lines: u64,
+    pub imported: u64,
+}
+
+/// Pass every line of `input` to `handle`, which returns whether it imported an event
+pub fn import_lines(
+    input: impl BufRead,
+    mut handle: impl FnMut(&str) -> Result<bool, Error>,
+) -> Result<Progress, Error> {
+    let mut progress = Progress::default();
+    for line in input.lines() {
+        if handle(&line?)? {
+            progress.imported += 1;
+        }
+        progress.lines += 1;
+        if progress.lines.is_multiple_of(PROGRESS_INTERVAL) {
+            info!(
+                "{} lines read, {} events imported",
+                progress.lines, progress.imported
+            );
+        }
+    }
+    Ok(progress)
+}
+
+/// Import all lines of the file at `path`
+pub fn import_file(
+    path: &Path,
+    handle: impl FnMut(&str) -> Result<bool, Error>,
+) -> Result<Progress, Error> {
+    let progress = import_lines(io::BufReader::new(File::open(path)?), handle)?;
+    info!(
+        "Done importing {}: {} lines read, {} events imported",
+        path.display(),
+        progress.lines,
+        progress.imported
+    );
+    Ok(progress)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::app::parse_event;
+    use std::path::PathBuf;
+    use time::macros::datetime;
+
+    fn fixture(name: &str) -> PathBuf {
+        [env!("CARGO_MANIFEST_DIR"), "tests", "fixtures", name]
+            .iter()
+            .collect()
+    }
+
+    fn import(name: &str, format: InputFormat) -> (Progress, Vec<Event>) {
+        let mut events = Vec::new();
+        let progress = import_file(&fixture(name), |line| {
+            let event = parse_event(
+                line,
+                format,
+                true,
+                false,
+                &Default::default(),
+                Default::default(),
+            );
+            let imported = event.is_some();
+            events.extend(event);
+            Ok(imported)
+        })
+        .unwrap();
+        (progress, events)
+    }
+
+    #[test]
+    fn import_rsyslog_file() {
+        let (progress, events) = import("rsyslog.ndjson", InputFormat::RsyslogJson);
+        assert_eq!(
+            progress,
+            Progress {
+                lines: 4,
+                imported: 2
+            }
+        );
+        assert_eq!(events[0].get_printable("hostname").unwrap(), "host1");
+        assert_eq!(events[1].get_printable("syslogseverity").unwrap(), "error");
+        assert_eq!(events[1].timestamp, datetime!(2022-03-04 05:06:09 +01:00));
+    }
+
+    #[test]
+    fn import_plain_file() {
+        let (progress, events) = import("plain.ndjson", InputFormat::PlainJson);
+        assert_eq!(
+            progress,
+            Progress {
+                lines: 4,
+                imported: 2
+            }
+        );
+        assert_eq!(events[0].timestamp, datetime!(2021-12-31 23:59:59 UTC));
+        assert_eq!(events[1].get_printable("msg").unwrap(), "first of the year");
+        assert_eq!(events[1].doc["count"], 3);
+    }
+
+    #[test]
+    fn handler_errors_stop_the_import() {
+        let mut calls = 0;
+        let result = import_file(&fixture("plain.ndjson"), |_| {
+            calls += 1;
+            Err(Error::Io(io::ErrorKind::Other.into()))
+        });
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+        assert!(import_file(&fixture("missing.ndjson"), |_| Ok(true)).is_err());
+    }
+}
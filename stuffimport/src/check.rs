@@ -0,0 +1,162 @@
+//! Self test verifying the database has what imports need
+use std::fmt;
+
+use crate::app::Error;
+use crate::partition::Partitioner;
+
+/// Functions from schema.sql used by logstuff's queries
+const FUNCTIONS: [&str; 4] = [
+    "to_number_or_null",
+    "to_inet_or_null",
+    "to_timestamp_or_null",
+    "cast_or_null",
+];
+
+/// A query returning whether some part of the schema is present
+#[derive(Debug, PartialEq, Eq)]
+pub struct Check {
+    pub description: String,
+    pub query: &'static str,
+    pub name: String,
+}
+
+impl Check {
+    fn table(name: &str) -> Self {
+        Self {
+            description: format!("table {}", name),
+            query: "select to_regclass($1::text) is not null",
+            name: name.into(),
+        }
+    }
+
+    fn sequence(name: &str) -> Self {
+        Self {
+            description: format!("sequence {}", name),
+            query: "select exists(select 1 from pg_class \
+                    where oid = to_regclass($1::text) and relkind = 'S')",
+            name: name.into(),
+        }
+    }
+
+    fn function(name: &str) -> Self {
+        Self {
+            description: format!("function {}", name),
+            query: "select to_regproc($1::text) is not null",
+            name: name.into(),
+        }
+    }
+}
+
+/// Checks for the root table of `parts`, its id sequence and the helper functions
+pub fn schema_checks(parts: &[&dyn Partitioner]) -> Result<Vec<Check>, Error> {
+    let root = parts[0];
+    let mut checks = vec![Check::table(
+        &root.table_name(&logstuff::event::Event::builder().build())?,
+    )];
+    checks.extend(root.sequence().map(Check::sequence));
+    checks.extend(FUNCTIONS.iter().map(|name| Check::function(name)));
+    Ok(checks)
+}
+
+/// Outcome of every check, an error message if it could not be run
+#[derive(Debug, Default)]
+pub struct Report(Vec<(String, Result<bool, String>)>);
+
+impl Report {
+    /// Run all `checks` using `present`, which returns the result of a check's query
+    pub fn run(checks: &[Check], mut present: impl FnMut(&Check) -> Result<bool, Error>) -> Self {
+        Self(
+            checks
+                .iter()
+                .map(|check| {
+                    let result = present(check).map_err(|err| err.to_string());
+                    (check.description.clone(), result)
+                })
+                .collect(),
+        )
+    }
+
+    /// Report of a failed connection attempt
+    pub fn connection_failed(error: &Error) -> Self {
+        Self(vec![("database connection".into(), Err(error.to_string()))])
+    }
+
+    pub fn passed(&self) -> bool {
+        self.0.iter().all(|(_, result)| matches!(result, Ok(true)))
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (description, result) in &self.0 {
+            match result {
+                Ok(true) => writeln!(f, "ok      {}", description)?,
+                Ok(false) => writeln!(f, "missing {}", description)?,
+                Err(err) => writeln!(f, "failed  {}: {}", description, err)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::partition::{IdDefault, Root, Timerange};
+
+    fn checks() -> Vec<Check> {
+        let root = Root::default();
+        let month = Timerange::default();
+        schema_checks(&[&root, &month]).unwrap()
+    }
+
+    #[test]
+    fn checks_from_config() {
+        let checks = checks();
+        let described: Vec<&str> = checks.iter().map(|c| c.description.as_str()).collect();
+        assert_eq!(
+            described,
+            [
+                "table logs",
+                "sequence logs_id",
+                "function to_number_or_null",
+                "function to_inet_or_null",
+                "function to_timestamp_or_null",
+                "function cast_or_null",
+            ]
+        );
+        assert_eq!(checks[1].name, "logs_id");
+
+        let root = Root {
+            id_default: IdDefault::Identity,
+            ..Default::default()
+        };
+        assert_eq!(schema_checks(&[&root]).unwrap().len(), 5);
+    }
+
+    #[test]
+    fn present_schema_passes() {
+        let report = Report::run(&checks(), |_| Ok(true));
+        assert!(report.passed());
+        assert!(report.to_string().starts_with("ok      table logs\n"));
+    }
+
+    #[test]
+    fn absent_schema_fails() {
+        let report = Report::run(&checks(), |check| Ok(check.name != "to_number_or_null"));
+        assert!(!report.passed());
+        assert!(report
+            .to_string()
+            .contains("missing function to_number_or_null\n"));
+
+        let report = Report::run(&checks(), |_| {
+            Err(Error::Io(std::io::ErrorKind::PermissionDenied.into()))
+        });
+        assert!(!report.passed());
+        assert!(report.to_string().starts_with("failed  table logs: "));
+
+        let report =
+            Report::connection_failed(&Error::Io(std::io::ErrorKind::ConnectionRefused.into()));
+        assert!(!report.passed());
+    }
+}
@@ -1,7 +1,13 @@
+use logstuff::config;
+use logstuff::event::{EventTime, SyslogFields};
 use logstuff::tls::TlsSettings;
 use std::fs::File;
+use std::path::Path;
 
+use crate::anonymize::AnonymizeIp;
+use crate::handshake::Handshake;
 use crate::partition::{self, Partitioner};
+use crate::sampling::Sampling;
 
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(deny_unknown_fields, default)]
@@ -10,7 +16,17 @@ pub struct Config {
     pub partitions: Vec<Box<dyn Partitioner>>,
     pub tls: TlsSettings,
     pub use_vars_msg: bool,
+    pub include_rawmsg: bool,
+    pub syslog_fields: SyslogFields,
+    pub partition_by: EventTime,
+    pub anonymize_ip: Option<AnonymizeIp>,
+    pub sampling: Option<Sampling>,
     pub statement_cache_size: usize,
+    pub worker_threads: usize,
+    pub queue_size: usize,
+    pub async_import: bool,
+    pub idle_timeout_sec: u64,
+    pub handshake: Handshake,
 }
 
 impl Default for Config {
@@ -23,19 +39,67 @@ impl Default for Config {
             ],
             tls: TlsSettings::default(),
             use_vars_msg: true,
+            include_rawmsg: false,
+            syslog_fields: SyslogFields::default(),
+            partition_by: EventTime::default(),
+            anonymize_ip: None,
+            sampling: None,
             statement_cache_size: 3,
+            worker_threads: 0,
+            queue_size: 100,
+            async_import: false,
+            idle_timeout_sec: 0,
+            handshake: Handshake::default(),
         }
     }
 }
 
 impl Config {
-    /// Load config using path specified in options
-    pub fn load(opts: &crate::Args) -> Result<Config, Box<dyn ::std::error::Error>> {
+    /// Load config using path specified in options, with the unknown keys ignored if lenient
+    pub fn load(opts: &crate::Args) -> Result<(Config, Vec<String>), Box<dyn ::std::error::Error>> {
         if let Some(path) = &opts.config_path {
-            let reader = File::open(path)?;
-            Ok(serde_yaml::from_reader(reader)?)
+            Self::from_path(path, opts.lenient_config)
         } else {
-            Ok(Config::default())
+            Ok((Config::default(), Vec::new()))
         }
     }
+
+    /// Load config from the file at `path`, with the unknown keys ignored if `lenient`
+    pub fn from_path(
+        path: &Path,
+        lenient: bool,
+    ) -> Result<(Config, Vec<String>), Box<dyn ::std::error::Error>> {
+        config::from_reader(File::open(path)?, lenient)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CONFIG: &str = "
+db_url: host=db
+worker_threads: 2
+new_setting: 1
+partitions:
+  - kind: root
+    table: events
+";
+
+    #[test]
+    fn lenient_load_ignores_unknown_keys() {
+        assert!(config::from_reader::<Config>(CONFIG.as_bytes(), false).is_err());
+
+        let (config, ignored) = config::from_reader::<Config>(CONFIG.as_bytes(), true).unwrap();
+        assert_eq!(ignored, ["new_setting"]);
+        assert_eq!(config.db_url, "host=db");
+        assert_eq!(config.worker_threads, 2);
+        assert_eq!(config.partitions.len(), 1);
+        assert_eq!(config.queue_size, Config::default().queue_size);
+
+        let typo = CONFIG.to_owned() + "  - kind: timerange\n    intervall: Day\n";
+        let (config, ignored) = config::from_reader::<Config>(typo.as_bytes(), true).unwrap();
+        assert_eq!(ignored, ["new_setting", "partitions.1.intervall"]);
+        assert_eq!(config.partitions.len(), 2);
+    }
 }
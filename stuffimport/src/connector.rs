@@ -0,0 +1,80 @@
+//! Connecting to postgres with or without TLS
+use postgres::NoTls;
+use postgres_native_tls::MakeTlsConnector;
+use tokio::runtime::Runtime;
+
+use logstuff::tls::{self, TlsSettings};
+
+/// How connections to postgres are made, as chosen by `tls.enabled`
+#[derive(Clone)]
+pub enum Connector {
+    Tls(MakeTlsConnector),
+    /// For trusted servers, e.g. on the local host or behind a unix socket
+    NoTls,
+}
+
+impl Connector {
+    pub fn new(settings: &TlsSettings) -> Result<Self, tls::Error> {
+        if settings.enabled {
+            Ok(Self::Tls(MakeTlsConnector::new(settings.connector()?)))
+        } else {
+            warn!("TLS is disabled, connecting to postgres unencrypted");
+            Ok(Self::NoTls)
+        }
+    }
+
+    pub fn connect(&self, db_url: &str) -> Result<postgres::Client, postgres::Error> {
+        match self {
+            Self::Tls(connector) => postgres::Client::connect(db_url, connector.clone()),
+            Self::NoTls => postgres::Client::connect(db_url, NoTls),
+        }
+    }
+
+    /// Connect and drive the connection on `runtime`
+    pub fn connect_async(
+        &self,
+        runtime: &Runtime,
+        db_url: &str,
+    ) -> Result<tokio_postgres::Client, tokio_postgres::Error> {
+        let client = match self {
+            Self::Tls(connector) => {
+                let (client, connection) =
+                    runtime.block_on(tokio_postgres::connect(db_url, connector.clone()))?;
+                runtime.spawn(drive(connection));
+                client
+            }
+            Self::NoTls => {
+                let (client, connection) =
+                    runtime.block_on(tokio_postgres::connect(db_url, NoTls))?;
+                runtime.spawn(drive(connection));
+                client
+            }
+        };
+        Ok(client)
+    }
+}
+
+async fn drive<F: std::future::Future<Output = Result<(), tokio_postgres::Error>>>(connection: F) {
+    if let Err(err) = connection.await {
+        error!("database connection failed: {}", err);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn tls_can_be_disabled() {
+        let settings = TlsSettings::default();
+        assert!(matches!(Connector::new(&settings), Ok(Connector::Tls(_))));
+
+        let settings = TlsSettings {
+            enabled: false,
+            // not even looked at without TLS
+            ca_certs: vec!["/nonexistent/ca.pem".into()],
+            ..Default::default()
+        };
+        assert!(matches!(Connector::new(&settings), Ok(Connector::NoTls)));
+    }
+}
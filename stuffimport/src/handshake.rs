@@ -0,0 +1,91 @@
+//! What stuffimport writes to standard output to tell its caller about progress
+use std::io::{self, Write};
+
+/// Replies to the program feeding events, by default rsyslog's omprog with confirmMessages="on"
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct Handshake {
+    /// Write nothing at all if false
+    pub confirm: bool,
+    /// Written once the database connection is up
+    pub ready: Option<String>,
+    /// Written for each imported event
+    pub ack: Option<String>,
+    /// Written for each line that could not be imported
+    pub error: Option<String>,
+}
+
+impl Default for Handshake {
+    fn default() -> Self {
+        Self {
+            confirm: true,
+            ready: Some("OK".into()),
+            ack: Some("OK".into()),
+            error: None,
+        }
+    }
+}
+
+impl Handshake {
+    pub fn ready(&self) -> Option<&str> {
+        self.ready.as_deref().filter(|_| self.confirm)
+    }
+
+    /// The reply to a line, depending on whether its event was imported
+    pub fn reply(&self, imported: bool) -> Option<&str> {
+        let reply = if imported { &self.ack } else { &self.error };
+        reply.as_deref().filter(|_| self.confirm)
+    }
+
+    pub fn write_ready(&self, output: impl Write) -> io::Result<()> {
+        write_line(output, self.ready())
+    }
+
+    pub fn write_reply(&self, output: impl Write, imported: bool) -> io::Result<()> {
+        write_line(output, self.reply(imported))
+    }
+}
+
+fn write_line(mut output: impl Write, line: Option<&str>) -> io::Result<()> {
+    if let Some(line) = line {
+        writeln!(output, "{}", line)?;
+        output.flush()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn written(handshake: &Handshake) -> String {
+        let mut output = Vec::new();
+        handshake.write_ready(&mut output).unwrap();
+        handshake.write_reply(&mut output, true).unwrap();
+        handshake.write_reply(&mut output, false).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn default_is_omprog() {
+        assert_eq!(written(&Handshake::default()), "OK\nOK\n");
+    }
+
+    #[test]
+    fn configured_strings() {
+        let handshake: Handshake =
+            serde_yaml::from_str("{ready: READY, ack: ACK, error: ERR}").unwrap();
+        assert_eq!(written(&handshake), "READY\nACK\nERR\n");
+
+        let handshake: Handshake = serde_yaml::from_str("{ready: null, ack: \"+\"}").unwrap();
+        assert_eq!(written(&handshake), "+\n");
+    }
+
+    #[test]
+    fn no_confirmation() {
+        let handshake: Handshake = serde_yaml::from_str("{confirm: false, error: ERR}").unwrap();
+        assert_eq!(written(&handshake), "");
+        assert_eq!(handshake.ready(), None);
+        assert_eq!(handshake.reply(true), None);
+    }
+}
@@ -0,0 +1,101 @@
+//! Input that ends once nothing arrives for a while
+use std::io::{self, Read};
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+
+/// Reads its input on a separate thread, reporting EOF after `timeout` without data
+pub struct IdleReader {
+    chunks: Receiver<io::Result<Vec<u8>>>,
+    pending: io::Cursor<Vec<u8>>,
+    timeout: Duration,
+    ended: bool,
+}
+
+impl IdleReader {
+    pub fn new(mut input: impl Read + Send + 'static, timeout: Duration) -> Self {
+        let (tx, chunks) = sync_channel(1);
+        thread::spawn(move || loop {
+            let mut chunk = vec![0; 8192];
+            let result = match input.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(len) => {
+                    chunk.truncate(len);
+                    Ok(chunk)
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => Err(err),
+            };
+            let failed = result.is_err();
+            if tx.send(result).is_err() || failed {
+                break;
+            }
+        });
+        Self {
+            chunks,
+            pending: Default::default(),
+            timeout,
+            ended: false,
+        }
+    }
+}
+
+impl Read for IdleReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pending.position() as usize == self.pending.get_ref().len() && !self.ended {
+            match self.chunks.recv_timeout(self.timeout) {
+                Ok(chunk) => self.pending = io::Cursor::new(chunk?),
+                Err(RecvTimeoutError::Timeout) => {
+                    info!("no input for {} seconds", self.timeout.as_secs());
+                    self.ended = true;
+                }
+                Err(RecvTimeoutError::Disconnected) => self.ended = true,
+            }
+        }
+        self.pending.read(buf)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::time::Instant;
+
+    /// Yields its lines, then blocks until dropped
+    struct Stalling(io::Cursor<Vec<u8>>);
+
+    impl Read for Stalling {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.read(buf)? {
+                0 => loop {
+                    thread::park();
+                },
+                len => Ok(len),
+            }
+        }
+    }
+
+    #[test]
+    fn ends_when_idle() {
+        let input = Stalling(io::Cursor::new(b"a\nb\n".to_vec()));
+        let mut reader = BufReader::new(IdleReader::new(input, Duration::from_millis(50)));
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "a\nb\n");
+
+        let start = Instant::now();
+        assert_eq!(reader.read_line(&mut line).unwrap(), 0);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert_eq!(reader.read_line(&mut line).unwrap(), 0);
+    }
+
+    #[test]
+    fn passes_input_through() {
+        let mut reader = IdleReader::new(&b"abc"[..], Duration::from_secs(10));
+        let mut data = String::new();
+        reader.read_to_string(&mut data).unwrap();
+        assert_eq!(data, "abc");
+    }
+}
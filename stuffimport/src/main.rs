@@ -9,16 +9,30 @@ extern crate serde_yaml;
 
 use std::process::exit;
 
+mod anonymize;
 mod app; // app stuff for *this* program
 mod application; // general app stuff
+mod batch;
+mod check;
 mod config;
+mod connector;
+mod handshake;
+mod idle;
 mod partition;
+mod pipeline;
+mod reindex;
+mod reload;
+mod sampling;
+mod workers;
 
 use app::App;
 use application::Application;
-use clap::Parser;
+use batch::InputFormat;
+use clap::{Parser, Subcommand};
 use config::Config;
+use logstuff::logging::{self, LogFormat};
 use std::path::PathBuf;
+use time::{macros::format_description, Date};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about)]
@@ -30,6 +44,57 @@ pub struct Args {
     /// Dump config file after loading it to stderr
     #[arg(short, long)]
     pub dump_config: bool,
+
+    /// Ignore unknown config keys with a warning instead of failing
+    #[arg(long)]
+    pub lenient_config: bool,
+
+    /// Import events from this file instead of standard input, then exit
+    #[arg(short, long, value_name = "FILE")]
+    pub input: Option<PathBuf>,
+
+    /// Format of the input's lines
+    #[arg(long, value_enum, default_value_t = InputFormat::RsyslogJson)]
+    pub format: InputFormat,
+
+    /// Check the database connection and schema instead of importing, then exit
+    #[arg(long)]
+    pub check: bool,
+
+    /// Format of log lines: text or json
+    #[arg(long, value_name = "FORMAT", default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Create the partitions needed for events between two days (inclusive), then exit
+    CreatePartitions {
+        /// First day (YYYY-MM-DD)
+        #[arg(value_parser = parse_date)]
+        from: Date,
+
+        /// Last day (YYYY-MM-DD)
+        #[arg(value_parser = parse_date)]
+        to: Date,
+    },
+    /// Recompute the full text search vectors of stored events, then exit
+    Reindex {
+        /// Events updated per transaction
+        #[arg(long, default_value_t = 1000)]
+        batch_size: i64,
+
+        /// Only events with a higher id, to resume from the last id logged by an earlier run
+        #[arg(long)]
+        after: Option<i64>,
+    },
+}
+
+fn parse_date(text: &str) -> Result<Date, time::error::Parse> {
+    Date::parse(text, format_description!("[year]-[month]-[day]"))
 }
 
 /// The main function
@@ -48,12 +113,32 @@ fn run<T: Application>() -> Result<(), Box<dyn ::std::error::Error>> {
     let opts = Args::parse();
 
     // Load configuration
-    let config = Config::load(&opts)?;
+    let (config, ignored) = Config::load(&opts)?;
 
     if opts.dump_config {
         eprintln!("{}", serde_yaml::to_string(&config)?)
     }
 
+    logging::init(opts.log_format)?;
+    logstuff::config::warn_ignored(&ignored);
+
+    if opts.check {
+        if !app::check(config)? {
+            return Err("schema check failed".into());
+        }
+        return Ok(());
+    }
+
+    if let Some(Command::CreatePartitions { from, to }) = opts.command {
+        app::create_partitions(config, from, to)?;
+        return Ok(());
+    }
+
+    if let Some(Command::Reindex { batch_size, after }) = opts.command {
+        app::reindex(config, batch_size, after.unwrap_or(i64::MIN))?;
+        return Ok(());
+    }
+
     // Initialize the application.
     application::run::<T>(opts, config)?;
     Ok(())
@@ -1,11 +1,14 @@
+use postgres::types::ToSql;
 use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashSet;
 use std::{error, fmt};
 use time::error::{Format, InvalidFormatDescription};
 use time::{
     format_description, Date, Duration, Month, OffsetDateTime, PrimitiveDateTime, Time, Weekday,
 };
 
-use logstuff::event::Event;
+use logstuff::event::{Event, EventBuilder};
 
 #[derive(Debug)]
 pub enum Error {
@@ -13,6 +16,8 @@ pub enum Error {
     NoPartition(String),
     InvalidDateTimeFormat(InvalidFormatDescription),
     DateTimeFormat(Format),
+    InvalidBounds(String),
+    NameTooLong(String),
 }
 
 impl error::Error for Error {}
@@ -25,18 +30,48 @@ impl fmt::Display for Error {
             NoPartition(e) => write!(f, "No parition: {}", e),
             InvalidDateTimeFormat(e) => write!(f, "Invalid date and time format: {}", e),
             DateTimeFormat(e) => write!(f, "Could not format time stamp: {}", e),
+            InvalidBounds(e) => write!(f, "Invalid partition bounds: {}", e),
+            NameTooLong(e) => write!(
+                f,
+                "Table name longer than {} characters: {}",
+                MAX_IDENTIFIER_LENGTH, e
+            ),
         }
     }
 }
 
 #[typetag::serde(tag = "kind")]
-pub trait Partitioner: std::fmt::Debug {
+pub trait Partitioner: std::fmt::Debug + Send + Sync {
     fn table_name(&self, event: &Event) -> Result<String, Error>;
     fn partition_by(&self) -> String;
     fn bounds(&self, event: &Event) -> String;
-    fn schema(&self) -> &str {
+    /// time stamps in the partition for `event` as given in `bounds`, upper bound exclusive
+    fn range(&self, _event: &Event) -> Option<(OffsetDateTime, OffsetDateTime)> {
+        None
+    }
+    fn schema(&self) -> String {
         unimplemented!()
     }
+    /// tablespace for tables created by this partitioner
+    fn tablespace(&self) -> Option<&str> {
+        None
+    }
+    /// start of the partition following the one `event` belongs to, `None` if there is only one
+    fn next_partition(&self, _event: &Event) -> Option<OffsetDateTime> {
+        None
+    }
+    /// sequence providing the table's ids
+    fn sequence(&self) -> Option<&str> {
+        None
+    }
+    /// column holding the table's ids
+    fn id_column(&self) -> Option<&str> {
+        None
+    }
+    /// typed columns filled from the event's fields
+    fn columns(&self) -> &[Column] {
+        &[]
+    }
 }
 
 impl From<postgres::Error> for Error {
@@ -57,36 +92,158 @@ impl From<Format> for Error {
     }
 }
 
+/// Longest identifier postgres keeps, longer ones are cut
+const MAX_IDENTIFIER_LENGTH: usize = 63;
+
+/// `name` as a valid unquoted postgres table name, optionally qualified by its schema
+///
+/// Every part is lowercased, characters other than letters, digits and underscores become
+/// underscores and parts starting with a digit get a leading underscore. Parts longer than
+/// `MAX_IDENTIFIER_LENGTH` are an error, as cutting them could merge e.g. two months' tables.
+pub fn sanitize_table_name(name: &str) -> Result<String, Error> {
+    let parts = name
+        .split('.')
+        .map(|part| {
+            let mut part: String = part
+                .chars()
+                .map(|c| match c.to_ascii_lowercase() {
+                    c @ ('a'..='z' | '0'..='9' | '_') => c,
+                    _ => '_',
+                })
+                .collect();
+            if part.is_empty() || part.starts_with(|c: char| c.is_ascii_digit()) {
+                part.insert(0, '_');
+            }
+            if part.len() > MAX_IDENTIFIER_LENGTH {
+                return Err(Error::NameTooLong(part));
+            }
+            Ok(part)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(parts.join("."))
+}
+
+/// event field also stored in a typed column of the root table
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Column {
+    pub field: String,
+    pub column: String,
+    /// SQL data type, the field's text is cast to it, values that can't be cast are null
+    #[serde(rename = "type")]
+    pub sql_type: String,
+}
+
+/// Statement inserting an event into `table`, parameters are tstamp, doc, search and `columns`
+pub fn insert_statement(table: &str, columns: &[Column]) -> String {
+    let mut names = vec!["tstamp".to_string(), "doc".into(), "search".into()];
+    let mut values = vec!["$1".to_string(), "$2".into(), "to_tsvector($3)".into()];
+    for (index, column) in columns.iter().enumerate() {
+        names.push(column.column.clone());
+        // an uncastable value, like "-" for a number, must not fail the whole event
+        values.push(format!(
+            "cast_or_null(${}::jsonb #>> '{{}}', null::{})::{}",
+            index + 4,
+            column.sql_type,
+            column.sql_type
+        ));
+    }
+    format!(
+        "insert into {} ({}) values ({})",
+        table,
+        names.join(", "),
+        values.join(", ")
+    )
+}
+
+/// Values of `columns` for `event` as `insert_statement` takes them, null for missing fields
+fn column_values(event: &Event, columns: &[Column]) -> Vec<Value> {
+    columns
+        .iter()
+        .map(|column| event.doc.get(&column.field).cloned().unwrap_or(Value::Null))
+        .collect()
+}
+
+/// Parameters of `insert_statement` for `event`, indexed with the text `search`
+pub struct InsertParams<'a> {
+    event: &'a Event,
+    search: &'a str,
+    values: Vec<Value>,
+}
+
+impl<'a> InsertParams<'a> {
+    pub fn new(event: &'a Event, search: &'a str, columns: &[Column]) -> Self {
+        Self {
+            event,
+            search,
+            values: column_values(event, columns),
+        }
+    }
+
+    /// The parameters in the order of the statement's placeholders
+    pub fn to_params(&self) -> Vec<&(dyn ToSql + Sync)> {
+        let mut params: Vec<&(dyn ToSql + Sync)> =
+            vec![&self.event.timestamp, &self.event.doc, &self.search];
+        params.extend(self.values.iter().map(|value| value as &(dyn ToSql + Sync)));
+        params
+    }
+}
+
+/// how the root table's id column gets its values
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum IdDefault {
+    /// `default nextval('<name>')`
+    Sequence { name: String },
+    /// `generated by default as identity`
+    Identity,
+}
+
 /// root table, usually "logs"
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(deny_unknown_fields, default)]
 pub struct Root {
     pub table: String,
-    pub schema: String,
+    /// complete column list, replaces the one assembled from the settings below
+    pub schema: Option<String>,
+    pub id_column: String,
+    pub id_type: String,
+    pub id_default: IdDefault,
+    /// typed columns after the standard ones, not added to a custom `schema`
+    pub columns: Vec<Column>,
+    pub tablespace: Option<String>,
 }
 
 impl Default for Root {
     fn default() -> Self {
         Self {
             table: "logs".into(),
-            schema: format!(
-                "({})",
-                [
-                    "id integer not null default nextval('logs_id'),",
-                    "tstamp timestamp with time zone not null,",
-                    "doc jsonb not null,",
-                    "search tsvector",
-                ]
-                .join(" ")
-            ),
+            schema: None,
+            id_column: "id".into(),
+            id_type: "integer".into(),
+            id_default: IdDefault::Sequence {
+                name: "logs_id".into(),
+            },
+            columns: Vec::new(),
+            tablespace: None,
         }
     }
 }
 
+impl Root {
+    fn id_definition(&self) -> String {
+        let default = match &self.id_default {
+            IdDefault::Sequence { name } => format!("not null default nextval('{}')", name),
+            IdDefault::Identity => "generated by default as identity".into(),
+        };
+        format!("{} {} {}", self.id_column, self.id_type, default)
+    }
+}
+
 #[typetag::serde(name = "root")]
 impl Partitioner for Root {
     fn table_name(&self, _event: &Event) -> Result<String, Error> {
-        Ok(self.table.to_string())
+        sanitize_table_name(&self.table)
     }
 
     fn partition_by(&self) -> String {
@@ -97,8 +254,41 @@ impl Partitioner for Root {
         unreachable!()
     }
 
-    fn schema(&self) -> &str {
-        &self.schema
+    fn schema(&self) -> String {
+        if let Some(schema) = &self.schema {
+            return schema.to_owned();
+        }
+        let mut columns = vec![
+            self.id_definition(),
+            "tstamp timestamp with time zone not null".into(),
+            "doc jsonb not null".into(),
+            "search tsvector".into(),
+        ];
+        columns.extend(
+            self.columns
+                .iter()
+                .map(|column| format!("{} {}", column.column, column.sql_type)),
+        );
+        format!("({})", columns.join(", "))
+    }
+
+    fn tablespace(&self) -> Option<&str> {
+        self.tablespace.as_deref()
+    }
+
+    fn sequence(&self) -> Option<&str> {
+        match &self.id_default {
+            IdDefault::Sequence { name } => Some(name),
+            IdDefault::Identity => None,
+        }
+    }
+
+    fn id_column(&self) -> Option<&str> {
+        Some(&self.id_column)
+    }
+
+    fn columns(&self) -> &[Column] {
+        &self.columns
     }
 }
 
@@ -145,14 +335,19 @@ impl TimeTruncate {
         date.with_time(time).assume_utc()
     }
 
+    /// start of the interval after the one `timestamp` lies in
+    ///
+    /// Computed from the start of its own interval, which is the first of a month for months and
+    /// quarters, so the day of `timestamp` need not exist in the next month.
     pub fn upper_bound(&self, timestamp: &OffsetDateTime) -> OffsetDateTime {
+        let start = self.lower_bound(timestamp);
         let next = match self {
-            Self::Year => timestamp.replace_date(
-                Date::from_calendar_date(timestamp.year() + 1, Month::January, 1).unwrap(),
+            Self::Year => start.replace_date(
+                Date::from_calendar_date(start.year() + 1, Month::January, 1).unwrap(),
             ),
             Self::Quarter => {
-                let mut year = timestamp.year();
-                let month = match timestamp.month() {
+                let mut year = start.year();
+                let month = match start.month() {
                     Month::January | Month::February | Month::March => Month::April,
                     Month::April | Month::May | Month::June => Month::July,
                     Month::July | Month::August | Month::September => Month::October,
@@ -162,14 +357,14 @@ impl TimeTruncate {
                     }
                 };
                 PrimitiveDateTime::new(
-                    Date::from_calendar_date(year, month, timestamp.day()).unwrap(),
-                    timestamp.time(),
+                    Date::from_calendar_date(year, month, 1).unwrap(),
+                    start.time(),
                 )
                 .assume_utc()
             }
             Self::Month => {
-                let mut year = timestamp.year();
-                let month = match timestamp.month() {
+                let mut year = start.year();
+                let month = match start.month() {
                     Month::December => {
                         year += 1;
                         Month::January
@@ -177,15 +372,15 @@ impl TimeTruncate {
                     month => month.next(),
                 };
                 PrimitiveDateTime::new(
-                    Date::from_calendar_date(year, month, timestamp.day()).unwrap(),
-                    timestamp.time(),
+                    Date::from_calendar_date(year, month, 1).unwrap(),
+                    start.time(),
                 )
                 .assume_utc()
             }
-            Self::Week => *timestamp + Duration::weeks(1),
-            Self::Day => *timestamp + Duration::days(1),
-            Self::Hour => *timestamp + Duration::hours(1),
-            Self::Minute => *timestamp + Duration::minutes(1),
+            Self::Week => start + Duration::weeks(1),
+            Self::Day => start + Duration::days(1),
+            Self::Hour => start + Duration::hours(1),
+            Self::Minute => start + Duration::minutes(1),
         };
 
         self.lower_bound(&next)
@@ -198,6 +393,7 @@ impl TimeTruncate {
 pub struct Timerange {
     pub name_template: String,
     pub interval: TimeTruncate,
+    pub tablespace: Option<String>,
 }
 
 impl Default for Timerange {
@@ -205,6 +401,7 @@ impl Default for Timerange {
         Self {
             name_template: "logs_%Y_%m".into(),
             interval: TimeTruncate::Month,
+            tablespace: None,
         }
     }
 }
@@ -212,8 +409,8 @@ impl Default for Timerange {
 #[typetag::serde(name = "timerange")]
 impl Partitioner for Timerange {
     fn table_name(&self, event: &Event) -> Result<String, Error> {
-        let format = format_description::parse(&self.name_template)?;
-        Ok(event.timestamp.format(&format)?)
+        let format = format_description::parse_borrowed::<1>(&self.name_template)?;
+        sanitize_table_name(&event.timestamp.format(&format)?)
     }
 
     fn partition_by(&self) -> String {
@@ -221,8 +418,7 @@ impl Partitioner for Timerange {
     }
 
     fn bounds(&self, event: &Event) -> String {
-        let from = self.interval.lower_bound(&event.timestamp);
-        let to = self.interval.upper_bound(&event.timestamp);
+        let (from, to) = self.range(event).unwrap();
         let format = time::macros::format_description!("[year]-[month]-[day]");
         format!(
             "from ('{}') to ('{}')",
@@ -230,6 +426,90 @@ impl Partitioner for Timerange {
             to.format(&format).unwrap()
         )
     }
+
+    /// bounds are written as dates, so they are cut to midnight
+    fn range(&self, event: &Event) -> Option<(OffsetDateTime, OffsetDateTime)> {
+        let from = self.interval.lower_bound(&event.timestamp);
+        let to = self.interval.upper_bound(&event.timestamp);
+        Some((
+            from.date().midnight().assume_utc(),
+            to.date().midnight().assume_utc(),
+        ))
+    }
+
+    fn tablespace(&self) -> Option<&str> {
+        self.tablespace.as_deref()
+    }
+
+    fn next_partition(&self, event: &Event) -> Option<OffsetDateTime> {
+        let start = self.interval.lower_bound(&event.timestamp);
+        Some(self.interval.upper_bound(&start))
+    }
+}
+
+/// whether `bounds` is written for the strategy in `partition_by`, e.g. "from .. to .." for ranges
+fn bounds_match_strategy(partition_by: &str, bounds: &str) -> bool {
+    let keyword = match partition_by.split_whitespace().next() {
+        Some(strategy) if strategy.eq_ignore_ascii_case("range") => "from",
+        Some(strategy) if strategy.eq_ignore_ascii_case("list") => "in",
+        Some(strategy) if strategy.eq_ignore_ascii_case("hash") => "with",
+        _ => return false,
+    };
+    bounds
+        .split_whitespace()
+        .next()
+        .map(|first| first.eq_ignore_ascii_case(keyword))
+        .unwrap_or(false)
+}
+
+/// Check that the tables `parts` create for `event` are distinct and fit into each other
+///
+/// Every range must be non-empty, contain the event and lie within its parent's range.
+pub fn validate(event: &Event, parts: &[&dyn Partitioner]) -> Result<(), Error> {
+    let mut names = Vec::new();
+    let mut parent_range: Option<(String, OffsetDateTime, OffsetDateTime)> = None;
+    for (index, part) in parts.iter().enumerate() {
+        let name = part.table_name(event)?;
+        if names.contains(&name) {
+            return Err(Error::InvalidBounds(format!(
+                "table {} is created by more than one partitioner",
+                name
+            )));
+        }
+        if index > 0 && !bounds_match_strategy(&part.partition_by(), &part.bounds(event)) {
+            return Err(Error::InvalidBounds(format!(
+                "{}: bounds \"{}\" don't fit partitioning by {}",
+                name,
+                part.bounds(event),
+                part.partition_by()
+            )));
+        }
+        if let Some((from, to)) = part.range(event) {
+            if from >= to {
+                return Err(Error::InvalidBounds(format!(
+                    "{}: empty range from {} to {}",
+                    name, from, to
+                )));
+            }
+            if event.timestamp < from || event.timestamp >= to {
+                return Err(Error::InvalidBounds(format!(
+                    "{}: range from {} to {} does not contain {}",
+                    name, from, to, event.timestamp
+                )));
+            }
+            if let Some((parent, parent_from, parent_to)) = &parent_range {
+                if from < *parent_from || to > *parent_to {
+                    return Err(Error::InvalidBounds(format!(
+                        "{}: range from {} to {} overlaps the bounds of {} ({} to {})",
+                        name, from, to, parent, parent_from, parent_to
+                    )));
+                }
+            }
+            parent_range = Some((name.clone(), from, to));
+        }
+        names.push(name);
+    }
+    Ok(())
 }
 
 fn single_create_statement(
@@ -244,18 +524,60 @@ fn single_create_statement(
             part.table_name(event)?,
             this.bounds(event)
         ),
-        None => this.schema().to_string(),
+        None => this.schema(),
     };
     let child_stmt = match child {
         Some(part) => format!("partition by {}", part.partition_by()),
         None => "".to_string(),
     };
-    Ok(format!(
-        "create table if not exists {} {} {}",
-        this.table_name(event)?,
+    let tablespace_stmt = match this.tablespace() {
+        Some(tablespace) => format!("tablespace {}", tablespace),
+        None => "".to_string(),
+    };
+    let stmt = [
+        format!("create table if not exists {}", this.table_name(event)?),
         parent_stmt,
-        child_stmt
-    ))
+        child_stmt,
+        tablespace_stmt,
+    ];
+    Ok(stmt
+        .into_iter()
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join(" "))
+}
+
+/// Held while creating partitions, so importers and workers creating the same table at once don't
+/// fail on the catalog's unique indexes despite `if not exists`
+pub const CREATE_LOCK: &str = "select pg_advisory_xact_lock(hashtext('logstuff partitions'))";
+
+/// Statements creating the tables `parts` need for `event`, from root to leaf
+pub fn create_statements(event: &Event, parts: &[&dyn Partitioner]) -> Result<Vec<String>, Error> {
+    validate(event, parts)?;
+    let mut statements = Vec::new();
+    for (index, part) in parts.iter().enumerate() {
+        let parent = match index {
+            0 => None,
+            i => Some(parts[i - 1]),
+        };
+        let child = parts.get(index + 1).copied();
+        statements.push(single_create_statement(event, parent, *part, child)?);
+
+        // TODO configurable owner
+        statements.push(format!(
+            "alter table {} owner to write_logs",
+            part.table_name(event)?
+        ));
+    }
+    Ok(statements)
+}
+
+/// Statements creating the tables for `event` as one batch, run in a single transaction
+/// holding `CREATE_LOCK`
+pub fn create_batch(event: &Event, parts: &[&dyn Partitioner]) -> Result<String, Error> {
+    let mut statements = vec![CREATE_LOCK.to_string()];
+    statements.extend(create_statements(event, parts)?);
+    Ok(statements.join(";\n"))
 }
 
 pub fn create_tables(
@@ -263,34 +585,556 @@ pub fn create_tables(
     event: &Event,
     parts: &[&dyn Partitioner],
 ) -> Result<(), Error> {
-    parts
+    let mut transaction = client.transaction()?;
+    transaction.execute(CREATE_LOCK, &[])?;
+    for statement in create_statements(event, parts)? {
+        transaction.execute(statement.as_str(), &[])?;
+    }
+    transaction.commit()?;
+    Ok(())
+}
+
+/// Leaf partitions known to exist, so their DDL isn't run again
+#[derive(Debug, Default)]
+pub struct CreatedTables(HashSet<String>);
+
+impl CreatedTables {
+    fn leaf_table(event: &Event, parts: &[&dyn Partitioner]) -> Result<String, Error> {
+        parts[parts.len() - 1].table_name(event)
+    }
+
+    /// Whether the tables for `event` were created before
+    pub fn contains(&self, event: &Event, parts: &[&dyn Partitioner]) -> Result<bool, Error> {
+        Ok(self.0.contains(&Self::leaf_table(event, parts)?))
+    }
+
+    /// Forget all tables, e.g. after the partitions changed
+    pub fn clear(&mut self) {
+        self.0.clear();
+    }
+
+    /// Forget the tables for `event`, e.g. after they were dropped
+    pub fn remove(&mut self, event: &Event, parts: &[&dyn Partitioner]) -> Result<(), Error> {
+        self.0.remove(&Self::leaf_table(event, parts)?);
+        Ok(())
+    }
+
+    /// Remember that the tables for `event` exist
+    pub fn insert(&mut self, event: &Event, parts: &[&dyn Partitioner]) -> Result<(), Error> {
+        self.0.insert(Self::leaf_table(event, parts)?);
+        Ok(())
+    }
+
+    /// Pass the statements creating the tables for `event` to `execute`, unless created before
+    ///
+    /// Returns whether any statements were executed.
+    pub fn create(
+        &mut self,
+        event: &Event,
+        parts: &[&dyn Partitioner],
+        mut execute: impl FnMut(&str) -> Result<(), Error>,
+    ) -> Result<bool, Error> {
+        if self.contains(event, parts)? {
+            return Ok(false);
+        }
+        for statement in create_statements(event, parts)? {
+            execute(&statement)?;
+        }
+        self.insert(event, parts)?;
+        Ok(true)
+    }
+}
+
+/// One event for each leaf partition needed for events from `start` until before `end`
+///
+/// Each event lies at the start of its partition, beginning with the one containing `start`.
+fn range_events(
+    start: OffsetDateTime,
+    end: OffsetDateTime,
+    parts: &[&dyn Partitioner],
+) -> Vec<Event> {
+    let first = EventBuilder::default().timestamp(start).build();
+    let mut events = Vec::new();
+    let mut timestamp = parts
         .iter()
-        .enumerate()
-        .try_for_each(|(index, part)| -> Result<(), Error> {
-            let parent = match index {
-                0 => None,
-                i => Some(parts[i - 1]),
-            };
-            let child = if index == parts.len() - 1 {
-                None
-            } else {
-                Some(parts[index + 1])
-            };
-            client.execute(
-                single_create_statement(event, parent, *part, child)?.as_str(),
-                &[],
-            )?;
-
-            // TODO configurable owner
-            client.execute(
-                format!(
-                    "alter table {} owner to write_logs",
-                    part.table_name(event)?
-                )
-                .as_str(),
-                &[],
-            )?;
+        .filter_map(|part| part.range(&first))
+        .map(|(from, _)| from)
+        .max()
+        .unwrap_or(start);
+    while timestamp < end {
+        let event = EventBuilder::default().timestamp(timestamp).build();
+        let next = parts
+            .iter()
+            .filter_map(|part| part.next_partition(&event))
+            .min();
+        events.push(event);
+        match next {
+            Some(next) => timestamp = next,
+            None => break,
+        }
+    }
+    events
+}
+
+/// Create all partitions for events from `start` until before `end`
+///
+/// Returns the names of the leaf partitions.
+pub fn create_tables_for_range(
+    client: &mut impl postgres::GenericClient,
+    start: OffsetDateTime,
+    end: OffsetDateTime,
+    parts: &[&dyn Partitioner],
+) -> Result<Vec<String>, Error> {
+    range_events(start, end, parts)
+        .iter()
+        .map(|event| {
+            create_tables(client, event, parts)?;
+            parts[parts.len() - 1].table_name(event)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use logstuff::event::EventTime;
+    use time::macros::datetime;
+
+    #[test]
+    fn created_tables_are_cached() {
+        let root = Root::default();
+        let month = timerange(None);
+        let parts: [&dyn Partitioner; 2] = [&root, &month];
+        let march = EventBuilder::default()
+            .timestamp(datetime!(2022-03-04 05:06:07 UTC))
+            .build();
+        let april = EventBuilder::default()
+            .timestamp(datetime!(2022-04-01 00:00 UTC))
+            .build();
+
+        let mut created = CreatedTables::default();
+        let executed = std::cell::RefCell::new(Vec::new());
+        let execute = |statement: &str| {
+            executed.borrow_mut().push(statement.to_string());
             Ok(())
-        })?;
-    Ok(())
+        };
+        assert!(created.create(&march, &parts, execute).unwrap());
+        assert!(!created.create(&march, &parts, execute).unwrap());
+        assert_eq!(executed.borrow().len(), 4);
+
+        assert!(created.create(&april, &parts, execute).unwrap());
+        assert_eq!(executed.borrow().len(), 8);
+        assert!(executed.borrow()[6].contains("logs_2022_04"));
+
+        // dropped tables are created again once forgotten
+        created.remove(&march, &parts).unwrap();
+        assert!(!created.contains(&march, &parts).unwrap());
+        assert!(created.contains(&april, &parts).unwrap());
+        assert!(created.create(&march, &parts, execute).unwrap());
+        assert_eq!(executed.borrow().len(), 12);
+
+        // failed DDL is tried again next time
+        let mut created = CreatedTables::default();
+        let fail = |_: &str| Err(Error::NoPartition("read only".into()));
+        assert!(created.create(&march, &parts, fail).is_err());
+        assert!(!created.contains(&march, &parts).unwrap());
+    }
+
+    #[test]
+    fn default_root_schema() {
+        assert_eq!(
+            Root::default().schema(),
+            "(id integer not null default nextval('logs_id'), \
+             tstamp timestamp with time zone not null, doc jsonb not null, search tsvector)"
+        );
+    }
+
+    #[test]
+    fn custom_root_schema() {
+        let root: Root = serde_yaml::from_str(
+            "id_column: event_id\nid_type: bigint\nid_default:\n  type: identity\n",
+        )
+        .unwrap();
+        assert_eq!(
+            root.schema(),
+            "(event_id bigint generated by default as identity, \
+             tstamp timestamp with time zone not null, doc jsonb not null, search tsvector)"
+        );
+
+        let root: Root =
+            serde_yaml::from_str("id_default:\n  type: sequence\n  name: events_seq\n").unwrap();
+        assert!(root
+            .schema()
+            .starts_with("(id integer not null default nextval('events_seq'), "));
+
+        let root: Root = serde_yaml::from_str("schema: (a int)").unwrap();
+        assert_eq!(root.schema(), "(a int)");
+    }
+
+    #[test]
+    fn typed_columns() {
+        let root: Root = serde_yaml::from_str(
+            "columns:\n  - {field: hostname, column: host, type: text}\n  - {field: syslogseverity_num, column: severity, type: smallint}\n",
+        )
+        .unwrap();
+        assert!(root
+            .schema()
+            .ends_with(", search tsvector, host text, severity smallint)"));
+        assert_eq!(
+            insert_statement("logs", root.columns()),
+            "insert into logs (tstamp, doc, search, host, severity) values \
+             ($1, $2, to_tsvector($3), cast_or_null($4::jsonb #>> '{}', null::text)::text, \
+             cast_or_null($5::jsonb #>> '{}', null::smallint)::smallint)"
+        );
+        assert_eq!(
+            insert_statement("logs", &[]),
+            "insert into logs (tstamp, doc, search) values ($1, $2, to_tsvector($3))"
+        );
+
+        let event = EventBuilder::default()
+            .field("hostname", "h")
+            .field("syslogseverity_num", 6)
+            .build();
+        assert_eq!(
+            column_values(&event, root.columns()),
+            [Value::from("h"), Value::from(6)]
+        );
+        let event = EventBuilder::default().field("hostname", "h").build();
+        assert_eq!(
+            column_values(&event, root.columns()),
+            [Value::from("h"), Value::Null]
+        );
+        // passed on as is, cast_or_null makes it null instead of failing the insert
+        let bad = EventBuilder::default()
+            .field("hostname", "h")
+            .field("syslogseverity_num", "-")
+            .build();
+        assert_eq!(
+            column_values(&bad, root.columns()),
+            [Value::from("h"), Value::from("-")]
+        );
+        let params = InsertParams::new(&event, "h", root.columns());
+        assert_eq!(params.to_params().len(), 5);
+        assert_eq!(InsertParams::new(&event, "h", &[]).to_params().len(), 3);
+    }
+
+    #[test]
+    fn partition_by_selects_the_table() {
+        let line = r#"{"msg":"m","rawmsg":"","timereported":"2022-03-31T23:59:59+00:00","hostname":"h","syslogtag":"t","inputname":"i","fromhost":"h","fromhost-ip":"127.0.0.1","pri":"30","syslogfacility":"3","syslogseverity":"6","timegenerated":"2022-04-01T00:00:02+00:00","programname":"p","protocol-version":"0","structured-data":"-","app-name":"p","procid":"1","msgid":"-","uuid":null,"$!":{}}"#;
+        let month = timerange(None);
+        let table = |time| {
+            let event = crate::app::parse_event(
+                line,
+                crate::batch::InputFormat::RsyslogJson,
+                true,
+                false,
+                &Default::default(),
+                time,
+            )
+            .unwrap();
+            month.table_name(&event).unwrap()
+        };
+        assert_eq!(table(EventTime::Timereported), "logs_2022_03");
+        assert_eq!(table(EventTime::Timegenerated), "logs_2022_04");
+    }
+
+    #[test]
+    fn table_names_are_sanitized() {
+        let sanitized = |name: &str| sanitize_table_name(name).unwrap();
+        assert_eq!(sanitized("logs_2022_03"), "logs_2022_03");
+        assert_eq!(sanitized("logs.logs"), "logs.logs");
+        assert_eq!(
+            sanitized("Logs-2022 03; drop table x"),
+            "logs_2022_03__drop_table_x"
+        );
+        assert_eq!(sanitized("2022_logs"), "_2022_logs");
+        assert_eq!(sanitized("lögs"), "l_gs");
+
+        let longest = format!("archive.{}", "x".repeat(MAX_IDENTIFIER_LENGTH));
+        assert_eq!(sanitized(&longest), longest);
+        assert!(matches!(
+            sanitize_table_name(&format!("archive.{}", "x".repeat(100))),
+            Err(Error::NameTooLong(_))
+        ));
+
+        let event = EventBuilder::default()
+            .timestamp(datetime!(2022-03-15 12:00 UTC))
+            .build();
+        let part = Timerange {
+            name_template: "Logs [year]-[month]".into(),
+            ..Default::default()
+        };
+        assert_eq!(part.table_name(&event).unwrap(), "logs_2022_03");
+
+        // the distinguishing end must not be cut off
+        let part = Timerange {
+            name_template: format!("{}_[year]_[month]", "events".repeat(9)),
+            ..Default::default()
+        };
+        let next_month = EventBuilder::default()
+            .timestamp(datetime!(2022-04-15 12:00 UTC))
+            .build();
+        let march = part.table_name(&event).unwrap();
+        assert_eq!(march.len(), MAX_IDENTIFIER_LENGTH - 1);
+        assert_ne!(part.table_name(&next_month).unwrap(), march);
+        let part = Timerange {
+            name_template: format!("{}_[year]_[month]", "events".repeat(10)),
+            ..Default::default()
+        };
+        assert!(matches!(
+            part.table_name(&event),
+            Err(Error::NameTooLong(_))
+        ));
+        assert!(matches!(
+            part.table_name(&next_month),
+            Err(Error::NameTooLong(_))
+        ));
+    }
+
+    fn timerange(tablespace: Option<&str>) -> Timerange {
+        Timerange {
+            name_template: "logs_[year]_[month]".into(),
+            tablespace: tablespace.map(String::from),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn create_statement_without_tablespace() {
+        let event = EventBuilder::default()
+            .timestamp(datetime!(2022-03-15 12:00 UTC))
+            .build();
+        let root = Root::default();
+        let month = timerange(None);
+        assert_eq!(
+            single_create_statement(&event, Some(&root), &month, None).unwrap(),
+            "create table if not exists logs_2022_03 partition of logs \
+             for values from ('2022-03-01') to ('2022-04-01')"
+        );
+    }
+
+    #[test]
+    fn bounds_at_the_end_of_long_months() {
+        let event = EventBuilder::default()
+            .timestamp(datetime!(2022-01-31 23:30 UTC))
+            .build();
+        assert_eq!(
+            timerange(None).bounds(&event),
+            "from ('2022-01-01') to ('2022-02-01')"
+        );
+        let quarter = Timerange {
+            interval: TimeTruncate::Quarter,
+            ..timerange(None)
+        };
+        assert_eq!(
+            quarter.bounds(&event),
+            "from ('2022-01-01') to ('2022-04-01')"
+        );
+        let august = datetime!(2022-08-31 10:00 UTC);
+        assert_eq!(
+            TimeTruncate::Quarter.upper_bound(&august),
+            datetime!(2022-10-01 00:00 UTC)
+        );
+        assert_eq!(
+            TimeTruncate::Month.upper_bound(&datetime!(2022-12-31 10:00 UTC)),
+            datetime!(2023-01-01 00:00 UTC)
+        );
+    }
+
+    #[test]
+    fn create_statement_with_tablespace() {
+        let event = EventBuilder::default()
+            .timestamp(datetime!(2022-03-15 12:00 UTC))
+            .build();
+        let root = Root {
+            tablespace: Some("slow".into()),
+            ..Default::default()
+        };
+        let month = timerange(Some("fast"));
+        assert!(single_create_statement(&event, None, &root, Some(&month))
+            .unwrap()
+            .ends_with("partition by range (tstamp) tablespace slow"));
+        assert_eq!(
+            single_create_statement(&event, Some(&root), &month, None).unwrap(),
+            "create table if not exists logs_2022_03 partition of logs \
+             for values from ('2022-03-01') to ('2022-04-01') tablespace fast"
+        );
+    }
+
+    #[test]
+    fn valid_partitions() {
+        let event = EventBuilder::default()
+            .timestamp(datetime!(2022-03-15 12:00 UTC))
+            .build();
+        let root = Root::default();
+        let month = timerange(None);
+        let day = Timerange {
+            name_template: "logs_[year]_[month]_[day]".into(),
+            interval: TimeTruncate::Day,
+            tablespace: None,
+        };
+        assert!(validate(&event, &[&root]).is_ok());
+        assert!(validate(&event, &[&root, &month, &day]).is_ok());
+    }
+
+    #[test]
+    fn invalid_partitions() {
+        let event = EventBuilder::default()
+            .timestamp(datetime!(2022-03-15 12:00 UTC))
+            .build();
+        let root = Root::default();
+        let month = timerange(None);
+        let invalid = |parts: &[&dyn Partitioner]| match validate(&event, parts) {
+            Err(Error::InvalidBounds(reason)) => reason,
+            result => panic!("unexpected result {:?}", result),
+        };
+
+        // same template twice
+        assert_eq!(
+            invalid(&[&root, &month, &timerange(None)]),
+            "table logs_2022_03 is created by more than one partitioner"
+        );
+
+        // yearly partitions of a monthly partition
+        let year = Timerange {
+            name_template: "logs_[year]".into(),
+            interval: TimeTruncate::Year,
+            tablespace: None,
+        };
+        assert!(invalid(&[&root, &month, &year])
+            .starts_with("logs_2022: range from 2022-01-01 0:00:00.0 +00:00:00 to "));
+
+        // bounds are dates, so hourly partitions would be empty
+        let hour = Timerange {
+            name_template: "logs_[year]_[month]_[day]_[hour]".into(),
+            interval: TimeTruncate::Hour,
+            tablespace: None,
+        };
+        assert!(invalid(&[&root, &hour]).starts_with("logs_2022_03_15_12: empty range"));
+    }
+
+    #[test]
+    fn strategies_and_bounds() {
+        assert!(bounds_match_strategy(
+            "range (tstamp)",
+            "from ('a') to ('b')"
+        ));
+        assert!(bounds_match_strategy("LIST (host)", "in ('a', 'b')"));
+        assert!(bounds_match_strategy(
+            "hash (id)",
+            "with (modulus 4, remainder 0)"
+        ));
+        assert!(!bounds_match_strategy("range (tstamp)", "in ('a')"));
+        assert!(!bounds_match_strategy("list (host)", "from ('a') to ('b')"));
+        assert!(!bounds_match_strategy("", "from ('a') to ('b')"));
+    }
+
+    #[test]
+    fn statements_from_root_to_leaf() {
+        let event = EventBuilder::default()
+            .timestamp(datetime!(2022-03-15 12:00 UTC))
+            .build();
+        let root = Root::default();
+        let month = timerange(None);
+        let statements = create_statements(&event, &[&root, &month]).unwrap();
+        assert_eq!(statements.len(), 4);
+        assert!(statements[0].starts_with("create table if not exists logs (id integer"));
+        assert!(statements[0].ends_with(" partition by range (tstamp)"));
+        assert_eq!(statements[1], "alter table logs owner to write_logs");
+        assert!(statements[2].starts_with("create table if not exists logs_2022_03 partition of"));
+        assert_eq!(
+            statements[3],
+            "alter table logs_2022_03 owner to write_logs"
+        );
+
+        let batch = create_batch(&event, &[&root, &month]).unwrap();
+        assert_eq!(
+            batch,
+            std::iter::once(CREATE_LOCK.to_string())
+                .chain(statements)
+                .collect::<Vec<_>>()
+                .join(";\n")
+        );
+    }
+
+    fn leaf_names(
+        start: OffsetDateTime,
+        end: OffsetDateTime,
+        parts: &[&dyn Partitioner],
+    ) -> Vec<String> {
+        range_events(start, end, parts)
+            .iter()
+            .map(|event| parts[parts.len() - 1].table_name(event).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn partitions_for_range() {
+        let root = Root::default();
+        let month = timerange(None);
+        let parts: [&dyn Partitioner; 2] = [&root, &month];
+        assert_eq!(
+            leaf_names(
+                datetime!(2022-01-31 00:00 UTC),
+                datetime!(2022-02-02 00:00 UTC),
+                &parts
+            ),
+            ["logs_2022_01", "logs_2022_02"]
+        );
+        assert_eq!(
+            leaf_names(
+                datetime!(2022-11-15 00:00 UTC),
+                datetime!(2023-01-01 00:00 UTC),
+                &parts
+            ),
+            ["logs_2022_11", "logs_2022_12"]
+        );
+
+        let day = Timerange {
+            name_template: "logs_[year]_[month]_[day]".into(),
+            interval: TimeTruncate::Day,
+            tablespace: None,
+        };
+        let parts: [&dyn Partitioner; 3] = [&root, &month, &day];
+        assert_eq!(
+            leaf_names(
+                datetime!(2022-02-27 06:00 UTC),
+                datetime!(2022-03-02 00:00 UTC),
+                &parts
+            ),
+            ["logs_2022_02_27", "logs_2022_02_28", "logs_2022_03_01"]
+        );
+
+        // events start at their partition, not on a day the next month lacks
+        let quarter = Timerange {
+            name_template: "logs_[year]_[month]".into(),
+            interval: TimeTruncate::Quarter,
+            tablespace: None,
+        };
+        let parts: [&dyn Partitioner; 2] = [&root, &quarter];
+        let events = range_events(
+            datetime!(2022-01-31 00:00 UTC),
+            datetime!(2022-05-01 00:00 UTC),
+            &parts,
+        );
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].timestamp, datetime!(2022-01-01 00:00 UTC));
+        assert_eq!(
+            quarter.bounds(&events[1]),
+            "from ('2022-04-01') to ('2022-07-01')"
+        );
+        assert!(create_statements(&events[0], &parts).unwrap().iter().any(
+            |statement| statement.ends_with("for values from ('2022-01-01') to ('2022-04-01')")
+        ));
+
+        let parts: [&dyn Partitioner; 1] = [&root];
+        assert_eq!(
+            leaf_names(
+                datetime!(2022-01-01 00:00 UTC),
+                datetime!(2022-03-01 00:00 UTC),
+                &parts
+            ),
+            ["logs"]
+        );
+    }
 }
@@ -0,0 +1,380 @@
+//! Asynchronous import, keeping several inserts in flight on a single connection
+use futures::future::BoxFuture;
+use futures::stream::{FuturesOrdered, StreamExt as _};
+use lru_cache::LruCache;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt as _, AsyncWrite, AsyncWriteExt as _, Lines};
+use tokio_postgres::{Client, Statement};
+
+use logstuff::event::{Event, EventTime, SyslogFields};
+
+use crate::anonymize::AnonymizeIp;
+use crate::app::{parse_event, Error};
+use crate::batch::InputFormat;
+use crate::handshake::Handshake;
+use crate::partition::{self, Partitioner};
+use crate::reload::{Generational, Partitions, SharedPartitions};
+use crate::sampling::Sampling;
+
+/// Where the pipeline puts events
+pub(crate) trait Sink: Sync {
+    fn insert<'a>(&'a self, event: &'a Event) -> BoxFuture<'a, Result<(), Error>>;
+    /// Create the tables for `event` unless created before, returns whether any were created
+    fn create_tables<'a>(&'a self, event: &'a Event) -> BoxFuture<'a, Result<bool, Error>>;
+    /// Forget that the tables for `event` were created, so `create_tables` creates them again
+    fn forget_tables<'a>(&'a self, event: &'a Event) -> BoxFuture<'a, Result<(), Error>>;
+}
+
+/// Inserts with prepared statements, concurrent inserts are pipelined by tokio-postgres
+pub(crate) struct Database {
+    client: Client,
+    partitions: SharedPartitions,
+    prepared_inserts: Mutex<Generational<LruCache<String, Statement>>>,
+    /// held while creating partitions, so concurrent inserts don't race for the same table
+    created_tables: tokio::sync::Mutex<Generational<partition::CreatedTables>>,
+}
+
+impl Database {
+    pub fn new(client: Client, partitions: SharedPartitions, statement_cache_size: usize) -> Self {
+        Self {
+            client,
+            partitions,
+            prepared_inserts: Mutex::new(Generational::new(LruCache::new(statement_cache_size))),
+            created_tables: tokio::sync::Mutex::new(Generational::new(Default::default())),
+        }
+    }
+
+    async fn insert_statement(
+        &self,
+        generation: u64,
+        partitions: &Partitions,
+        root_table: &str,
+    ) -> Result<Statement, Error> {
+        if let Some(statement) = self
+            .prepared_inserts
+            .lock()
+            .unwrap()
+            .get(generation, LruCache::clear)
+            .get_mut(root_table)
+        {
+            return Ok(statement.clone());
+        }
+        info!("Preparing insert statement for root table {}", root_table);
+        let statement = self
+            .client
+            .prepare(&partition::insert_statement(
+                root_table,
+                partitions[0].columns(),
+            ))
+            .await?;
+        self.prepared_inserts
+            .lock()
+            .unwrap()
+            .get(generation, LruCache::clear)
+            .insert(root_table.to_owned(), statement.clone());
+        Ok(statement)
+    }
+}
+
+impl Sink for Database {
+    fn insert<'a>(&'a self, event: &'a Event) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            let (generation, partitions) = self.partitions.current();
+            let root_table = partitions[0].table_name(event)?;
+            let statement = self
+                .insert_statement(generation, &partitions, &root_table)
+                .await?;
+            let search = event.search_string();
+            let params = partition::InsertParams::new(event, &search, partitions[0].columns());
+            self.client.execute(&statement, &params.to_params()).await?;
+            Ok(())
+        })
+    }
+
+    fn create_tables<'a>(&'a self, event: &'a Event) -> BoxFuture<'a, Result<bool, Error>> {
+        Box::pin(async move {
+            let (generation, partitions) = self.partitions.current();
+            let parts: Vec<&dyn Partitioner> =
+                partitions.iter().map(|part| part.as_ref()).collect();
+            let mut created = self.created_tables.lock().await;
+            let created = created.get(generation, partition::CreatedTables::clear);
+            if created.contains(event, &parts)? {
+                return Ok(false);
+            }
+            // a batch runs in one transaction, which keeps the lock until the tables exist
+            let batch = partition::create_batch(event, &parts)?;
+            self.client.batch_execute(&batch).await?;
+            created.insert(event, &parts)?;
+            Ok(true)
+        })
+    }
+
+    fn forget_tables<'a>(&'a self, event: &'a Event) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            let (generation, partitions) = self.partitions.current();
+            let parts: Vec<&dyn Partitioner> =
+                partitions.iter().map(|part| part.as_ref()).collect();
+            let mut created = self.created_tables.lock().await;
+            created
+                .get(generation, partition::CreatedTables::clear)
+                .remove(event, &parts)?;
+            Ok(())
+        })
+    }
+}
+
+/// Imports lines of input, replying to each in input order
+pub(crate) struct Pipeline<S> {
+    pub sink: S,
+    pub use_vars_msg: bool,
+    pub include_rawmsg: bool,
+    pub syslog_fields: SyslogFields,
+    pub partition_by: EventTime,
+    pub anonymize_ip: Option<AnonymizeIp>,
+    pub sampling: Option<Sampling>,
+    /// most events being inserted at the same time
+    pub depth: usize,
+    pub handshake: Handshake,
+    /// treat the input as ended once no line arrived for this long
+    pub idle_timeout: Option<Duration>,
+}
+
+impl<S: Sink> Pipeline<S> {
+    /// Import the event in `line`, returns whether it is to be confirmed
+    async fn handle_event(&self, line: String) -> Result<bool, Error> {
+        let mut event = match parse_event(
+            &line,
+            InputFormat::RsyslogJson,
+            self.use_vars_msg,
+            self.include_rawmsg,
+            &self.syslog_fields,
+            self.partition_by,
+        ) {
+            Some(event) => event,
+            None => return Ok(false),
+        };
+        if let Some(sampling) = &self.sampling {
+            if sampling.should_drop(&event) {
+                debug!("Dropping event by sampling");
+                return Ok(true);
+            }
+        }
+        if let Some(anonymize_ip) = &self.anonymize_ip {
+            anonymize_ip.apply(&mut event);
+        }
+        if self.sink.insert(&event).await.is_err() {
+            info!("Event insertion failed, trying to create missing partitions");
+            let created = self.sink.create_tables(&event).await?;
+            debug!("Partitions created, retrying event insertion");
+            if let Err(err) = self.sink.insert(&event).await {
+                if created {
+                    return Err(err);
+                }
+                // tables created before may have been dropped since, e.g. by retention
+                info!("Event insertion still failed, creating its partitions again");
+                self.sink.forget_tables(&event).await?;
+                self.sink.create_tables(&event).await?;
+                self.sink.insert(&event).await?;
+            }
+        }
+        Ok(true)
+    }
+
+    /// Next line of `lines`, `None` at its end or after `idle_timeout` without input
+    async fn next_line<R: AsyncBufRead + Unpin>(
+        &self,
+        lines: &mut Lines<R>,
+    ) -> std::io::Result<Option<String>> {
+        let timeout = match self.idle_timeout {
+            Some(timeout) => timeout,
+            None => return lines.next_line().await,
+        };
+        match tokio::time::timeout(timeout, lines.next_line()).await {
+            Ok(line) => line,
+            Err(_) => {
+                info!("no input for {} seconds", timeout.as_secs());
+                Ok(None)
+            }
+        }
+    }
+
+    /// Import all lines of `input` until its end, `idle_timeout` or the first error
+    pub async fn run(
+        &self,
+        input: impl AsyncBufRead + Unpin,
+        mut output: impl AsyncWrite + Unpin,
+    ) -> Result<(), Error> {
+        let mut lines = input.lines();
+        let mut pending = FuturesOrdered::new();
+        let mut at_eof = false;
+        loop {
+            tokio::select! {
+                line = self.next_line(&mut lines), if !at_eof && pending.len() < self.depth.max(1) => {
+                    match line? {
+                        Some(line) => pending.push_back(self.handle_event(line)),
+                        None => at_eof = true,
+                    }
+                }
+                Some(confirm) = pending.next() => {
+                    if let Some(reply) = self.handshake.reply(confirm?) {
+                        output.write_all(format!("{}\n", reply).as_bytes()).await?;
+                        output.flush().await?;
+                    }
+                }
+                else => break,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashSet;
+
+    /// Keeps events in memory, inserts fail for days without partition
+    #[derive(Default)]
+    struct MemorySink {
+        days: Mutex<HashSet<time::Date>>,
+        /// days `create_tables` remembers, like `CreatedTables`
+        created: Mutex<HashSet<time::Date>>,
+        events: Mutex<Vec<String>>,
+        fail_creating: bool,
+    }
+
+    impl Sink for MemorySink {
+        fn insert<'a>(&'a self, event: &'a Event) -> BoxFuture<'a, Result<(), Error>> {
+            Box::pin(async move {
+                let msg = event.get_printable("msg").unwrap();
+                // later events overtake slow ones
+                if msg.contains("slow") {
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                }
+                if !self.days.lock().unwrap().contains(&event.timestamp.date()) {
+                    return Err(Error::Partition(partition::Error::NoPartition(msg)));
+                }
+                self.events.lock().unwrap().push(msg);
+                Ok(())
+            })
+        }
+
+        fn create_tables<'a>(&'a self, event: &'a Event) -> BoxFuture<'a, Result<bool, Error>> {
+            Box::pin(async move {
+                if self.fail_creating {
+                    return Err(Error::Partition(partition::Error::NoPartition(
+                        "read only".into(),
+                    )));
+                }
+                let day = event.timestamp.date();
+                if !self.created.lock().unwrap().insert(day) {
+                    return Ok(false);
+                }
+                self.days.lock().unwrap().insert(day);
+                Ok(true)
+            })
+        }
+
+        fn forget_tables<'a>(&'a self, event: &'a Event) -> BoxFuture<'a, Result<(), Error>> {
+            Box::pin(async move {
+                self.created.lock().unwrap().remove(&event.timestamp.date());
+                Ok(())
+            })
+        }
+    }
+
+    fn line(day: u8, msg: &str) -> String {
+        format!(
+            r#"{{"msg":"{}","rawmsg":"","timereported":"2022-03-{:02}T05:06:07+00:00","hostname":"h","syslogtag":"t","inputname":"i","fromhost":"h","fromhost-ip":"127.0.0.1","pri":"30","syslogfacility":"3","syslogseverity":"6","timegenerated":"2022-03-{:02}T05:06:07+00:00","programname":"p","protocol-version":"0","structured-data":"-","app-name":"p","procid":"1","msgid":"-","uuid":null,"$!":{{}}}}"#,
+            msg, day, day
+        )
+    }
+
+    fn pipeline(sink: MemorySink) -> Pipeline<MemorySink> {
+        Pipeline {
+            sink,
+            use_vars_msg: true,
+            include_rawmsg: false,
+            syslog_fields: SyslogFields::default(),
+            partition_by: EventTime::default(),
+            anonymize_ip: None,
+            sampling: None,
+            depth: 4,
+            handshake: Handshake::default(),
+            idle_timeout: None,
+        }
+    }
+
+    async fn run(pipeline: &Pipeline<MemorySink>, lines: &[String]) -> (Result<(), Error>, String) {
+        let input = lines.join("\n");
+        let mut output = Vec::new();
+        let result = pipeline.run(input.as_bytes(), &mut output).await;
+        (result, String::from_utf8(output).unwrap())
+    }
+
+    #[tokio::test]
+    async fn insert_and_confirm_in_order() {
+        let pipeline = pipeline(MemorySink::default());
+        let lines = [
+            line(1, "slow"),
+            line(1, "fast"),
+            "not json".into(),
+            line(2, "next day"),
+        ];
+        let (result, output) = run(&pipeline, &lines).await;
+        assert!(result.is_ok());
+        assert_eq!(output, "OK\nOK\nOK\n");
+
+        let mut events = pipeline.sink.events.lock().unwrap().clone();
+        events.sort();
+        assert_eq!(events, ["fast", "next day", "slow"]);
+        assert_eq!(pipeline.sink.days.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn errors_stop_the_import() {
+        let pipeline = pipeline(MemorySink {
+            fail_creating: true,
+            ..Default::default()
+        });
+        let (result, output) = run(&pipeline, &[line(1, "a"), line(1, "b")]).await;
+        assert!(result.is_err());
+        assert_eq!(output, "");
+        assert!(pipeline.sink.events.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn dropped_partitions_are_created_again() {
+        let pipeline = pipeline(MemorySink::default());
+        let (result, _) = run(&pipeline, &[line(1, "a")]).await;
+        assert!(result.is_ok());
+
+        // e.g. removed by retention, while still remembered as created
+        pipeline.sink.days.lock().unwrap().clear();
+        let (result, output) = run(&pipeline, &[line(1, "b")]).await;
+        assert!(result.is_ok());
+        assert_eq!(output, "OK\n");
+        assert_eq!(*pipeline.sink.events.lock().unwrap(), ["a", "b"]);
+    }
+
+    #[tokio::test]
+    async fn stops_when_idle() {
+        let mut pipeline = pipeline(MemorySink::default());
+        pipeline.idle_timeout = Some(Duration::from_millis(50));
+        let (mut writer, reader) = tokio::io::duplex(1024);
+        writer
+            .write_all(format!("{}\n", line(1, "a")).as_bytes())
+            .await
+            .unwrap();
+        let mut output = Vec::new();
+        // the writer stays open, only the timeout ends the input
+        let result = pipeline
+            .run(tokio::io::BufReader::new(reader), &mut output)
+            .await;
+        assert!(result.is_ok());
+        assert_eq!(String::from_utf8(output).unwrap(), "OK\n");
+        assert_eq!(*pipeline.sink.events.lock().unwrap(), ["a"]);
+        drop(writer);
+    }
+}
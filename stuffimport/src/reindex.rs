@@ -0,0 +1,120 @@
+//! Rebuilding the full text search vectors of stored events
+use postgres::Client;
+use serde_json::Value;
+use time::OffsetDateTime;
+
+use logstuff::event::Event;
+
+use crate::app::Error;
+
+/// Statement selecting id, time stamp and document of at most `$2` events following id `$1`
+fn select_statement(table: &str, id_column: &str) -> String {
+    format!(
+        "select {id}::bigint, tstamp, doc from {table} where {id} > $1::bigint order by {id} limit $2",
+        id = id_column,
+        table = table
+    )
+}
+
+/// Statement setting `search` of the events with the ids in `$1` to the vectors of the texts in `$2`
+fn update_statement(table: &str, id_column: &str) -> String {
+    format!(
+        "update {table} set search = to_tsvector(batch.search) \
+         from unnest($1::bigint[], $2::text[]) as batch(id, search) \
+         where {table}.{id} = batch.id",
+        id = id_column,
+        table = table
+    )
+}
+
+/// Ids and current search strings of `rows` holding id, time stamp and document
+fn search_strings(rows: Vec<(i64, OffsetDateTime, Value)>) -> (Vec<i64>, Vec<String>) {
+    rows.into_iter()
+        .map(|(id, timestamp, doc)| (id, Event { timestamp, doc }.search_string()))
+        .unzip()
+}
+
+/// Recompute `search` of all events with an id above `after`, `batch_size` of them per transaction
+///
+/// Logs the last id of each batch, passing it as `after` resumes an interrupted run. Returns the
+/// number of updated events.
+pub fn reindex(
+    client: &mut Client,
+    table: &str,
+    id_column: &str,
+    batch_size: i64,
+    mut after: i64,
+) -> Result<u64, Error> {
+    let select = client.prepare(&select_statement(table, id_column))?;
+    let update = client.prepare(&update_statement(table, id_column))?;
+    let last: Option<i64> = client
+        .query_one(
+            format!("select max({})::bigint from {}", id_column, table).as_str(),
+            &[],
+        )?
+        .get(0);
+    info!(
+        "Reindexing {} after {} {} up to {}",
+        table,
+        id_column,
+        after,
+        last.map_or("-".into(), |id| id.to_string())
+    );
+
+    let mut updated = 0;
+    loop {
+        let mut transaction = client.transaction()?;
+        let rows = transaction.query(&select, &[&after, &batch_size.max(1)])?;
+        if rows.is_empty() {
+            break;
+        }
+        let (ids, searches) = search_strings(
+            rows.iter()
+                .map(|row| (row.get(0), row.get(1), row.get(2)))
+                .collect(),
+        );
+        transaction.execute(&update, &[&ids, &searches])?;
+        transaction.commit()?;
+        updated += ids.len() as u64;
+        after = ids[ids.len() - 1];
+        info!(
+            "Reindexed {} events, up to {} {}",
+            updated, id_column, after
+        );
+    }
+    Ok(updated)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+    use time::macros::datetime;
+
+    #[test]
+    fn batch_statements() {
+        assert_eq!(
+            select_statement("logs", "event_id"),
+            "select event_id::bigint, tstamp, doc from logs where event_id > $1::bigint \
+             order by event_id limit $2"
+        );
+        assert_eq!(
+            update_statement("logs", "event_id"),
+            "update logs set search = to_tsvector(batch.search) \
+             from unnest($1::bigint[], $2::text[]) as batch(id, search) \
+             where logs.event_id = batch.id"
+        );
+    }
+
+    #[test]
+    fn search_strings_are_recomputed() {
+        let timestamp = datetime!(2022-01-01 00:00 UTC);
+        let doc = json!({"hostname": "h", "msg": "m", "vars.user": "u", "pid": 1});
+        let (ids, searches) =
+            search_strings(vec![(3, timestamp, doc.clone()), (7, timestamp, json!({}))]);
+        assert_eq!(ids, [3, 7]);
+        assert_eq!(searches[0], Event { timestamp, doc }.search_string());
+        assert_eq!(searches[0], r#""h" "m" vars.user="u""#);
+        assert_eq!(searches[1], "");
+    }
+}
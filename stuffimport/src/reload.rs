@@ -0,0 +1,258 @@
+//! Replacing the partition list of a running importer when it receives SIGHUP
+use serde_json::Value;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::{error, fmt, io, thread};
+use tokio::signal::unix::{signal, SignalKind};
+
+use logstuff::event::EventBuilder;
+
+use crate::config::Config;
+use crate::partition::{self, Partitioner};
+
+pub(crate) type Partitions = Arc<Vec<Box<dyn Partitioner>>>;
+
+/// Partition list shared by all importers, counting how often it was replaced
+#[derive(Clone)]
+pub(crate) struct SharedPartitions(Arc<Mutex<(u64, Partitions)>>);
+
+impl SharedPartitions {
+    pub fn new(partitions: Partitions) -> Self {
+        Self(Arc::new(Mutex::new((0, partitions))))
+    }
+
+    /// Generation and partitions of the current list
+    pub fn current(&self) -> (u64, Partitions) {
+        let current = self.0.lock().unwrap();
+        (current.0, current.1.clone())
+    }
+
+    fn replace(&self, partitions: Partitions) {
+        let mut current = self.0.lock().unwrap();
+        *current = (current.0 + 1, partitions);
+    }
+}
+
+/// Cache of statements or tables of one generation of the partition list
+pub(crate) struct Generational<T> {
+    generation: u64,
+    value: T,
+}
+
+impl<T> Generational<T> {
+    pub fn new(value: T) -> Self {
+        Self {
+            generation: 0,
+            value,
+        }
+    }
+
+    /// The cache for `generation`, emptied with `clear` if it was filled for an older one
+    pub fn get(&mut self, generation: u64, clear: impl FnOnce(&mut T)) -> &mut T {
+        if self.generation != generation {
+            clear(&mut self.value);
+            self.generation = generation;
+        }
+        &mut self.value
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// The config file could not be read, with the reason
+    Load(String),
+    Json(serde_json::Error),
+    Partition(partition::Error),
+    /// Settings that can't change without restarting
+    Incompatible(Vec<String>),
+}
+
+/// Settings of `config` other than its partitions
+pub(crate) fn fixed_settings(config: &Config) -> Result<Value, serde_json::Error> {
+    let mut settings = serde_json::to_value(config)?;
+    if let Some(settings) = settings.as_object_mut() {
+        settings.remove("partitions");
+    }
+    Ok(settings)
+}
+
+/// Applies the partitions of a changed config file to running importers
+pub(crate) struct Reloader {
+    path: PathBuf,
+    lenient: bool,
+    settings: Value,
+    partitions: SharedPartitions,
+}
+
+impl Reloader {
+    /// Reloader for the config at `path`, which started the importers with `settings`
+    pub fn new(
+        path: PathBuf,
+        lenient: bool,
+        settings: Value,
+        partitions: SharedPartitions,
+    ) -> Self {
+        Self {
+            path,
+            lenient,
+            settings,
+            partitions,
+        }
+    }
+
+    /// Replace the partitions with those of `config`, if its other settings are unchanged
+    fn apply(&self, mut config: Config) -> Result<(), Error> {
+        let settings = fixed_settings(&config)?;
+        let changed: Vec<String> = match (self.settings.as_object(), settings.as_object()) {
+            (Some(running), Some(loaded)) => running
+                .iter()
+                .filter(|(key, value)| loaded.get(*key) != Some(value))
+                .map(|(key, _)| key.to_owned())
+                .collect(),
+            _ => Vec::new(),
+        };
+        if !changed.is_empty() {
+            return Err(Error::Incompatible(changed));
+        }
+        let partitions = std::mem::take(&mut config.partitions);
+        let parts: Vec<&dyn Partitioner> = partitions.iter().map(|part| part.as_ref()).collect();
+        partition::validate(&EventBuilder::default().build(), &parts)?;
+        self.partitions.replace(Arc::new(partitions));
+        Ok(())
+    }
+
+    /// Load the config file again and apply its partitions, logging the outcome
+    fn reload(&self) {
+        info!("Reloading partitions from {}", self.path.display());
+        let result = Config::from_path(&self.path, self.lenient)
+            .map_err(|err| Error::Load(err.to_string()))
+            .and_then(|(config, ignored)| {
+                logstuff::config::warn_ignored(&ignored);
+                self.apply(config)
+            });
+        match result {
+            Ok(()) => info!("Partitions reloaded: {:?}", self.partitions.current().1),
+            Err(err) => error!("Keeping the current partitions: {}", err),
+        }
+    }
+}
+
+/// Reload the partitions whenever SIGHUP is received, on a thread of its own
+pub(crate) fn reload_on_hangup(reloader: Reloader) -> Result<(), io::Error> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let mut hangup = runtime.block_on(async { signal(SignalKind::hangup()) })?;
+    thread::spawn(move || {
+        runtime.block_on(async {
+            while hangup.recv().await.is_some() {
+                reloader.reload();
+            }
+        })
+    });
+    Ok(())
+}
+
+impl error::Error for Error {}
+
+impl From<serde_json::Error> for Error {
+    fn from(error: serde_json::Error) -> Self {
+        Self::Json(error)
+    }
+}
+
+impl From<partition::Error> for Error {
+    fn from(error: partition::Error) -> Self {
+        Self::Partition(error)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+        match self {
+            Load(e) => write!(f, "Could not load config: {}", e),
+            Json(e) => write!(f, "Could not compare settings: {}", e),
+            Partition(e) => write!(f, "Invalid partitions: {}", e),
+            Incompatible(keys) => write!(
+                f,
+                "Changing {} needs a restart, only partitions are reloaded",
+                keys.join(", ")
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const RUNNING: &str = "
+db_url: host=db
+partitions:
+  - kind: root
+    table: logs
+";
+
+    fn config(yaml: &str) -> Config {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn reloader() -> Reloader {
+        let mut running = config(RUNNING);
+        let settings = fixed_settings(&running).unwrap();
+        let partitions = std::mem::take(&mut running.partitions);
+        Reloader::new(
+            "settings.yaml".into(),
+            false,
+            settings,
+            SharedPartitions::new(Arc::new(partitions)),
+        )
+    }
+
+    #[test]
+    fn reload_applies_new_partitioner() {
+        let reloader = reloader();
+        let (generation, partitions) = reloader.partitions.current();
+        assert_eq!((generation, partitions.len()), (0, 1));
+
+        let added = RUNNING.to_owned()
+            + "  - kind: timerange\n    name_template: logs_[year]\n    interval: Year\n";
+        reloader.apply(config(&added)).unwrap();
+        let (generation, partitions) = reloader.partitions.current();
+        assert_eq!((generation, partitions.len()), (1, 2));
+        let event = EventBuilder::default().build();
+        assert!(partitions[1]
+            .table_name(&event)
+            .unwrap()
+            .starts_with("logs_"));
+    }
+
+    #[test]
+    fn reload_keeps_partitions_on_incompatible_changes() {
+        let reloader = reloader();
+        let changed = RUNNING.replace("host=db", "host=other") + "worker_threads: 4\n";
+        match reloader.apply(config(&changed)) {
+            Err(Error::Incompatible(keys)) => assert_eq!(keys, ["db_url", "worker_threads"]),
+            other => panic!("unexpected {:?}", other),
+        }
+        // two partitioners creating the same table
+        let invalid = RUNNING.to_owned()
+            + "  - kind: timerange\n    name_template: logs\n    interval: Year\n";
+        assert!(matches!(
+            reloader.apply(config(&invalid)),
+            Err(Error::Partition(_))
+        ));
+        assert_eq!(reloader.partitions.current().0, 0);
+    }
+
+    #[test]
+    fn caches_are_emptied_for_new_generations() {
+        let mut cache = Generational::new(vec![1]);
+        assert_eq!(cache.get(0, Vec::clear), &[1]);
+        cache.get(0, Vec::clear).push(2);
+        assert_eq!(cache.get(1, Vec::clear), &[] as &[i32]);
+        cache.get(1, Vec::clear).push(3);
+        assert_eq!(cache.get(1, Vec::clear), &[3]);
+    }
+}
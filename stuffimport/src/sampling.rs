@@ -0,0 +1,164 @@
+//! Dropping part of the less severe events, e.g. during log storms
+use serde_json::Value;
+
+use logstuff::event::{Event, SyslogSeverity};
+
+/// Events less severe than `below` are dropped with probability `drop_rate`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct Sampling {
+    #[serde(with = "severity_name")]
+    pub below: SyslogSeverity,
+    pub drop_rate: f64,
+}
+
+impl Default for Sampling {
+    fn default() -> Self {
+        Self {
+            below: SyslogSeverity::Notice,
+            drop_rate: 0.0,
+        }
+    }
+}
+
+impl Sampling {
+    /// Whether to drop an event of `severity`, `random` being uniformly distributed in [0, 1)
+    ///
+    /// Events without severity are always kept.
+    pub fn drops(&self, severity: Option<u64>, random: f64) -> bool {
+        match severity {
+            Some(severity) => severity > self.below.as_u8().into() && random < self.drop_rate,
+            None => false,
+        }
+    }
+
+    /// Randomly decide whether to drop `event`
+    pub fn should_drop(&self, event: &Event) -> bool {
+        self.drops(severity(event), rand::random())
+    }
+}
+
+/// Number of `event`'s severity, from "syslogseverity_num" or else "syslogseverity"
+///
+/// The latter is read in each of the representations `syslog_fields` allows, so sampling works
+/// without "syslogseverity_num", too.
+fn severity(event: &Event) -> Option<u64> {
+    if let Some(num) = event.doc.get("syslogseverity_num").and_then(Value::as_u64) {
+        return Some(num);
+    }
+    match event.doc.get("syslogseverity")? {
+        Value::Number(num) => num.as_u64(),
+        Value::String(name) => name
+            .to_lowercase()
+            .parse::<SyslogSeverity>()
+            .ok()
+            .map(|severity| severity.as_u8().into()),
+        _ => None,
+    }
+}
+
+/// Severities by name, like "notice"
+mod severity_name {
+    use logstuff::event::SyslogSeverity;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn deserialize<'de, D>(d: D) -> Result<SyslogSeverity, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        String::deserialize(d)?.parse().map_err(D::Error::custom)
+    }
+
+    pub fn serialize<S>(severity: &SyslogSeverity, s: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        s.serialize_str(&severity.to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sampling(drop_rate: f64) -> Sampling {
+        Sampling {
+            below: SyslogSeverity::Notice,
+            drop_rate,
+        }
+    }
+
+    #[test]
+    fn drop_decision() {
+        let info = Some(6);
+        let debug = Some(7);
+        let sampling = sampling(0.25);
+        assert!(sampling.drops(info, 0.0));
+        assert!(sampling.drops(debug, 0.24));
+        assert!(!sampling.drops(info, 0.25));
+        assert!(!sampling.drops(info, 0.99));
+    }
+
+    #[test]
+    fn severe_events_are_kept() {
+        let sampling = sampling(1.0);
+        for severity in 0..=5 {
+            assert!(!sampling.drops(Some(severity), 0.0));
+        }
+        assert!(sampling.drops(Some(6), 0.999));
+        assert!(!sampling.drops(None, 0.0));
+        assert!(!Sampling::default().drops(Some(7), 0.0));
+    }
+
+    #[test]
+    fn events_and_config() {
+        let sampling: Sampling = serde_yaml::from_str("{below: warning, drop_rate: 1}").unwrap();
+        assert_eq!(sampling.below, SyslogSeverity::Warning);
+
+        let event = Event::builder().field("syslogseverity_num", 5).build();
+        assert!(sampling.should_drop(&event));
+        let event = Event::builder().field("syslogseverity_num", 4).build();
+        assert!(!sampling.should_drop(&event));
+        assert!(!sampling.should_drop(&Event::builder().build()));
+
+        assert!(serde_yaml::from_str::<Sampling>("below: loud").is_err());
+        assert_eq!(
+            serde_yaml::to_string(&sampling).unwrap(),
+            "below: warning\ndrop_rate: 1.0\n"
+        );
+    }
+
+    #[test]
+    fn severity_in_any_representation() {
+        use crate::app::parse_event;
+        use crate::batch::InputFormat;
+        use logstuff::event::{CodeName, EventTime, SyslogFields};
+
+        let line = r#"{"msg":"m","rawmsg":"","timereported":"2022-03-04T05:06:07+00:00","hostname":"h","syslogtag":"t","inputname":"i","fromhost":"h","fromhost-ip":"127.0.0.1","pri":"31","syslogfacility":"3","syslogseverity":"7","timegenerated":"2022-03-04T05:06:07+00:00","programname":"p","protocol-version":"0","structured-data":"-","app-name":"p","procid":"1","msgid":"-","uuid":null,"$!":{}}"#;
+        let sampling = sampling(1.0);
+        for severity in [CodeName::Lowercase, CodeName::Uppercase, CodeName::Number] {
+            for severity_num in [true, false] {
+                let fields = SyslogFields {
+                    severity,
+                    severity_num,
+                    ..Default::default()
+                };
+                let event = parse_event(
+                    line,
+                    InputFormat::RsyslogJson,
+                    false,
+                    false,
+                    &fields,
+                    EventTime::default(),
+                )
+                .unwrap();
+                assert!(sampling.should_drop(&event), "{:?}", fields);
+            }
+        }
+
+        let event = Event::builder().field("syslogseverity", "ERROR").build();
+        assert_eq!(severity(&event), Some(3));
+        let event = Event::builder().field("syslogseverity", "loud").build();
+        assert_eq!(severity(&event), None);
+    }
+}
@@ -0,0 +1,190 @@
+//! Handle input lines on several threads, confirming them in input order
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, Write};
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::handshake::Handshake;
+
+type Done<E> = Result<(u64, bool), E>;
+
+fn read_lines<E: From<io::Error>>(
+    input: impl BufRead,
+    lines: std::sync::mpsc::SyncSender<(u64, String)>,
+    done: Sender<Done<E>>,
+) {
+    for (seq, line) in (0..).zip(input.lines()) {
+        let sent = match line {
+            Ok(line) => lines.send((seq, line)).is_ok(),
+            Err(err) => {
+                let _ = done.send(Err(err.into()));
+                false
+            }
+        };
+        if !sent {
+            break;
+        }
+    }
+}
+
+fn work<E>(
+    mut handler: impl FnMut(&str) -> Result<bool, E>,
+    lines: Arc<Mutex<Receiver<(u64, String)>>>,
+    done: Sender<Done<E>>,
+) {
+    loop {
+        let next = lines.lock().unwrap().recv();
+        let (seq, line) = match next {
+            Ok(next) => next,
+            Err(_) => break,
+        };
+        let result = handler(&line).map(|confirm| (seq, confirm));
+        let failed = result.is_err();
+        if done.send(result).is_err() || failed {
+            break;
+        }
+    }
+}
+
+/// Read `input` on a separate thread and pass each line to one of `handlers`, each on its own thread
+///
+/// At most `queue_size` lines wait for a free handler. A handler returns whether its line is to be
+/// confirmed. The `handshake`'s reply to a line is written to `output` once it and all lines
+/// before it were handled, so replies keep the input's order. The first error stops confirming and is returned.
+pub fn run<E>(
+    input: impl BufRead + Send + 'static,
+    mut output: impl Write,
+    handlers: Vec<impl FnMut(&str) -> Result<bool, E> + Send + 'static>,
+    queue_size: usize,
+    handshake: &Handshake,
+) -> Result<(), E>
+where
+    E: From<io::Error> + Send + 'static,
+{
+    let (lines_tx, lines_rx) = sync_channel(queue_size);
+    let lines_rx = Arc::new(Mutex::new(lines_rx));
+    let (done_tx, done_rx) = channel();
+
+    let reader_done = done_tx.clone();
+    thread::spawn(move || read_lines(input, lines_tx, reader_done));
+    for handler in handlers {
+        let lines = lines_rx.clone();
+        let done = done_tx.clone();
+        thread::spawn(move || work(handler, lines, done));
+    }
+    drop(lines_rx);
+    drop(done_tx);
+
+    let mut next = 0;
+    let mut finished = BTreeMap::new();
+    for result in done_rx {
+        let (seq, confirm) = result?;
+        finished.insert(seq, confirm);
+        while let Some(confirm) = finished.remove(&next) {
+            handshake.write_reply(&mut output, confirm)?;
+            next += 1;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[derive(Debug)]
+    struct Error;
+
+    impl From<io::Error> for Error {
+        fn from(_: io::Error) -> Self {
+            Error
+        }
+    }
+
+    fn input(lines: &[&str]) -> io::Cursor<Vec<u8>> {
+        io::Cursor::new(lines.join("\n").into_bytes())
+    }
+
+    #[test]
+    fn every_line_is_handled_once() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let handlers = (0..4)
+            .map(|_| {
+                let count = count.clone();
+                move |_: &str| -> Result<bool, Error> {
+                    count.fetch_add(1, Ordering::SeqCst);
+                    Ok(true)
+                }
+            })
+            .collect();
+        let lines: Vec<String> = (0..100).map(|i| i.to_string()).collect();
+        let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+        let mut output = Vec::new();
+        run(
+            input(&lines),
+            &mut output,
+            handlers,
+            2,
+            &Handshake::default(),
+        )
+        .unwrap();
+        assert_eq!(count.load(Ordering::SeqCst), 100);
+        assert_eq!(output, "OK\n".repeat(100).into_bytes());
+    }
+
+    #[test]
+    fn confirmations_wait_for_earlier_lines() {
+        let handled = Arc::new(Mutex::new(Vec::new()));
+        let handlers = (0..2)
+            .map(|_| {
+                let handled = handled.clone();
+                move |line: &str| -> Result<bool, Error> {
+                    if line == "slow" {
+                        thread::sleep(Duration::from_millis(100));
+                    }
+                    handled.lock().unwrap().push(line.to_string());
+                    Ok(line != "skip")
+                }
+            })
+            .collect();
+        let mut output = Vec::new();
+        let handshake = Handshake {
+            error: Some("ERR".into()),
+            ..Default::default()
+        };
+        run(
+            input(&["slow", "fast", "skip"]),
+            &mut output,
+            handlers,
+            4,
+            &handshake,
+        )
+        .unwrap();
+        assert_eq!(handled.lock().unwrap()[2], "slow");
+        assert_eq!(output, b"OK\nOK\nERR\n");
+    }
+
+    #[test]
+    fn errors_stop_confirming() {
+        let handlers = vec![|line: &str| -> Result<bool, Error> {
+            if line == "bad" {
+                Err(Error)
+            } else {
+                Ok(true)
+            }
+        }];
+        let mut output = Vec::new();
+        assert!(run(
+            input(&["good", "bad", "good"]),
+            &mut output,
+            handlers,
+            1,
+            &Handshake::default()
+        )
+        .is_err());
+        assert_eq!(output, b"OK\n");
+    }
+}
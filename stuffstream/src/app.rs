@@ -1,31 +1,57 @@
 use bb8_postgres::tokio_postgres;
+use bb8_postgres::tokio_postgres::error::SqlState;
 use bb8_postgres::{bb8, PostgresConnectionManager};
 use futures::lock::Mutex;
 use rustls::client::ClientConfig;
 use std::convert::Infallible;
 use std::sync::Arc;
+use std::time::Duration;
 use std::{fmt, io};
 use tokio_postgres_rustls::MakeRustlsConnect;
 use warp::http::StatusCode;
 use warp::{reject, reply, Filter, Rejection, Reply};
 
 use logstuff::tls;
-use logstuff_query::{ExpressionParser, IdentifierParser};
+use logstuff_query::{
+    Aliases, AllowedFields, ExpressionParser, FieldColumns, IdentifierParser, QueryError,
+    SqlOptions,
+};
 
 use crate::application::{Application, Stopping};
-use crate::config::{Config, HttpSettings, TlsClientAuth};
+use crate::audit::{self, AuditLog};
+use crate::config::{
+    Config, CountsRollup, EventDefaults, EventLimits, HttpSettings, PoolSettings, RateLimit,
+};
 use crate::counts;
 use crate::events;
+use crate::explain;
+use crate::export;
+use crate::fields_over_time;
+use crate::limits;
+use crate::ratelimit::{self, RateLimiter};
+use crate::schema;
+use crate::server::{self, Server};
+use crate::tls_server;
 use crate::Args;
 
 pub(crate) type DBPool = bb8::Pool<PostgresConnectionManager<MakeRustlsConnect>>;
 
+/// Connection pool together with the TLS setup needed to cancel queries running on it
+#[derive(Clone)]
+pub(crate) struct Database {
+    pub pool: DBPool,
+    pub tls: MakeRustlsConnect,
+}
+
 /// Error type for the core program logic
 #[derive(Debug)]
 pub enum Error {
-    Logger(log::SetLoggerError),
     Io(io::Error),
     Db(tokio_postgres::Error),
+    /// The root table lacks the column full text search uses
+    MissingSearchColumn,
+    /// No pool connection became free in time
+    PoolTimeout,
     Tls(tls::Error),
 }
 
@@ -35,22 +61,45 @@ pub enum Error {
 pub struct App {
     auto_restart: bool,
     db_url: String,
+    db_pool: PoolSettings,
     postgres_tls: tls::ClientConfig,
     http_settings: HttpSettings,
+    event_limits: EventLimits,
+    event_defaults: EventDefaults,
+    rate_limit: Option<RateLimit>,
     table_name: String,
+    counts_cache_ttl: Duration,
+    counts_rollups: Vec<CountsRollup>,
+    audit_table: Option<String>,
+    query_aliases: Aliases,
+    queryable_fields: AllowedFields,
+    field_columns: FieldColumns,
 }
 
 impl Application for App {
     type Err = Error;
 
     fn new(_opts: Args, config: Config) -> Result<Self, Self::Err> {
-        env_logger::try_init()?;
+        let field_columns = config.field_columns();
         Ok(App {
             auto_restart: config.auto_restart,
             db_url: config.db_url,
+            db_pool: config.db_pool,
             postgres_tls: config.postgres_tls.client_config()?,
             http_settings: config.http_settings,
+            event_limits: config.event_limits,
+            event_defaults: EventDefaults {
+                limit: config.default_events_limit,
+                order: config.default_events_order,
+            },
+            rate_limit: config.rate_limit,
             table_name: config.root_table_name,
+            counts_cache_ttl: Duration::from_secs(config.counts_cache_ttl_sec),
+            counts_rollups: config.counts_rollups,
+            audit_table: config.audit_table,
+            query_aliases: config.query_aliases,
+            queryable_fields: config.queryable_fields,
+            field_columns,
         })
     }
 
@@ -61,9 +110,19 @@ impl Application for App {
             .unwrap()
             .block_on(start_server(
                 &self.http_settings,
+                self.event_limits,
+                self.event_defaults,
+                self.rate_limit,
                 &self.db_url,
+                &self.db_pool,
                 &self.postgres_tls,
                 &self.table_name,
+                self.counts_cache_ttl,
+                &self.counts_rollups,
+                self.audit_table.as_deref(),
+                &self.query_aliases,
+                &self.queryable_fields,
+                &self.field_columns,
             ))?;
 
         if self.auto_restart {
@@ -76,95 +135,298 @@ impl Application for App {
 
 impl App {}
 
+/// Request parameters that can't be used, answered with 400
 #[derive(Debug)]
-pub struct MalformedQuery;
+pub enum MalformedQuery {
+    /// Invalid syntax
+    Syntax,
+    /// Parameters that parse but make no sense, with the reason shown to the user
+    Semantic(String),
+}
 
 impl reject::Reject for MalformedQuery {}
 
-async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible> {
-    if err.is_not_found() {
-        Ok(reply::with_status("NOT_FOUND", StatusCode::NOT_FOUND))
-    } else if err.find::<MalformedQuery>().is_some() {
-        Ok(reply::with_status("BAD_REQUEST", StatusCode::BAD_REQUEST))
+impl From<QueryError> for MalformedQuery {
+    fn from(err: QueryError) -> Self {
+        match err {
+            QueryError::Parse(_) => Self::Syntax,
+            QueryError::Semantic(reason) => Self::Semantic(reason),
+        }
+    }
+}
+
+pub(crate) async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible> {
+    let (message, status) = if err.is_not_found() {
+        ("NOT_FOUND".to_string(), StatusCode::NOT_FOUND)
+    } else if let Some(malformed) = err.find::<MalformedQuery>() {
+        let message = match malformed {
+            MalformedQuery::Syntax => "BAD_REQUEST".to_string(),
+            MalformedQuery::Semantic(reason) => format!("BAD_REQUEST: {}", reason),
+        };
+        (message, StatusCode::BAD_REQUEST)
+    } else if err.find::<limits::RequestTooLarge>().is_some() {
+        (
+            "PAYLOAD_TOO_LARGE".to_string(),
+            StatusCode::PAYLOAD_TOO_LARGE,
+        )
+    } else if err.find::<ratelimit::TooManyRequests>().is_some() {
+        (
+            "TOO_MANY_REQUESTS".to_string(),
+            StatusCode::TOO_MANY_REQUESTS,
+        )
     } else {
         error!("unhandled rejection: {:?}", err);
-        Ok(reply::with_status(
-            "INTERNAL_SERVER_ERROR",
+        (
+            "INTERNAL_SERVER_ERROR".to_string(),
             StatusCode::INTERNAL_SERVER_ERROR,
-        ))
-    }
+        )
+    };
+    Ok(reply::with_status(message, status))
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn start_server(
     http_settings: &HttpSettings,
+    event_limits: EventLimits,
+    event_defaults: EventDefaults,
+    rate_limit: Option<RateLimit>,
     db_url: &str,
+    db_pool: &PoolSettings,
     postgres_tls: &ClientConfig,
     table_name: &str,
+    counts_cache_ttl: Duration,
+    counts_rollups: &[CountsRollup],
+    audit_table: Option<&str>,
+    query_aliases: &Aliases,
+    queryable_fields: &AllowedFields,
+    field_columns: &FieldColumns,
 ) -> Result<(), Error> {
     let connector = MakeRustlsConnect::new(postgres_tls.clone());
-    let manager = PostgresConnectionManager::new_from_stringlike(db_url, connector)?;
-    let dbpool = bb8::Pool::builder()
-        .max_size(3)
-        .build(manager)
-        .await
-        .unwrap();
+    let manager = PostgresConnectionManager::new_from_stringlike(db_url, connector.clone())?;
+    let dbpool = Database {
+        pool: db_pool.builder().build(manager).await?,
+        tls: connector,
+    };
 
-    let expr_parser = Arc::new(Mutex::new(ExpressionParser::default()));
-    let id_parser = Arc::new(Mutex::new(IdentifierParser::default()));
+    let audit_log = audit_table.map(|table| Arc::new(AuditLog::new(dbpool.clone(), table)));
+    let expr_parser = Arc::new(Mutex::new(
+        ExpressionParser::default()
+            .with_aliases(query_aliases.clone())
+            .with_allowed_fields(queryable_fields.clone())
+            .with_field_columns(field_columns.clone()),
+    ));
+    let id_parser = Arc::new(Mutex::new(
+        IdentifierParser::default()
+            .with_allowed_fields(queryable_fields.clone())
+            .with_field_columns(field_columns.clone()),
+    ));
 
+    let max_queries = db_pool.queries_per_request();
     let p = expr_parser.clone();
     let table = table_name.to_owned();
     let events = warp::get()
         .and(warp::path("events"))
+        .and(audit::audited::<events::Request>(
+            audit_log.clone(),
+            "events",
+        ))
+        .and(with_db(dbpool.clone()))
+        .and_then(move |params, dbpool| {
+            events::handler(
+                p.clone(),
+                table.to_owned(),
+                event_limits,
+                event_defaults,
+                max_queries,
+                params,
+                dbpool,
+            )
+        });
+
+    let p = expr_parser.clone();
+    let table = table_name.to_owned();
+    let explain_events = warp::get()
+        .and(warp::path!("explain" / "events"))
+        .and(explain::enabled(http_settings.enable_explain))
         .and(warp::query::<events::Request>())
         .and(with_db(dbpool.clone()))
         .and_then(move |params, dbpool| {
-            events::handler(p.clone(), table.to_owned(), params, dbpool)
+            events::explain_handler(
+                p.clone(),
+                table.to_owned(),
+                event_limits,
+                event_defaults,
+                params,
+                dbpool,
+            )
+        });
+
+    let rollups: Arc<[CountsRollup]> = counts_rollups.into();
+
+    let p = expr_parser.clone();
+    let i = id_parser.clone();
+    let r = rollups.clone();
+    let table = table_name.to_owned();
+    let explain_counts = warp::get()
+        .and(warp::path!("explain" / "counts"))
+        .and(explain::enabled(http_settings.enable_explain))
+        .and(warp::query::<counts::Request>())
+        .and(with_db(dbpool.clone()))
+        .and_then(move |params, dbpool| {
+            counts::explain_handler(
+                p.clone(),
+                i.clone(),
+                r.clone(),
+                table.to_owned(),
+                params,
+                dbpool,
+            )
+        });
+
+    let p = expr_parser.clone();
+    let table = table_name.to_owned();
+    let sql_events = warp::get()
+        .and(warp::path!("debug" / "sql" / "events"))
+        .and(explain::enabled(http_settings.enable_debug_sql))
+        .and(warp::query::<events::Request>())
+        .and(with_db(dbpool.clone()))
+        .and_then(move |params, dbpool| {
+            events::sql_handler(
+                p.clone(),
+                table.to_owned(),
+                event_limits,
+                event_defaults,
+                params,
+                dbpool,
+            )
+        });
+
+    let p = expr_parser.clone();
+    let i = id_parser.clone();
+    let r = rollups.clone();
+    let table = table_name.to_owned();
+    let sql_counts = warp::get()
+        .and(warp::path!("debug" / "sql" / "counts"))
+        .and(explain::enabled(http_settings.enable_debug_sql))
+        .and(warp::query::<counts::Request>())
+        .and(with_db(dbpool.clone()))
+        .and_then(move |params, dbpool| {
+            counts::sql_handler(
+                p.clone(),
+                i.clone(),
+                r.clone(),
+                table.to_owned(),
+                params,
+                dbpool,
+            )
+        });
+
+    let p = expr_parser.clone();
+    let i = id_parser.clone();
+    let table = table_name.to_owned();
+    let fields_over_time = warp::get()
+        .and(warp::path("fields_over_time"))
+        .and(warp::query::<fields_over_time::Request>())
+        .and(with_db(dbpool.clone()))
+        .and_then(move |params, dbpool| {
+            fields_over_time::handler(p.clone(), i.clone(), table.to_owned(), params, dbpool)
+        });
+
+    let p = expr_parser.clone();
+    let table = table_name.to_owned();
+    let export = warp::get()
+        .and(warp::path("export"))
+        .and(audit::audited::<export::Request>(
+            audit_log.clone(),
+            "export",
+        ))
+        .and(with_db(dbpool.clone()))
+        .and_then(move |params, dbpool| {
+            export::handler(p.clone(), table.to_owned(), params, dbpool)
         });
 
+    let table = table_name.to_owned();
+    let schema = warp::get()
+        .and(warp::path("schema"))
+        .and(warp::path::end())
+        .and(with_db(dbpool.clone()))
+        .and_then(move |dbpool| schema::handler(table.to_owned(), dbpool));
+
+    let cache = if counts_cache_ttl.is_zero() {
+        None
+    } else {
+        Some(Arc::new(counts::Cache::new(counts_cache_ttl)))
+    };
     let table = table_name.to_owned();
     let counts = warp::get()
         .and(warp::path("counts"))
-        .and(warp::query::<counts::Request>())
+        .and(audit::audited::<counts::Request>(audit_log, "counts"))
         .and(with_db(dbpool.clone()))
         .and_then(move |params, dbpool| {
             counts::handler(
                 expr_parser.clone(),
                 id_parser.clone(),
+                rollups.clone(),
+                cache.clone(),
                 table.to_owned(),
                 params,
                 dbpool,
             )
         });
 
-    let routes = events.or(counts).recover(handle_rejection);
-    let server = warp::serve(routes);
-    if http_settings.use_tls {
-        let server = server
-            .tls()
-            .cert_path(&http_settings.tls_cert)
-            .key_path(&http_settings.tls_key);
-
-        match &http_settings.tls_client_auth {
-            None => server,
-            Some(TlsClientAuth::Required { trusted_certs }) => {
-                server.client_auth_required_path(trusted_certs)
-            }
-            Some(TlsClientAuth::Optional { trusted_certs }) => {
-                server.client_auth_optional_path(trusted_certs)
+    let rate_limiter = rate_limit.map(|settings| Arc::new(RateLimiter::new(settings)));
+    let routes = limits::request_size(http_settings.max_query_length, http_settings.max_body_size)
+        .and(ratelimit::rate_limit(rate_limiter))
+        .and(
+            events
+                .or(counts)
+                .or(fields_over_time)
+                .or(export)
+                .or(schema)
+                .or(explain_events)
+                .or(explain_counts)
+                .or(sql_events)
+                .or(sql_counts),
+        )
+        .recover(handle_rejection);
+    let tls_config = if http_settings.use_tls {
+        let tls_config = Arc::new(tls_server::ReloadableConfig::new(http_settings)?);
+        let reload_config = tls_config.clone();
+        tokio::spawn(async move {
+            if let Err(err) = tls_server::reload_on_hangup(reload_config).await {
+                error!("Certificate reloading disabled: {}", err);
             }
-        }
-        .run(http_settings.listen_address)
-        .await;
+        });
+        Some(tls_config)
     } else {
-        server.run(http_settings.listen_address).await;
-    }
+        None
+    };
+    let listener = server::bind(http_settings)?;
+    Server::new(http_settings)
+        .serve(listener, tls_config, warp::service(routes))
+        .await;
 
     Ok(())
 }
 
-fn with_db(db_pool: DBPool) -> impl Filter<Extract = (DBPool,), Error = Infallible> + Clone {
-    warp::any().map(move || db_pool.clone())
+/// Pool that connects lazily, for tests that never reach the database
+#[cfg(test)]
+pub(crate) fn unconnected_pool() -> Database {
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(rustls::RootCertStore::empty())
+        .with_no_client_auth();
+    let tls = MakeRustlsConnect::new(config);
+    let manager =
+        PostgresConnectionManager::new_from_stringlike("host=localhost", tls.clone()).unwrap();
+    Database {
+        pool: bb8::Pool::builder().build_unchecked(manager),
+        tls,
+    }
+}
+
+fn with_db(db: Database) -> impl Filter<Extract = (Database,), Error = Infallible> + Clone {
+    warp::any().map(move || db.clone())
 }
 
 impl std::error::Error for Error {}
@@ -175,15 +437,33 @@ impl From<std::io::Error> for Error {
     }
 }
 
-impl From<log::SetLoggerError> for Error {
-    fn from(error: log::SetLoggerError) -> Self {
-        Self::Logger(error)
-    }
+/// Whether postgres reported the error `code` with `message` for a missing column `column`
+///
+/// The message may be translated, so only the column's name is looked for, as a whole word.
+fn is_missing_column(code: &SqlState, message: &str, column: &str) -> bool {
+    let is_identifier = |c: char| c.is_alphanumeric() || c == '_';
+    *code == SqlState::UNDEFINED_COLUMN
+        && message.match_indices(column).any(|(start, _)| {
+            let before = message[..start].chars().next_back();
+            let after = message[start + column.len()..].chars().next();
+            !before.is_some_and(is_identifier) && !after.is_some_and(is_identifier)
+        })
 }
 
 impl From<tokio_postgres::Error> for Error {
     fn from(error: tokio_postgres::Error) -> Self {
-        Self::Db(error)
+        match error.as_db_error() {
+            Some(db)
+                if is_missing_column(
+                    db.code(),
+                    db.message(),
+                    &SqlOptions::default().search_column,
+                ) =>
+            {
+                Self::MissingSearchColumn
+            }
+            _ => Self::Db(error),
+        }
     }
 }
 
@@ -197,10 +477,58 @@ impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use self::Error::*;
         match self {
-            Logger(e) => write!(f, "Could not set logger: {}", e),
             Io(e) => write!(f, "I/O Error: {}", e),
             Db(e) => write!(f, "Database connection error: {}", e),
+            MissingSearchColumn => write!(
+                f,
+                "Full text search needs the column \"search\" in the root table, add it with \
+                 \"alter table <root table> add column search tsvector\" (see schema.sql) and \
+                 fill it with \"stuffimport reindex\""
+            ),
+            PoolTimeout => write!(f, "Timed out waiting for a database connection"),
             Tls(e) => write!(f, "TLS setup error: {}", e),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn missing_search_column() {
+        let missing = |code: &SqlState, message: &str| is_missing_column(code, message, "search");
+        assert!(missing(
+            &SqlState::UNDEFINED_COLUMN,
+            r#"column "search" does not exist"#
+        ));
+        // translated messages
+        assert!(missing(
+            &SqlState::UNDEFINED_COLUMN,
+            "Spalte »search« existiert nicht"
+        ));
+        assert!(missing(
+            &SqlState::UNDEFINED_COLUMN,
+            "la colonne « search » n'existe pas"
+        ));
+        assert!(!missing(
+            &SqlState::UNDEFINED_COLUMN,
+            r#"column "host" does not exist"#
+        ));
+        assert!(!missing(
+            &SqlState::UNDEFINED_COLUMN,
+            r#"column "research" does not exist"#
+        ));
+        assert!(!missing(
+            &SqlState::UNDEFINED_COLUMN,
+            r#"column "search_text" does not exist"#
+        ));
+        assert!(!missing(
+            &SqlState::UNDEFINED_TABLE,
+            r#"column "search" does not exist"#
+        ));
+        assert!(Error::MissingSearchColumn
+            .to_string()
+            .contains("alter table <root table> add column search tsvector"));
+    }
+}
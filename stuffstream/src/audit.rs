@@ -0,0 +1,179 @@
+//! Record who requested which logs in an audit table
+use bb8_postgres::tokio_postgres::types::ToSql;
+use serde::de::DeserializeOwned;
+use std::net::IpAddr;
+use std::sync::Arc;
+use time::OffsetDateTime;
+use warp::{Filter, Rejection};
+
+use crate::app::Database;
+use crate::server::Peer;
+
+/// Requests whose query and time range get audited
+pub(crate) trait Audited {
+    fn query(&self) -> Option<&str>;
+    fn time_range(&self) -> (OffsetDateTime, OffsetDateTime);
+}
+
+/// A single row of the audit table
+#[derive(Debug)]
+pub struct Record {
+    pub timestamp: OffsetDateTime,
+    pub source_ip: Option<IpAddr>,
+    pub subject: Option<String>,
+    pub endpoint: &'static str,
+    pub query: Option<String>,
+    pub start: OffsetDateTime,
+    pub end: OffsetDateTime,
+}
+
+impl Record {
+    pub fn new(endpoint: &'static str, peer: Option<Peer>, request: &impl Audited) -> Self {
+        let (start, end) = request.time_range();
+        let (source_ip, subject) = match peer {
+            Some(peer) => (Some(peer.addr.ip()), peer.subject),
+            None => (None, None),
+        };
+        Self {
+            timestamp: OffsetDateTime::now_utc(),
+            source_ip,
+            subject,
+            endpoint,
+            query: request.query().map(String::from),
+            start,
+            end,
+        }
+    }
+
+    fn params(&self) -> [&(dyn ToSql + Sync); 7] {
+        [
+            &self.timestamp,
+            &self.source_ip,
+            &self.subject,
+            &self.endpoint,
+            &self.query,
+            &self.start,
+            &self.end,
+        ]
+    }
+}
+
+fn insert_statement(table: &str) -> String {
+    format!(
+        "insert into {} (tstamp, source_ip, subject, endpoint, query, range_start, range_end) \
+         values ($1, $2, $3, $4, $5, $6, $7)",
+        table
+    )
+}
+
+pub(crate) struct AuditLog {
+    db: Database,
+    statement: String,
+}
+
+impl AuditLog {
+    pub fn new(db: Database, table: &str) -> Self {
+        Self {
+            db,
+            statement: insert_statement(table),
+        }
+    }
+
+    /// Insert `record` in the background, failures are only logged
+    pub fn record(self: &Arc<Self>, record: Record) {
+        let this = self.clone();
+        tokio::spawn(async move {
+            let result = match this.db.pool.get().await {
+                Ok(conn) => conn
+                    .execute(this.statement.as_str(), &record.params())
+                    .await
+                    .map(|_| ())
+                    .map_err(|err| err.to_string()),
+                Err(err) => Err(err.to_string()),
+            };
+            if let Err(err) = result {
+                error!("Could not write audit record {:?}: {}", record, err);
+            }
+        });
+    }
+}
+
+/// Query parameters of type `R`, recorded in `log` if auditing is enabled
+pub(crate) fn audited<R>(
+    log: Option<Arc<AuditLog>>,
+    endpoint: &'static str,
+) -> impl Filter<Extract = (R,), Error = Rejection> + Clone
+where
+    R: Audited + DeserializeOwned + Send + 'static,
+{
+    warp::query::<R>().and(warp::ext::optional::<Peer>()).map(
+        move |request: R, peer: Option<Peer>| {
+            if let Some(log) = &log {
+                log.record(Record::new(endpoint, peer, &request));
+            }
+            request
+        },
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use time::macros::datetime;
+
+    struct Request;
+
+    impl Audited for Request {
+        fn query(&self) -> Option<&str> {
+            Some(r#"host = "a""#)
+        }
+
+        fn time_range(&self) -> (OffsetDateTime, OffsetDateTime) {
+            (
+                datetime!(2022-01-01 00:00 UTC),
+                datetime!(2022-01-02 00:00 UTC),
+            )
+        }
+    }
+
+    #[test]
+    fn record_from_request() {
+        let peer = Peer {
+            addr: "192.0.2.7:51234".parse().unwrap(),
+            subject: Some("CN=alice".into()),
+        };
+        let record = Record::new("events", Some(peer), &Request);
+        assert_eq!(record.source_ip, Some("192.0.2.7".parse().unwrap()));
+        assert_eq!(record.subject.as_deref(), Some("CN=alice"));
+        assert_eq!(record.endpoint, "events");
+        assert_eq!(record.query.as_deref(), Some(r#"host = "a""#));
+        assert_eq!(record.start, datetime!(2022-01-01 00:00 UTC));
+        assert_eq!(record.end, datetime!(2022-01-02 00:00 UTC));
+        assert!(record.timestamp > datetime!(2022-01-02 00:00 UTC));
+        assert_eq!(record.params().len(), 7);
+
+        let record = Record::new("counts", None, &Request);
+        assert_eq!(record.source_ip, None);
+        assert_eq!(record.subject, None);
+    }
+
+    #[test]
+    fn insert_into_configured_table() {
+        assert_eq!(
+            insert_statement("logs.audit"),
+            "insert into logs.audit (tstamp, source_ip, subject, endpoint, query, range_start, \
+             range_end) values ($1, $2, $3, $4, $5, $6, $7)"
+        );
+    }
+
+    #[tokio::test]
+    async fn filter_passes_request_on() {
+        let log = Arc::new(AuditLog::new(crate::app::unconnected_pool(), "audit"));
+        let filter = audited::<crate::counts::Request>(Some(log), "counts");
+        let request = warp::test::request()
+            .path("/?start=1640995200&end=1641081600&query=a")
+            .filter(&filter)
+            .await;
+        assert!(request.is_ok());
+    }
+}
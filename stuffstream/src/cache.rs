@@ -0,0 +1,71 @@
+//! Small in-process cache for responses that may be served again for a while
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Maps keys to values that expire `ttl` after insertion
+pub struct TtlCache<K, V> {
+    ttl: Duration,
+    entries: Mutex<HashMap<K, (Instant, V)>>,
+}
+
+impl<K: Hash + Eq, V: Clone> TtlCache<K, V> {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        self.get_at(key, Instant::now())
+    }
+
+    /// Store a value, dropping all expired entries on the way
+    pub fn insert(&self, key: K, value: V) {
+        self.insert_at(key, value, Instant::now())
+    }
+
+    fn get_at(&self, key: &K, now: Instant) -> Option<V> {
+        let entries = self.entries.lock().unwrap();
+        match entries.get(key) {
+            Some((inserted, value)) if now.duration_since(*inserted) < self.ttl => {
+                Some(value.clone())
+            }
+            _ => None,
+        }
+    }
+
+    fn insert_at(&self, key: K, value: V, now: Instant) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, (inserted, _)| now.duration_since(*inserted) < self.ttl);
+        entries.insert(key, (now, value));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn hit_and_miss() {
+        let cache = TtlCache::new(Duration::from_secs(10));
+        assert_eq!(cache.get(&"a"), None);
+        cache.insert("a", 1);
+        assert_eq!(cache.get(&"a"), Some(1));
+        assert_eq!(cache.get(&"b"), None);
+    }
+
+    #[test]
+    fn expiry() {
+        let cache = TtlCache::new(Duration::from_secs(10));
+        let start = Instant::now();
+        cache.insert_at("a", 1, start);
+        assert_eq!(cache.get_at(&"a", start + Duration::from_secs(9)), Some(1));
+        assert_eq!(cache.get_at(&"a", start + Duration::from_secs(10)), None);
+
+        cache.insert_at("b", 2, start + Duration::from_secs(20));
+        assert_eq!(cache.entries.lock().unwrap().len(), 1);
+    }
+}
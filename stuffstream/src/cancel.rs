@@ -0,0 +1,182 @@
+//! Stop queries on the server when nobody is waiting for their rows any more
+use bb8_postgres::bb8::{PooledConnection, RunError};
+use bb8_postgres::tokio_postgres::types::BorrowToSql;
+use bb8_postgres::tokio_postgres::{CancelToken, CopyOutStream, RowStream, ToStatement};
+use bb8_postgres::PostgresConnectionManager;
+use futures::stream::Stream;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio_postgres_rustls::MakeRustlsConnect;
+
+use crate::app::{Database, Error};
+
+/// Pooled connection owned by the stream reading from it
+pub(crate) type Connection =
+    PooledConnection<'static, PostgresConnectionManager<MakeRustlsConnect>>;
+
+/// Stream wrapper calling `on_cancel` if it is dropped before the end was reached
+///
+/// hyper drops the response body when the client disconnects, which makes this the place to
+/// notice abandoned requests.
+pub struct CancelOnDrop<S, G> {
+    inner: Pin<Box<S>>,
+    /// kept until the end of `inner`, e.g. the connection it reads from
+    guard: Option<G>,
+    on_cancel: Option<Box<dyn FnOnce(G) + Send>>,
+}
+
+impl<S, G> CancelOnDrop<S, G> {
+    /// Keep `guard` until `inner` ends, hand it to `on_cancel` if dropped before
+    pub fn with_guard(inner: S, guard: G, on_cancel: impl FnOnce(G) + Send + 'static) -> Self {
+        Self {
+            inner: Box::pin(inner),
+            guard: Some(guard),
+            on_cancel: Some(Box::new(on_cancel)),
+        }
+    }
+}
+
+impl<S: Stream, G: Unpin> Stream for CancelOnDrop<S, G> {
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let item = self.inner.as_mut().poll_next(cx);
+        if let Poll::Ready(None) = item {
+            self.on_cancel = None;
+            self.guard = None;
+        }
+        item
+    }
+}
+
+impl<S, G> Drop for CancelOnDrop<S, G> {
+    fn drop(&mut self) {
+        if let (Some(on_cancel), Some(guard)) = (self.on_cancel.take(), self.guard.take()) {
+            on_cancel(guard);
+        }
+    }
+}
+
+/// A connection of the pool, owned so it can outlive the borrow of `db`
+async fn connection(db: &Database) -> Result<Connection, Error> {
+    db.pool.get_owned().await.map_err(|err| match err {
+        RunError::User(err) => Error::from(err),
+        RunError::TimedOut => Error::PoolTimeout,
+    })
+}
+
+/// `query_raw` on a pooled connection, cancelled on the server once the rows are dropped
+///
+/// The connection returns to the pool after the last row, or after cancelling.
+pub(crate) async fn query_raw<T, P, I>(
+    db: &Database,
+    statement: &T,
+    params: I,
+) -> Result<CancelOnDrop<RowStream, Connection>, Error>
+where
+    T: ?Sized + ToStatement,
+    P: BorrowToSql,
+    I: IntoIterator<Item = P>,
+    I::IntoIter: ExactSizeIterator,
+{
+    let conn = connection(db).await?;
+    let token = conn.cancel_token();
+    let rows = conn.query_raw(statement, params).await?;
+    Ok(CancelOnDrop::with_guard(
+        rows,
+        conn,
+        cancel_query(db, token),
+    ))
+}
+
+/// `copy_out` on a pooled connection, cancelled on the server once the data is dropped
+///
+/// The connection returns to the pool after the last chunk, or after cancelling.
+pub(crate) async fn copy_out<T>(
+    db: &Database,
+    statement: &T,
+) -> Result<CancelOnDrop<CopyOutStream, Connection>, Error>
+where
+    T: ?Sized + ToStatement,
+{
+    let conn = connection(db).await?;
+    let token = conn.cancel_token();
+    let data = conn.copy_out(statement).await?;
+    Ok(CancelOnDrop::with_guard(
+        data,
+        conn,
+        cancel_query(db, token),
+    ))
+}
+
+/// Callback cancelling the query of `token` in the background, then releasing its connection
+///
+/// Releasing it before could cancel the query of the next request using the connection.
+fn cancel_query(db: &Database, token: CancelToken) -> impl FnOnce(Connection) + Send + 'static {
+    let tls = db.tls.clone();
+    move |conn| {
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(async move {
+                debug!("Client went away, cancelling query");
+                if let Err(err) = token.cancel_query(tls).await {
+                    warn!("Could not cancel query: {}", err);
+                }
+                drop(conn);
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::stream::{self, StreamExt};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    fn tracked<S>(inner: S) -> (CancelOnDrop<S, ()>, Arc<AtomicBool>) {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let flag = cancelled.clone();
+        let stream =
+            CancelOnDrop::with_guard(inner, (), move |()| flag.store(true, Ordering::SeqCst));
+        (stream, cancelled)
+    }
+
+    #[tokio::test]
+    async fn dropping_early_cancels() {
+        let (mut rows, cancelled) = tracked(stream::iter(1..4).chain(stream::pending()));
+        assert_eq!(rows.next().await, Some(1));
+        assert!(!cancelled.load(Ordering::SeqCst));
+        drop(rows);
+        assert!(cancelled.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn guard_is_kept_until_the_end() {
+        let guard = Arc::new(());
+        let mut rows = CancelOnDrop::with_guard(stream::iter(1..3), guard.clone(), drop);
+        assert_eq!(rows.next().await, Some(1));
+        assert_eq!(Arc::strong_count(&guard), 2);
+        assert_eq!(rows.next().await, Some(2));
+        assert_eq!(rows.next().await, None);
+        assert_eq!(Arc::strong_count(&guard), 1);
+
+        // cancelling gets the guard
+        let handed = Arc::new(AtomicBool::new(false));
+        let flag = handed.clone();
+        let rows = CancelOnDrop::with_guard(stream::pending::<()>(), guard.clone(), move |g| {
+            assert_eq!(Arc::strong_count(&g), 2);
+            flag.store(true, Ordering::SeqCst);
+        });
+        drop(rows);
+        assert!(handed.load(Ordering::SeqCst));
+        assert_eq!(Arc::strong_count(&guard), 1);
+    }
+
+    #[tokio::test]
+    async fn finished_stream_is_not_cancelled() {
+        let (rows, cancelled) = tracked(stream::iter(1..4));
+        assert_eq!(rows.collect::<Vec<_>>().await, vec![1, 2, 3]);
+        assert!(!cancelled.load(Ordering::SeqCst));
+    }
+}
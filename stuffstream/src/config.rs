@@ -1,17 +1,21 @@
+use bb8_postgres::bb8;
 use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 
+use logstuff::config;
 use logstuff::tls::TlsSettings;
+use logstuff_query::{Aliases, AllowedFields, FieldColumn, FieldColumns};
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(tag = "type")]
 pub enum TlsClientAuth {
     Required { trusted_certs: String },
     Optional { trusted_certs: String },
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(deny_unknown_fields, default)]
 pub struct HttpSettings {
     pub listen_address: SocketAddr,
@@ -19,6 +23,14 @@ pub struct HttpSettings {
     pub tls_cert: String,
     pub tls_key: String,
     pub tls_client_auth: Option<TlsClientAuth>,
+    pub enable_explain: bool,
+    pub enable_debug_sql: bool,
+    pub listen_backlog: u32,
+    pub keep_alive_timeout_sec: u64,
+    pub tls_handshake_timeout_sec: u64,
+    pub max_connections: usize,
+    pub max_query_length: usize,
+    pub max_body_size: u64,
 }
 
 impl Default for HttpSettings {
@@ -29,6 +41,177 @@ impl Default for HttpSettings {
             tls_cert: String::new(),
             tls_key: String::new(),
             tls_client_auth: None,
+            enable_explain: false,
+            enable_debug_sql: false,
+            listen_backlog: 1024,
+            keep_alive_timeout_sec: 60,
+            tls_handshake_timeout_sec: 10,
+            max_connections: 512,
+            max_query_length: 64 * 1024,
+            max_body_size: 1024 * 1024,
+        }
+    }
+}
+
+/// Connection pool to the database
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields, default)]
+pub struct PoolSettings {
+    pub max_size: u32,
+    /// Connections kept open even when idle, so requests after a quiet period are fast
+    pub min_idle: Option<u32>,
+    /// Check connections with a cheap round trip before handing them out
+    pub test_on_check_out: bool,
+    /// Most queries of a single request running at the same time, `max_size - 1` if not set
+    pub max_queries_per_request: Option<u32>,
+}
+
+impl Default for PoolSettings {
+    fn default() -> Self {
+        Self {
+            max_size: 3,
+            min_idle: None,
+            test_on_check_out: true,
+            max_queries_per_request: None,
+        }
+    }
+}
+
+impl PoolSettings {
+    pub fn builder<M: bb8::ManageConnection>(&self) -> bb8::Builder<M> {
+        bb8::Pool::builder()
+            .max_size(self.max_size)
+            .min_idle(self.min_idle)
+            .test_on_check_out(self.test_on_check_out)
+    }
+
+    /// Queries a request may run at the same time, at least one and at most `max_size`
+    ///
+    /// Unless configured, a request leaves one connection of the pool to the others.
+    pub fn queries_per_request(&self) -> usize {
+        let max_size = self.max_size.max(1);
+        self.max_queries_per_request
+            .unwrap_or(max_size - 1)
+            .clamp(1, max_size) as usize
+    }
+}
+
+/// Bounds on the size of `/events` responses
+///
+/// Unsigned, so negative limits are rejected when the config is loaded.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(deny_unknown_fields, default)]
+pub struct EventLimits {
+    /// Most events returned, also used for requests without `limit_events`
+    pub max_events: u32,
+    /// Documents with longer JSON text are replaced by a placeholder
+    pub max_document_size: u64,
+}
+
+impl Default for EventLimits {
+    fn default() -> Self {
+        Self {
+            max_events: 10000,
+            max_document_size: 1024 * 1024,
+        }
+    }
+}
+
+impl EventLimits {
+    /// Number of events to return when `requested` were asked for
+    pub fn clamp(&self, requested: Option<i64>) -> i64 {
+        let max_events = i64::from(self.max_events);
+        requested.unwrap_or(max_events).clamp(0, max_events)
+    }
+}
+
+/// Order of the events in `/events` responses
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum EventOrder {
+    /// newest first
+    #[default]
+    Desc,
+    /// oldest first
+    Asc,
+}
+
+impl EventOrder {
+    /// Direction for SQL's `order by`
+    pub fn sql(&self) -> &'static str {
+        match self {
+            Self::Desc => "desc",
+            Self::Asc => "asc",
+        }
+    }
+}
+
+/// What `/events` uses for requests without `limit_events` or `order`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EventDefaults {
+    /// `None` returns as many events as `EventLimits` allows
+    pub limit: Option<i64>,
+    pub order: EventOrder,
+}
+
+/// What requests are counted together by the rate limit
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitKey {
+    /// the client's IP address
+    #[default]
+    ClientIp,
+    /// the subject of the client's certificate, its IP address without certificate
+    ClientCertificate,
+}
+
+/// Token bucket limiting the requests of each client
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(deny_unknown_fields, default)]
+pub struct RateLimit {
+    /// Rate at which the bucket refills
+    pub requests_per_sec: f64,
+    /// Size of the bucket, requests that may be made at once
+    pub burst: u32,
+    pub key: RateLimitKey,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self {
+            requests_per_sec: 10.0,
+            burst: 20,
+            key: RateLimitKey::default(),
+        }
+    }
+}
+
+/// Table with pre-aggregated event counts, e.g. a TimescaleDB continuous aggregate
+///
+/// It has a row with columns `tstamp` (start of the bucket) and `count` for each bucket.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct CountsRollup {
+    pub table: String,
+    /// Width of the buckets as `/counts` names them, e.g. "1 hour"
+    pub interval: String,
+}
+
+/// Typed column of the root table stuffimport fills with a field
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct ColumnSettings {
+    pub column: String,
+    /// SQL data type of the column, as in stuffimport's root partition
+    #[serde(rename = "type")]
+    pub sql_type: String,
+}
+
+impl From<ColumnSettings> for FieldColumn {
+    fn from(settings: ColumnSettings) -> Self {
+        Self {
+            column: settings.column,
+            sql_type: settings.sql_type,
         }
     }
 }
@@ -39,8 +222,24 @@ pub struct Config {
     pub db_url: String,
     pub auto_restart: bool,
     pub postgres_tls: TlsSettings,
+    pub db_pool: PoolSettings,
     pub http_settings: HttpSettings,
+    pub event_limits: EventLimits,
+    /// Requests of a client over this limit are answered with 429, no limit if `None`
+    pub rate_limit: Option<RateLimit>,
+    pub default_events_limit: Option<i64>,
+    pub default_events_order: EventOrder,
     pub root_table_name: String,
+    pub counts_cache_ttl_sec: u64,
+    /// Used for unfiltered `/counts` requests in their interval instead of the root table
+    pub counts_rollups: Vec<CountsRollup>,
+    pub audit_table: Option<String>,
+    /// Queries that `@name` in requests expands to
+    pub query_aliases: Aliases,
+    /// Fields requests may use, all if `None`
+    pub queryable_fields: AllowedFields,
+    /// Fields read from typed columns of the root table instead of `doc`
+    pub field_columns: BTreeMap<String, ColumnSettings>,
 }
 
 impl Default for Config {
@@ -51,20 +250,223 @@ impl Default for Config {
                     .into(),
             auto_restart: false,
             postgres_tls: TlsSettings::default(),
+            db_pool: PoolSettings::default(),
             http_settings: HttpSettings::default(),
+            event_limits: EventLimits::default(),
+            rate_limit: None,
+            default_events_limit: None,
+            default_events_order: EventOrder::default(),
             root_table_name: "logs".into(),
+            counts_cache_ttl_sec: 0,
+            counts_rollups: Vec::new(),
+            audit_table: None,
+            query_aliases: Aliases::new(),
+            queryable_fields: None,
+            field_columns: BTreeMap::new(),
         }
     }
 }
 
 impl Config {
-    /// Load config using path specified in options
-    pub fn load(opts: &crate::Args) -> Result<Config, Box<dyn ::std::error::Error>> {
-        if let Some(path) = &opts.config_path {
+    /// `field_columns` as the query parsers take them
+    pub fn field_columns(&self) -> FieldColumns {
+        self.field_columns
+            .iter()
+            .map(|(field, column)| (field.clone(), column.clone().into()))
+            .collect()
+    }
+
+    /// Load config using path specified in options, with the unknown keys ignored if lenient
+    pub fn load(opts: &crate::Args) -> Result<(Config, Vec<String>), Box<dyn ::std::error::Error>> {
+        let (config, ignored) = if let Some(path) = &opts.config_path {
             let reader = File::open(path)?;
-            Ok(serde_yaml::from_reader(reader)?)
+            config::from_reader::<Config>(reader, opts.lenient_config)?
         } else {
-            Ok(Config::default())
+            (Config::default(), Vec::new())
+        };
+        config.validate()?;
+        Ok((config, ignored))
+    }
+
+    /// Reject settings that are accepted by the shared types but not supported here
+    fn validate(&self) -> Result<(), String> {
+        if !self.postgres_tls.enabled {
+            return Err(
+                "postgres_tls.enabled: false is not supported, stuffstream always uses TLS".into(),
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// Connections that count how often they are validated
+    struct Counting(Arc<AtomicUsize>);
+
+    #[async_trait::async_trait]
+    impl bb8::ManageConnection for Counting {
+        type Connection = ();
+        type Error = std::io::Error;
+
+        async fn connect(&self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        async fn is_valid(&self, _: &mut ()) -> Result<(), Self::Error> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+
+        fn has_broken(&self, _: &mut ()) -> bool {
+            false
+        }
+    }
+
+    async fn validations(settings: PoolSettings) -> usize {
+        let count = Arc::new(AtomicUsize::new(0));
+        let pool = settings
+            .builder()
+            .build(Counting(count.clone()))
+            .await
+            .unwrap();
+        for _ in 0..3 {
+            drop(pool.get().await.unwrap());
         }
+        count.load(Ordering::SeqCst)
+    }
+
+    #[tokio::test]
+    async fn checked_out_connections_are_validated() {
+        assert_eq!(validations(PoolSettings::default()).await, 3);
+
+        let settings = PoolSettings {
+            test_on_check_out: false,
+            ..Default::default()
+        };
+        assert_eq!(validations(settings).await, 0);
+    }
+
+    #[test]
+    fn event_limit_clamping() {
+        let limits = EventLimits {
+            max_events: 100,
+            ..Default::default()
+        };
+        assert_eq!(limits.clamp(None), 100);
+        assert_eq!(limits.clamp(Some(10)), 10);
+        assert_eq!(limits.clamp(Some(100)), 100);
+        assert_eq!(limits.clamp(Some(101)), 100);
+        assert_eq!(limits.clamp(Some(i64::MAX)), 100);
+        assert_eq!(limits.clamp(Some(0)), 0);
+        assert_eq!(limits.clamp(Some(-5)), 0);
+
+        let limits = EventLimits {
+            max_events: 0,
+            ..Default::default()
+        };
+        assert_eq!(limits.clamp(Some(10)), 0);
+
+        let negative = "event_limits:\n  max_events: -1\n";
+        assert!(config::from_reader::<Config>(negative.as_bytes(), false).is_err());
+        let negative = "event_limits:\n  max_document_size: -1\n";
+        assert!(config::from_reader::<Config>(negative.as_bytes(), false).is_err());
+    }
+
+    #[tokio::test]
+    async fn idle_connections_are_opened_up_front() {
+        let settings = PoolSettings {
+            min_idle: Some(2),
+            ..Default::default()
+        };
+        let pool = settings
+            .builder()
+            .build(Counting(Arc::new(AtomicUsize::new(0))))
+            .await
+            .unwrap();
+        assert_eq!(pool.state().idle_connections, 2);
+
+        let config: Config = serde_yaml::from_str("db_pool: {min_idle: 1}").unwrap();
+        assert_eq!(config.db_pool.min_idle, Some(1));
+        assert_eq!(config.db_pool.max_size, 3);
+    }
+
+    #[test]
+    fn queries_per_request_fit_into_the_pool() {
+        let settings = |max_size, max_queries_per_request| PoolSettings {
+            max_size,
+            max_queries_per_request,
+            ..Default::default()
+        };
+        assert_eq!(PoolSettings::default().queries_per_request(), 2);
+        assert_eq!(settings(2, None).queries_per_request(), 1);
+        assert_eq!(settings(10, Some(10)).queries_per_request(), 10);
+        assert_eq!(settings(10, Some(4)).queries_per_request(), 4);
+        assert_eq!(settings(2, Some(4)).queries_per_request(), 2);
+        assert_eq!(settings(2, Some(0)).queries_per_request(), 1);
+        assert_eq!(settings(0, None).queries_per_request(), 1);
+    }
+
+    #[test]
+    fn disabling_postgres_tls_is_rejected() {
+        assert!(Config::default().validate().is_ok());
+        let config: Config = serde_yaml::from_str("postgres_tls:\n  enabled: false\n").unwrap();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn lenient_load_ignores_unknown_keys() {
+        let yaml = "http_settings:\n  max_connections: 10\n  listen_adress: 0.0.0.0:80\n\
+                    rate_limit:\n  brust: 5\n";
+        assert!(config::from_reader::<Config>(yaml.as_bytes(), false).is_err());
+
+        let (config, ignored) = config::from_reader::<Config>(yaml.as_bytes(), true).unwrap();
+        assert_eq!(ignored, ["http_settings.listen_adress", "rate_limit.brust"]);
+        assert_eq!(config.http_settings.max_connections, 10);
+        assert_eq!(
+            config.http_settings.listen_address,
+            HttpSettings::default().listen_address
+        );
+    }
+    #[test]
+    fn typed_field_columns() {
+        let config: Config = serde_yaml::from_str(
+            "field_columns:\n  hostname: {column: host, type: text}\n  pid: {column: pid, type: integer}\n",
+        )
+        .unwrap();
+        let columns = config.field_columns();
+        assert_eq!(
+            columns["pid"],
+            FieldColumn {
+                column: "pid".into(),
+                sql_type: "integer".into()
+            }
+        );
+        assert_eq!(columns["hostname"].column, "host");
+        // the column's type is needed to compare with it
+        assert!(serde_yaml::from_str::<Config>("field_columns:\n  hostname: host\n").is_err());
+    }
+
+    #[test]
+    fn load_from_command_line() {
+        use clap::Parser;
+
+        let settings = concat!(env!("CARGO_MANIFEST_DIR"), "/settings.yaml");
+        let opts = crate::Args::try_parse_from(["stuffstream", "--config-file", settings]).unwrap();
+        assert!(Config::load(&opts).is_ok());
+
+        let (config, _) = Config::load(&opts).unwrap();
+        assert_eq!(config.field_columns(), FieldColumns::new());
+
+        let opts = crate::Args::try_parse_from(["stuffstream", "--lenient-config"]).unwrap();
+        let (config, _) = Config::load(&opts).unwrap();
+        assert_eq!(
+            config.http_settings.listen_address,
+            HttpSettings::default().listen_address
+        );
     }
 }
@@ -1,4 +1,3 @@
-use bb8_postgres::tokio_postgres::types::ToSql;
 use futures::lock::Mutex;
 use futures::stream;
 use futures::stream::StreamExt as _;
@@ -6,41 +5,117 @@ use futures::stream::TryStreamExt as _;
 use serde_derive::{Deserialize, Serialize};
 use serde_json::Value;
 use std::sync::Arc;
+use std::time::Instant;
 use time::OffsetDateTime;
 use warp::http;
+use warp::hyper::body::{Body, Bytes};
 
-use logstuff::serde::de::rfc3339;
+use logstuff::serde::de::rfc3339_or_epoch;
 use logstuff_query::{ExpressionParser, IdentifierParser};
 
-use crate::app::DBPool;
+use crate::app::Database;
 use crate::app::Error;
 use crate::app::MalformedQuery;
+use crate::audit::Audited;
+use crate::cache::TtlCache;
+use crate::cancel;
+use crate::config::CountsRollup;
+use crate::envelope;
+use crate::explain::{self, OwnedParam, Statement};
 use crate::interval::CountsInterval;
+use crate::metadata::Metadata;
 
 // const DEFAULT_SPLIT_BUCKETS: u16 = 5;
 
+pub(crate) type Cache = TtlCache<(String, Request), Bytes>;
+
 pub(crate) async fn handler(
     expr_parser: Arc<Mutex<ExpressionParser>>,
     id_parser: Arc<Mutex<IdentifierParser>>,
+    rollups: Arc<[CountsRollup]>,
+    cache: Option<Arc<Cache>>,
     table_name: String,
     params: Request,
-    db: DBPool,
+    db: Database,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let response = Response::new(expr_parser, id_parser, &table_name, db.clone());
-    Ok(http::Response::builder()
+    let cache_key = (table_name.clone(), params.snapped());
+    if let Some(body) = cache.as_ref().and_then(|c| c.get(&cache_key)) {
+        return Ok(reply(Body::from(body)));
+    }
+
+    let response =
+        Response::new(expr_parser, id_parser, &table_name, db.clone()).with_rollups(rollups);
+    let body = response.streams(params).await?;
+    if let Some(cache) = cache {
+        let body = body
+            .try_fold(Vec::new(), |mut buf, chunk| async move {
+                buf.extend_from_slice(&chunk.into());
+                Ok(buf)
+            })
+            .await
+            .map(Bytes::from)
+            .map_err(|err| {
+                error!("fetch counts: {}", err);
+                warp::reject::custom(CountsFailed)
+            })?;
+        cache.insert(cache_key, body.clone());
+        Ok(reply(Body::from(body)))
+    } else {
+        Ok(reply(Body::wrap_stream(body)))
+    }
+}
+
+fn reply(body: Body) -> http::Response<Body> {
+    http::Response::builder()
         .status(http::StatusCode::OK)
         .header("Content-Type", "application/json")
-        .body(warp::hyper::Body::wrap_stream(
-            response.streams(params).await,
-        ))
-        .unwrap())
+        .body(body)
+        .unwrap()
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Debug)]
+pub struct CountsFailed;
+
+impl warp::reject::Reject for CountsFailed {}
+
+pub(crate) async fn explain_handler(
+    expr_parser: Arc<Mutex<ExpressionParser>>,
+    id_parser: Arc<Mutex<IdentifierParser>>,
+    rollups: Arc<[CountsRollup]>,
+    table_name: String,
+    params: Request,
+    db: Database,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let response =
+        Response::new(expr_parser, id_parser, &table_name, db.clone()).with_rollups(rollups);
+    let statement = response
+        .statement(&params)
+        .await
+        .map_err(warp::reject::custom)?;
+    explain::handler(statement, db).await
+}
+
+pub(crate) async fn sql_handler(
+    expr_parser: Arc<Mutex<ExpressionParser>>,
+    id_parser: Arc<Mutex<IdentifierParser>>,
+    rollups: Arc<[CountsRollup]>,
+    table_name: String,
+    params: Request,
+    db: Database,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let response = Response::new(expr_parser, id_parser, &table_name, db).with_rollups(rollups);
+    let statement = response
+        .statement(&params)
+        .await
+        .map_err(warp::reject::custom)?;
+    Ok(explain::sql_handler(statement))
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Request {
-    #[serde(deserialize_with = "rfc3339")]
+    #[serde(deserialize_with = "rfc3339_or_epoch")]
     start: OffsetDateTime,
-    #[serde(deserialize_with = "rfc3339")]
+    #[serde(deserialize_with = "rfc3339_or_epoch")]
     end: OffsetDateTime,
     query: Option<String>,
     split_by: Option<String>,
@@ -48,19 +123,64 @@ pub struct Request {
     value: Option<String>,
     aggregate: Option<String>,
     missing_value_is_zero: Option<bool>,
+    /// Aggregate over the elements of array values, treating other values as single element arrays
+    value_is_array: Option<bool>,
+    /// Send buckets as Postgres returns them instead of aggregating them into one row first
+    stream_buckets: Option<bool>,
+    /// Bucket events by this field's time stamp instead of their own
+    time_field: Option<String>,
+}
+
+impl Audited for Request {
+    fn query(&self) -> Option<&str> {
+        self.query.as_deref()
+    }
+
+    fn time_range(&self) -> (OffsetDateTime, OffsetDateTime) {
+        (self.start, self.end)
+    }
 }
 
-type Param = (dyn ToSql + Sync);
+impl Request {
+    /// Copy with start and end rounded down to the counts interval
+    ///
+    /// Requests for a moving time range compare equal until a new interval begins.
+    fn snapped(&self) -> Self {
+        let seconds = CountsInterval::from(self.end - self.start).seconds as i64;
+        let snap = |t: OffsetDateTime| {
+            let ts = t.unix_timestamp();
+            OffsetDateTime::from_unix_timestamp(ts - ts.rem_euclid(seconds)).unwrap_or(t)
+        };
+        Self {
+            start: snap(self.start),
+            end: snap(self.end),
+            ..self.clone()
+        }
+    }
+}
 
 pub struct Response {
     expr_parser: Arc<Mutex<ExpressionParser>>,
     id_parser: Arc<Mutex<IdentifierParser>>,
     table: String,
-    db: DBPool,
+    rollups: Arc<[CountsRollup]>,
+    db: Database,
+}
+
+/// Join condition counting rows with the truncated time `log_time` in the bucket `gen_time`
+///
+/// Shared by the live and the rollup queries, so both put the same rows into a bucket.
+fn bucket_condition(interval: &CountsInterval) -> String {
+    format!(
+        "log_time between gen_time - '{}'::interval and gen_time",
+        interval.interval
+    )
 }
 
+#[allow(clippy::too_many_arguments)]
 fn split_counts_query(
     table: &str,
+    time: &str,
     split_by: &Option<String>,
     expr: &str,
     start_id: usize,
@@ -69,20 +189,30 @@ fn split_counts_query(
     max_buckets_id: usize,
     outer_value_getter: &str,
     inner_value_getter: &str,
+    value_source: &str,
+    per_bucket: bool,
 ) -> String {
     let (getter, split_subquery) = if let Some(split_by) = split_by {
         let getter = format!("coalesce({}, '(null)') as id", split_by);
         let query = format!(
             r#"
                 select {}, {}
-                from {}
+                from {}{}
                 where {}
-                and tstamp between ${} and ${}
+                and {} between ${} and ${}
                 group by 1
                 order by subvalue desc
                 limit ${}
             "#,
-            getter, inner_value_getter, table, expr, start_id, end_id, max_buckets_id
+            getter,
+            inner_value_getter,
+            table,
+            value_source,
+            expr,
+            time,
+            start_id,
+            end_id,
+            max_buckets_id
         );
         (getter, query)
     } else {
@@ -90,61 +220,174 @@ fn split_counts_query(
         let query = format!("select {} limit ${}", getter, max_buckets_id);
         (getter, query)
     };
-    format!(
+    let buckets = format!(
         r#"
-            select jsonb_object_agg(tstamp, points) as doc from (
                 select tstamp, jsonb_object_agg(id, value) as points from (
                     select date_trunc('{}', gen_time) as tstamp, series.id as id, {}
                     from (select gen_time, id from 
-                            generate_series(${}, ${}, '{}'::interval) gen_time,
+                            generate_series({}, ${}, '{}'::interval) gen_time,
                             ({}) split
                         ) series
-                    left join (select date_trunc('{}', tstamp) as log_time, {}, {}
-                            from {}
+                    left join (select date_trunc('{}', {}) as log_time, {}, {}
+                            from {}{}
                             where {}
-                            and tstamp between ${} and ${}
+                            and {} between ${} and ${}
                             group by log_time, 2
                         ) l
-                    on log_time between gen_time - '{}'::interval and gen_time
+                    on {}
                     and series.id = l.id
                     group by tstamp, series.id
                     order by tstamp, series.id
                 ) p
                 group by tstamp
-            ) c
         "#,
         &interval.truncate,
         outer_value_getter,
-        start_id,
+        interval.aligned(&format!("${}", start_id)),
         end_id,
         &interval.interval,
         split_subquery,
         &interval.truncate,
+        time,
         getter,
         inner_value_getter,
         table,
+        value_source,
+        expr,
+        time,
+        start_id,
+        end_id,
+        bucket_condition(interval)
+    );
+    if per_bucket {
+        format!(
+            "select tstamp::text as key, points from ({}) c order by c.tstamp",
+            buckets
+        )
+    } else {
+        format!(
+            "select jsonb_object_agg(tstamp, points) as doc from ({}) c",
+            buckets
+        )
+    }
+}
+
+/// Plain event counts in `table` matching `expr` per bucket of `interval`, as `/counts` has them
+///
+/// The time range is `$<start_id>` to `$<end_id>`, `$<max_buckets_id>` is not used without a
+/// split but must be given, e.g. as `NULL`.
+pub(crate) fn event_counts_query(
+    table: &str,
+    expr: &str,
+    start_id: usize,
+    end_id: usize,
+    interval: &CountsInterval,
+    max_buckets_id: usize,
+) -> String {
+    split_counts_query(
+        table,
+        "tstamp",
+        &None,
         expr,
         start_id,
         end_id,
-        &interval.interval
+        interval,
+        max_buckets_id,
+        "sum(coalesce(subvalue, 0)) as value",
+        "count(*) as subvalue",
+        "",
+        false,
     )
 }
 
+/// Counts per bucket summed up from the `rollup` table
+fn rollup_counts_query(
+    rollup: &CountsRollup,
+    start_id: usize,
+    end_id: usize,
+    interval: &CountsInterval,
+    per_bucket: bool,
+) -> String {
+    let buckets = format!(
+        r#"
+                select date_trunc('{}', gen_time) as tstamp,
+                    jsonb_build_object('value', coalesce(sum(r.count), 0)) as points
+                from generate_series({}, ${}, '{}'::interval) gen_time
+                left join (select date_trunc('{}', tstamp) as log_time, count from {}) r
+                on {}
+                group by gen_time
+        "#,
+        &interval.truncate,
+        interval.aligned(&format!("${}", start_id)),
+        end_id,
+        &interval.interval,
+        &interval.truncate,
+        rollup.table,
+        bucket_condition(interval)
+    );
+    if per_bucket {
+        format!(
+            "select tstamp::text as key, points from ({}) c order by c.tstamp",
+            buckets
+        )
+    } else {
+        format!(
+            "select jsonb_object_agg(tstamp, points) as doc from ({}) c",
+            buckets
+        )
+    }
+}
+
+/// JSON object members `"<key>":<points>`, one chunk per bucket row
+fn bucket_members<E>(
+    rows: impl stream::Stream<Item = Result<(String, Value), E>>,
+) -> impl stream::Stream<Item = Result<String, E>> {
+    rows.enumerate().map(|(i, row)| {
+        row.map(|(key, points)| {
+            let separator = if i == 0 { "" } else { "," };
+            format!("{}{}:{}", separator, Value::from(key), points)
+        })
+    })
+}
+
 impl Response {
     pub fn new(
         expr_parser: Arc<Mutex<ExpressionParser>>,
         id_parser: Arc<Mutex<IdentifierParser>>,
         table: &str,
-        db: DBPool,
+        db: Database,
     ) -> Self {
         Self {
             expr_parser,
             id_parser,
             table: table.to_owned(),
+            rollups: Arc::new([]),
             db,
         }
     }
 
+    pub fn with_rollups(mut self, rollups: Arc<[CountsRollup]>) -> Self {
+        self.rollups = rollups;
+        self
+    }
+
+    /// The rollup that can answer `params`, in buckets of `interval`
+    ///
+    /// Rollups only have plain counts by `tstamp`, so requests with a query, split, value or time
+    /// field need the root table.
+    fn rollup(&self, params: &Request, interval: &CountsInterval) -> Option<&CountsRollup> {
+        if params.query.is_some()
+            || params.split_by.is_some()
+            || params.value.is_some()
+            || params.time_field.is_some()
+        {
+            return None;
+        }
+        self.rollups
+            .iter()
+            .find(|rollup| rollup.interval == interval.interval)
+    }
+
     async fn parse_query(
         &self,
         query: &Option<String>,
@@ -152,7 +395,10 @@ impl Response {
     ) -> Result<(String, Vec<Value>), MalformedQuery> {
         let p = self.expr_parser.lock().await;
         let (query, query_params) = if let Some(query) = query {
-            p.to_sql(query, param_offset).map_err(|_| MalformedQuery)?
+            for hint in p.performance_hints(query).unwrap_or_default() {
+                debug!("{}: {}", hint, query);
+            }
+            p.to_sql(query, param_offset)?
         } else {
             ("1 = 1".into(), Vec::new())
         };
@@ -166,23 +412,66 @@ impl Response {
         param_offset: usize,
     ) -> Result<(String, Vec<Value>), MalformedQuery> {
         let p = self.id_parser.lock().await;
-        let (expr, params) = p.sql_string(id, param_offset).map_err(|_| MalformedQuery)?;
+        let (expr, params) = p.sql_string(id, param_offset)?;
+        drop(p);
+        Ok((expr, params))
+    }
+
+    async fn parse_timestamp_identifier(
+        &self,
+        id: &str,
+        param_offset: usize,
+    ) -> Result<(String, Vec<Value>), MalformedQuery> {
+        let p = self.id_parser.lock().await;
+        let (expr, params) = p.sql_timestamp(id, param_offset)?;
+        drop(p);
+        Ok((expr, params))
+    }
+
+    async fn parse_json_identifier(
+        &self,
+        id: &str,
+        param_offset: usize,
+    ) -> Result<(String, Vec<Value>), MalformedQuery> {
+        let p = self.id_parser.lock().await;
+        let (expr, params) = p.sql_json(id, param_offset)?;
         drop(p);
         Ok((expr, params))
     }
 
+    /// Outer and inner aggregate expressions, and what to add to the inner `from` clause
     async fn value_getters(
         &self,
         params: Request,
         param_offset: usize,
-    ) -> Result<(String, String, Vec<Value>), MalformedQuery> {
+    ) -> Result<(String, String, String, Vec<Value>), MalformedQuery> {
         if let Some(value) = params.value {
             if params.aggregate.is_none() {
-                return Err(MalformedQuery {}); // TODO query is not malformed, parameters don't make sense
+                return Err(MalformedQuery::Semantic("value needs an aggregate".into()));
             }
             let agg = params.aggregate.unwrap();
 
-            let (expr, query_params) = self.parse_identifier(&value, param_offset).await?;
+            let (expr, source, query_params) = if params.value_is_array.unwrap_or(false) {
+                let (expr, query_params) = self.parse_json_identifier(&value, param_offset).await?;
+                let source = format!(
+                    r#",
+                    jsonb_array_elements(
+                        case
+                            when jsonb_typeof({expr}) = 'array' then {expr}
+                            else jsonb_build_array({expr})
+                        end) as element"#,
+                    expr = expr
+                );
+                (
+                    "case when jsonb_typeof(element) = 'number' then (element #>> '{}')::numeric end"
+                        .to_string(),
+                    source,
+                    query_params,
+                )
+            } else {
+                let (expr, query_params) = self.parse_identifier(&value, param_offset).await?;
+                (expr, String::new(), query_params)
+            };
 
             let coalesce = params.missing_value_is_zero.unwrap_or(false);
             let outer = if coalesce {
@@ -191,46 +480,63 @@ impl Response {
                 format!("{}(subvalue) as value", agg)
             };
             let inner = format!("{}({}) as subvalue", agg, expr);
-            Ok((outer, inner, query_params))
+            Ok((outer, inner, source, query_params))
         } else {
             Ok((
                 "sum(coalesce(subvalue, 0)) as value".to_string(),
                 "count(*) as subvalue".to_string(),
+                String::new(),
                 Vec::new(),
             ))
         }
     }
 
-    pub async fn streams(
-        self,
-        params: Request,
-    ) -> impl futures::Stream<Item = Result<impl Into<warp::hyper::body::Bytes>, Error>> {
-        let params_clone = params.clone();
+    /// The counts query with its parameters, as run by `streams`
+    pub async fn statement(&self, params: &Request) -> Result<Statement, MalformedQuery> {
+        let interval = CountsInterval::from(params.end - params.start);
+        if let Some(rollup) = self.rollup(params, &interval) {
+            debug!("Counting from rollup {}", rollup.table);
+            return Ok(Statement {
+                query: rollup_counts_query(
+                    rollup,
+                    1,
+                    2,
+                    &interval,
+                    params.stream_buckets.unwrap_or(false),
+                ),
+                params: vec![Box::new(params.start), Box::new(params.end)],
+            });
+        }
 
-        let (expr, mut query_params) = self.parse_query(&params.query, 1).await.unwrap();
-        let getter = if let Some(split_by) = params.split_by {
+        let (expr, mut query_params) = self.parse_query(&params.query, 1).await?;
+        let getter = if let Some(split_by) = &params.split_by {
             let (getter, getter_params) = self
-                .parse_identifier(&split_by, query_params.len() + 1)
-                .await
-                .unwrap();
+                .parse_identifier(split_by, query_params.len() + 1)
+                .await?;
             query_params.extend(getter_params);
             Some(getter)
         } else {
             None
         };
 
-        let (outer_value_getter, inner_value_getter, value_params) = self
-            .value_getters(params_clone, query_params.len() + 1)
-            .await
-            .unwrap();
+        let (outer_value_getter, inner_value_getter, value_source, value_params) = self
+            .value_getters(params.clone(), query_params.len() + 1)
+            .await?;
         query_params.extend(value_params);
+        let time = if let Some(time_field) = &params.time_field {
+            let (time, time_params) = self
+                .parse_timestamp_identifier(time_field, query_params.len() + 1)
+                .await?;
+            query_params.extend(time_params);
+            time
+        } else {
+            "tstamp".to_string()
+        };
         let param_offset = query_params.len() + 1;
 
-        let db = self.db.get().await.unwrap();
-        let interval = CountsInterval::from(params.end - params.start);
-
         let query = split_counts_query(
             &self.table,
+            &time,
             &getter,
             &expr,
             param_offset,
@@ -239,35 +545,437 @@ impl Response {
             param_offset + 2,
             &outer_value_getter,
             &inner_value_getter,
+            &value_source,
+            params.stream_buckets.unwrap_or(false),
         );
-        let counts = db
-            .query_raw(
-                query.as_str(),
-                query_params
-                    .iter()
-                    .map(|e| e as &Param)
-                    .chain(std::iter::once::<&Param>(&params.start.to_owned()))
-                    .chain(std::iter::once::<&Param>(&params.end.to_owned()))
-                    .chain(std::iter::once::<&Param>(&params.max_buckets.to_owned()))
-                    .collect::<Vec<&Param>>(),
-            )
-            .await;
-
-        stream::once(async move {
-            Ok(format!(
-                r#"{{"metadata":{{"counts_interval_sec": {}}},"counts":"#,
-                interval.seconds
-            ))
+        let mut sql_params: Vec<OwnedParam> = query_params
+            .into_iter()
+            .map(|v| Box::new(v) as OwnedParam)
+            .collect();
+        sql_params.push(Box::new(params.start));
+        sql_params.push(Box::new(params.end));
+        sql_params.push(Box::new(params.max_buckets));
+        Ok(Statement {
+            query,
+            params: sql_params,
         })
-        .chain(
+    }
+
+    pub async fn streams(
+        self,
+        params: Request,
+    ) -> Result<
+        impl futures::Stream<Item = Result<impl Into<warp::hyper::body::Bytes>, Error>>,
+        warp::Rejection,
+    > {
+        let statement = self
+            .statement(&params)
+            .await
+            .map_err(warp::reject::custom)?;
+        let interval = CountsInterval::from(params.end - params.start);
+        let limit = params.max_buckets;
+
+        let started = Instant::now();
+        let counts = cancel::query_raw(&self.db, statement.query.as_str(), statement.sql_params())
+            .await
+            .map_err(|err| {
+                error!("fetch counts: {}", err);
+                warp::reject::custom(CountsFailed)
+            })?;
+
+        let counts = if params.stream_buckets.unwrap_or(false) {
+            let rows = counts.map_ok(|row| {
+                let points: Option<Value> = row.get("points");
+                (row.get("key"), points.unwrap_or(Value::Null))
+            });
+            stream::once(async { Ok("{".to_string()) })
+                .chain(bucket_members(rows))
+                .chain(stream::once(async { Ok("}".to_string()) }))
+                .map_err(Error::from)
+                .left_stream()
+        } else {
             counts
-                .unwrap()
                 .map_ok(|row| {
                     let value: Option<Value> = row.get("doc");
                     value.unwrap_or(Value::Null).to_string()
                 })
-                .map_err(Error::from),
+                .map_err(Error::from)
+                .right_stream()
+        };
+
+        let metadata =
+            stream::once(async move { Ok(Metadata::new(&interval, limit, started).to_string()) });
+        Ok(envelope::object(vec![
+            envelope::section("counts", counts),
+            envelope::section("metadata", metadata),
+        ]))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+    use time::macros::datetime;
+    use warp::Reply;
+
+    fn request() -> Request {
+        Request {
+            start: datetime!(2022-01-01 00:00 UTC),
+            end: datetime!(2022-01-02 00:00 UTC),
+            query: None,
+            split_by: None,
+            max_buckets: None,
+            value: None,
+            aggregate: None,
+            missing_value_is_zero: None,
+            value_is_array: None,
+            stream_buckets: None,
+            time_field: None,
+        }
+    }
+
+    fn response() -> Response {
+        Response::new(
+            Arc::new(Mutex::new(ExpressionParser::default())),
+            Arc::new(Mutex::new(IdentifierParser::default())),
+            "logs",
+            crate::app::unconnected_pool(),
         )
-        .chain(stream::once(async { Ok(r#"}"#.to_string()) }))
+    }
+
+    #[tokio::test]
+    async fn malformed_query_is_rejected() {
+        let mut params = request();
+        params.query = Some(r#"id = "#.into());
+        assert!(response().streams(params).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn malformed_identifiers_are_rejected() {
+        let mut params = request();
+        params.split_by = Some("0invalid".into());
+        assert!(response().streams(params).await.is_err());
+
+        let mut params = request();
+        params.value = Some("-invalid".into());
+        params.aggregate = Some("sum".into());
+        assert!(response().streams(params).await.is_err());
+
+        let mut params = request();
+        params.value = Some("valid".into());
+        assert!(response().streams(params).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn fields_outside_the_allowlist_are_rejected() {
+        let allowed = Some(["host".to_string()].into());
+        let response = Response::new(
+            Arc::new(Mutex::new(
+                ExpressionParser::default().with_allowed_fields(allowed.clone()),
+            )),
+            Arc::new(Mutex::new(
+                IdentifierParser::default().with_allowed_fields(allowed),
+            )),
+            "logs",
+            crate::app::unconnected_pool(),
+        );
+        let rejection = |result: Result<_, MalformedQuery>| match result {
+            Err(MalformedQuery::Semantic(reason)) => reason,
+            _ => panic!("not rejected"),
+        };
+
+        let mut params = request();
+        params.query = Some(r#"host = "a" and secret = 1"#.into());
+        assert_eq!(
+            rejection(response.statement(&params).await.map(|_| ())),
+            "field secret can't be queried"
+        );
+
+        let mut params = request();
+        params.split_by = Some("secret".into());
+        assert_eq!(
+            rejection(response.statement(&params).await.map(|_| ())),
+            "field secret can't be queried"
+        );
+
+        let mut params = request();
+        params.query = Some(r#"host = "a""#.into());
+        params.split_by = Some("host".into());
+        assert!(response.statement(&params).await.is_ok());
+    }
+
+    #[test]
+    fn snapped_requests() {
+        let mut a = request();
+        a.start = datetime!(2022-01-01 00:00:05 UTC);
+        a.end = datetime!(2022-01-01 00:05:05 UTC);
+        let mut b = request();
+        b.start = datetime!(2022-01-01 00:00:09 UTC);
+        b.end = datetime!(2022-01-01 00:05:09 UTC);
+        assert_eq!(a.snapped(), b.snapped());
+        assert_eq!(a.snapped().start, datetime!(2022-01-01 00:00:05 UTC));
+
+        b.start = datetime!(2022-01-01 00:00:10 UTC);
+        b.end = datetime!(2022-01-01 00:05:10 UTC);
+        assert_ne!(a.snapped(), b.snapped());
+    }
+
+    #[tokio::test]
+    async fn cached_response_skips_database() {
+        let cache = Arc::new(Cache::new(std::time::Duration::from_secs(60)));
+        let mut params = request();
+        params.start = datetime!(2022-01-01 00:10 UTC);
+        params.end = datetime!(2022-01-02 00:10 UTC);
+        cache.insert(("logs".into(), request().snapped()), "cached".into());
+
+        let reply = handler(
+            Arc::new(Mutex::new(ExpressionParser::default())),
+            Arc::new(Mutex::new(IdentifierParser::default())),
+            Arc::new([]),
+            Some(cache),
+            "logs".into(),
+            params,
+            crate::app::unconnected_pool(),
+        )
+        .await
+        .map_err(|_| ())
+        .unwrap();
+        let body = warp::hyper::body::to_bytes(reply.into_response().into_body())
+            .await
+            .unwrap();
+        assert_eq!(body, "cached");
+    }
+
+    #[tokio::test]
+    async fn buckets_are_streamed_individually() {
+        let rows = stream::iter(vec![
+            Ok::<_, ()>(("2022-01-01 00:00:00+00".to_string(), json!({"value": 1}))),
+            Ok(("2022-01-01 00:30:00+00".to_string(), json!({"value": 0}))),
+            Ok(("2022-01-01 01:00:00+00".to_string(), json!({"value": 7}))),
+        ]);
+        let chunks: Vec<String> = bucket_members(rows).try_collect().await.unwrap();
+        assert_eq!(chunks.len(), 3);
+        let counts: Value = serde_json::from_str(&format!("{{{}}}", chunks.concat())).unwrap();
+        assert_eq!(
+            counts,
+            json!({
+                "2022-01-01 00:00:00+00": {"value": 1},
+                "2022-01-01 00:30:00+00": {"value": 0},
+                "2022-01-01 01:00:00+00": {"value": 7},
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn per_bucket_statement() {
+        let mut params = request();
+        let statement = response().statement(&params).await.unwrap();
+        assert!(statement
+            .query
+            .starts_with("select jsonb_object_agg(tstamp, points) as doc from ("));
+
+        params.stream_buckets = Some(true);
+        let statement = response().statement(&params).await.unwrap();
+        assert!(statement
+            .query
+            .starts_with("select tstamp::text as key, points from ("));
+        assert!(statement.query.ends_with(") c order by c.tstamp"));
+    }
+
+    #[tokio::test]
+    async fn debug_sql_shows_counts_query() {
+        let params = request();
+        let statement = response().statement(&params).await.unwrap();
+        let reply = explain::sql_handler(statement).into_response();
+        let body = warp::hyper::body::to_bytes(reply.into_body())
+            .await
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let expected = split_counts_query(
+            "logs",
+            "tstamp",
+            &None,
+            "1 = 1",
+            1,
+            2,
+            &CountsInterval::from(params.end - params.start),
+            3,
+            "sum(coalesce(subvalue, 0)) as value",
+            "count(*) as subvalue",
+            "",
+            false,
+        );
+        assert_eq!(json["query"], expected);
+        assert_eq!(
+            json["params"],
+            json!(["2022-01-01T00:00:00Z", "2022-01-02T00:00:00Z", null])
+        );
+    }
+
+    #[tokio::test]
+    async fn statement_matches_parameters() {
+        let mut params = request();
+        params.query = Some(r#"host = "a""#.into());
+        params.split_by = Some("program".into());
+        let statement = response().statement(&params).await.unwrap();
+        assert_eq!(statement.params.len(), 6);
+        assert!(statement
+            .query
+            .contains(", $5, '30 minutes'::interval) gen_time"));
+        assert!(statement.query.contains("limit $6"));
+        assert!(explain::explain_query(&statement.query)
+            .starts_with("EXPLAIN (FORMAT JSON) select jsonb_object_agg("));
+    }
+
+    #[tokio::test]
+    async fn unaligned_start_is_aligned_to_buckets() {
+        let mut params = request();
+        params.start = datetime!(2022-01-01 00:07:13 UTC);
+        params.end = datetime!(2022-01-01 04:07:13 UTC);
+        let statement = response().statement(&params).await.unwrap();
+        assert!(statement.query.contains(
+            "generate_series(to_timestamp(floor(extract(epoch from $1::timestamptz) / 300) * 300), \
+             $2, '5 minutes'::interval) gen_time"
+        ));
+
+        params.end = datetime!(2022-03-01 00:00 UTC);
+        let statement = response().statement(&params).await.unwrap();
+        assert!(statement.query.contains(
+            "generate_series(date_trunc('day', $1::timestamptz), $2, '1 day'::interval)"
+        ));
+    }
+
+    #[tokio::test]
+    async fn rollups_answer_plain_counts_in_their_interval() {
+        let response = response().with_rollups(Arc::new([CountsRollup {
+            table: "logs_hourly".into(),
+            interval: "1 hour".into(),
+        }]));
+        let uses_rollup = |statement: Statement| statement.query.contains("logs_hourly");
+
+        let mut params = request();
+        params.end = datetime!(2022-01-04 00:00 UTC);
+        let statement = response.statement(&params).await.unwrap();
+        assert_eq!(statement.params.len(), 2);
+        assert!(statement
+            .query
+            .contains("/ 3600) * 3600), $2, '1 hour'::interval) gen_time"));
+        assert!(uses_rollup(statement));
+
+        params.stream_buckets = Some(true);
+        let statement = response.statement(&params).await.unwrap();
+        assert!(statement
+            .query
+            .starts_with("select tstamp::text as key, points from ("));
+        assert!(uses_rollup(statement));
+
+        // other intervals
+        assert!(!uses_rollup(response.statement(&request()).await.unwrap()));
+
+        // anything but plain counts
+        let mut filtered = params.clone();
+        filtered.query = Some(r#"host = "a""#.into());
+        assert!(!uses_rollup(response.statement(&filtered).await.unwrap()));
+        let mut split = params.clone();
+        split.split_by = Some("host".into());
+        assert!(!uses_rollup(response.statement(&split).await.unwrap()));
+        let mut value = params;
+        value.value = Some("duration".into());
+        value.aggregate = Some("sum".into());
+        assert!(!uses_rollup(response.statement(&value).await.unwrap()));
+    }
+
+    #[tokio::test]
+    async fn rollups_use_the_live_bucket_boundaries() {
+        let with_rollup = response().with_rollups(Arc::new([CountsRollup {
+            table: "logs_hourly".into(),
+            interval: "1 hour".into(),
+        }]));
+        let mut params = request();
+        params.end = datetime!(2022-01-04 00:00 UTC);
+        let from_rollup = with_rollup.statement(&params).await.unwrap().query;
+        let live = response().statement(&params).await.unwrap().query;
+        assert!(from_rollup.contains("logs_hourly"));
+        assert!(!live.contains("logs_hourly"));
+
+        // rows are truncated alike, then joined to their bucket alike
+        let boundaries = |query: &str| {
+            let truncate = query.find("date_trunc('hour', tstamp) as log_time");
+            let join = query
+                .lines()
+                .map(str::trim)
+                .find(|line| line.starts_with("on log_time"))
+                .map(str::to_string);
+            (truncate.is_some(), join)
+        };
+        assert_eq!(
+            boundaries(&live),
+            (
+                true,
+                Some("on log_time between gen_time - '1 hour'::interval and gen_time".into())
+            )
+        );
+        assert_eq!(boundaries(&from_rollup), boundaries(&live));
+    }
+
+    #[tokio::test]
+    async fn buckets_by_document_time() {
+        let mut params = request();
+        params.query = Some(r#"host = "a""#.into());
+        params.split_by = Some("program".into());
+        params.time_field = Some("event_time".into());
+        let statement = response().statement(&params).await.unwrap();
+        let time = "to_timestamp_or_null(doc ->> ($4::jsonb #>> '{}'))";
+        assert_eq!(statement.params.len(), 7);
+        assert!(statement.query.contains(&format!(
+            "select date_trunc('minute', {}) as log_time",
+            time
+        )));
+        assert_eq!(
+            statement
+                .query
+                .matches(&format!("and {} between $5 and $6", time))
+                .count(),
+            2
+        );
+        assert!(!statement.query.contains("and tstamp between"));
+        assert!(statement.query.contains("limit $7"));
+        let reply = explain::sql_handler(statement).into_response();
+        let body = warp::hyper::body::to_bytes(reply.into_body())
+            .await
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["params"][3], "event_time");
+
+        params.time_field = Some("0invalid".into());
+        assert!(response().statement(&params).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn sum_over_array_values() {
+        let mut params = request();
+        params.value = Some("durations".into());
+        params.aggregate = Some("sum".into());
+        let statement = response().statement(&params).await.unwrap();
+        assert!(!statement.query.contains("jsonb_array_elements"));
+        assert!(statement
+            .query
+            .contains("sum(doc ->> ($1::jsonb #>> '{}')) as subvalue"));
+
+        params.value_is_array = Some(true);
+        let statement = response().statement(&params).await.unwrap();
+        assert_eq!(statement.params.len(), 4);
+        assert_eq!(
+            statement
+                .query
+                .matches("when jsonb_typeof(doc -> ($1::jsonb #>> '{}')) = 'array' then doc -> ($1::jsonb #>> '{}')")
+                .count(),
+            1
+        );
+        assert!(statement.query.contains(
+            "sum(case when jsonb_typeof(element) = 'number' then (element #>> '{}')::numeric end) as subvalue"
+        ));
+        assert!(statement.query.contains("from logs,\n"));
     }
 }
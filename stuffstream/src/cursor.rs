@@ -0,0 +1,140 @@
+//! Keyset pagination for `/events`
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt;
+use std::str::FromStr;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use crate::config::EventOrder;
+
+/// Position of the last event of a page, the next page starts with the event following it
+///
+/// Written as `<microseconds since the epoch>_<id>`, which needs no escaping in URLs.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct Cursor {
+    pub tstamp: OffsetDateTime,
+    pub id: i64,
+}
+
+impl Cursor {
+    /// SQL condition for events after the cursor when ordered by `tstamp, id` in `order`
+    pub fn condition(order: EventOrder, tstamp_id: usize, id_id: usize) -> String {
+        let comparison = match order {
+            EventOrder::Desc => "<",
+            EventOrder::Asc => ">",
+        };
+        format!(
+            "(tstamp, id) {} (${}, ${}::bigint)",
+            comparison, tstamp_id, id_id
+        )
+    }
+
+    /// Cursor after a page of `events` events, `last` being the position of the last one
+    ///
+    /// `None` if the page has less than `limit` events, there are no more then.
+    pub fn after(events: i64, last: Option<Self>, limit: i64) -> Option<Self> {
+        if limit <= 0 || events < limit {
+            return None;
+        }
+        last
+    }
+
+    /// Position of `event`, an element of the `/events` document
+    pub fn of_event(event: &Value) -> Option<Self> {
+        Some(Self {
+            tstamp: OffsetDateTime::parse(event["timestamp"].as_str()?, &Rfc3339).ok()?,
+            id: event["id"].as_i64()?,
+        })
+    }
+}
+
+impl fmt::Display for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}_{}",
+            self.tstamp.unix_timestamp_nanos() / 1000,
+            self.id
+        )
+    }
+}
+
+impl FromStr for Cursor {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid cursor: {}", s);
+        let (micros, id) = s.split_once('_').ok_or_else(invalid)?;
+        let micros: i128 = micros.parse().map_err(|_| invalid())?;
+        Ok(Self {
+            tstamp: OffsetDateTime::from_unix_timestamp_nanos(micros * 1000)
+                .map_err(|_| invalid())?,
+            id: id.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+impl TryFrom<String> for Cursor {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Cursor> for String {
+    fn from(cursor: Cursor) -> Self {
+        cursor.to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use time::macros::datetime;
+
+    #[test]
+    fn round_trip() {
+        let cursor = Cursor {
+            tstamp: datetime!(2022-01-01 00:00:00.123456 UTC),
+            id: 42,
+        };
+        assert_eq!(cursor.to_string(), "1640995200123456_42");
+        assert_eq!("1640995200123456_42".parse::<Cursor>().unwrap(), cursor);
+        for invalid in ["", "1640995200123456", "x_1", "1_x", "1_2_3"] {
+            assert!(invalid.parse::<Cursor>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn keyset_condition() {
+        assert_eq!(
+            Cursor::condition(EventOrder::Desc, 4, 5),
+            "(tstamp, id) < ($4, $5::bigint)"
+        );
+        assert_eq!(
+            Cursor::condition(EventOrder::Asc, 4, 5),
+            "(tstamp, id) > ($4, $5::bigint)"
+        );
+    }
+
+    #[test]
+    fn next_page() {
+        let last = Cursor::of_event(&serde_json::json!(
+            {"timestamp": "2022-01-01T09:00:00+00:00", "id": 3, "source": {}}
+        ));
+        assert_eq!(
+            last,
+            Some(Cursor {
+                tstamp: datetime!(2022-01-01 09:00 UTC),
+                id: 3
+            })
+        );
+        assert_eq!(Cursor::after(2, last, 2), last);
+        assert_eq!(Cursor::after(2, last, 3), None);
+        assert_eq!(Cursor::after(0, None, 2), None);
+        assert_eq!(Cursor::after(0, None, 0), None);
+        assert_eq!(Cursor::of_event(&serde_json::json!({"id": 3})), None);
+    }
+}
@@ -0,0 +1,118 @@
+//! JSON objects streamed member by member
+use futures::future::ready;
+use futures::stream::{self, BoxStream, Stream, StreamExt as _};
+use serde_json::Value;
+
+/// Name of an object member and the chunks of JSON text making up its value
+pub type Section<'a, E> = (&'static str, BoxStream<'a, Result<String, E>>);
+
+/// Section `name` with the value sent as `chunks`
+pub fn section<'a, E>(
+    name: &'static str,
+    chunks: impl Stream<Item = Result<String, E>> + Send + 'a,
+) -> Section<'a, E> {
+    (name, chunks.fuse().boxed())
+}
+
+/// Section `name` with the complete JSON text `value`
+pub fn value<'a, E: Send + 'a>(name: &'static str, value: String) -> Section<'a, E> {
+    section(name, stream::once(ready(Ok(value))))
+}
+
+/// `prefix` followed by `chunks`, `null` if there are none
+fn member<'a, E: 'a>(
+    prefix: String,
+    chunks: BoxStream<'a, Result<String, E>>,
+) -> impl Stream<Item = Result<String, E>> + 'a {
+    let value = stream::unfold((chunks, true), |(mut chunks, empty)| async move {
+        match chunks.next().await {
+            Some(chunk) => Some((chunk, (chunks, false))),
+            None if empty => Some((Ok("null".to_string()), (chunks, false))),
+            None => None,
+        }
+    });
+    stream::once(ready(Ok(prefix))).chain(value)
+}
+
+/// The JSON object with a member for each of `sections`, in their order
+pub fn object<'a, E: 'a>(
+    sections: Vec<Section<'a, E>>,
+) -> impl Stream<Item = Result<String, E>> + 'a {
+    let members = stream::iter(sections.into_iter().enumerate()).flat_map(|(i, (name, chunks))| {
+        let separator = if i == 0 { "" } else { "," };
+        member(format!("{}{}:", separator, Value::from(name)), chunks)
+    });
+    stream::once(ready(Ok("{".to_string())))
+        .chain(members)
+        .chain(stream::once(ready(Ok("}".to_string()))))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::TryStreamExt as _;
+    use serde_json::json;
+
+    async fn text(sections: Vec<Section<'static, ()>>) -> String {
+        let chunks: Vec<String> = object(sections).try_collect().await.unwrap();
+        chunks.concat()
+    }
+
+    fn chunked(chunks: &[&str]) -> BoxStream<'static, Result<String, ()>> {
+        let chunks: Vec<_> = chunks.iter().map(|c| Ok(c.to_string())).collect();
+        stream::iter(chunks).boxed()
+    }
+
+    #[tokio::test]
+    async fn sections_are_members() {
+        let one = text(vec![value("counts", "[1,2]".into())]).await;
+        assert_eq!(one, r#"{"counts":[1,2]}"#);
+
+        let two = text(vec![
+            section("counts", chunked(&["{", r#""a":1"#, "}"])),
+            value("metadata", "{}".into()),
+        ])
+        .await;
+        assert_eq!(
+            serde_json::from_str::<Value>(&two).unwrap(),
+            json!({"counts": {"a": 1}, "metadata": {}})
+        );
+
+        let four = text(vec![
+            value("events", "[]".into()),
+            value("next_cursor", "null".into()),
+            section("fields", chunked(&["[", "\"a\"", ",\"b\"", "]"])),
+            value("metadata", r#"{"x":1}"#.into()),
+        ])
+        .await;
+        assert_eq!(
+            serde_json::from_str::<Value>(&four).unwrap(),
+            json!({"events": [], "next_cursor": null, "fields": ["a", "b"], "metadata": {"x": 1}})
+        );
+    }
+
+    #[tokio::test]
+    async fn empty_sections_are_null() {
+        assert_eq!(text(Vec::new()).await, "{}");
+        let text = text(vec![
+            section("counts", chunked(&[])),
+            value("metadata", "{}".into()),
+        ])
+        .await;
+        assert_eq!(
+            serde_json::from_str::<Value>(&text).unwrap(),
+            json!({"counts": null, "metadata": {}})
+        );
+    }
+
+    #[tokio::test]
+    async fn errors_are_passed_on() {
+        let chunks: Vec<Result<String, ()>> = object(vec![section(
+            "counts",
+            stream::iter(vec![Ok("[".to_string()), Err(())]),
+        )])
+        .collect()
+        .await;
+        assert!(chunks.contains(&Err(())));
+    }
+}
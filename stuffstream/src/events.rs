@@ -1,59 +1,220 @@
 use bb8_postgres::tokio_postgres;
 use bb8_postgres::tokio_postgres::types::ToSql;
+use futures::future::ready;
 use futures::lock::Mutex;
-use futures::stream;
-use futures::{StreamExt, TryStreamExt};
+use futures::stream::{self, BoxStream, StreamExt as _};
+use futures::TryStreamExt;
 use serde_derive::{Deserialize, Serialize};
 use serde_json::Value;
+use std::future::Future;
 use std::iter::Iterator;
 use std::sync::Arc;
+use std::time::Instant;
 use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
 use warp::http;
 
-use logstuff::serde::de::rfc3339;
-use logstuff_query::ExpressionParser;
+use logstuff::event::{flattened, FTS_FIELDS};
+use logstuff::serde::de::rfc3339_or_epoch;
+use logstuff_query::{ExpressionParser, TsQuery};
 
-use crate::app::DBPool;
+use crate::app::Database;
 use crate::app::Error;
 use crate::app::MalformedQuery;
+use crate::audit::Audited;
+use crate::cancel;
+use crate::config::{EventDefaults, EventLimits, EventOrder, PoolSettings};
+use crate::counts;
+use crate::cursor::Cursor;
+use crate::envelope;
+use crate::explain::{self, OwnedParam, Statement};
 use crate::interval::CountsInterval;
+use crate::metadata::Metadata;
 
-type Param = (dyn ToSql + Sync);
+type Param = dyn ToSql + Sync;
 
 pub(crate) async fn handler(
     parser: Arc<Mutex<ExpressionParser>>,
     table_name: String,
+    limits: EventLimits,
+    defaults: EventDefaults,
+    max_queries: usize,
     params: Request,
-    db: DBPool,
+    db: Database,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let response = Response::new(parser, &table_name, db.clone());
+    let response = Response::new(parser, &table_name, db.clone())
+        .with_limits(limits)
+        .with_defaults(defaults)
+        .with_max_queries(max_queries);
+    let body = response
+        .streams(params)
+        .await
+        .map_err(warp::reject::custom)?;
     Ok(http::Response::builder()
         .status(http::StatusCode::OK)
         .header("Content-Type", "application/json")
-        .body(warp::hyper::Body::wrap_stream(
-            response.streams(params).await,
-        ))
+        .body(warp::hyper::Body::wrap_stream(body))
         .unwrap())
 }
 
+pub(crate) async fn explain_handler(
+    parser: Arc<Mutex<ExpressionParser>>,
+    table_name: String,
+    limits: EventLimits,
+    defaults: EventDefaults,
+    params: Request,
+    db: Database,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let response = Response::new(parser, &table_name, db.clone())
+        .with_limits(limits)
+        .with_defaults(defaults);
+    let statement = response
+        .statement(&params)
+        .await
+        .map_err(warp::reject::custom)?;
+    explain::handler(statement, db).await
+}
+
+pub(crate) async fn sql_handler(
+    parser: Arc<Mutex<ExpressionParser>>,
+    table_name: String,
+    limits: EventLimits,
+    defaults: EventDefaults,
+    params: Request,
+    db: Database,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let response = Response::new(parser, &table_name, db)
+        .with_limits(limits)
+        .with_defaults(defaults);
+    let statement = response
+        .statement(&params)
+        .await
+        .map_err(warp::reject::custom)?;
+    Ok(explain::sql_handler(statement))
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Request {
-    #[serde(deserialize_with = "rfc3339")]
+    #[serde(deserialize_with = "rfc3339_or_epoch")]
     start: OffsetDateTime,
-    #[serde(deserialize_with = "rfc3339")]
+    #[serde(deserialize_with = "rfc3339_or_epoch")]
     end: OffsetDateTime,
     query: Option<String>,
     limit_events: Option<i64>,
+    /// `asc` for the oldest events first, `desc` for the newest
+    order: Option<EventOrder>,
+    highlight: Option<bool>,
+    /// Include the full text search rank of each event as `score`
+    score: Option<bool>,
+    /// `time` (default) or `score`, the rank against the query's full text search terms
+    ///
+    /// Events ordered by score have no `next_cursor`.
+    order_by: Option<OrderBy>,
+    /// Flatten nested event documents to dotted keys
+    flatten: Option<bool>,
+    /// Only events following this in the requested order, the `next_cursor` of the previous page
+    before: Option<Cursor>,
+    /// Sections of the response to compute, `events,fields,metadata` if not given
+    include: Option<Sections>,
+}
+
+/// What `/events` orders by, in the direction of `order`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum OrderBy {
+    #[default]
+    Time,
+    Score,
+}
+
+/// Selected members of the response, parsed from a comma separated list like `fields,metadata`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct Sections {
+    pub events: bool,
+    pub fields: bool,
+    /// Event counts per interval like `/counts` has them, only if asked for
+    pub counts: bool,
+    pub metadata: bool,
+}
+
+impl Default for Sections {
+    fn default() -> Self {
+        Self {
+            events: true,
+            fields: true,
+            counts: false,
+            metadata: true,
+        }
+    }
+}
+
+impl TryFrom<String> for Sections {
+    type Error = String;
+
+    fn try_from(list: String) -> Result<Self, Self::Error> {
+        let mut sections = Self {
+            events: false,
+            fields: false,
+            counts: false,
+            metadata: false,
+        };
+        for name in list
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+        {
+            match name {
+                "events" => sections.events = true,
+                "fields" => sections.fields = true,
+                "counts" => sections.counts = true,
+                "metadata" => sections.metadata = true,
+                _ => return Err(format!("unknown section '{}'", name)),
+            }
+        }
+        Ok(sections)
+    }
+}
+
+impl From<Sections> for String {
+    fn from(sections: Sections) -> Self {
+        let names = [
+            (sections.events, "events"),
+            (sections.fields, "fields"),
+            (sections.counts, "counts"),
+            (sections.metadata, "metadata"),
+        ];
+        let selected: Vec<&str> = names
+            .iter()
+            .filter(|(selected, _)| *selected)
+            .map(|(_, name)| *name)
+            .collect();
+        selected.join(",")
+    }
+}
+
+impl Audited for Request {
+    fn query(&self) -> Option<&str> {
+        self.query.as_deref()
+    }
+
+    fn time_range(&self) -> (OffsetDateTime, OffsetDateTime) {
+        (self.start, self.end)
+    }
 }
 
 pub struct Response {
     parser: Arc<Mutex<ExpressionParser>>,
     table: String,
-    db: DBPool,
+    db: Database,
+    limits: EventLimits,
+    defaults: EventDefaults,
+    /// sections queried at the same time
+    max_queries: usize,
 }
 
 fn fetch_doc(
-    rows: tokio_postgres::RowStream,
+    rows: impl stream::Stream<Item = Result<tokio_postgres::Row, tokio_postgres::Error>>,
 ) -> impl stream::Stream<Item = Result<String, tokio_postgres::Error>> {
     rows.map_ok(|row| {
         let value: Option<Value> = row.get("doc");
@@ -61,25 +222,92 @@ fn fetch_doc(
     })
 }
 
+/// Text of the full text search fields, for `ts_headline`
+fn headline_document() -> String {
+    let fields: Vec<String> = FTS_FIELDS
+        .iter()
+        .map(|field| format!("doc ->> '{}'", field))
+        .collect();
+    format!("concat_ws(' ', {})", fields.join(", "))
+}
+
+/// `doc`, or a placeholder telling its size if its JSON text is longer than `max_size`
+fn guarded_doc(max_size: u64) -> String {
+    format!(
+        "case when octet_length(doc::text) > {} \
+         then jsonb_build_object('truncated', true, 'size', octet_length(doc::text)) \
+         else doc end",
+        max_size
+    )
+}
+
+/// Full text search terms in the parameters `terms` and what the events query does with them
+#[derive(Debug, Clone, Default)]
+struct FullText {
+    /// parameter and kind of tsquery of each term
+    terms: Vec<(usize, TsQuery)>,
+    highlight: bool,
+    score: bool,
+    order_by_score: bool,
+}
+
+#[allow(clippy::too_many_arguments)]
 fn events_query(
     table: &str,
     expr: &str,
     start_id: usize,
     end_id: usize,
     limit_id: usize,
+    full_text: Option<FullText>,
+    max_document_size: u64,
+    order: EventOrder,
 ) -> String {
+    let mut members = String::new();
+    let mut rank_order = String::new();
+    if let Some(full_text) = full_text {
+        let tsqueries: Vec<String> = full_text
+            .terms
+            .iter()
+            .map(|(id, tsquery)| format!("{}(${}::jsonb #>> '{{}}')", tsquery.sql_function(), id))
+            .collect();
+        let tsquery = match tsqueries.as_slice() {
+            [tsquery] => tsquery.to_owned(),
+            _ => format!("({})", tsqueries.join(" || ")),
+        };
+        let rank = format!("ts_rank(search, {})", tsquery);
+        if full_text.highlight {
+            members += &format!(
+                ", 'highlight', ts_headline({}, {})",
+                headline_document(),
+                tsquery
+            );
+        }
+        if full_text.score {
+            members += &format!(", 'score', {}", rank);
+        }
+        if full_text.order_by_score {
+            rank_order = format!("{} {}, ", rank, order.sql());
+        }
+    }
     format!(
         r#"
-            select jsonb_agg(doc) as doc from (
-                select jsonb_build_object('timestamp', tstamp, 'id', id, 'source', doc) as doc
-                from {}
-                where {}
-                and tstamp between ${} and ${}
-                order by tstamp desc
-                limit ${}
-            ) e
+            select jsonb_build_object('timestamp', tstamp, 'id', id, 'source', {}{}) as doc
+            from {}
+            where {}
+            and tstamp between ${} and ${}
+            order by {}tstamp {}, id {}
+            limit ${}
         "#,
-        table, expr, start_id, end_id, limit_id,
+        guarded_doc(max_document_size),
+        members,
+        table,
+        expr,
+        start_id,
+        end_id,
+        rank_order,
+        order.sql(),
+        order.sql(),
+        limit_id,
     )
 }
 
@@ -119,131 +347,308 @@ fn fields_query(table: &str, expr: &str, start_id: usize, end_id: usize) -> Stri
 }
 
 fn metadata_query(table: &str, start: &OffsetDateTime, end: &OffsetDateTime) -> String {
-    let interval = CountsInterval::from(*end - *start);
     format!(
         r#"
-            select jsonb_object_agg(key, value) as doc from (
-                select 'event_count' as key, count_estimate('select * from {} where tstamp between ''{}'' and ''{}''') as value
-                union
-                select 'counts_interval_sec' as key, {} as value
-            ) m
+            select jsonb_build_object(
+                'event_count', count_estimate('select * from {} where tstamp between ''{}'' and ''{}''')
+            ) as doc
         "#,
         table,
         start.format(&Rfc3339).unwrap(),
         end.format(&Rfc3339).unwrap(),
-        &interval.seconds
     )
 }
 
+/// Text of the document in `rows`, `null` if there are none
+async fn collect_doc(
+    rows: impl stream::Stream<Item = Result<tokio_postgres::Row, tokio_postgres::Error>>,
+) -> Result<String, tokio_postgres::Error> {
+    let docs: Vec<String> = fetch_doc(rows).try_collect().await?;
+    let doc = docs.concat();
+    Ok(if doc.is_empty() { "null".into() } else { doc })
+}
+
 async fn metadata(
-    db: DBPool,
+    db: Database,
     table: Arc<String>,
     start: &OffsetDateTime,
     end: &OffsetDateTime,
-) -> impl stream::Stream<Item = Result<String, Error>> {
-    let db = db.get().await.unwrap();
+) -> Result<String, Error> {
     let empty_params: Vec<&str> = Vec::new();
-    fetch_doc(
-        db.query_raw(
-            metadata_query(table.as_ref(), start, end).as_str(),
-            empty_params,
-        )
-        .await
-        .unwrap(),
+    let rows = cancel::query_raw(
+        &db,
+        metadata_query(table.as_ref(), start, end).as_str(),
+        empty_params,
     )
-    .map_err(|err| {
-        error!("fetch metadata: {:?}", err);
-        Error::from(err)
-    })
+    .await?;
+    Ok(collect_doc(rows).await?)
 }
 
 async fn fields(
-    db: DBPool,
+    db: Database,
     table: Arc<String>,
     expr: Arc<String>,
     params: Arc<Vec<Value>>,
     start: &OffsetDateTime,
     end: &OffsetDateTime,
-) -> impl stream::Stream<Item = Result<String, Error>> {
-    let db = db.get().await.unwrap();
-    fetch_doc(
-        db.query_raw(
-            fields_query(
-                table.as_ref(),
-                expr.as_ref(),
-                params.len() + 1,
-                params.len() + 2,
-            )
-            .as_str(),
-            params
-                .iter()
-                .map(|e| e as &Param)
-                .chain(std::iter::once::<&Param>(&start.to_owned()))
-                .chain(std::iter::once::<&Param>(&end.to_owned()))
-                .collect::<Vec<&Param>>(),
+) -> Result<String, Error> {
+    let rows = cancel::query_raw(
+        &db,
+        fields_query(
+            table.as_ref(),
+            expr.as_ref(),
+            params.len() + 1,
+            params.len() + 2,
         )
-        .await
-        .unwrap(),
+        .as_str(),
+        params
+            .iter()
+            .map(|e| e as &Param)
+            .chain(std::iter::once::<&Param>(&start.to_owned()))
+            .chain(std::iter::once::<&Param>(&end.to_owned()))
+            .collect::<Vec<&Param>>(),
     )
-    .map_err(|err| {
-        error!("fetch fields: {:?}", err);
-        Error::from(err)
-    })
+    .await?;
+    Ok(collect_doc(rows).await?)
 }
 
-async fn events(
-    db: DBPool,
+async fn counts(
+    db: Database,
     table: Arc<String>,
     expr: Arc<String>,
     params: Arc<Vec<Value>>,
     start: &OffsetDateTime,
     end: &OffsetDateTime,
-    limit: &Option<i64>,
-) -> impl stream::Stream<Item = Result<String, Error>> {
-    let db = db.get().await.unwrap();
-    fetch_doc(
-        db.query_raw(
-            events_query(
-                table.as_ref(),
-                expr.as_ref(),
-                params.len() + 1,
-                params.len() + 2,
-                params.len() + 3,
-            )
-            .as_str(),
-            params
-                .iter()
-                .map(|e| e as &Param)
-                .chain(std::iter::once::<&Param>(&start.to_owned()))
-                .chain(std::iter::once::<&Param>(&end.to_owned()))
-                .chain(std::iter::once::<&Param>(&limit.to_owned()))
-                .collect::<Vec<&Param>>(),
+) -> Result<String, Error> {
+    let interval = CountsInterval::from(*end - *start);
+    let no_limit: Option<i64> = None;
+    let rows = cancel::query_raw(
+        &db,
+        counts::event_counts_query(
+            table.as_ref(),
+            expr.as_ref(),
+            params.len() + 1,
+            params.len() + 2,
+            &interval,
+            params.len() + 3,
         )
-        .await
-        .unwrap(),
+        .as_str(),
+        params
+            .iter()
+            .map(|e| e as &Param)
+            .chain(std::iter::once::<&Param>(&start.to_owned()))
+            .chain(std::iter::once::<&Param>(&end.to_owned()))
+            .chain(std::iter::once::<&Param>(&no_limit))
+            .collect::<Vec<&Param>>(),
     )
-    .map_err(|err| {
-        error!("fetch events: {:?}", err);
-        Error::from(err)
+    .await?;
+    Ok(collect_doc(rows).await?)
+}
+
+/// Rows of the events query, one event each
+async fn events(
+    db: Database,
+    statement: Statement,
+) -> Result<cancel::CancelOnDrop<tokio_postgres::RowStream, cancel::Connection>, Error> {
+    cancel::query_raw(&db, statement.query.as_str(), statement.sql_params()).await
+}
+
+/// Document of each event in `rows`
+fn event_docs(
+    rows: impl stream::Stream<Item = Result<tokio_postgres::Row, tokio_postgres::Error>>,
+) -> impl stream::Stream<Item = Result<Value, Error>> {
+    rows.map(|row| match row {
+        Ok(row) => {
+            let doc: Option<Value> = row.get("doc");
+            Ok(doc.unwrap_or(Value::Null))
+        }
+        Err(err) => {
+            error!("fetch events: {}", err);
+            Err(Error::from(err))
+        }
     })
 }
 
+/// Events streamed so far and the position of the last one, for the cursor after the page
+#[derive(Debug, Default)]
+struct Page {
+    events: i64,
+    last: Option<Cursor>,
+}
+
+/// The events array, one chunk per event of `docs`, each `flatten`ed if requested
+///
+/// Events are recorded in `page` as they pass. An empty page has no chunks and so is `null`.
+/// `permit` is kept until the last event was read.
+fn event_chunks(
+    docs: impl stream::Stream<Item = Result<Value, Error>> + Send + 'static,
+    flatten: bool,
+    page: Arc<std::sync::Mutex<Page>>,
+    permit: OwnedSemaphorePermit,
+) -> impl stream::Stream<Item = Result<String, Error>> + Send + 'static {
+    let end = page.clone();
+    let events = docs.map_ok(move |mut event| {
+        let mut page = page.lock().unwrap();
+        page.events += 1;
+        page.last = Cursor::of_event(&event);
+        if flatten {
+            flatten_source(&mut event);
+        }
+        let separator = if page.events == 1 { "[" } else { "," };
+        format!("{}{}", separator, event)
+    });
+    let close = stream::once(async move {
+        drop(permit);
+        (end.lock().unwrap().events > 0).then(|| Ok("]".to_string()))
+    })
+    .filter_map(ready);
+    events.chain(close)
+}
+
+/// Run `section`, if included, once one of the request's `queries` permits is free
+async fn limited<S: Future>(queries: &Semaphore, section: Option<S>) -> Option<S::Output> {
+    let section = section?;
+    let _permit = queries.acquire().await.expect("request semaphore closed");
+    Some(section.await)
+}
+
+/// Like `limited`, but the permit is returned with the output, to keep it until rows are read
+async fn limited_owned<S: Future>(
+    queries: Arc<Semaphore>,
+    section: Option<S>,
+) -> Option<(S::Output, OwnedSemaphorePermit)> {
+    let section = section?;
+    let permit = queries
+        .acquire_owned()
+        .await
+        .expect("request semaphore closed");
+    Some((section.await, permit))
+}
+
+/// Results of the included sections, at most `max_queries` of them running at the same time
+///
+/// Each section holds its pool connection until its rows are read, so this bounds the
+/// connections a single request takes and its sections can't starve the pool. The events are
+/// streamed with the response and keep their permit until then, so they queue for one last and
+/// the other sections never wait for them.
+async fn run_sections<E: Future, F: Future, C: Future, M: Future>(
+    max_queries: usize,
+    events: Option<E>,
+    fields: Option<F>,
+    counts: Option<C>,
+    metadata: Option<M>,
+) -> (
+    Option<(E::Output, OwnedSemaphorePermit)>,
+    Option<F::Output>,
+    Option<C::Output>,
+    Option<M::Output>,
+) {
+    let queries = Arc::new(Semaphore::new(max_queries.max(1)));
+    let (fields, counts, metadata, events) = futures::join!(
+        limited(&queries, fields),
+        limited(&queries, counts),
+        limited(&queries, metadata),
+        limited_owned(queries.clone(), events),
+    );
+    (events, fields, counts, metadata)
+}
+
+/// `event` with its `source` flattened to dotted keys
+fn flatten_source(event: &mut Value) {
+    if let Some(source) = event.get_mut("source") {
+        *source = flattened(source);
+    }
+}
+
+/// JSON text of the cursor for the page after `page`, `null` if it was the last one
+fn next_cursor(page: &Page, limit: i64) -> String {
+    Cursor::after(page.events, page.last, limit)
+        .map_or_else(|| "null".into(), |cursor| format!("\"{}\"", cursor))
+}
+
+/// Chunks of a section whose complete document is `doc`
+fn complete(doc: String) -> BoxStream<'static, Result<String, Error>> {
+    stream::once(ready(Ok(doc))).boxed()
+}
+
+/// Chunks of a section, or the error that kept it from being computed
+type SectionResult = (
+    &'static str,
+    Result<BoxStream<'static, Result<String, Error>>, Error>,
+);
+
+/// Envelope sections with the chunks of each section
+///
+/// Failed sections are `null` and marked as failed in the `errors` member, the errors
+/// themselves are only logged.
+fn response_sections(sections: Vec<SectionResult>) -> Vec<envelope::Section<'static, Error>> {
+    let mut members = Vec::new();
+    let mut errors = serde_json::Map::new();
+    for (name, result) in sections {
+        let chunks = match result {
+            Ok(chunks) => chunks,
+            Err(err) => {
+                error!("fetch {}: {:?}", name, err);
+                errors.insert(name.into(), "failed".into());
+                complete("null".into())
+            }
+        };
+        members.push(envelope::section(name, chunks));
+    }
+    if !errors.is_empty() {
+        members.push(envelope::value("errors", Value::Object(errors).to_string()));
+    }
+    members
+}
+
 impl Response {
-    pub fn new(parser: Arc<Mutex<ExpressionParser>>, table: &str, db: DBPool) -> Self {
+    pub fn new(parser: Arc<Mutex<ExpressionParser>>, table: &str, db: Database) -> Self {
         Self {
             parser,
             table: table.to_owned(),
             db,
+            limits: EventLimits::default(),
+            defaults: EventDefaults::default(),
+            max_queries: PoolSettings::default().queries_per_request(),
         }
     }
 
+    pub fn with_max_queries(mut self, max_queries: usize) -> Self {
+        self.max_queries = max_queries;
+        self
+    }
+
+    pub fn with_limits(mut self, limits: EventLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn with_defaults(mut self, defaults: EventDefaults) -> Self {
+        self.defaults = defaults;
+        self
+    }
+
+    /// Number of events to return for `params`
+    fn limit(&self, params: &Request) -> i64 {
+        self.limits
+            .clamp(params.limit_events.or(self.defaults.limit))
+    }
+
+    /// Order of the events for `params`
+    fn order(&self, params: &Request) -> EventOrder {
+        params.order.unwrap_or(self.defaults.order)
+    }
+
     async fn parse_query(
         &self,
         query: &Option<String>,
     ) -> Result<(String, Vec<Value>), MalformedQuery> {
         let p = self.parser.lock().await;
         let (query, query_params) = if let Some(query) = query {
-            p.to_sql(query, 1).map_err(|_| MalformedQuery)?
+            for hint in p.performance_hints(query).unwrap_or_default() {
+                debug!("{}: {}", hint, query);
+            }
+            p.to_sql(query, 1)?
         } else {
             ("1 = 1".into(), Vec::new())
         };
@@ -251,42 +656,812 @@ impl Response {
         Ok((query, query_params))
     }
 
+    /// What to do with the full text search terms of the query for `params`
+    fn full_text_usage(params: &Request) -> FullText {
+        FullText {
+            terms: Vec::new(),
+            highlight: params.highlight == Some(true),
+            score: params.score == Some(true),
+            order_by_score: params.order_by == Some(OrderBy::Score),
+        }
+    }
+
+    /// Full text search terms to highlight, score or order by, if requested
+    async fn parse_full_text(
+        &self,
+        params: &Request,
+    ) -> Result<Vec<(String, TsQuery)>, MalformedQuery> {
+        let usage = Self::full_text_usage(params);
+        if usage.order_by_score && params.before.is_some() {
+            return Err(MalformedQuery::Semantic(
+                "\"before\" only pages events ordered by time".into(),
+            ));
+        }
+        let terms = match &params.query {
+            Some(query) if usage.highlight || usage.score || usage.order_by_score => {
+                let p = self.parser.lock().await;
+                p.full_text_query(query)
+                    .map_err(|_| MalformedQuery::Syntax)?
+            }
+            _ => Vec::new(),
+        };
+        if usage.order_by_score && terms.is_empty() {
+            return Err(MalformedQuery::Semantic(
+                "ordering by score needs full text search terms in the query".into(),
+            ));
+        }
+        Ok(terms)
+    }
+
+    fn events_statement(
+        &self,
+        expr: &str,
+        query_params: &[Value],
+        full_text: Vec<(String, TsQuery)>,
+        params: &Request,
+    ) -> Statement {
+        let offset = query_params.len();
+        let mut sql_params: Vec<OwnedParam> = query_params
+            .iter()
+            .map(|v| Box::new(v.clone()) as OwnedParam)
+            .collect();
+        sql_params.push(Box::new(params.start));
+        sql_params.push(Box::new(params.end));
+        sql_params.push(Box::new(self.limit(params)));
+        let expr = match params.before {
+            Some(cursor) => {
+                sql_params.push(Box::new(cursor.tstamp));
+                sql_params.push(Box::new(cursor.id));
+                format!(
+                    "({}) and {}",
+                    expr,
+                    Cursor::condition(self.order(params), offset + 4, offset + 5)
+                )
+            }
+            None => expr.to_owned(),
+        };
+        let terms: Vec<(usize, TsQuery)> = full_text
+            .into_iter()
+            .map(|(term, tsquery)| {
+                sql_params.push(Box::new(Value::from(term)));
+                (sql_params.len(), tsquery)
+            })
+            .collect();
+        let full_text = (!terms.is_empty()).then(|| FullText {
+            terms,
+            ..Self::full_text_usage(params)
+        });
+        Statement {
+            query: events_query(
+                &self.table,
+                &expr,
+                offset + 1,
+                offset + 2,
+                offset + 3,
+                full_text,
+                self.limits.max_document_size,
+                self.order(params),
+            ),
+            params: sql_params,
+        }
+    }
+
+    /// The events query with its parameters, as run by `streams`
+    pub async fn statement(&self, params: &Request) -> Result<Statement, MalformedQuery> {
+        let (expr, query_params) = self.parse_query(&params.query).await?;
+        let full_text = self.parse_full_text(params).await?;
+        Ok(self.events_statement(&expr, &query_params, full_text, params))
+    }
+
     pub async fn streams(
         self,
         params: Request,
-    ) -> impl futures::Stream<Item = Result<impl Into<warp::hyper::body::Bytes>, Error>> {
-        let (expr, query_params) = self.parse_query(&params.query).await.unwrap();
+    ) -> Result<
+        impl futures::Stream<Item = Result<impl Into<warp::hyper::body::Bytes>, Error>>,
+        MalformedQuery,
+    > {
+        let (expr, query_params) = self.parse_query(&params.query).await?;
+        let full_text = self.parse_full_text(&params).await?;
+        let statement = self.events_statement(&expr, &query_params, full_text, &params);
         let expr = Arc::new(expr);
         let query_params = Arc::new(query_params);
         let table = Arc::new(self.table.to_owned());
+        let interval = CountsInterval::from(params.end - params.start);
+        let limit = self.limit(&params);
+        let include = params.include.unwrap_or_default();
+        let flatten = params.flatten.unwrap_or(false);
+        let by_score = params.order_by == Some(OrderBy::Score);
 
-        let (e, f, m) = futures::join!(
-            events(
-                self.db.clone(),
-                table.clone(),
-                expr.clone(),
-                query_params.clone(),
-                &params.start,
-                &params.end,
-                &params.limit_events,
-            ),
-            fields(
-                self.db.clone(),
-                table.clone(),
-                expr.clone(),
-                query_params.clone(),
-                &params.start,
-                &params.end,
+        let started = Instant::now();
+        let (e, f, c, m) = run_sections(
+            self.max_queries,
+            include.events.then(|| events(self.db.clone(), statement)),
+            include.fields.then(|| {
+                fields(
+                    self.db.clone(),
+                    table.clone(),
+                    expr.clone(),
+                    query_params.clone(),
+                    &params.start,
+                    &params.end,
+                )
+            }),
+            include.counts.then(|| {
+                counts(
+                    self.db.clone(),
+                    table.clone(),
+                    expr.clone(),
+                    query_params.clone(),
+                    &params.start,
+                    &params.end,
+                )
+            }),
+            include
+                .metadata
+                .then(|| metadata(self.db.clone(), table.clone(), &params.start, &params.end)),
+        )
+        .await;
+
+        let mut sections: Vec<SectionResult> = Vec::new();
+        if let Some((e, permit)) = e {
+            let page = Arc::new(std::sync::Mutex::new(Page::default()));
+            let last_page = page.clone();
+            let e = e.map(|rows| event_chunks(event_docs(rows), flatten, page, permit).boxed());
+            sections.push(("events", e));
+            // streamed after the events, when the page is complete
+            let next = stream::once(async move {
+                Ok(if by_score {
+                    "null".into()
+                } else {
+                    next_cursor(&last_page.lock().unwrap(), limit)
+                })
+            });
+            sections.push(("next_cursor", Ok(next.boxed())));
+        }
+        if let Some(f) = f {
+            sections.push(("fields", f.map(complete)));
+        }
+        if let Some(c) = c {
+            sections.push(("counts", c.map(complete)));
+        }
+        if let Some(m) = m {
+            let m = m.map(|doc| Metadata::new(&interval, Some(limit), started).merged_with(&doc));
+            sections.push(("metadata", m.map(complete)));
+        }
+        Ok(envelope::object(response_sections(sections)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bb8_postgres::bb8;
+    use time::macros::datetime;
+    use warp::Reply;
+
+    fn request(query: &str) -> Request {
+        Request {
+            start: datetime!(2022-01-01 00:00 UTC),
+            end: datetime!(2022-01-02 00:00 UTC),
+            query: Some(query.to_string()),
+            limit_events: None,
+            order: None,
+            highlight: None,
+            score: None,
+            order_by: None,
+            flatten: None,
+            before: None,
+            include: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn request_accepts_epoch_and_rfc3339() {
+        let params = warp::test::request()
+            .path("/?start=1640995200&end=2022-01-02T00:00:00Z")
+            .filter(&warp::query::<Request>())
+            .await
+            .unwrap();
+        assert_eq!(params.start, datetime!(2022-01-01 00:00 UTC));
+        assert_eq!(params.end, datetime!(2022-01-02 00:00 UTC));
+
+        let params = warp::test::request()
+            .path("/?start=1640995200000&end=1641081600000")
+            .filter(&warp::query::<Request>())
+            .await
+            .unwrap();
+        assert_eq!(params.start, datetime!(2022-01-01 00:00 UTC));
+        assert_eq!(params.end, datetime!(2022-01-02 00:00 UTC));
+
+        let params = warp::test::request()
+            .path("/?start=2022-01-01T00:00:00.123Z&end=2022-01-02T00:00:00.123456%2B00:00")
+            .filter(&warp::query::<Request>())
+            .await
+            .unwrap();
+        assert_eq!(params.start, datetime!(2022-01-01 00:00:00.123 UTC));
+        assert_eq!(params.end, datetime!(2022-01-02 00:00:00.123456 UTC));
+    }
+
+    #[tokio::test]
+    async fn request_selects_sections() {
+        let params = warp::test::request()
+            .path("/?start=1640995200&end=1641081600&include=metadata")
+            .filter(&warp::query::<Request>())
+            .await
+            .unwrap();
+        let include = params.include.unwrap();
+        assert!(include.metadata);
+        assert!(!include.events);
+        assert!(!include.fields);
+        assert!(!include.counts);
+        assert_eq!(String::from(include), "metadata");
+
+        let params = warp::test::request()
+            .path("/?start=1640995200&end=1641081600")
+            .filter(&warp::query::<Request>())
+            .await
+            .unwrap();
+        assert_eq!(params.include.unwrap_or_default(), Sections::default());
+
+        assert_eq!(
+            Sections::try_from(" fields, events".to_string()),
+            Ok(Sections {
+                events: true,
+                fields: true,
+                counts: false,
+                metadata: false
+            })
+        );
+
+        // counts only with the metadata, none of the events and fields queries
+        let params = warp::test::request()
+            .path("/?start=1640995200&end=1641081600&include=counts,metadata")
+            .filter(&warp::query::<Request>())
+            .await
+            .unwrap();
+        let include = params.include.unwrap();
+        assert!(include.counts && include.metadata);
+        assert!(!include.events && !include.fields);
+        assert_eq!(String::from(include), "counts,metadata");
+        assert!(!Sections::default().counts);
+
+        assert!(warp::test::request()
+            .path("/?start=1640995200&end=1641081600&include=histogram")
+            .filter(&warp::query::<Request>())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn counts_section() {
+        let doc = response(vec![
+            (
+                "counts",
+                Ok(r#"{"2022-01-01T00:00:00+00:00": {"value": 3}}"#),
             ),
-            metadata(self.db, table, &params.start, &params.end),
+            ("metadata", Ok(r#"{"event_count": 3}"#)),
+        ])
+        .await;
+        assert_eq!(
+            doc,
+            serde_json::json!({
+                "counts": {"2022-01-01T00:00:00+00:00": {"value": 3}},
+                "metadata": {"event_count": 3}
+            })
+        );
+
+        let interval = CountsInterval::from(time::Duration::days(1));
+        let query = counts::event_counts_query("logs", "doc @> $1", 2, 3, &interval, 4);
+        assert!(query.contains("count(*) as subvalue"));
+        assert!(query.contains("tstamp between $2 and $3"));
+        assert!(query.contains("select jsonb_object_agg(tstamp, points) as doc"));
+    }
+
+    async fn response(sections: Vec<(&'static str, Result<&str, Error>)>) -> Value {
+        let sections = sections
+            .into_iter()
+            .map(|(name, doc)| (name, doc.map(|doc| complete(doc.into()))))
+            .collect();
+        let chunks: Vec<String> = envelope::object(response_sections(sections))
+            .try_collect()
+            .await
+            .unwrap();
+        serde_json::from_str(&chunks.concat()).unwrap()
+    }
+
+    fn failure() -> Error {
+        Error::Io(std::io::ErrorKind::TimedOut.into())
+    }
+
+    #[tokio::test]
+    async fn unselected_sections_are_omitted() {
+        let doc = response(vec![("metadata", Ok(r#"{"event_count": 3}"#))]).await;
+        assert_eq!(doc, serde_json::json!({"metadata": {"event_count": 3}}));
+
+        let doc = response(vec![("events", Ok("[]")), ("fields", Ok("{}"))]).await;
+        assert_eq!(doc, serde_json::json!({"events": [], "fields": {}}));
+
+        assert_eq!(response(Vec::new()).await, serde_json::json!({}));
+    }
+
+    #[tokio::test]
+    async fn failed_sections_are_marked() {
+        let doc = response(vec![
+            ("events", Ok(r#"[{"id": 1}]"#)),
+            ("fields", Err(failure())),
+            ("metadata", Ok(r#"{"event_count": 1}"#)),
+        ])
+        .await;
+        assert_eq!(doc["events"][0]["id"], 1);
+        assert_eq!(doc["fields"], Value::Null);
+        assert_eq!(doc["metadata"]["event_count"], 1);
+        // details of the error stay in the log
+        assert_eq!(doc["errors"], serde_json::json!({"fields": "failed"}));
+
+        let doc = response(vec![("events", Err(failure())), ("fields", Err(failure()))]).await;
+        assert_eq!(doc["events"], Value::Null);
+        assert_eq!(
+            doc["errors"],
+            serde_json::json!({"events": "failed", "fields": "failed"})
         );
+    }
+
+    fn event(id: i64) -> Value {
+        serde_json::json!({
+            "timestamp": "2022-01-01T00:00:00+00:00",
+            "id": id,
+            "source": {"host": "a", "vars": {"user": {"name": "x"}}}
+        })
+    }
+
+    /// Chunks of the events section for `docs`, the page after them and the permits left
+    async fn streamed(
+        docs: Vec<Result<Value, Error>>,
+        flatten: bool,
+    ) -> (Vec<Result<String, Error>>, Page, usize) {
+        let queries = Arc::new(Semaphore::new(1));
+        let permit = queries.clone().acquire_owned().await.unwrap();
+        let page = Arc::new(std::sync::Mutex::new(Page::default()));
+        let chunks = event_chunks(stream::iter(docs), flatten, page.clone(), permit)
+            .collect()
+            .await;
+        let page = std::mem::take(&mut *page.lock().unwrap());
+        (chunks, page, queries.available_permits())
+    }
+
+    #[tokio::test]
+    async fn events_are_streamed_one_by_one() {
+        let (chunks, page, permits) = streamed(vec![Ok(event(1)), Ok(event(2))], false).await;
+        let chunks: Vec<String> = chunks.into_iter().map(Result::unwrap).collect();
+        assert_eq!(chunks.len(), 3);
+        assert!(chunks[0].starts_with('['));
+        assert!(chunks[1].starts_with(','));
+        assert_eq!(chunks[2], "]");
+        let events: Value = serde_json::from_str(&chunks.concat()).unwrap();
+        assert_eq!(events, serde_json::json!([event(1), event(2)]));
+        assert_eq!(page.events, 2);
+        assert_eq!(page.last.unwrap().id, 2);
+        assert_eq!(permits, 1);
+
+        let (chunks, page, permits) = streamed(Vec::new(), false).await;
+        assert!(chunks.is_empty());
+        assert_eq!(page.events, 0);
+        assert_eq!(permits, 1);
+
+        let (chunks, _, _) = streamed(vec![Ok(event(1)), Err(failure())], false).await;
+        assert!(chunks[0].is_ok());
+        assert!(chunks[1].is_err());
+    }
+
+    #[tokio::test]
+    async fn flattened_sources() {
+        let (chunks, _, _) = streamed(vec![Ok(event(1))], true).await;
+        let chunks: Vec<String> = chunks.into_iter().map(Result::unwrap).collect();
+        let flat: Value = serde_json::from_str(&chunks.concat()).unwrap();
+        assert_eq!(
+            flat,
+            serde_json::json!([{
+                "timestamp": "2022-01-01T00:00:00+00:00",
+                "id": 1,
+                "source": {"host": "a", "vars.user.name": "x"}
+            }])
+        );
+    }
+
+    #[tokio::test]
+    async fn malformed_query_is_rejected() {
+        let parser = Arc::new(Mutex::new(ExpressionParser::default()));
+        for query in [r#"id = "#, r#"id = 99999999999999999999"#, r#"(("a")"#] {
+            let response = Response::new(parser.clone(), "logs", crate::app::unconnected_pool());
+            assert!(response.streams(request(query)).await.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn malformed_query_kinds() {
+        let parser = Arc::new(Mutex::new(ExpressionParser::default()));
+        let response = Response::new(parser, "logs", crate::app::unconnected_pool());
+        assert!(matches!(
+            response.statement(&request("id = ")).await,
+            Err(MalformedQuery::Syntax)
+        ));
+        let reason = match response.statement(&request(r#"x > "a""#)).await {
+            Err(MalformedQuery::Semantic(reason)) => reason,
+            _ => panic!("expected a semantic error"),
+        };
+
+        let reply = crate::app::handle_rejection(warp::reject::custom(MalformedQuery::Semantic(
+            reason.clone(),
+        )))
+        .await
+        .unwrap()
+        .into_response();
+        assert_eq!(reply.status(), 400);
+        let body = warp::hyper::body::to_bytes(reply.into_body())
+            .await
+            .unwrap();
+        assert_eq!(body, format!("BAD_REQUEST: {}", reason));
+
+        let reply = crate::app::handle_rejection(warp::reject::custom(MalformedQuery::Syntax))
+            .await
+            .unwrap()
+            .into_response();
+        let body = warp::hyper::body::to_bytes(reply.into_body())
+            .await
+            .unwrap();
+        assert_eq!(body, "BAD_REQUEST");
+    }
 
-        stream::once(async { Ok(r#"{"events":"#.to_string()) })
-            .chain(e)
-            .chain(stream::once(async { Ok(r#", "fields":"#.to_string()) }))
-            .chain(f)
-            .chain(stream::once(async { Ok(r#", "metadata":"#.to_string()) }))
-            .chain(m)
-            .chain(stream::once(async { Ok("}".to_string()) }))
+    #[test]
+    fn highlight_sql() {
+        let query = events_query("logs", "1 = 1", 1, 2, 3, None, 100, EventOrder::Desc);
+        assert!(!query.contains("ts_headline"));
+
+        let highlight = FullText {
+            terms: vec![(4, TsQuery::Websearch)],
+            highlight: true,
+            ..Default::default()
+        };
+        let query = events_query(
+            "logs",
+            "1 = 1",
+            1,
+            2,
+            3,
+            Some(highlight),
+            100,
+            EventOrder::Desc,
+        );
+        assert!(!query.contains("ts_rank"));
+        assert!(query.contains(
+            "'source', case when octet_length(doc::text) > 100 \
+             then jsonb_build_object('truncated', true, 'size', octet_length(doc::text)) \
+             else doc end, 'highlight', ts_headline(concat_ws(' ', doc ->> 'hostname', \
+             doc ->> 'syslogtag', doc ->> 'msg'), websearch_to_tsquery($4::jsonb #>> '{}'))) as doc"
+        ));
+    }
+
+    #[tokio::test]
+    async fn highlight_parameter() {
+        let parser = Arc::new(Mutex::new(ExpressionParser::default()));
+        let response = Response::new(parser, "logs", crate::app::unconnected_pool());
+
+        let mut params = request(r#""error" and host = "a" or "timeout""#);
+        params.highlight = Some(true);
+        let statement = response.statement(&params).await.unwrap();
+        assert_eq!(statement.params.len(), 9);
+        assert!(statement.query.contains(
+            "(websearch_to_tsquery($8::jsonb #>> '{}') || websearch_to_tsquery($9::jsonb #>> '{}')))"
+        ));
+
+        // terms keep their kind of tsquery
+        params.query = Some(r#"phrase "disk full" or plain "timeout""#.into());
+        let statement = response.statement(&params).await.unwrap();
+        assert!(statement.query.contains(
+            "(phraseto_tsquery($6::jsonb #>> '{}') || plainto_tsquery($7::jsonb #>> '{}')))"
+        ));
+
+        params.query = Some(r#"host = "a""#.into());
+        let statement = response.statement(&params).await.unwrap();
+        assert!(!statement.query.contains("ts_headline"));
+
+        params.query = Some(r#""error""#.into());
+        params.highlight = Some(false);
+        let statement = response.statement(&params).await.unwrap();
+        assert!(!statement.query.contains("ts_headline"));
+    }
+
+    #[test]
+    fn score_sql() {
+        let score = FullText {
+            terms: vec![(4, TsQuery::Websearch)],
+            score: true,
+            ..Default::default()
+        };
+        let query = events_query(
+            "logs",
+            "1 = 1",
+            1,
+            2,
+            3,
+            Some(score.clone()),
+            100,
+            EventOrder::Desc,
+        );
+        assert!(query.contains(
+            "else doc end, 'score', ts_rank(search, websearch_to_tsquery($4::jsonb #>> '{}'))) as doc"
+        ));
+        assert!(query.contains("order by tstamp desc, id desc"));
+
+        let ordered = FullText {
+            order_by_score: true,
+            ..score
+        };
+        let query = events_query(
+            "logs",
+            "1 = 1",
+            1,
+            2,
+            3,
+            Some(ordered),
+            100,
+            EventOrder::Asc,
+        );
+        assert!(query.contains(
+            "order by ts_rank(search, websearch_to_tsquery($4::jsonb #>> '{}')) asc, \
+             tstamp asc, id asc"
+        ));
+    }
+
+    #[tokio::test]
+    async fn score_parameters() {
+        let parser = Arc::new(Mutex::new(ExpressionParser::default()));
+        let response = Response::new(parser, "logs", crate::app::unconnected_pool());
+
+        let mut params = request(r#""error" and host = "a""#);
+        params.score = Some(true);
+        params.highlight = Some(true);
+        params.order_by = Some(OrderBy::Score);
+        let statement = response.statement(&params).await.unwrap();
+        // highlight, score and order share the parameter with the terms
+        assert_eq!(statement.params.len(), 7);
+        assert_eq!(statement.to_json()["params"][6], "error");
+        assert_eq!(
+            statement.query.matches("websearch_to_tsquery($7").count(),
+            3
+        );
+        assert!(statement.query.contains("'score', ts_rank(search"));
+        assert!(statement.query.contains(
+            "order by ts_rank(search, websearch_to_tsquery($7::jsonb #>> '{}')) desc, tstamp desc"
+        ));
+
+        // no full text search, nothing to score
+        params.order_by = None;
+        params.query = Some(r#"host = "a""#.into());
+        let statement = response.statement(&params).await.unwrap();
+        assert!(!statement.query.contains("ts_rank"));
+
+        params.order_by = Some(OrderBy::Score);
+        assert!(matches!(
+            response.statement(&params).await,
+            Err(MalformedQuery::Semantic(_))
+        ));
+        params.query = Some(r#""error""#.into());
+        params.before = Some("1640995200123456_42".parse().unwrap());
+        assert!(matches!(
+            response.statement(&params).await,
+            Err(MalformedQuery::Semantic(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn statement_matches_parameters() {
+        let parser = Arc::new(Mutex::new(ExpressionParser::default()));
+        let response = Response::new(parser, "logs", crate::app::unconnected_pool());
+        let statement = response
+            .statement(&request(r#"host = "a" and "error""#))
+            .await
+            .unwrap();
+        assert_eq!(statement.params.len(), 6);
+        assert!(statement.query.contains("between $4 and $5"));
+        assert!(statement.query.contains("limit $6"));
+        assert!(explain::explain_query(&statement.query)
+            .starts_with("EXPLAIN (FORMAT JSON) \n            select jsonb_build_object("));
+    }
+
+    #[tokio::test]
+    async fn limits_are_enforced() {
+        let parser = Arc::new(Mutex::new(ExpressionParser::default()));
+        let limits = EventLimits {
+            max_events: 50,
+            max_document_size: 4096,
+        };
+        let response =
+            Response::new(parser, "logs", crate::app::unconnected_pool()).with_limits(limits);
+        let mut params = request(r#"host = "a""#);
+        for (requested, limit) in [(None, 50), (Some(10), 10), (Some(1000), 50), (Some(-1), 0)] {
+            params.limit_events = requested;
+            let statement = response.statement(&params).await.unwrap();
+            assert_eq!(statement.to_json()["params"][4], limit);
+        }
+        let statement = response.statement(&params).await.unwrap();
+        assert!(statement.query.contains(
+            "'source', case when octet_length(doc::text) > 4096 \
+             then jsonb_build_object('truncated', true, 'size', octet_length(doc::text)) \
+             else doc end) as doc"
+        ));
+    }
+
+    #[tokio::test]
+    async fn configured_defaults() {
+        let parser = Arc::new(Mutex::new(ExpressionParser::default()));
+        let response = Response::new(parser, "logs", crate::app::unconnected_pool())
+            .with_limits(EventLimits {
+                max_events: 50,
+                max_document_size: 4096,
+            })
+            .with_defaults(EventDefaults {
+                limit: Some(20),
+                order: EventOrder::Asc,
+            });
+        let mut params = request(r#"host = "a""#);
+        params.before = Some("1640995200123456_42".parse().unwrap());
+        let statement = response.statement(&params).await.unwrap();
+        assert_eq!(statement.to_json()["params"][4], 20);
+        assert!(statement.query.contains("order by tstamp asc, id asc"));
+        assert!(statement.query.contains("(tstamp, id) > ($6, $7::bigint)"));
+
+        // explicit parameters win, the maximum still applies
+        params.limit_events = Some(100);
+        params.order = Some(EventOrder::Desc);
+        let statement = response.statement(&params).await.unwrap();
+        assert_eq!(statement.to_json()["params"][4], 50);
+        assert!(statement.query.contains("order by tstamp desc, id desc"));
+        assert!(statement.query.contains("(tstamp, id) < ($6, $7::bigint)"));
+    }
+
+    #[tokio::test]
+    async fn keyset_pagination() {
+        let parser = Arc::new(Mutex::new(ExpressionParser::default()));
+        let response = Response::new(parser, "logs", crate::app::unconnected_pool());
+        let mut params = request(r#"host = "a" or "x""#);
+        params.highlight = Some(true);
+        let statement = response.statement(&params).await.unwrap();
+        assert!(statement.query.contains("order by tstamp desc, id desc"));
+        assert!(!statement.query.contains("(tstamp, id)"));
+
+        params.before = Some("1640995200123456_42".parse().unwrap());
+        let statement = response.statement(&params).await.unwrap();
+        assert!(statement.query.contains(
+            "where ((doc -> ($1::jsonb #>> '{}') @> $2 \
+             OR search @@ websearch_to_tsquery($3::jsonb #>> '{}'))) \
+             and (tstamp, id) < ($7, $8::bigint)\n"
+        ));
+        assert!(statement.query.contains("websearch_to_tsquery($9::jsonb"));
+        let json = statement.to_json();
+        assert_eq!(json["params"][6], "2022-01-01T00:00:00.123456Z");
+        assert_eq!(json["params"][7], 42);
+        assert_eq!(json["params"][8], "x");
+    }
+
+    /// Connections that are always there
+    struct Idle;
+
+    #[async_trait::async_trait]
+    impl bb8::ManageConnection for Idle {
+        type Connection = ();
+        type Error = std::io::Error;
+
+        async fn connect(&self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        async fn is_valid(&self, _: &mut ()) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn has_broken(&self, _: &mut ()) -> bool {
+            false
+        }
+    }
+
+    /// Whether each section of `run_sections` got a connection of a one connection pool
+    ///
+    /// Like the sections of `streams`, each holds its connection while reading the rows.
+    async fn sections_connected(max_queries: usize) -> Vec<Option<bool>> {
+        let pool = bb8::Pool::builder()
+            .max_size(1)
+            .connection_timeout(std::time::Duration::from_millis(500))
+            .build(Idle)
+            .await
+            .unwrap();
+        let section = || {
+            Some(async {
+                let conn = pool.get().await;
+                tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+                conn.is_ok()
+            })
+        };
+        let (e, f, c, m) =
+            run_sections(max_queries, section(), section(), section(), section()).await;
+        vec![e.map(|(connected, _)| connected), f, c, m]
+    }
+
+    #[tokio::test]
+    async fn sections_do_not_starve_small_pools() {
+        // all at once, the last section times out waiting for the connection
+        assert!(sections_connected(3).await.contains(&Some(false)));
+        // one at a time, as with max_queries_per_request 1
+        assert_eq!(sections_connected(1).await, [Some(true); 4]);
+
+        // sections not included are skipped
+        let skipped = || None::<futures::future::Ready<()>>;
+        let (e, f, c, m) =
+            run_sections(1, skipped(), Some(async { 1 }), skipped(), skipped()).await;
+        assert!(e.is_none());
+        assert_eq!((f, c, m), (Some(1), None, None));
+    }
+
+    #[tokio::test]
+    async fn events_keep_their_permit_without_blocking_others() {
+        // the other sections run before the events, which hold the only permit afterwards
+        let (e, f, c, m) = run_sections(
+            1,
+            Some(async { "events" }),
+            Some(async { 1 }),
+            Some(async { 2 }),
+            Some(async { 3 }),
+        )
+        .await;
+        assert_eq!((f, c, m), (Some(1), Some(2), Some(3)));
+        let (events, permit) = e.unwrap();
+        assert_eq!(events, "events");
+        drop(permit);
+    }
+
+    #[test]
+    fn next_cursor_of_full_pages() {
+        let page = Page {
+            events: 1,
+            last: Cursor::of_event(&event(5)),
+        };
+        assert_eq!(next_cursor(&page, 1), "\"1640995200000000_5\"");
+        assert_eq!(next_cursor(&page, 2), "null");
+        assert_eq!(next_cursor(&Page::default(), 1), "null");
+    }
+
+    #[tokio::test]
+    async fn debug_sql_shows_events_query() {
+        let parser = Arc::new(Mutex::new(ExpressionParser::default()));
+        let response = Response::new(parser, "logs", crate::app::unconnected_pool());
+        let statement = response.statement(&request(r#"host = "a""#)).await.unwrap();
+        let reply = explain::sql_handler(statement).into_response();
+        let body = warp::hyper::body::to_bytes(reply.into_body())
+            .await
+            .unwrap();
+        let json: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            json["query"],
+            events_query(
+                "logs",
+                "doc -> ($1::jsonb #>> '{}') @> $2",
+                3,
+                4,
+                5,
+                None,
+                EventLimits::default().max_document_size,
+                EventOrder::Desc
+            )
+        );
+        assert_eq!(
+            json["params"],
+            serde_json::json!([
+                "host",
+                "a",
+                "2022-01-01T00:00:00Z",
+                "2022-01-02T00:00:00Z",
+                10000
+            ])
+        );
     }
 }
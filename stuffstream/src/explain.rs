@@ -0,0 +1,168 @@
+//! Query plans for the SQL behind `/events` and `/counts`
+use bb8_postgres::tokio_postgres::types::ToSql;
+use serde_json::{json, Value};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use warp::{reject, reply, Filter, Rejection, Reply};
+
+use crate::app::Database;
+
+/// A query parameter which can also be shown as JSON
+pub(crate) trait Param: ToSql + Sync + Send {
+    fn as_sql(&self) -> &(dyn ToSql + Sync);
+    fn to_json(&self) -> Value;
+}
+
+impl Param for Value {
+    fn as_sql(&self) -> &(dyn ToSql + Sync) {
+        self
+    }
+
+    fn to_json(&self) -> Value {
+        self.clone()
+    }
+}
+
+impl Param for i64 {
+    fn as_sql(&self) -> &(dyn ToSql + Sync) {
+        self
+    }
+
+    fn to_json(&self) -> Value {
+        Value::from(*self)
+    }
+}
+
+impl Param for OffsetDateTime {
+    fn as_sql(&self) -> &(dyn ToSql + Sync) {
+        self
+    }
+
+    fn to_json(&self) -> Value {
+        self.format(&Rfc3339).map_or(Value::Null, Value::from)
+    }
+}
+
+impl<T: Param> Param for Option<T> {
+    fn as_sql(&self) -> &(dyn ToSql + Sync) {
+        self
+    }
+
+    fn to_json(&self) -> Value {
+        self.as_ref().map_or(Value::Null, Param::to_json)
+    }
+}
+
+pub(crate) type OwnedParam = Box<dyn Param>;
+
+/// A generated SQL query together with its parameters
+pub struct Statement {
+    pub query: String,
+    pub params: Vec<OwnedParam>,
+}
+
+impl Statement {
+    /// Parameters as expected by tokio_postgres
+    pub(crate) fn sql_params(&self) -> Vec<&(dyn ToSql + Sync)> {
+        self.params.iter().map(|p| p.as_sql()).collect()
+    }
+
+    /// The query and its parameters, for `/debug/sql`
+    pub fn to_json(&self) -> Value {
+        json!({
+            "query": self.query,
+            "params": self.params.iter().map(|p| p.to_json()).collect::<Vec<_>>(),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct ExplainFailed;
+
+impl reject::Reject for ExplainFailed {}
+
+pub(crate) fn explain_query(query: &str) -> String {
+    format!("EXPLAIN (FORMAT JSON) {}", query)
+}
+
+/// Passes only if explaining queries is enabled in the config
+pub(crate) fn enabled(enabled: bool) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::any()
+        .and_then(move || async move {
+            if enabled {
+                Ok(())
+            } else {
+                Err(reject::not_found())
+            }
+        })
+        .untuple_one()
+}
+
+/// Run EXPLAIN for the given statement and reply with the JSON plan
+pub(crate) async fn handler(statement: Statement, db: Database) -> Result<impl Reply, Rejection> {
+    let db = db.pool.get().await.map_err(|err| {
+        error!("explain: {:?}", err);
+        reject::custom(ExplainFailed)
+    })?;
+    let row = db
+        .query_one(
+            explain_query(&statement.query).as_str(),
+            &statement.sql_params(),
+        )
+        .await
+        .map_err(|err| {
+            error!("explain: {:?}", err);
+            reject::custom(ExplainFailed)
+        })?;
+    let plan: Value = row.get(0);
+    Ok(reply::json(&plan))
+}
+
+/// Reply with the statement's SQL and parameters without running it
+pub(crate) fn sql_handler(statement: Statement) -> impl Reply {
+    reply::json(&statement.to_json())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn explain_prefix() {
+        assert_eq!(
+            explain_query("select * from logs where 1 = 1"),
+            "EXPLAIN (FORMAT JSON) select * from logs where 1 = 1"
+        );
+    }
+
+    #[test]
+    fn statement_as_json() {
+        let statement = Statement {
+            query: "select $1, $2, $3, $4".into(),
+            params: vec![
+                Box::new(json!("a")),
+                Box::new(time::macros::datetime!(2022-01-01 00:00 UTC)),
+                Box::new(Some(5_i64)),
+                Box::new(None::<i64>),
+            ],
+        };
+        assert_eq!(
+            statement.to_json(),
+            json!({
+                "query": "select $1, $2, $3, $4",
+                "params": ["a", "2022-01-01T00:00:00Z", 5, null],
+            })
+        );
+        assert_eq!(statement.sql_params().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn disabled_is_not_found() {
+        let filter = warp::path("explain").and(enabled(false)).map(|| "plan");
+        let res = warp::test::request().path("/explain").reply(&filter).await;
+        assert_eq!(res.status(), 404);
+
+        let filter = warp::path("explain").and(enabled(true)).map(|| "plan");
+        let res = warp::test::request().path("/explain").reply(&filter).await;
+        assert_eq!(res.status(), 200);
+    }
+}
@@ -0,0 +1,271 @@
+//! Bulk export of matching events, streamed from postgres' `COPY ... TO STDOUT`
+use bb8_postgres::tokio_postgres;
+use futures::lock::Mutex;
+use futures::TryStreamExt as _;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::Arc;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use warp::{http, reject, Rejection, Reply};
+
+use logstuff::serde::de::rfc3339_or_epoch;
+use logstuff_query::ExpressionParser;
+
+use crate::app::{Database, Error, MalformedQuery};
+use crate::audit::Audited;
+use crate::cancel;
+
+#[derive(Debug)]
+pub struct ExportFailed;
+
+impl reject::Reject for ExportFailed {}
+
+/// Output of `/export`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    /// One JSON object per line with `timestamp`, `id` and `source`
+    #[default]
+    Ndjson,
+    /// Columns `timestamp`, `id` and `source` below a header line
+    Csv,
+}
+
+impl ExportFormat {
+    /// Options for `COPY ... TO STDOUT`
+    ///
+    /// ndjson uses csv with quote and delimiter characters that JSON text always escapes, so the
+    /// single column is written verbatim.
+    fn copy_options(&self) -> &'static str {
+        match self {
+            Self::Ndjson => r#"(FORMAT csv, QUOTE e'\x01', DELIMITER e'\x02')"#,
+            Self::Csv => "(FORMAT csv, HEADER)",
+        }
+    }
+
+    fn content_type(&self) -> &'static str {
+        match self {
+            Self::Ndjson => "application/x-ndjson",
+            Self::Csv => "text/csv",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Request {
+    #[serde(deserialize_with = "rfc3339_or_epoch")]
+    start: OffsetDateTime,
+    #[serde(deserialize_with = "rfc3339_or_epoch")]
+    end: OffsetDateTime,
+    query: Option<String>,
+    format: Option<ExportFormat>,
+}
+
+impl Audited for Request {
+    fn query(&self) -> Option<&str> {
+        self.query.as_deref()
+    }
+
+    fn time_range(&self) -> (OffsetDateTime, OffsetDateTime) {
+        (self.start, self.end)
+    }
+}
+
+/// SQL string literal with the text `value`
+fn quote_literal(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+/// `expr` with its numbered placeholders replaced by the JSON values of `params`
+///
+/// `COPY` takes no bind parameters, so they have to be part of the statement.
+fn inline_params(expr: &str, params: &[Value]) -> String {
+    let mut inlined = String::with_capacity(expr.len());
+    let mut rest = expr;
+    while let Some(pos) = rest.find('$') {
+        inlined.push_str(&rest[..pos]);
+        let digits = rest[pos + 1..]
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len() - pos - 1);
+        let param = rest[pos + 1..pos + 1 + digits]
+            .parse::<usize>()
+            .ok()
+            .and_then(|index| params.get(index.wrapping_sub(1)));
+        match param {
+            Some(value) => {
+                inlined.push_str(&format!("({}::jsonb)", quote_literal(&value.to_string())))
+            }
+            None => inlined.push_str(&rest[pos..pos + 1 + digits]),
+        }
+        rest = &rest[pos + 1 + digits..];
+    }
+    inlined.push_str(rest);
+    inlined
+}
+
+/// `COPY` of the events in `table` matching `expr` between `start` and `end`, oldest first
+fn copy_statement(
+    table: &str,
+    expr: &str,
+    start: &OffsetDateTime,
+    end: &OffsetDateTime,
+    format: ExportFormat,
+) -> String {
+    let columns = match format {
+        ExportFormat::Ndjson => {
+            "jsonb_build_object('timestamp', tstamp, 'id', id, 'source', doc)::text".to_string()
+        }
+        ExportFormat::Csv => "tstamp as timestamp, id, doc as source".to_string(),
+    };
+    format!(
+        "COPY (select {} from {} where {} and tstamp between {}::timestamptz and {}::timestamptz \
+         order by tstamp, id) TO STDOUT {}",
+        columns,
+        table,
+        expr,
+        quote_literal(&start.format(&Rfc3339).unwrap()),
+        quote_literal(&end.format(&Rfc3339).unwrap()),
+        format.copy_options()
+    )
+}
+
+/// The `COPY` statement for `params`
+async fn statement(
+    parser: Arc<Mutex<ExpressionParser>>,
+    table: &str,
+    params: &Request,
+) -> Result<String, MalformedQuery> {
+    let expr = match &params.query {
+        Some(query) => {
+            let (expr, query_params) = parser.lock().await.to_sql(query, 1)?;
+            inline_params(&expr, &query_params)
+        }
+        None => "1 = 1".into(),
+    };
+    Ok(copy_statement(
+        table,
+        &expr,
+        &params.start,
+        &params.end,
+        params.format.unwrap_or_default(),
+    ))
+}
+
+/// Stream the `COPY` output as the response body
+///
+/// The body owns the pooled connection, which is released once the export is complete or
+/// cancelled. Failing to get a connection or to start the copy is an `ExportFailed`.
+pub(crate) async fn handler(
+    parser: Arc<Mutex<ExpressionParser>>,
+    table_name: String,
+    params: Request,
+    db: Database,
+) -> Result<impl Reply, Rejection> {
+    let statement = statement(parser, &table_name, &params)
+        .await
+        .map_err(reject::custom)?;
+    let rows = cancel::copy_out(&db, statement.as_str())
+        .await
+        .map_err(|err| {
+            error!("export: {}", err);
+            reject::custom(ExportFailed)
+        })?;
+    let body = rows.map_err(|err: tokio_postgres::Error| {
+        let err = Error::from(err);
+        error!("export: {}", err);
+        err
+    });
+    Ok(http::Response::builder()
+        .status(http::StatusCode::OK)
+        .header(
+            "Content-Type",
+            params.format.unwrap_or_default().content_type(),
+        )
+        .body(warp::hyper::Body::wrap_stream(body))
+        .unwrap())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use time::macros::datetime;
+
+    fn request(query: Option<&str>, format: Option<ExportFormat>) -> Request {
+        Request {
+            start: datetime!(2022-01-01 00:00 UTC),
+            end: datetime!(2022-01-02 00:00 UTC),
+            query: query.map(String::from),
+            format,
+        }
+    }
+
+    #[test]
+    fn params_are_inlined() {
+        let params = [Value::from("it's"), Value::from(12)];
+        assert_eq!(
+            inline_params("doc ->> ($1::jsonb #>> '{}') = $2", &params),
+            r#"doc ->> (('"it''s"'::jsonb)::jsonb #>> '{}') = ('12'::jsonb)"#
+        );
+        // $1 does not match the start of $10
+        let params: Vec<Value> = (1..=10).map(Value::from).collect();
+        assert_eq!(
+            inline_params("$10 $1", &params),
+            "('10'::jsonb) ('1'::jsonb)"
+        );
+        // unknown placeholders are left alone
+        assert_eq!(inline_params("$3 $", &params[..2]), "$3 $");
+    }
+
+    #[tokio::test]
+    async fn copy_statements() {
+        let parser = Arc::new(Mutex::new(ExpressionParser::default()));
+        let ndjson = statement(parser.clone(), "logs", &request(None, None))
+            .await
+            .unwrap();
+        assert_eq!(
+            ndjson,
+            "COPY (select jsonb_build_object('timestamp', tstamp, 'id', id, 'source', doc)::text \
+             from logs where 1 = 1 \
+             and tstamp between '2022-01-01T00:00:00Z'::timestamptz \
+             and '2022-01-02T00:00:00Z'::timestamptz order by tstamp, id) \
+             TO STDOUT (FORMAT csv, QUOTE e'\\x01', DELIMITER e'\\x02')"
+        );
+
+        let csv = statement(
+            parser.clone(),
+            "logs",
+            &request(Some(r#"host = "a""#), Some(ExportFormat::Csv)),
+        )
+        .await
+        .unwrap();
+        assert!(csv.starts_with("COPY (select tstamp as timestamp, id, doc as source from logs "));
+        assert!(csv.ends_with(") TO STDOUT (FORMAT csv, HEADER)"));
+        assert!(!csv.contains('$'));
+        assert!(csv.contains(
+            r#"where doc -> (('"host"'::jsonb)::jsonb #>> '{}') @> ('"a"'::jsonb) and tstamp"#
+        ));
+
+        assert!(statement(parser, "logs", &request(Some("host ="), None))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn request_formats() {
+        let params = warp::test::request()
+            .path("/?start=1640995200&end=1641081600&format=csv")
+            .filter(&warp::query::<Request>())
+            .await
+            .unwrap();
+        assert_eq!(params.format, Some(ExportFormat::Csv));
+        assert_eq!(
+            ExportFormat::default().content_type(),
+            "application/x-ndjson"
+        );
+        assert!(warp::test::request()
+            .path("/?start=1640995200&end=1641081600&format=xml")
+            .filter(&warp::query::<Request>())
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,262 @@
+use futures::lock::Mutex;
+use futures::stream::TryStreamExt as _;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::Arc;
+use time::OffsetDateTime;
+use warp::http;
+
+use logstuff::serde::de::rfc3339_or_epoch;
+use logstuff_query::{ExpressionParser, IdentifierParser};
+
+use crate::app::Database;
+use crate::app::Error;
+use crate::app::MalformedQuery;
+use crate::cancel;
+use crate::envelope;
+use crate::explain::{OwnedParam, Statement};
+use crate::interval::CountsInterval;
+
+const DEFAULT_TOP_VALUES: i64 = 5;
+
+pub(crate) async fn handler(
+    expr_parser: Arc<Mutex<ExpressionParser>>,
+    id_parser: Arc<Mutex<IdentifierParser>>,
+    table_name: String,
+    params: Request,
+    db: Database,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let response = Response::new(expr_parser, id_parser, &table_name, db);
+    let body = response.streams(params).await?;
+    Ok(http::Response::builder()
+        .status(http::StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(warp::hyper::Body::wrap_stream(body))
+        .unwrap())
+}
+
+#[derive(Debug)]
+pub struct FieldsOverTimeFailed;
+
+impl warp::reject::Reject for FieldsOverTimeFailed {}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Request {
+    #[serde(deserialize_with = "rfc3339_or_epoch")]
+    start: OffsetDateTime,
+    #[serde(deserialize_with = "rfc3339_or_epoch")]
+    end: OffsetDateTime,
+    query: Option<String>,
+    field: String,
+    /// Number of most frequent values per bucket (default 5)
+    top: Option<i64>,
+}
+
+pub struct Response {
+    expr_parser: Arc<Mutex<ExpressionParser>>,
+    id_parser: Arc<Mutex<IdentifierParser>>,
+    table: String,
+    db: Database,
+}
+
+/// Top `top_id` values of `getter` with their counts, per counts interval
+///
+/// Buckets are built like in the counts query, buckets without events are left out.
+fn fields_over_time_query(
+    table: &str,
+    getter: &str,
+    expr: &str,
+    start_id: usize,
+    end_id: usize,
+    interval: &CountsInterval,
+    top_id: usize,
+) -> String {
+    format!(
+        r#"
+            select jsonb_object_agg(tstamp, values) as doc from (
+                select tstamp, jsonb_object_agg(value, count) as values from (
+                    select tstamp, value, count, row_number() over (
+                            partition by tstamp
+                            order by count desc, value
+                        ) as row_number
+                    from (
+                        select date_trunc('{}', gen_time) as tstamp, l.value, sum(l.count)::integer as count
+                        from generate_series({}, ${}, '{}'::interval) gen_time
+                        join (select date_trunc('{}', tstamp) as log_time, coalesce({}, '(null)') as value, count(*)
+                                from {}
+                                where {}
+                                and tstamp between ${} and ${}
+                                group by log_time, 2
+                            ) l
+                        on log_time between gen_time - '{}'::interval and gen_time
+                        group by 1, l.value
+                    ) counted
+                ) ranked
+                where row_number <= ${}
+                group by tstamp
+            ) f
+        "#,
+        &interval.truncate,
+        interval.aligned(&format!("${}", start_id)),
+        end_id,
+        &interval.interval,
+        &interval.truncate,
+        getter,
+        table,
+        expr,
+        start_id,
+        end_id,
+        &interval.interval,
+        top_id,
+    )
+}
+
+impl Response {
+    pub fn new(
+        expr_parser: Arc<Mutex<ExpressionParser>>,
+        id_parser: Arc<Mutex<IdentifierParser>>,
+        table: &str,
+        db: Database,
+    ) -> Self {
+        Self {
+            expr_parser,
+            id_parser,
+            table: table.to_owned(),
+            db,
+        }
+    }
+
+    /// The fields over time query with its parameters, as run by `streams`
+    pub async fn statement(&self, params: &Request) -> Result<Statement, MalformedQuery> {
+        let p = self.expr_parser.lock().await;
+        let (expr, mut query_params) = if let Some(query) = &params.query {
+            p.to_sql(query, 1)?
+        } else {
+            ("1 = 1".into(), Vec::new())
+        };
+        drop(p);
+
+        let p = self.id_parser.lock().await;
+        let (getter, getter_params) = p.sql_string(&params.field, query_params.len() + 1)?;
+        drop(p);
+        query_params.extend(getter_params);
+
+        let param_offset = query_params.len() + 1;
+        let query = fields_over_time_query(
+            &self.table,
+            &getter,
+            &expr,
+            param_offset,
+            param_offset + 1,
+            &CountsInterval::from(params.end - params.start),
+            param_offset + 2,
+        );
+        let mut sql_params: Vec<OwnedParam> = query_params
+            .into_iter()
+            .map(|v| Box::new(v) as OwnedParam)
+            .collect();
+        sql_params.push(Box::new(params.start));
+        sql_params.push(Box::new(params.end));
+        sql_params.push(Box::new(params.top.unwrap_or(DEFAULT_TOP_VALUES)));
+        Ok(Statement {
+            query,
+            params: sql_params,
+        })
+    }
+
+    pub async fn streams(
+        self,
+        params: Request,
+    ) -> Result<
+        impl futures::Stream<Item = Result<impl Into<warp::hyper::body::Bytes>, Error>>,
+        warp::Rejection,
+    > {
+        let statement = self
+            .statement(&params)
+            .await
+            .map_err(warp::reject::custom)?;
+        let interval = CountsInterval::from(params.end - params.start);
+
+        let fields = cancel::query_raw(&self.db, statement.query.as_str(), statement.sql_params())
+            .await
+            .map_err(|err| {
+                error!("fetch fields over time: {}", err);
+                warp::reject::custom(FieldsOverTimeFailed)
+            })?
+            .map_ok(|row| {
+                let value: Option<Value> = row.get("doc");
+                value.unwrap_or(Value::Null).to_string()
+            })
+            .map_err(|err| {
+                error!("fetch fields over time: {:?}", err);
+                Error::from(err)
+            });
+
+        Ok(envelope::object(vec![
+            envelope::value(
+                "metadata",
+                format!(r#"{{"counts_interval_sec": {}}}"#, interval.seconds),
+            ),
+            envelope::section("fields", fields),
+        ]))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use time::macros::datetime;
+
+    fn request() -> Request {
+        Request {
+            start: datetime!(2022-01-01 00:00 UTC),
+            end: datetime!(2022-01-02 00:00 UTC),
+            query: None,
+            field: "program".into(),
+            top: None,
+        }
+    }
+
+    fn response() -> Response {
+        Response::new(
+            Arc::new(Mutex::new(ExpressionParser::default())),
+            Arc::new(Mutex::new(IdentifierParser::default())),
+            "logs",
+            crate::app::unconnected_pool(),
+        )
+    }
+
+    #[test]
+    fn buckets_and_ranking() {
+        let interval = CountsInterval::from(time::Duration::hours(4));
+        let query = fields_over_time_query("logs", "doc ->> 'x'", "1 = 1", 1, 2, &interval, 3);
+        assert!(query.contains(
+            "generate_series(to_timestamp(floor(extract(epoch from $1::timestamptz) / 300) * 300), \
+             $2, '5 minutes'::interval) gen_time"
+        ));
+        assert!(query.contains("coalesce(doc ->> 'x', '(null)') as value"));
+        assert!(query.contains("partition by tstamp"));
+        assert!(query.contains("where row_number <= $3"));
+    }
+
+    #[tokio::test]
+    async fn statement_matches_parameters() {
+        let mut params = request();
+        params.query = Some(r#"host = "a""#.into());
+        params.top = Some(3);
+        let statement = response().statement(&params).await.unwrap();
+        assert_eq!(statement.params.len(), 6);
+        assert!(statement
+            .query
+            .contains(", $5, '30 minutes'::interval) gen_time"));
+        assert!(statement.query.contains("tstamp between $4 and $5"));
+        assert!(statement.query.contains("where row_number <= $6"));
+    }
+
+    #[tokio::test]
+    async fn malformed_field_is_rejected() {
+        let mut params = request();
+        params.field = "0invalid".into();
+        assert!(response().streams(params).await.is_err());
+    }
+}
@@ -1,7 +1,7 @@
 use time::Duration;
 
 const INTERVALS: &[(u64, &str, &str)] = &[
-    (1, "1 seconds", "second"),
+    (1, "1 second", "second"),
     (2, "2 seconds", "second"),
     (5, "5 seconds", "second"),
     (10, "10 seconds", "second"),
@@ -17,11 +17,12 @@ const INTERVALS: &[(u64, &str, &str)] = &[
     (10 * 3600, "10 hours", "hour"),
     (24 * 3600, "1 day", "day"),
     (2 * 24 * 3600, "2 days", "day"),
+    (3 * 24 * 3600, "3 days", "day"),
     (7 * 24 * 3600, "1 week", "week"),
-    (2 * 7 * 24 * 3600, "2 week", "week"),
+    (2 * 7 * 24 * 3600, "2 weeks", "week"),
     (30 * 24 * 3600, "1 month", "month"),
     (2 * 30 * 24 * 3600, "2 months", "month"),
-    (3 * 30 * 24 * 3600, "3 months", "month"),
+    (3 * 30 * 24 * 3600, "3 months", "quarter"),
     (4 * 30 * 24 * 3600, "4 months", "month"),
     (6 * 30 * 24 * 3600, "6 months", "month"),
     (365 * 24 * 3600, "1 year", "year"),
@@ -60,6 +61,24 @@ impl From<Duration> for CountsInterval {
     }
 }
 
+impl CountsInterval {
+    /// SQL expression rounding the timestamp `value` down to the start of its bucket
+    ///
+    /// Intervals evenly dividing a day are aligned to multiples of the interval since the epoch,
+    /// all others to the start of their `truncate` unit.
+    pub fn aligned(&self, value: &str) -> String {
+        const DAY: u64 = 24 * 3600;
+        if self.seconds < DAY && DAY.is_multiple_of(self.seconds) {
+            format!(
+                "to_timestamp(floor(extract(epoch from {}::timestamptz) / {}) * {})",
+                value, self.seconds, self.seconds
+            )
+        } else {
+            format!("date_trunc('{}', {}::timestamptz)", self.truncate, value)
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -67,9 +86,93 @@ mod test {
     #[test]
     fn intervals() {
         let i = CountsInterval::from(Duration::seconds(50));
-        assert_eq!(i.interval, "1 seconds");
+        assert_eq!(i.interval, "1 second");
 
         let i = CountsInterval::from(Duration::hours(4));
         assert_eq!(i.interval, "5 minutes");
     }
+
+    #[test]
+    fn long_ranges() {
+        let i = CountsInterval::from(Duration::days(90));
+        assert_eq!((i.interval.as_str(), i.truncate.as_str()), ("1 day", "day"));
+
+        let i = CountsInterval::from(Duration::days(200));
+        assert_eq!(
+            (i.interval.as_str(), i.truncate.as_str()),
+            ("2 days", "day")
+        );
+
+        let i = CountsInterval::from(Duration::days(300));
+        assert_eq!(
+            (i.interval.as_str(), i.truncate.as_str()),
+            ("3 days", "day")
+        );
+
+        let i = CountsInterval::from(Duration::days(25 * 365));
+        assert_eq!(
+            (i.interval.as_str(), i.truncate.as_str()),
+            ("3 months", "quarter")
+        );
+        assert_eq!(i.aligned("$1"), "date_trunc('quarter', $1::timestamptz)");
+    }
+
+    #[test]
+    fn interval_strings_are_valid() {
+        const UNITS: &[(&str, u64)] = &[
+            ("second", 1),
+            ("minute", 60),
+            ("hour", 3600),
+            ("day", 24 * 3600),
+            ("week", 7 * 24 * 3600),
+            ("month", 30 * 24 * 3600),
+            ("quarter", 3 * 30 * 24 * 3600),
+            ("year", 365 * 24 * 3600),
+        ];
+        let unit_seconds = |unit: &str| UNITS.iter().find(|(u, _)| *u == unit).map(|(_, s)| *s);
+
+        for (seconds, interval, truncate) in INTERVALS {
+            assert!(
+                unit_seconds(truncate).is_some(),
+                "bad date_trunc unit {}",
+                truncate
+            );
+
+            let (count, unit) = interval.split_once(' ').unwrap();
+            let count: u64 = count.parse().unwrap();
+            let unit = if count == 1 {
+                unit
+            } else {
+                unit.strip_suffix('s')
+                    .unwrap_or_else(|| panic!("{} not plural", interval))
+            };
+            assert_eq!(
+                unit_seconds(unit).map(|s| s * count),
+                Some(*seconds),
+                "{} does not match its length",
+                interval
+            );
+            assert!(
+                unit_seconds(truncate) <= Some(*seconds),
+                "{} truncated to {}",
+                interval,
+                truncate
+            );
+        }
+    }
+
+    #[test]
+    fn aligned_start() {
+        let i = CountsInterval::from(Duration::hours(4));
+        assert_eq!(
+            i.aligned("$1"),
+            "to_timestamp(floor(extract(epoch from $1::timestamptz) / 300) * 300)"
+        );
+
+        let i = CountsInterval::from(Duration::days(200));
+        assert_eq!(i.aligned("$1"), "date_trunc('day', $1::timestamptz)");
+
+        let i = CountsInterval::from(Duration::hours(500));
+        assert_eq!(i.aligned("$1"), "date_trunc('hour', $1::timestamptz)");
+    }
 }
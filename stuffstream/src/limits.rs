@@ -0,0 +1,90 @@
+//! Refusing requests too large to be handled in memory
+use warp::{reject, Filter, Rejection};
+
+/// Query string or body above the configured limits, answered with 413
+#[derive(Debug)]
+pub struct RequestTooLarge;
+
+impl reject::Reject for RequestTooLarge {}
+
+/// Reject requests with a query string longer than `max_query_length` or a body larger than
+/// `max_body_size` bytes
+///
+/// The body's size is taken from its Content-Length header. Routes reading chunked bodies have
+/// to limit them themselves.
+pub(crate) fn request_size(
+    max_query_length: usize,
+    max_body_size: u64,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    let query = warp::query::raw().or(warp::any().map(String::new)).unify();
+    query
+        .and(warp::header::optional::<u64>("content-length"))
+        .and_then(move |query: String, body_size: Option<u64>| async move {
+            if query.len() > max_query_length || body_size.unwrap_or(0) > max_body_size {
+                debug!(
+                    "rejecting request with {} bytes query and {:?} bytes body",
+                    query.len(),
+                    body_size
+                );
+                Err(reject::custom(RequestTooLarge))
+            } else {
+                Ok(())
+            }
+        })
+        .untuple_one()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn small_requests_pass() {
+        let filter = request_size(20, 100);
+        assert!(warp::test::request().path("/").matches(&filter).await);
+        assert!(
+            warp::test::request()
+                .path("/events?query=a")
+                .body("x".repeat(100))
+                .matches(&filter)
+                .await
+        );
+    }
+
+    #[tokio::test]
+    async fn oversized_body_is_rejected() {
+        let filter = request_size(20, 100);
+        let rejection = warp::test::request()
+            .method("POST")
+            .path("/events")
+            .body("x".repeat(101))
+            .filter(&filter)
+            .await
+            .unwrap_err();
+        assert!(rejection.find::<RequestTooLarge>().is_some());
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/events")
+            .body("x".repeat(101))
+            .reply(
+                &filter
+                    .map(warp::reply)
+                    .recover(crate::app::handle_rejection),
+            )
+            .await;
+        assert_eq!(response.status(), 413);
+    }
+
+    #[tokio::test]
+    async fn long_query_is_rejected() {
+        let filter = request_size(20, 100);
+        let path = format!("/events?query={}", "a".repeat(20));
+        let rejection = warp::test::request()
+            .path(&path)
+            .filter(&filter)
+            .await
+            .unwrap_err();
+        assert!(rejection.find::<RequestTooLarge>().is_some());
+    }
+}
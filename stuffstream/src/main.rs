@@ -6,14 +6,29 @@ use std::process::exit;
 
 mod app;
 mod application;
+mod audit;
+mod cache;
+mod cancel;
 mod config;
 mod counts;
+mod cursor;
+mod envelope;
 mod events;
+mod explain;
+mod export;
+mod fields_over_time;
 mod interval;
+mod limits;
+mod metadata;
+mod ratelimit;
+mod schema;
+mod server;
+mod tls_server;
 
 use app::App;
 use application::Application;
 use config::Config;
+use logstuff::logging::{self, LogFormat};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about)]
@@ -25,6 +40,14 @@ pub struct Args {
     /// Dump config file after loading it to stderr
     #[arg(short, long)]
     pub dump_config: bool,
+
+    /// Ignore unknown config keys with a warning instead of failing
+    #[arg(long)]
+    pub lenient_config: bool,
+
+    /// Format of log lines: text or json
+    #[arg(long, value_name = "FORMAT", default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
 }
 
 fn main() {
@@ -37,10 +60,12 @@ fn main() {
 
 fn run<T: Application>() -> Result<(), Box<dyn std::error::Error>> {
     let opts = Args::parse();
-    let config = Config::load(&opts)?;
+    let (config, ignored) = Config::load(&opts)?;
     if opts.dump_config {
         eprintln!("{}", serde_yaml::to_string(&config)?)
     }
+    logging::init(opts.log_format)?;
+    logstuff::config::warn_ignored(&ignored);
     application::run::<T>(opts, config)?;
     Ok(())
 }
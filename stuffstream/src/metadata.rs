@@ -0,0 +1,64 @@
+//! Details about how a request was answered, sent in the `metadata` block of responses
+use serde_derive::Serialize;
+use serde_json::Value;
+use std::time::Instant;
+
+use crate::interval::CountsInterval;
+
+#[derive(Serialize, Debug)]
+pub struct Metadata {
+    pub counts_interval_sec: u64,
+    /// Row limit passed to the database, `null` for none
+    pub limit: Option<i64>,
+    /// Time from sending the first query until the last row arrived
+    pub db_time_ms: f64,
+}
+
+impl Metadata {
+    pub fn new(interval: &CountsInterval, limit: Option<i64>, started: Instant) -> Self {
+        Self {
+            counts_interval_sec: interval.seconds,
+            limit,
+            db_time_ms: started.elapsed().as_secs_f64() * 1000.0,
+        }
+    }
+
+    /// JSON text of this metadata, including all members of the JSON object `doc`
+    pub fn merged_with(&self, doc: &str) -> String {
+        let mut merged = match serde_json::from_str(doc) {
+            Ok(Value::Object(members)) => members,
+            _ => Default::default(),
+        };
+        if let Value::Object(members) = serde_json::to_value(self).unwrap() {
+            merged.extend(members);
+        }
+        Value::Object(merged).to_string()
+    }
+}
+
+impl std::fmt::Display for Metadata {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", serde_json::to_string(self).unwrap())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn metadata_fields() {
+        let interval = CountsInterval::from(time::Duration::hours(4));
+        let metadata = Metadata::new(&interval, Some(100), Instant::now());
+        let doc: Value =
+            serde_json::from_str(&metadata.merged_with(r#"{"event_count": 7}"#)).unwrap();
+        assert!(doc["db_time_ms"].as_f64().unwrap() >= 0.0);
+        assert_eq!(doc["counts_interval_sec"], 300);
+        assert_eq!(doc["limit"], 100);
+        assert_eq!(doc["event_count"], 7);
+
+        let doc: Value = serde_json::from_str(&metadata.to_string()).unwrap();
+        assert!(doc["db_time_ms"].as_f64().unwrap() >= 0.0);
+        assert_eq!(doc.as_object().unwrap().len(), 3);
+    }
+}
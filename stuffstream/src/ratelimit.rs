@@ -0,0 +1,186 @@
+//! Limiting the request rate of each client
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use warp::{reject, Filter, Rejection};
+
+use crate::config::{RateLimit, RateLimitKey};
+use crate::server::Peer;
+
+/// Clients tracked before those with a full bucket are forgotten
+const MAX_TRACKED_CLIENTS: usize = 10000;
+
+/// Client over its request rate, answered with 429
+#[derive(Debug)]
+pub struct TooManyRequests;
+
+impl reject::Reject for TooManyRequests {}
+
+/// Requests a client may still make, refilled over time
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token bucket for every client
+pub(crate) struct RateLimiter {
+    settings: RateLimit,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(settings: RateLimit) -> Self {
+        Self {
+            settings,
+            buckets: Default::default(),
+        }
+    }
+
+    fn burst(&self) -> f64 {
+        f64::from(self.settings.burst.max(1))
+    }
+
+    /// Key of the client `peer`, requests without peer share a bucket
+    fn key(&self, peer: Option<&Peer>) -> String {
+        match (self.settings.key, peer) {
+            (
+                RateLimitKey::ClientCertificate,
+                Some(Peer {
+                    subject: Some(subject),
+                    ..
+                }),
+            ) => subject.clone(),
+            (_, Some(peer)) => peer.addr.ip().to_string(),
+            (_, None) => String::new(),
+        }
+    }
+
+    /// Whether client `key` may make another request at `now`, taking a token if it may
+    fn allow(&self, key: String, now: Instant) -> bool {
+        let burst = self.burst();
+        let rate = self.settings.requests_per_sec;
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_TRACKED_CLIENTS {
+            buckets.retain(|_, bucket| {
+                bucket.tokens + now.saturating_duration_since(bucket.updated).as_secs_f64() * rate
+                    < burst
+            });
+        }
+        let bucket = buckets.entry(key).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Reject requests of clients over their rate with `TooManyRequests`, pass all without `limiter`
+pub(crate) fn rate_limit(
+    limiter: Option<Arc<RateLimiter>>,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::ext::optional::<Peer>()
+        .and_then(move |peer: Option<Peer>| {
+            let limiter = limiter.clone();
+            async move {
+                match limiter {
+                    Some(limiter) if !limiter.allow(limiter.key(peer.as_ref()), Instant::now()) => {
+                        debug!("rate limit exceeded by {:?}", peer);
+                        Err(reject::custom(TooManyRequests))
+                    }
+                    _ => Ok(()),
+                }
+            }
+        })
+        .untuple_one()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    fn limiter(requests_per_sec: f64, burst: u32, key: RateLimitKey) -> RateLimiter {
+        RateLimiter::new(RateLimit {
+            requests_per_sec,
+            burst,
+            key,
+        })
+    }
+
+    fn peer(addr: &str, subject: Option<&str>) -> Peer {
+        Peer {
+            addr: addr.parse().unwrap(),
+            subject: subject.map(String::from),
+        }
+    }
+
+    #[tokio::test]
+    async fn requests_over_the_limit_are_rejected() {
+        let filter = rate_limit(Some(Arc::new(limiter(0.001, 2, RateLimitKey::ClientIp))))
+            .map(warp::reply)
+            .recover(crate::app::handle_rejection);
+        for expected in [200, 200, 429, 429] {
+            let response = warp::test::request()
+                .extension(peer("192.0.2.1:1000", None))
+                .reply(&filter)
+                .await;
+            assert_eq!(response.status(), expected);
+        }
+        // other clients have their own limit
+        let response = warp::test::request()
+            .extension(peer("192.0.2.2:1000", None))
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 200);
+    }
+
+    #[tokio::test]
+    async fn disabled_by_default() {
+        let filter = rate_limit(None);
+        for _ in 0..100 {
+            assert!(warp::test::request().matches(&filter).await);
+        }
+    }
+
+    #[test]
+    fn limit_resets_over_time() {
+        let limiter = limiter(2.0, 2, RateLimitKey::ClientIp);
+        let start = Instant::now();
+        assert!(limiter.allow("a".into(), start));
+        assert!(limiter.allow("a".into(), start));
+        assert!(!limiter.allow("a".into(), start));
+        assert!(!limiter.allow("a".into(), start + Duration::from_millis(400)));
+        assert!(limiter.allow("a".into(), start + Duration::from_millis(500)));
+        // refilled up to the burst only
+        let later = start + Duration::from_secs(60);
+        assert!(limiter.allow("a".into(), later));
+        assert!(limiter.allow("a".into(), later));
+        assert!(!limiter.allow("a".into(), later));
+    }
+
+    #[test]
+    fn clients_by_certificate() {
+        let limiter = limiter(1.0, 1, RateLimitKey::ClientCertificate);
+        assert_eq!(
+            limiter.key(Some(&peer("192.0.2.1:1000", Some("CN=alice")))),
+            "CN=alice"
+        );
+        assert_eq!(
+            limiter.key(Some(&peer("192.0.2.1:1000", None))),
+            "192.0.2.1"
+        );
+        let limiter = self::limiter(1.0, 1, RateLimitKey::ClientIp);
+        assert_eq!(
+            limiter.key(Some(&peer("[2001:db8::1]:1000", Some("CN=alice")))),
+            "2001:db8::1"
+        );
+    }
+}
@@ -0,0 +1,115 @@
+//! Fields found in recent events with the JSON type they mostly have
+use serde_json::Value;
+use time::OffsetDateTime;
+use warp::{reject, reply, Rejection, Reply};
+
+use crate::app::Database;
+use crate::explain::Statement;
+
+const SAMPLE_SIZE: i64 = 500;
+/// Only events this recent are sampled, which keeps the scan to the newest partitions
+const SAMPLE_PERIOD: time::Duration = time::Duration::DAY;
+
+#[derive(Debug)]
+pub struct SchemaFailed;
+
+impl reject::Reject for SchemaFailed {}
+
+/// Object mapping each key of the newest `sample_id` documents between `start_id` and `end_id` to
+/// its predominant `jsonb_typeof`
+///
+/// JSON nulls only count if a key never has another type.
+fn schema_query(table: &str, start_id: usize, end_id: usize, sample_id: usize) -> String {
+    format!(
+        r#"
+            select coalesce(jsonb_object_agg(key, type), '{{}}'::jsonb) as doc from (
+                select distinct on (key) key, type from (
+                    select key, jsonb_typeof(value) as type, count(*) as count
+                    from (
+                        select doc
+                        from {}
+                        where tstamp between ${} and ${}
+                        order by tstamp desc
+                        limit ${}
+                    ) sampled, jsonb_each(doc)
+                    group by key, type
+                ) typed
+                order by key, type = 'null', count desc, type
+            ) predominant
+        "#,
+        table, start_id, end_id, sample_id
+    )
+}
+
+/// The schema query sampling the events of the `SAMPLE_PERIOD` before `now`
+fn statement(table: &str, now: OffsetDateTime) -> Statement {
+    Statement {
+        query: schema_query(table, 1, 2, 3),
+        params: vec![
+            Box::new(now - SAMPLE_PERIOD),
+            Box::new(now),
+            Box::new(SAMPLE_SIZE),
+        ],
+    }
+}
+
+pub(crate) async fn handler(table: String, db: Database) -> Result<impl Reply, Rejection> {
+    let db = db.pool.get().await.map_err(|err| {
+        error!("schema: {:?}", err);
+        reject::custom(SchemaFailed)
+    })?;
+    let statement = statement(&table, OffsetDateTime::now_utc());
+    let row = db
+        .query_one(statement.query.as_str(), &statement.sql_params())
+        .await
+        .map_err(|err| {
+            error!("schema: {:?}", err);
+            reject::custom(SchemaFailed)
+        })?;
+    let schema: Value = row.get("doc");
+    Ok(reply::json(&schema))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+    use time::macros::datetime;
+
+    #[test]
+    fn samples_newest_documents_of_the_last_day() {
+        let statement = statement("logs", datetime!(2022-03-04 05:06:07 UTC));
+        let sampled = statement
+            .query
+            .lines()
+            .map(str::trim)
+            .skip_while(|line| *line != "select doc")
+            .take(5)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            sampled,
+            [
+                "select doc",
+                "from logs",
+                "where tstamp between $1 and $2",
+                "order by tstamp desc",
+                "limit $3",
+            ]
+        );
+        assert_eq!(
+            statement.to_json()["params"],
+            json!(["2022-03-03T05:06:07Z", "2022-03-04T05:06:07Z", 500])
+        );
+    }
+
+    #[test]
+    fn most_frequent_type_wins() {
+        let query = schema_query("logs", 1, 2, 3);
+        assert!(query.contains("select key, jsonb_typeof(value) as type, count(*) as count"));
+        assert!(query.contains("group by key, type"));
+        assert!(query.contains("select distinct on (key) key, type"));
+        // null only if there is nothing else, ties are broken by name
+        assert!(query.contains("order by key, type = 'null', count desc, type"));
+        assert!(query.contains("coalesce(jsonb_object_agg(key, type), '{}'::jsonb) as doc"));
+    }
+}
@@ -0,0 +1,359 @@
+//! Accept loop serving HTTP or HTTPS connections with the tuning from `HttpSettings`
+use std::convert::Infallible;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpSocket};
+use tokio::sync::Semaphore;
+use tokio_rustls::TlsAcceptor;
+use warp::hyper::server::conn::Http;
+use warp::hyper::service::{service_fn, Service};
+use warp::hyper::{Body, Request, Response};
+
+use crate::config::HttpSettings;
+use crate::tls_server::ReloadableConfig;
+
+/// Listen on the configured address with the configured backlog
+pub fn bind(settings: &HttpSettings) -> io::Result<TcpListener> {
+    let socket = if settings.listen_address.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    socket.set_reuseaddr(true)?;
+    socket.bind(settings.listen_address)?;
+    socket.listen(settings.listen_backlog)
+}
+
+/// Client of a connection, added to each of its requests' extensions
+#[derive(Clone, Debug)]
+pub struct Peer {
+    pub addr: SocketAddr,
+    /// Subject of the client certificate, if one was presented
+    pub subject: Option<String>,
+}
+
+impl Peer {
+    fn from_tls(addr: SocketAddr, conn: &rustls::ServerConnection) -> Self {
+        let subject = conn
+            .peer_certificates()
+            .and_then(|certs| certs.first())
+            .and_then(|cert| x509_parser::parse_x509_certificate(&cert.0).ok())
+            .map(|(_, cert)| cert.subject().to_string());
+        Self { addr, subject }
+    }
+}
+
+/// `service` with `peer` inserted into every request
+fn with_peer<S>(
+    mut service: S,
+    peer: Peer,
+) -> impl Service<Request<Body>, Response = Response<Body>, Error = Infallible, Future = S::Future> + Send
+where
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible> + Send,
+{
+    service_fn(move |mut request: Request<Body>| {
+        request.extensions_mut().insert(peer.clone());
+        service.call(request)
+    })
+}
+
+struct Activity {
+    last: Instant,
+    received_data: bool,
+}
+
+/// Connection remembering when data was last sent or received
+struct Tracked<T> {
+    inner: T,
+    activity: Arc<Mutex<Activity>>,
+}
+
+impl<T> Tracked<T> {
+    fn touch(&self, received_data: bool) {
+        let mut activity = self.activity.lock().unwrap();
+        activity.last = Instant::now();
+        activity.received_data |= received_data;
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Tracked<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if buf.filled().len() > filled {
+            self.touch(true);
+        }
+        result
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Tracked<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = result {
+            if n > 0 {
+                self.touch(false);
+            }
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Serve one connection, ending keep-alive once it was quiet for `keep_alive_timeout`
+///
+/// A response still being produced is finished before the connection is closed. Connections
+/// that never sent anything are dropped right away.
+async fn serve_connection<I, S>(
+    http: Http,
+    io: I,
+    service: S,
+    keep_alive_timeout: Option<Duration>,
+) -> Result<(), warp::hyper::Error>
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible> + Send + 'static,
+    S::Future: Send + 'static,
+{
+    let activity = Arc::new(Mutex::new(Activity {
+        last: Instant::now(),
+        received_data: false,
+    }));
+    let io = Tracked {
+        inner: io,
+        activity: activity.clone(),
+    };
+    let conn = http.serve_connection(io, service);
+    tokio::pin!(conn);
+    let timeout = match keep_alive_timeout {
+        Some(timeout) => timeout,
+        None => return conn.await,
+    };
+    loop {
+        let deadline = activity.lock().unwrap().last + timeout;
+        tokio::select! {
+            result = conn.as_mut() => return result,
+            _ = tokio::time::sleep_until(deadline.into()) => {
+                let (last, received_data) = {
+                    let activity = activity.lock().unwrap();
+                    (activity.last, activity.received_data)
+                };
+                if last.elapsed() < timeout {
+                    continue;
+                }
+                if !received_data {
+                    return Ok(());
+                }
+                conn.as_mut().graceful_shutdown();
+                return conn.await;
+            }
+        }
+    }
+}
+
+pub struct Server {
+    http: Http,
+    keep_alive_timeout: Option<Duration>,
+    tls_handshake_timeout: Duration,
+    connections: Arc<Semaphore>,
+}
+
+impl Server {
+    pub fn new(settings: &HttpSettings) -> Self {
+        Self {
+            http: Http::new(),
+            keep_alive_timeout: match settings.keep_alive_timeout_sec {
+                0 => None,
+                seconds => Some(Duration::from_secs(seconds)),
+            },
+            tls_handshake_timeout: Duration::from_secs(settings.tls_handshake_timeout_sec.max(1)),
+            connections: Arc::new(Semaphore::new(settings.max_connections)),
+        }
+    }
+
+    /// Accept connections on `listener` and serve them using `service`
+    ///
+    /// Connections are wrapped in TLS if `tls` is given, clients not done with the handshake
+    /// within `tls_handshake_timeout_sec` are dropped. Once `max_connections` are open, new
+    /// connections wait in the listen backlog.
+    pub async fn serve<S>(
+        self,
+        listener: TcpListener,
+        tls: Option<Arc<ReloadableConfig>>,
+        service: S,
+    ) where
+        S: Service<Request<Body>, Response = Response<Body>, Error = Infallible>
+            + Clone
+            + Send
+            + 'static,
+        S::Future: Send + 'static,
+    {
+        loop {
+            let permit = self.connections.clone().acquire_owned().await.unwrap();
+            let (stream, peer) = match listener.accept().await {
+                Ok(conn) => conn,
+                Err(err) => {
+                    error!("Could not accept connection: {}", err);
+                    continue;
+                }
+            };
+            let http = self.http.clone();
+            let keep_alive_timeout = self.keep_alive_timeout;
+            let handshake_timeout = self.tls_handshake_timeout;
+            let service = service.clone();
+            let acceptor = tls
+                .as_ref()
+                .map(|config| TlsAcceptor::from(config.current()));
+            tokio::spawn(async move {
+                let result = match acceptor {
+                    Some(acceptor) => {
+                        match tokio::time::timeout(handshake_timeout, acceptor.accept(stream)).await
+                        {
+                            Ok(Ok(stream)) => {
+                                let service =
+                                    with_peer(service, Peer::from_tls(peer, stream.get_ref().1));
+                                serve_connection(http, stream, service, keep_alive_timeout).await
+                            }
+                            Ok(Err(err)) => {
+                                debug!("TLS handshake with {} failed: {}", peer, err);
+                                return;
+                            }
+                            Err(_) => {
+                                debug!("TLS handshake with {} timed out", peer);
+                                return;
+                            }
+                        }
+                    }
+                    None => {
+                        let service = with_peer(
+                            service,
+                            Peer {
+                                addr: peer,
+                                subject: None,
+                            },
+                        );
+                        serve_connection(http, stream, service, keep_alive_timeout).await
+                    }
+                };
+                if let Err(err) = result {
+                    debug!("Connection from {} failed: {}", peer, err);
+                }
+                drop(permit);
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::SocketAddr;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use tokio::time::timeout;
+    use warp::Filter;
+
+    async fn start(settings: HttpSettings) -> SocketAddr {
+        let listener = bind(&settings).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let service = warp::service(warp::any().map(|| "ok"));
+        tokio::spawn(Server::new(&settings).serve(listener, None, service));
+        addr
+    }
+
+    async fn request(stream: &mut TcpStream) {
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nhost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+    }
+
+    async fn response(stream: &mut TcpStream) -> String {
+        let mut buf = [0; 1024];
+        let n = stream.read(&mut buf).await.unwrap();
+        String::from_utf8_lossy(&buf[..n]).into()
+    }
+
+    async fn get(stream: &mut TcpStream) -> String {
+        request(stream).await;
+        response(stream).await
+    }
+
+    #[test]
+    fn settings_from_config() {
+        let settings: HttpSettings = serde_yaml::from_str(
+            "listen_address: 127.0.0.1:0\nlisten_backlog: 16\nkeep_alive_timeout_sec: 5\nmax_connections: 2\n",
+        )
+        .unwrap();
+        assert_eq!(settings.listen_backlog, 16);
+        assert_eq!(settings.keep_alive_timeout_sec, 5);
+        assert_eq!(settings.max_connections, 2);
+
+        let server = Server::new(&settings);
+        assert_eq!(server.keep_alive_timeout, Some(Duration::from_secs(5)));
+        assert_eq!(server.tls_handshake_timeout, Duration::from_secs(10));
+        assert_eq!(server.connections.available_permits(), 2);
+    }
+
+    #[tokio::test]
+    async fn connection_limit() {
+        let addr = start(HttpSettings {
+            listen_address: "127.0.0.1:0".parse().unwrap(),
+            max_connections: 1,
+            ..Default::default()
+        })
+        .await;
+
+        let mut first = TcpStream::connect(addr).await.unwrap();
+        assert!(get(&mut first).await.starts_with("HTTP/1.1 200"));
+
+        // waits in the backlog until the first connection is closed
+        let mut second = TcpStream::connect(addr).await.unwrap();
+        request(&mut second).await;
+        assert!(timeout(Duration::from_millis(200), response(&mut second))
+            .await
+            .is_err());
+        drop(first);
+        assert!(response(&mut second).await.starts_with("HTTP/1.1 200"));
+    }
+
+    #[tokio::test]
+    async fn idle_connections_are_closed() {
+        let addr = start(HttpSettings {
+            listen_address: "127.0.0.1:0".parse().unwrap(),
+            keep_alive_timeout_sec: 1,
+            ..Default::default()
+        })
+        .await;
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let mut buf = [0; 16];
+        let read = timeout(Duration::from_secs(5), stream.read(&mut buf)).await;
+        assert_eq!(read.unwrap().unwrap(), 0);
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        assert!(get(&mut stream).await.starts_with("HTTP/1.1 200"));
+        let read = timeout(Duration::from_secs(5), stream.read(&mut buf)).await;
+        assert_eq!(read.unwrap().unwrap(), 0);
+    }
+}
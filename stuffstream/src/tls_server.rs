@@ -0,0 +1,237 @@
+//! Server TLS setup whose certificate can be replaced without a restart
+use rustls::server::{
+    AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, NoClientAuth,
+};
+use rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig};
+use rustls_pemfile::{read_one, Item};
+use std::sync::{Arc, RwLock};
+use std::{fs, io, iter};
+use tokio::signal::unix::{signal, SignalKind};
+
+use logstuff::tls::{self, TlsSettings};
+
+use crate::app::Error;
+use crate::config::{HttpSettings, TlsClientAuth};
+
+fn load_certs(path: &str) -> Result<Vec<Certificate>, Error> {
+    let mut reader = io::BufReader::new(fs::File::open(path)?);
+    let mut certs = Vec::new();
+    for item in iter::from_fn(|| read_one(&mut reader).transpose()) {
+        if let Item::X509Certificate(cert) = item? {
+            certs.push(Certificate(cert));
+        }
+    }
+    Ok(certs)
+}
+
+fn load_private_key(path: &str) -> Result<PrivateKey, Error> {
+    let mut reader = io::BufReader::new(fs::File::open(path)?);
+    for item in iter::from_fn(|| read_one(&mut reader).transpose()) {
+        match item? {
+            Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) => {
+                return Ok(PrivateKey(key))
+            }
+            _ => continue,
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("no private key found in {}", path),
+    )
+    .into())
+}
+
+fn client_roots(path: &str) -> Result<RootCertStore, Error> {
+    let settings = TlsSettings {
+        ca_certs: vec![path.to_string()],
+        disable_system_trust: true,
+        ..Default::default()
+    };
+    Ok(settings.root_trust_store()?)
+}
+
+/// Build the server's TLS config from the files named in `settings`
+pub fn server_config(settings: &HttpSettings) -> Result<ServerConfig, Error> {
+    let verifier = match &settings.tls_client_auth {
+        None => NoClientAuth::new(),
+        Some(TlsClientAuth::Required { trusted_certs }) => {
+            AllowAnyAuthenticatedClient::new(client_roots(trusted_certs)?)
+        }
+        Some(TlsClientAuth::Optional { trusted_certs }) => {
+            AllowAnyAnonymousOrAuthenticatedClient::new(client_roots(trusted_certs)?)
+        }
+    };
+    let mut config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(
+            load_certs(&settings.tls_cert)?,
+            load_private_key(&settings.tls_key)?,
+        )
+        .map_err(tls::Error::from)?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+/// Server TLS config that is re-read from disk on `reload`
+///
+/// New connections use the most recently loaded config, established connections are unaffected.
+pub struct ReloadableConfig {
+    settings: HttpSettings,
+    current: RwLock<Arc<ServerConfig>>,
+}
+
+impl ReloadableConfig {
+    pub fn new(settings: &HttpSettings) -> Result<Self, Error> {
+        Ok(Self {
+            settings: settings.clone(),
+            current: RwLock::new(Arc::new(server_config(settings)?)),
+        })
+    }
+
+    pub fn current(&self) -> Arc<ServerConfig> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Load certificate and key again, keeping the old ones if that fails
+    pub fn reload(&self) -> Result<(), Error> {
+        let config = server_config(&self.settings)?;
+        *self.current.write().unwrap() = Arc::new(config);
+        Ok(())
+    }
+}
+
+/// Reload the server certificate whenever SIGHUP is received
+pub async fn reload_on_hangup(config: Arc<ReloadableConfig>) -> Result<(), Error> {
+    let mut hangup = signal(SignalKind::hangup())?;
+    while hangup.recv().await.is_some() {
+        match config.reload() {
+            Ok(()) => info!("Reloaded server certificate"),
+            Err(err) => error!(
+                "Could not reload server certificate, keeping the old one: {}",
+                err
+            ),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rustls::{ClientConfig, ServerName};
+    use std::net::SocketAddr;
+    use std::path::PathBuf;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_rustls::TlsConnector;
+    use warp::Filter;
+
+    struct Pki {
+        ca: String,
+        cert: String,
+        key: String,
+    }
+
+    fn pki() -> Pki {
+        let mut ca_params = rcgen::CertificateParams::new(Vec::new());
+        ca_params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        let ca = rcgen::Certificate::from_params(ca_params).unwrap();
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        Pki {
+            ca: ca.serialize_pem().unwrap(),
+            cert: cert.serialize_pem_with_signer(&ca).unwrap(),
+            key: cert.serialize_private_key_pem(),
+        }
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("stuffstream-{}-{}", std::process::id(), name))
+    }
+
+    fn install(pki: &Pki, settings: &HttpSettings) {
+        fs::write(&settings.tls_cert, &pki.cert).unwrap();
+        fs::write(&settings.tls_key, &pki.key).unwrap();
+    }
+
+    async fn handshake(addr: SocketAddr, ca: &str) -> bool {
+        let mut roots = RootCertStore::empty();
+        let ca = rustls_pemfile::certs(&mut ca.as_bytes()).unwrap();
+        roots.add_parsable_certificates(&ca);
+        let config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let stream = TcpStream::connect(addr).await.unwrap();
+        TlsConnector::from(Arc::new(config))
+            .connect(ServerName::try_from("localhost").unwrap(), stream)
+            .await
+            .is_ok()
+    }
+
+    #[tokio::test]
+    async fn reload_picks_up_new_certificate() {
+        let settings = HttpSettings {
+            use_tls: true,
+            tls_cert: temp_path("cert.pem").to_string_lossy().into(),
+            tls_key: temp_path("key.pem").to_string_lossy().into(),
+            ..Default::default()
+        };
+        let (old, new) = (pki(), pki());
+        install(&old, &settings);
+
+        let config = Arc::new(ReloadableConfig::new(&settings).unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let service = warp::service(warp::any().map(|| "ok"));
+        let server = crate::server::Server::new(&settings);
+        tokio::spawn(server.serve(listener, Some(config.clone()), service));
+
+        assert!(handshake(addr, &old.ca).await);
+        assert!(!handshake(addr, &new.ca).await);
+
+        install(&new, &settings);
+        // nothing changes until the reload
+        assert!(handshake(addr, &old.ca).await);
+        config.reload().unwrap();
+        assert!(handshake(addr, &new.ca).await);
+        assert!(!handshake(addr, &old.ca).await);
+
+        // a broken file keeps the current certificate
+        fs::write(&settings.tls_key, "garbage").unwrap();
+        assert!(config.reload().is_err());
+        assert!(handshake(addr, &new.ca).await);
+
+        fs::remove_file(&settings.tls_cert).unwrap();
+        fs::remove_file(&settings.tls_key).unwrap();
+    }
+
+    #[tokio::test]
+    async fn stalled_handshake_releases_connection() {
+        let settings = HttpSettings {
+            use_tls: true,
+            tls_cert: temp_path("stalled-cert.pem").to_string_lossy().into(),
+            tls_key: temp_path("stalled-key.pem").to_string_lossy().into(),
+            tls_handshake_timeout_sec: 1,
+            max_connections: 1,
+            ..Default::default()
+        };
+        let pki = pki();
+        install(&pki, &settings);
+
+        let config = Arc::new(ReloadableConfig::new(&settings).unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let service = warp::service(warp::any().map(|| "ok"));
+        let server = crate::server::Server::new(&settings);
+        tokio::spawn(server.serve(listener, Some(config), service));
+
+        // never starts the handshake, but only holds the only connection until the timeout
+        let _stalled = TcpStream::connect(addr).await.unwrap();
+        let handshake =
+            tokio::time::timeout(std::time::Duration::from_secs(5), handshake(addr, &pki.ca));
+        assert!(handshake.await.unwrap());
+
+        fs::remove_file(&settings.tls_cert).unwrap();
+        fs::remove_file(&settings.tls_key).unwrap();
+    }
+}
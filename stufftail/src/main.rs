@@ -1,10 +1,16 @@
 use clap::Parser;
+use log::warn;
 use postgres::types::ToSql;
 use postgres_native_tls::MakeTlsConnector;
-use std::thread;
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+use std::{fs, io, thread};
 use time::macros::format_description;
+use time::OffsetDateTime;
 
-use logstuff::event::Event;
+use logstuff::db::{with_retry, Backoff};
+use logstuff::event::printable;
+use logstuff::logging::{self, LogFormat};
 use logstuff::tls::TlsSettings;
 use logstuff_query::{ExpressionParser, QueryParams};
 
@@ -52,13 +58,42 @@ struct Args {
     #[arg(short, long)]
     query: Option<String>,
 
-    /// Print field name in output
+    /// Print field name in output, `id` and `tstamp` print the row's columns
     #[arg(short, long, value_name = "NAME")]
     field: Vec<String>,
 
     /// CA certificate (bundle) to verify server's cert
     #[arg(short, long, value_name = "FILE")]
     ca_cert: Vec<String>,
+
+    /// Remember the last printed id in this file to continue from there after a restart
+    #[arg(short, long, value_name = "FILE")]
+    state_file: Option<PathBuf>,
+
+    /// Format of log lines: text or json
+    #[arg(long, value_name = "FORMAT", default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+}
+
+/// Something printed for each event
+#[derive(Debug, PartialEq)]
+enum Field {
+    /// Row id
+    Id,
+    /// Time stamp column
+    Timestamp,
+    /// Member of the event document
+    Doc(String),
+}
+
+impl From<String> for Field {
+    fn from(name: String) -> Self {
+        match name.as_str() {
+            "id" => Field::Id,
+            "tstamp" => Field::Timestamp,
+            _ => Field::Doc(name),
+        }
+    }
 }
 
 #[derive(Default, Debug)]
@@ -68,9 +103,11 @@ struct Settings {
     poll_interval_ms: u64,
     query_expr: String,
     query_params: QueryParams,
-    fields: Vec<String>,
+    fields: Vec<Field>,
     db_config: String,
     tls: TlsSettings,
+    state_file: Option<PathBuf>,
+    log_format: LogFormat,
 }
 
 impl Settings {
@@ -93,6 +130,7 @@ impl Settings {
         } else {
             matches.field
         };
+        let fields = fields.into_iter().map(Field::from).collect();
 
         let mut tls = TlsSettings::default();
         if !matches.ca_cert.is_empty() {
@@ -108,86 +146,235 @@ impl Settings {
             fields,
             db_config: matches.db_connection,
             tls,
+            state_file: matches.state_file,
+            log_format: matches.log_format,
         }
     }
 }
 
-fn prepare_query<'a>(
-    client: &'_ mut postgres::Client,
-    settings: &'a Settings,
-) -> (postgres::Statement, Vec<&'a (dyn ToSql + Sync)>) {
-    let next_param = settings.query_params.len() + 1;
-    let query = format!(
+impl Settings {
+    /// Names of the document members to select, each passed as parameter
+    fn doc_fields(&self) -> impl Iterator<Item = (usize, &String)> {
+        self.fields
+            .iter()
+            .enumerate()
+            .filter_map(|(index, field)| match field {
+                Field::Doc(name) => Some((index, name)),
+                _ => None,
+            })
+    }
+}
+
+/// Poll for new rows, selecting id and time stamp along with each requested document member
+///
+/// Document members are selected as `field_<index in settings.fields>`.
+fn poll_query(settings: &Settings) -> String {
+    let mut next_param = settings.query_params.len() + 1;
+    let mut columns = vec!["id".to_string(), "tstamp".to_string()];
+    for (index, _) in settings.doc_fields() {
+        columns.push(format!("doc -> ${}::text as field_{}", next_param, index));
+        next_param += 1;
+    }
+    format!(
         r#"
-        select id, tstamp, doc from logs
+        select {} from logs
         where {}
         and id > ${}
         and tstamp > now() - cast(${}::varchar as interval)
         order by id desc
         limit ${}
         "#,
+        columns.join(", "),
         settings.query_expr,
         next_param,
         next_param + 1,
         next_param + 2
-    );
+    )
+}
 
-    let our_params = settings
+fn prepare_query<'a>(
+    client: &'_ mut postgres::Client,
+    settings: &'a Settings,
+) -> Result<(postgres::Statement, Vec<&'a (dyn ToSql + Sync)>), postgres::Error> {
+    let mut our_params = settings
         .query_params
         .iter()
         .map(|e| e as &(dyn ToSql + Sync))
         .collect::<Vec<&(dyn ToSql + Sync)>>();
+    our_params.extend(
+        settings
+            .doc_fields()
+            .map(|(_, name)| name as &(dyn ToSql + Sync)),
+    );
 
-    let stmt = client.prepare(query.as_str()).unwrap();
-    (stmt, our_params)
+    let stmt = client.prepare(poll_query(settings).as_str())?;
+    Ok((stmt, our_params))
+}
+
+/// A polled row, `values` holding the document members in the order of `Settings.fields`
+struct Line {
+    id: i32,
+    timestamp: OffsetDateTime,
+    values: Vec<Option<Value>>,
+}
+
+impl Line {
+    fn from_row(row: &postgres::Row, settings: &Settings) -> Self {
+        let values = settings
+            .fields
+            .iter()
+            .enumerate()
+            .map(|(index, field)| match field {
+                Field::Doc(_) => row.get(format!("field_{}", index).as_str()),
+                _ => None,
+            })
+            .collect();
+        Self {
+            id: row.get("id"),
+            timestamp: row.get("tstamp"),
+            values,
+        }
+    }
+
+    fn format(&self, fields: &[Field]) -> String {
+        let timeformat = format_description!("[year]-[month]-[day] [hour]:[minute]:[second]");
+        let timestamp = self.timestamp.format(&timeformat).unwrap();
+        let values = fields
+            .iter()
+            .zip(&self.values)
+            .map(|(field, value)| match (field, value) {
+                (Field::Id, _) => self.id.to_string(),
+                (Field::Timestamp, _) => timestamp.clone(),
+                (Field::Doc(_), Some(value)) => printable(value),
+                (Field::Doc(_), None) => "None".to_string(),
+            })
+            .collect::<Vec<String>>();
+        format!("{} {}", timestamp, values.join(" "))
+    }
+}
+
+/// Last printed id stored in `path`, 0 if there is none or it can't be read
+fn read_last_id(path: &Path) -> i32 {
+    match fs::read_to_string(path) {
+        Ok(content) => content.trim().parse().unwrap_or_else(|err| {
+            warn!("Ignoring state file {}: {}", path.display(), err);
+            0
+        }),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => 0,
+        Err(err) => {
+            warn!("Ignoring state file {}: {}", path.display(), err);
+            0
+        }
+    }
+}
+
+/// Store `last_id` in `path`, replacing the file at once so it never holds a partial id
+fn write_last_id(path: &Path, last_id: i32) -> io::Result<()> {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    fs::write(&temp, format!("{}\n", last_id))?;
+    fs::rename(&temp, path)
 }
 
 fn main() {
-    env_logger::init();
     let settings = Settings::from_cli_args();
+    logging::init(settings.log_format).unwrap();
     let connector = MakeTlsConnector::new(settings.tls.connector().unwrap());
-    let mut client = postgres::Client::connect(&settings.db_config, connector).unwrap();
+    let backoff = Backoff::default();
+    let connect = || postgres::Client::connect(&settings.db_config, connector.clone());
+    let mut client = with_retry(&backoff, connect).unwrap();
 
-    let (stmt, our_params) = prepare_query(&mut client, &settings);
-    let mut last_id = 0;
+    let (mut stmt, our_params) = prepare_query(&mut client, &settings).unwrap();
+    let mut last_id = settings.state_file.as_deref().map_or(0, read_last_id);
     loop {
         let mut query_params = our_params[..].to_vec();
         query_params.push(&last_id);
         query_params.push(&settings.max_age);
         query_params.push(&settings.max_lines);
-        client
-            .query(&stmt, &query_params)
-            .unwrap()
-            .iter()
-            .rev()
-            .for_each(|row| {
-                let event = Event {
-                    timestamp: row.get("tstamp"),
-                    doc: row.get("doc"),
-                };
-                print_event(event, &settings);
-                let id: i32 = row.get("id");
-                last_id = max(last_id, id);
-            });
+        let rows = with_retry(&backoff, || {
+            if client.is_closed() {
+                client = connect()?;
+                stmt = prepare_query(&mut client, &settings)?.0;
+            }
+            client.query(&stmt, &query_params)
+        })
+        .unwrap();
+        rows.iter().rev().for_each(|row| {
+            let line = Line::from_row(row, &settings);
+            println!("{}", line.format(&settings.fields));
+            last_id = max(last_id, line.id);
+        });
+        if let (Some(path), false) = (&settings.state_file, rows.is_empty()) {
+            if let Err(err) = write_last_id(path, last_id) {
+                warn!("Could not write state file {}: {}", path.display(), err);
+            }
+        }
         thread::sleep(std::time::Duration::from_millis(settings.poll_interval_ms));
     }
 }
 
-fn print_event(event: Event, settings: &Settings) {
-    let timeformat = format_description!("[year]-[month]-[day] [hour]:[minute]:[second]");
-    println!(
-        "{} {}",
-        event.timestamp.format(&timeformat).unwrap(),
-        settings
-            .fields
-            .iter()
-            .map(|field| {
-                match event.get_printable(field) {
-                    Some(content) => content,
-                    None => "None".to_string(),
-                }
-            })
-            .collect::<Vec<String>>()
-            .join(" ")
-    );
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+    use time::macros::datetime;
+
+    fn settings(fields: &[&str]) -> Settings {
+        Settings {
+            query_expr: "1 = 1".into(),
+            fields: fields.iter().map(|f| Field::from(f.to_string())).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn select_requested_fields() {
+        let query = poll_query(&settings(&["id", "hostname", "tstamp", "msg"]));
+        assert!(query.contains(
+            "select id, tstamp, doc -> $1::text as field_1, doc -> $2::text as field_3 from logs"
+        ));
+        assert!(query.contains("and id > $3"));
+        assert!(query.contains("limit $5"));
+
+        let mut settings = settings(&["id"]);
+        settings.query_params = vec![json!("host")];
+        let query = poll_query(&settings);
+        assert!(query.contains("select id, tstamp from logs"));
+        assert!(query.contains("and id > $2"));
+    }
+
+    #[test]
+    fn state_file_round_trip() {
+        let dir = std::env::temp_dir().join(format!("stufftail-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("state");
+
+        assert_eq!(read_last_id(&path), 0);
+        write_last_id(&path, 1234).unwrap();
+        assert_eq!(read_last_id(&path), 1234);
+        write_last_id(&path, 1300).unwrap();
+        assert_eq!(read_last_id(&path), 1300);
+        assert!(!dir.join("state.tmp").exists());
+
+        fs::write(&path, "garbage").unwrap();
+        assert_eq!(read_last_id(&path), 0);
+        // a directory can't be read as file
+        assert_eq!(read_last_id(&dir), 0);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn print_columns_and_members() {
+        let settings = settings(&["id", "hostname", "tstamp", "missing"]);
+        let line = Line {
+            id: 42,
+            timestamp: datetime!(2022-03-04 05:06:07 UTC),
+            values: vec![None, Some(json!("host1")), None, None],
+        };
+        assert_eq!(
+            line.format(&settings.fields),
+            "2022-03-04 05:06:07 42 host1 2022-03-04 05:06:07 None"
+        );
+    }
 }